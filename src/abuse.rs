@@ -0,0 +1,313 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many times the same message may be repeated before it is suppressed
+const MAX_REPEATS: u32 = 2;
+
+/// Suppressed messages allowed before the client gets banned
+pub const STRIKE_LIMIT: u32 = 3;
+
+/// Minimum size before a message is checked for paste floods
+const FLOOD_MIN_CHARS: usize = 200;
+const FLOOD_MIN_WORDS: usize = 30;
+
+/// Moderation outcome for one message, also recorded in JSONL logs
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    Ok,
+    Repeated,
+    Flood,
+}
+
+/// Per-session detector for repeated messages and copy-paste floods
+#[derive(Default)]
+pub struct AbuseDetector {
+    last_message: Option<String>,
+    repeat_count: u32,
+    strikes: u32,
+}
+
+impl AbuseDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn check(&mut self, input: &str) -> Verdict {
+        let normalized = input.trim().to_lowercase();
+
+        if self.last_message.as_deref() == Some(normalized.as_str()) {
+            self.repeat_count += 1;
+        } else {
+            self.repeat_count = 0;
+            self.last_message = Some(normalized);
+        }
+
+        let verdict = if self.repeat_count >= MAX_REPEATS {
+            Verdict::Repeated
+        } else if is_flood(input) {
+            Verdict::Flood
+        } else {
+            Verdict::Ok
+        };
+
+        if !matches!(verdict, Verdict::Ok) {
+            self.strikes += 1;
+        }
+
+        verdict
+    }
+
+    pub fn strikes(&self) -> u32 {
+        self.strikes
+    }
+}
+
+/// Detect massive pastes made of a handful of repeated words or characters
+fn is_flood(input: &str) -> bool {
+    if input.chars().count() < FLOOD_MIN_CHARS {
+        return false;
+    }
+
+    let distinct_chars: HashSet<char> = input.chars().filter(|c| !c.is_whitespace()).collect();
+    if distinct_chars.len() <= 3 {
+        return true;
+    }
+
+    let words: Vec<String> = input.split_whitespace().map(|w| w.to_lowercase()).collect();
+    if words.len() < FLOOD_MIN_WORDS {
+        return false;
+    }
+
+    let unique: HashSet<&String> = words.iter().collect();
+    unique.len() * 4 < words.len()
+}
+
+/// Longest ban an operator can hand out
+pub const MAX_BAN: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Temporary IP bans shared by all sessions
+pub struct BanList {
+    duration: Duration,
+    bans: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl BanList {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            bans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ban(&self, ip: IpAddr) {
+        if self.duration.is_zero() {
+            return;
+        }
+        self.ban_for(ip, self.duration);
+    }
+
+    /// Ban regardless of the configured duration, used by operators,
+    /// for at most `MAX_BAN`
+    pub fn ban_for(&self, ip: IpAddr, duration: Duration) {
+        let Some(until) = Instant::now().checked_add(duration.min(MAX_BAN)) else {
+            return;
+        };
+        self.bans.lock().unwrap().insert(ip, until);
+    }
+
+    /// Returns false when the IP wasn't banned
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.bans.lock().unwrap().remove(&ip).is_some()
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        let now = Instant::now();
+        bans.retain(|_, until| *until > now);
+        bans.contains_key(&ip)
+    }
+}
+
+/// Failed logins an address or account gets before it has to wait
+const FREE_LOGIN_FAILURES: u32 = 5;
+/// Wait after the free failures, doubling with every further one
+const LOGIN_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_LOGIN_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// Failures are forgotten this long after the last one
+const LOGIN_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct LoginFailures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Slows down password guessing on /login, per address and per account so
+/// neither many accounts from one address nor one account from many
+/// addresses get unlimited tries, and on the admin console per address
+#[derive(Default)]
+pub struct LoginThrottle {
+    failures: Mutex<HashMap<String, LoginFailures>>,
+}
+
+impl LoginThrottle {
+    fn keys(ip: IpAddr, account: &str) -> [String; 2] {
+        [ip.to_string(), format!("@{}", account)]
+    }
+
+    /// The admin console counts apart from /login, so neither locks out the other
+    fn admin_key(ip: IpAddr) -> [String; 1] {
+        [format!("admin {}", ip)]
+    }
+
+    /// How long `ip` or `account` still has to wait before the next try
+    pub fn wait(&self, ip: IpAddr, account: &str) -> Option<Duration> {
+        self.wait_for(&Self::keys(ip, account))
+    }
+
+    pub fn failed(&self, ip: IpAddr, account: &str) {
+        self.fail(&Self::keys(ip, account));
+    }
+
+    /// A good password clears the account's and the address's failures
+    pub fn succeeded(&self, ip: IpAddr, account: &str) {
+        self.clear(&Self::keys(ip, account));
+    }
+
+    /// How long `ip` still has to wait before its next admin password
+    pub fn admin_wait(&self, ip: IpAddr) -> Option<Duration> {
+        self.wait_for(&Self::admin_key(ip))
+    }
+
+    pub fn admin_failed(&self, ip: IpAddr) {
+        self.fail(&Self::admin_key(ip));
+    }
+
+    pub fn admin_succeeded(&self, ip: IpAddr) {
+        self.clear(&Self::admin_key(ip));
+    }
+
+    fn wait_for(&self, keys: &[String]) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        keys.iter()
+            .filter_map(|key| failures.get(key)?.locked_until)
+            .filter(|until| *until > now)
+            .max()
+            .map(|until| until - now)
+    }
+
+    fn fail(&self, keys: &[String]) {
+        let mut failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        failures.retain(|_, f| now.duration_since(f.last) < LOGIN_MEMORY);
+        for key in keys {
+            let entry = failures.entry(key.clone()).or_insert(LoginFailures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            entry.count += 1;
+            entry.last = now;
+            if let Some(extra) = entry.count.checked_sub(FREE_LOGIN_FAILURES) {
+                let backoff = LOGIN_BACKOFF.saturating_mul(1 << extra.min(16)).min(MAX_LOGIN_BACKOFF);
+                entry.locked_until = Some(now + backoff);
+            }
+        }
+    }
+
+    fn clear(&self, keys: &[String]) {
+        let mut failures = self.failures.lock().unwrap();
+        for key in keys {
+            failures.remove(key);
+        }
+    }
+}
+
+/// Per-IP reconnect limiter applied before any session setup
+pub struct ConnectThrottle {
+    limit: usize,
+    window: Duration,
+    recent: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl ConnectThrottle {
+    /// `limit` connections per minute per IP, 0 disables the throttle
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            window: Duration::from_secs(60),
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a connection attempt, returning false when the IP is over the limit
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        let now = Instant::now();
+
+        // Forget attempts that fell out of the window
+        recent.retain(|_, attempts| {
+            while attempts.front().is_some_and(|t| now.duration_since(*t) > self.window) {
+                attempts.pop_front();
+            }
+            !attempts.is_empty()
+        });
+
+        let attempts = recent.entry(ip).or_default();
+        attempts.push_back(now);
+        attempts.len() <= self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_logins_lock_the_address_and_the_account() {
+        let throttle = LoginThrottle::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        for _ in 0..FREE_LOGIN_FAILURES {
+            assert!(throttle.wait(ip, "alice").is_none());
+            throttle.failed(ip, "alice");
+        }
+        assert!(throttle.wait(ip, "bob").is_some());
+        assert!(throttle.wait(other, "alice").is_some());
+        assert!(throttle.wait(other, "bob").is_none());
+
+        throttle.failed(ip, "alice");
+        assert!(throttle.wait(ip, "alice").unwrap() > LOGIN_BACKOFF);
+
+        throttle.succeeded(ip, "alice");
+        assert!(throttle.wait(ip, "alice").is_none());
+    }
+
+    #[test]
+    fn admin_failures_are_counted_apart() {
+        let throttle = LoginThrottle::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..FREE_LOGIN_FAILURES {
+            throttle.admin_failed(ip);
+        }
+        assert!(throttle.admin_wait(ip).is_some());
+        assert!(throttle.wait(ip, "admin").is_none());
+    }
+
+    #[test]
+    fn huge_bans_are_capped() {
+        let bans = BanList::new(Duration::ZERO);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        bans.ban_for(ip, Duration::MAX);
+        assert!(bans.is_banned(ip));
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::{Context, Result, anyhow, bail};
+use argon2::Argon2;
+use argon2::password_hash::{
+    PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::profile;
+
+/// Credentials stored next to the account's profile and chats
+#[derive(Serialize, Deserialize)]
+struct Account {
+    password_hash: String,
+    created: DateTime<Local>,
+}
+
+/// Directory of a registered account, laid out like an IP client directory
+pub fn account_dir(logs_dir: &str, username: &str) -> PathBuf {
+    Path::new(logs_dir).join("accounts").join(username)
+}
+
+fn validate_username(username: &str) -> Result<()> {
+    let valid_chars = username
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if !(3..=32).contains(&username.len()) || !valid_chars {
+        bail!("Usernames are 3-32 characters of a-z, 0-9, _ and -");
+    }
+    Ok(())
+}
+
+/// Create an account, returns its directory
+pub fn register(logs_dir: &str, username: &str, password: &str) -> Result<PathBuf> {
+    validate_username(username)?;
+    if password.len() < 6 {
+        bail!("Passwords need at least 6 characters");
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))?
+        .to_string();
+    let account = Account {
+        password_hash,
+        created: Local::now(),
+    };
+
+    let dir = account_dir(logs_dir, username);
+    fs::create_dir_all(&dir).context("Failed to create account directory")?;
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dir.join("account.json"))
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            bail!("The username {} is taken", username)
+        }
+        Err(e) => return Err(e).context("Failed to create account"),
+    };
+    file.write_all(serde_json::to_string_pretty(&account)?.as_bytes())
+        .context("Failed to write account")?;
+
+    Ok(dir)
+}
+
+/// Check credentials, returns the account directory
+pub fn login(logs_dir: &str, username: &str, password: &str) -> Result<PathBuf> {
+    let dir = account_dir(logs_dir, username);
+    let account: Account = validate_username(username)
+        .ok()
+        .and_then(|_| fs::read_to_string(dir.join("account.json")).ok())
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .ok_or_else(|| anyhow!("Invalid username or password"))?;
+
+    let hash = PasswordHash::new(&account.password_hash)
+        .map_err(|e| anyhow!("Corrupt account {}: {}", username, e))?;
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .map_err(|_| anyhow!("Invalid username or password"))?;
+
+    Ok(dir)
+}
+
+/// Whether an IP client directory holds anything worth carrying over
+pub fn has_history(client_dir: &Path) -> bool {
+    client_dir.join("chats").is_dir() || !matches!(profile::load(client_dir), Ok(None))
+}
+
+/// Copy the chats, exports and profile of an IP client directory into an account.
+/// Chat logs of the same day are appended, profile fields the account already
+/// has win. Returns the number of chat logs carried over.
+pub fn merge_history(from: &Path, into: &Path, remove_source: bool) -> Result<usize> {
+    let mut merged = 0;
+
+    for sub in ["chats", "exports"] {
+        let Ok(entries) = fs::read_dir(from.join(sub)) else {
+            continue;
+        };
+        let target_dir = into.join(sub);
+        fs::create_dir_all(&target_dir)
+            .with_context(|| format!("Failed to create {}", target_dir.display()))?;
+
+        for entry in entries.flatten() {
+            let target = target_dir.join(entry.file_name());
+            let content = fs::read(entry.path())
+                .with_context(|| format!("Failed to read {}", entry.path().display()))?;
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&target)
+                .with_context(|| format!("Failed to open {}", target.display()))?;
+            file.write_all(&content)
+                .with_context(|| format!("Failed to write {}", target.display()))?;
+            if sub == "chats" {
+                merged += 1;
+            }
+        }
+    }
+
+    if let Some(old) = profile::load(from)? {
+        profile::update(into, |p| p.fill_from(old))?;
+    }
+
+    if remove_source {
+        fs::remove_dir_all(from)
+            .with_context(|| format!("Failed to remove {}", from.display()))?;
+    }
+
+    Ok(merged)
+}
@@ -0,0 +1,806 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::{error, info, warn};
+
+use crate::abuse::{ConnectThrottle, MAX_BAN};
+use crate::audit::Outcome;
+use crate::escalation;
+use crate::geoip::GeoTag;
+use crate::handover;
+use crate::logger;
+use crate::logsearch;
+use crate::profile;
+use crate::schedule;
+use crate::session::SessionContext;
+use crate::stats::{self, human_size};
+use crate::totp;
+
+/// Instructions the admin console sends to a running session
+pub enum Control {
+    Kick,
+    Broadcast(String),
+    /// The server is stopping, save the conversation and end
+    Shutdown,
+    /// An operator takes over from the AI; the user's messages go here until `Release`
+    Attach(UnboundedSender<String>),
+    /// A line from the attached operator
+    Operator(String),
+    /// The operator hands the session back to the AI
+    Release,
+}
+
+struct SessionEntry {
+    addr: SocketAddr,
+    /// Country and network of the address, with --geoip-db or --geoip-asn-db
+    geo: Option<GeoTag>,
+    since: DateTime<Local>,
+    control: UnboundedSender<Control>,
+    /// When the user asked for a human, cleared once an operator attaches
+    escalated: Option<DateTime<Local>>,
+    usage: Arc<SessionUsage>,
+}
+
+/// A connected session as the admin console and metrics see it
+pub struct SessionInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub geo: Option<GeoTag>,
+    pub since: DateTime<Local>,
+    pub escalated: Option<DateTime<Local>>,
+    pub usage: UsageSnapshot,
+}
+
+/// What a session has used so far, kept up to date by the session itself
+#[derive(Default)]
+pub struct SessionUsage {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    messages: AtomicU64,
+    tokens: AtomicU64,
+    history_bytes: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UsageSnapshot {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Chat messages the user sent
+    pub messages: u64,
+    /// Prompt and reply tokens of the session's LLM calls, estimated where the backend didn't say
+    pub tokens: u64,
+    /// Conversation held in memory
+    pub history_bytes: u64,
+}
+
+impl SessionUsage {
+    pub fn add_bytes_in(&self, bytes: u64) {
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_bytes_out(&self, total: u64) {
+        self.bytes_out.store(total, Ordering::Relaxed);
+    }
+
+    pub fn add_message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// One LLM call with the tokens the backend counted
+    pub fn add_tokens(&self, tokens: u64) {
+        self.tokens.fetch_add(tokens, Ordering::Relaxed);
+    }
+
+    /// One LLM call the backend gave no count for, with estimated tokens
+    pub fn add_estimated(&self, tokens: u64) {
+        self.tokens.fetch_add(tokens, Ordering::Relaxed);
+    }
+
+    pub fn set_history_bytes(&self, bytes: u64) {
+        self.history_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> UsageSnapshot {
+        UsageSnapshot {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            messages: self.messages.load(Ordering::Relaxed),
+            tokens: self.tokens.load(Ordering::Relaxed),
+            history_bytes: self.history_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Live sessions the admin console can reach
+#[derive(Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, SessionEntry>>,
+}
+
+impl Registry {
+    pub fn register(&self, addr: SocketAddr, geo: Option<GeoTag>) -> (u64, UnboundedReceiver<Control>, Arc<SessionUsage>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (control, receiver) = mpsc::unbounded_channel();
+        let usage = Arc::new(SessionUsage::default());
+        let entry = SessionEntry {
+            addr,
+            geo,
+            since: Local::now(),
+            control,
+            escalated: None,
+            usage: Arc::clone(&usage),
+        };
+        self.sessions.lock().unwrap().insert(id, entry);
+        (id, receiver, usage)
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    /// Oldest first
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut list: Vec<_> = sessions
+            .iter()
+            .map(|(id, entry)| SessionInfo {
+                id: *id,
+                addr: entry.addr,
+                geo: entry.geo.clone(),
+                since: entry.since,
+                escalated: entry.escalated,
+                usage: entry.usage.snapshot(),
+            })
+            .collect();
+        list.sort_by_key(|s| s.id);
+        list
+    }
+
+    /// Mark the session as waiting for a human
+    pub fn escalate(&self, id: u64) {
+        if let Some(entry) = self.sessions.lock().unwrap().get_mut(&id) {
+            entry.escalated = Some(Local::now());
+        }
+    }
+
+    /// Send to one session, false if it is gone
+    fn send(&self, id: u64, control: Control) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(entry) = sessions.get_mut(&id) else {
+            return false;
+        };
+        if matches!(control, Control::Attach(_)) {
+            entry.escalated = None;
+        }
+        entry.control.send(control).is_ok()
+    }
+
+    /// Send to every session from `ip`, returns how many were reached
+    fn send_to(&self, ip: IpAddr, control: impl Fn() -> Control) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .values()
+            .filter(|entry| entry.addr.ip() == ip)
+            .filter(|entry| entry.control.send(control()).is_ok())
+            .count()
+    }
+
+    pub fn count(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Send to every session, returns how many were reached
+    pub fn send_all(&self, control: impl Fn() -> Control) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .values()
+            .filter(|entry| entry.control.send(control()).is_ok())
+            .count()
+    }
+
+    fn broadcast(&self, text: &str) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .values()
+            .filter(|entry| entry.control.send(Control::Broadcast(text.to_string())).is_ok())
+            .count()
+    }
+}
+
+/// Maintenance mode: new connections get the banner instead of a session
+pub struct Maintenance {
+    banner: String,
+    active: AtomicBool,
+    /// Bumped on every toggle so a stale grace period doesn't kick anyone
+    generation: AtomicU64,
+}
+
+impl Maintenance {
+    pub fn new(banner: String, active: bool) -> Self {
+        Self {
+            banner,
+            active: AtomicBool::new(active),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// The banner while maintenance mode is on
+    pub fn banner(&self) -> Option<&str> {
+        self.is_active().then_some(self.banner.as_str())
+    }
+
+    fn set(&self, active: bool) -> u64 {
+        self.active.store(active, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+const ADMIN_HELP: &str = "\
+Commands:
+  sessions [sort]           - List connected sessions, largest first by in, out, messages, tokens or memory
+  kick <ip>                 - Disconnect every session from an IP
+  ban <ip> [minutes]        - Ban an IP (default 60 minutes) and disconnect it
+  unban <ip>                - Lift a ban
+  broadcast <text>          - Show a message in every session
+  escalations [clear]       - List users who asked for a human, or empty the list
+  attach <id>               - Take over a session from the AI, detach to hand it back
+  endpoints                 - Show the health of each LLM endpoint
+  activity [days]           - Heatmap of sessions and messages by weekday and hour (default 28 days)
+  quota <ip> <mb|default>   - Override the storage quota of a client
+  search <words>            - Find messages in the chat logs, narrowed by since:, until: and client:
+  reload                    - Read the --config file again, for new sessions and commands
+  maintenance [on [min]|off] - Show or toggle maintenance mode, sessions get a grace period
+  upgrade                   - Re-exec the binary, new connections go to the new process
+  help                      - Show this help
+  quit                      - Close the console
+";
+
+/// What an operator has to present before the console opens
+pub struct AdminAuth {
+    pub password: Option<String>,
+    /// Second factor, provisioned with `telllm admin-totp`
+    pub totp: Option<totp::Authenticator>,
+}
+
+/// The password in the first line of `--admin-password-file`
+pub fn read_password_file(path: &Path) -> Result<String> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the admin password from {}", path.display()))?;
+    let password = text.lines().next().unwrap_or_default().trim_end();
+    if password.is_empty() {
+        anyhow::bail!("No admin password in {}", path.display());
+    }
+    Ok(password.to_string())
+}
+
+/// Longest answer read at a login prompt, before the operator is known
+const MAX_AUTH_LINE: u64 = 256;
+/// How long a peer gets to answer each login prompt
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Accept operator connections on the admin listener, behind the same bans
+/// and reconnect throttle as the chat listeners
+pub async fn serve(
+    listener: TcpListener,
+    ctx: Arc<SessionContext>,
+    auth: AdminAuth,
+    grace: Duration,
+    throttle: Arc<ConnectThrottle>,
+) {
+    let auth = Arc::new(auth);
+    let mut stopped = ctx.handover.stopped();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = stopped.changed() => break,
+        };
+        match accepted {
+            Ok((mut stream, addr)) => {
+                if ctx.bans.is_banned(addr.ip()) {
+                    info!("Rejected banned admin client {}", addr);
+                    ctx.audit.record(addr.ip(), Outcome::Banned, Some("admin"));
+                    let _ = stream.write_all(b"You are temporarily banned.\r\n").await;
+                    continue;
+                }
+                if !throttle.allow(addr.ip()) {
+                    info!("Throttled reconnecting admin client {}", addr);
+                    ctx.audit.record(addr.ip(), Outcome::RateLimited, Some("admin"));
+                    let _ = stream.write_all(b"Too many connections, slow down.\r\n").await;
+                    continue;
+                }
+
+                let ctx = Arc::clone(&ctx);
+                let auth = Arc::clone(&auth);
+                tokio::spawn(async move {
+                    if let Err(e) = console(stream, addr, &ctx, &auth, grace).await {
+                        warn!("Admin console error for {}: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept admin connection: {}", e),
+        }
+    }
+}
+
+/// Ask for one line before the console is unlocked, with a bounded length and
+/// time to answer. None when the peer took too long, hung up or sent too much.
+async fn prompt(reader: &mut BufReader<OwnedReadHalf>, writer: &mut OwnedWriteHalf, text: &[u8]) -> Result<Option<String>> {
+    writer.write_all(text).await?;
+    let mut line = String::new();
+    let read = tokio::time::timeout(AUTH_TIMEOUT, (&mut *reader).take(MAX_AUTH_LINE).read_line(&mut line)).await;
+    match read {
+        Ok(read) => {
+            read?;
+            Ok(line.ends_with('\n').then_some(line))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Compares MACs of both sides so the time taken says nothing about the password
+pub fn password_matches(attempt: &str, password: &str) -> bool {
+    let key: [u8; 32] = rand::random();
+    let mac = |text: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC accepts any key length");
+        mac.update(text.as_bytes());
+        mac
+    };
+    mac(attempt).verify_slice(&mac(password).finalize().into_bytes()).is_ok()
+}
+
+async fn console(
+    stream: TcpStream,
+    addr: SocketAddr,
+    ctx: &Arc<SessionContext>,
+    auth: &AdminAuth,
+    grace: Duration,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    if (auth.password.is_some() || auth.totp.is_some())
+        && let Some(wait) = ctx.logins.admin_wait(addr.ip())
+    {
+        warn!("Admin login from {} refused after too many failures", addr);
+        writer
+            .write_all(format!("Too many failed logins, try again in {}s.\r\n", wait.as_secs().max(1)).as_bytes())
+            .await?;
+        return Ok(());
+    }
+
+    if let Some(password) = &auth.password {
+        let Some(attempt) = prompt(&mut reader, &mut writer, b"Password: ").await? else {
+            return Ok(());
+        };
+        if !password_matches(attempt.trim(), password) {
+            ctx.audit.record(addr.ip(), Outcome::AuthFailed, Some("admin password"));
+            ctx.logins.admin_failed(addr.ip());
+            warn!("Failed admin login from {}", addr);
+            writer.write_all(b"Access denied.\r\n").await?;
+            return Ok(());
+        }
+    }
+
+    if let Some(totp) = &auth.totp {
+        let Some(code) = prompt(&mut reader, &mut writer, b"Authenticator code: ").await? else {
+            return Ok(());
+        };
+        if !totp.verify(&code) {
+            ctx.audit.record(addr.ip(), Outcome::AuthFailed, Some("admin totp"));
+            ctx.logins.admin_failed(addr.ip());
+            warn!("Failed admin TOTP code from {}", addr);
+            writer.write_all(b"Access denied.\r\n").await?;
+            return Ok(());
+        }
+    }
+    ctx.logins.admin_succeeded(addr.ip());
+    let mut lines = reader.lines();
+
+    let actor = format!("admin@{}", addr);
+    ctx.audit.record(addr.ip(), Outcome::Login, Some("admin"));
+    info!("Admin console opened by {}", addr);
+    writer.write_all(b"telllm admin console, type help for commands\r\n> ").await?;
+
+    while let Some(line) = lines.next_line().await.context("Failed to read admin command")? {
+        let line = line.trim();
+        if line == "quit" || line == "exit" {
+            break;
+        }
+        if let Some(id) = line.strip_prefix("attach ") {
+            match id.trim().trim_start_matches('#').parse::<u64>() {
+                Ok(id) => {
+                    if !attach(id, &actor, ctx, &mut lines, &mut writer).await? {
+                        break;
+                    }
+                }
+                Err(_) => writer.write_all(b"Usage: attach <id>\r\n").await?,
+            }
+        } else if !line.is_empty() {
+            let reply = execute(line, &actor, ctx, grace);
+            writer.write_all(reply.replace('\n', "\r\n").as_bytes()).await?;
+        }
+        writer.write_all(b"> ").await?;
+    }
+
+    info!("Admin console closed by {}", addr);
+    Ok(())
+}
+
+/// Relay between the console and session `id` until the operator types `detach`
+/// or the session ends. Returns false if the console itself was closed.
+async fn attach(
+    id: u64,
+    actor: &str,
+    ctx: &SessionContext,
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    writer: &mut OwnedWriteHalf,
+) -> Result<bool> {
+    let (sender, mut from_user) = mpsc::unbounded_channel();
+    if !ctx.sessions.send(id, Control::Attach(sender)) {
+        writer.write_all(format!("No session #{}\r\n", id).as_bytes()).await?;
+        return Ok(true);
+    }
+    audit(ctx, actor, "attach", Some(&format!("#{}", id)), None);
+    writer
+        .write_all(format!("Attached to #{}, your lines go to the user, detach hands back to the AI\r\n", id).as_bytes())
+        .await?;
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line.context("Failed to read admin command")? else {
+                    ctx.sessions.send(id, Control::Release);
+                    return Ok(false);
+                };
+                let line = line.trim();
+                if line == "detach" {
+                    break;
+                }
+                if !line.is_empty() && !ctx.sessions.send(id, Control::Operator(line.to_string())) {
+                    writer.write_all(b"The session has ended\r\n").await?;
+                    return Ok(true);
+                }
+            }
+            text = from_user.recv() => match text {
+                Some(text) => writer.write_all(format!("{}\r\n", text.replace('\n', "\r\n")).as_bytes()).await?,
+                None => {
+                    writer.write_all(b"The session has ended\r\n").await?;
+                    return Ok(true);
+                }
+            },
+        }
+    }
+
+    ctx.sessions.send(id, Control::Release);
+    audit(ctx, actor, "detach", Some(&format!("#{}", id)), None);
+    writer.write_all(format!("Detached from #{}\r\n", id).as_bytes()).await?;
+    Ok(true)
+}
+
+/// Record an admin action in the audit log, and in tracing when asked to
+pub fn audit(ctx: &SessionContext, actor: &str, action: &str, target: Option<&str>, params: Option<&str>) {
+    ctx.audit.record_admin(actor, action, target, params);
+    if ctx.announce_admin {
+        info!(
+            "Admin {} ran {} {} {}",
+            actor,
+            action,
+            target.unwrap_or(""),
+            params.unwrap_or("")
+        );
+    }
+}
+
+/// Matches `search` shows at most
+const MAX_SEARCH_HITS: usize = 20;
+
+/// Put the `--config` file in force again, or keep the running config if it doesn't load
+fn reload(ctx: &SessionContext, actor: &str) -> String {
+    let Some(path) = ctx.config.path() else {
+        return "No --config file to reload\n".to_string();
+    };
+    let path = path.display().to_string();
+    let config = match ctx.config.reread() {
+        Ok(config) => config,
+        Err(e) => return format!("Reload failed, keeping the running config: {:#}\n", e),
+    };
+    if ctx.hmac_auth && config.clients.is_empty() {
+        return "Reload failed, --hmac-auth needs at least one [[client]]\n".to_string();
+    }
+    ctx.config.replace(config);
+    schedule::refresh(ctx);
+    audit(ctx, actor, "reload", Some(&path), None);
+    format!(
+        "Reloaded {}. Models, aliases, LLM headers, the chat template, redactions, \
+         MCP servers and persona ports take a restart.\n",
+        path
+    )
+}
+
+fn execute(line: &str, actor: &str, ctx: &Arc<SessionContext>, grace: Duration) -> String {
+    let mut parts = line.splitn(2, ' ');
+    let cmd = parts.next().unwrap_or("").to_lowercase();
+    let rest = parts.next().map(str::trim).unwrap_or("");
+    let args: Vec<&str> = rest.split_whitespace().collect();
+    let ip = args.first().and_then(|ip| ip.parse::<IpAddr>().ok());
+
+    match (cmd.as_str(), ip) {
+        ("sessions", _) => {
+            let mut sessions = ctx.sessions.list();
+            let key: fn(&UsageSnapshot) -> u64 = match args.first().copied() {
+                None => |_| 0,
+                Some("in") => |u| u.bytes_in,
+                Some("out") => |u| u.bytes_out,
+                Some("messages") => |u| u.messages,
+                Some("tokens") => |u| u.tokens,
+                Some("memory") => |u| u.history_bytes,
+                Some(_) => return "Usage: sessions [in|out|messages|tokens|memory]\n".to_string(),
+            };
+            sessions.sort_by_key(|s| std::cmp::Reverse(key(&s.usage)));
+            let mut out = format!("{} sessions\n", sessions.len());
+            for s in sessions {
+                out.push_str(&format!("  #{} {}", s.id, s.addr));
+                if let Some(geo) = &s.geo {
+                    out.push_str(&format!(" ({})", geo.describe()));
+                }
+                out.push_str(&format!(" since {}", s.since.format("%d-%m-%Y %H:%M:%S")));
+                if let Some(at) = s.escalated {
+                    out.push_str(&format!(", wants a human since {}", at.format("%H:%M")));
+                }
+                out.push_str(&format!(
+                    "\n      {} messages, ~{} tokens, in {}, out {}, history {}\n",
+                    s.usage.messages,
+                    s.usage.tokens,
+                    human_size(s.usage.bytes_in),
+                    human_size(s.usage.bytes_out),
+                    human_size(s.usage.history_bytes)
+                ));
+            }
+            out
+        }
+        ("escalations", _) if rest == "clear" => match escalation::clear(&ctx.logs_dir) {
+            Ok(count) => {
+                audit(ctx, actor, "escalations clear", None, None);
+                format!("Removed {} escalations\n", count)
+            }
+            Err(e) => format!("Failed to clear escalations: {}\n", e),
+        },
+        ("escalations", _) => {
+            let pending = escalation::pending(&ctx.logs_dir);
+            let mut out = format!("{} escalations\n", pending.len());
+            for e in pending {
+                out.push_str(&format!(
+                    "  {} #{} {} {}: {}\n",
+                    e.requested_at.format("%d-%m-%Y %H:%M"),
+                    e.session,
+                    e.addr,
+                    e.name.as_deref().unwrap_or("-"),
+                    e.reason.as_deref().unwrap_or("(no reason given)")
+                ));
+            }
+            out
+        }
+        ("kick", Some(ip)) => {
+            let count = ctx.sessions.send_to(ip, || Control::Kick);
+            audit(ctx, actor, "kick", Some(&ip.to_string()), None);
+            format!("Kicked {} sessions\n", count)
+        }
+        ("ban", Some(ip)) => {
+            let minutes = match args.get(1).map(|m| m.parse::<u64>()) {
+                None => 60,
+                Some(Ok(minutes)) if minutes <= MAX_BAN.as_secs() / 60 => minutes,
+                Some(_) => return format!("Usage: ban <ip> [minutes], at most {}\n", MAX_BAN.as_secs() / 60),
+            };
+            ctx.bans.ban_for(ip, Duration::from_secs(minutes * 60));
+            let count = ctx.sessions.send_to(ip, || Control::Kick);
+            audit(ctx, actor, "ban", Some(&ip.to_string()), Some(&format!("minutes={}", minutes)));
+            format!("Banned {} for {} minutes, kicked {} sessions\n", ip, minutes, count)
+        }
+        ("unban", Some(ip)) => {
+            let lifted = ctx.bans.unban(ip);
+            audit(ctx, actor, "unban", Some(&ip.to_string()), None);
+            if lifted {
+                format!("Unbanned {}\n", ip)
+            } else {
+                format!("{} was not banned\n", ip)
+            }
+        }
+        ("broadcast", _) if !rest.is_empty() => {
+            let count = ctx.sessions.broadcast(rest);
+            audit(ctx, actor, "broadcast", None, Some(rest));
+            format!("Sent to {} sessions\n", count)
+        }
+        ("quota", Some(ip)) => {
+            let quota_mb = match args.get(1) {
+                Some(&"default") => None,
+                Some(mb) => match mb.parse::<u64>() {
+                    Ok(mb) => Some(mb),
+                    Err(_) => return "Usage: quota <ip> <mb|default>\n".to_string(),
+                },
+                None => return "Usage: quota <ip> <mb|default>\n".to_string(),
+            };
+            let dir = logger::client_dir(&ctx.logs_dir, ip);
+            if let Err(e) = profile::update(&dir, |p| p.quota_mb = quota_mb) {
+                return format!("Failed to set quota: {}\n", e);
+            }
+            let params = quota_mb
+                .map(|mb| format!("mb={}", mb))
+                .unwrap_or_else(|| "default".to_string());
+            audit(ctx, actor, "quota", Some(&ip.to_string()), Some(&params));
+            format!("Quota of {} set to {}\n", ip, params)
+        }
+        ("search", _) => {
+            let query = match logsearch::Query::parse(rest) {
+                Ok(query) if !query.terms.is_empty() => query,
+                Ok(_) => return "Usage: search <words> [since:YYYY-MM-DD] [until:YYYY-MM-DD] [client:<dir>]\n".to_string(),
+                Err(e) => return format!("{}\n", e),
+            };
+            audit(ctx, actor, "search", query.client.as_deref(), Some(rest));
+            match logsearch::search(&ctx.logs_dir, &query, MAX_SEARCH_HITS) {
+                Ok(hits) if hits.is_empty() => "No matches\n".to_string(),
+                Ok(hits) => format!("{}{} matches, best first\n", logsearch::render(&hits, &query.terms), hits.len()),
+                Err(e) => format!("Search failed: {}\n", e),
+            }
+        }
+        ("endpoints", _) => {
+            let endpoints = ctx.llm.endpoints();
+            let mut out = format!("Balancing: {:?}\n", endpoints.balance());
+            for (i, endpoint) in endpoints.iter().enumerate() {
+                out.push_str(&format!(
+                    "{}. {}  weight {}, {} in flight, {}\n",
+                    i + 1,
+                    endpoint.base,
+                    endpoint.weight,
+                    endpoint.in_flight(),
+                    endpoint.breaker.describe()
+                ));
+            }
+            for (alias, llm) in ctx.aliases.own_clients() {
+                for endpoint in llm.endpoints().iter() {
+                    out.push_str(&format!(
+                        "{}: {}  {} in flight, {}\n",
+                        alias.name,
+                        endpoint.base,
+                        endpoint.in_flight(),
+                        endpoint.breaker.describe()
+                    ));
+                }
+            }
+            out
+        }
+        ("activity", _) => {
+            let days = match args.first().map(|d| d.parse::<u32>()) {
+                None => 28,
+                Some(Ok(days)) if days > 0 => days,
+                Some(_) => return "Usage: activity [days]\n".to_string(),
+            };
+            match stats::activity(&ctx.logs_dir, days) {
+                Ok(activity) => activity.render(days),
+                Err(e) => format!("Failed to read the chat logs: {}\n", e),
+            }
+        }
+        ("maintenance", _) => maintenance(&args, actor, ctx, grace),
+        ("upgrade", _) => {
+            tokio::spawn(handover::upgrade_logged(Arc::clone(ctx)));
+            audit(ctx, actor, "upgrade", None, None);
+            "Starting the new process, see the server log for the outcome\n".to_string()
+        }
+        ("broadcast", _) => "Usage: broadcast <text>\n".to_string(),
+        ("help", _) => ADMIN_HELP.to_string(),
+        ("reload", _) => reload(ctx, actor),
+        ("kick" | "ban" | "unban" | "quota", None) => format!("Usage: {} <ip> ...\n", cmd),
+        _ => format!("Unknown command: {}\n{}", line, ADMIN_HELP),
+    }
+}
+
+/// Longest grace period `maintenance on` gives connected sessions, a week
+const MAX_GRACE_MINUTES: u64 = 7 * 24 * 60;
+
+fn maintenance(args: &[&str], actor: &str, ctx: &Arc<SessionContext>, grace: Duration) -> String {
+    match args.first().copied() {
+        None if ctx.maintenance.is_active() => {
+            format!("Maintenance mode is on, {} sessions connected\n", ctx.sessions.count())
+        }
+        None => "Maintenance mode is off\n".to_string(),
+        Some("on") => {
+            let grace = match args.get(1) {
+                Some(minutes) => match minutes.parse::<u64>() {
+                    Ok(minutes) if minutes <= MAX_GRACE_MINUTES => Duration::from_secs(minutes * 60),
+                    _ => return format!("Usage: maintenance on [minutes], at most {}\n", MAX_GRACE_MINUTES),
+                },
+                None => grace,
+            };
+            let generation = ctx.maintenance.set(true);
+            let minutes = grace.as_secs().div_ceil(60);
+            let warned = ctx.sessions.broadcast(&format!(
+                "The server is going down for maintenance in {} minutes.",
+                minutes
+            ));
+
+            let task_ctx = Arc::clone(ctx);
+            tokio::spawn(async move {
+                tokio::time::sleep(grace).await;
+                if task_ctx.maintenance.generation.load(Ordering::Relaxed) == generation {
+                    let kicked = task_ctx.sessions.send_all(|| Control::Kick);
+                    info!("Maintenance grace period over, closed {} sessions", kicked);
+                }
+            });
+
+            audit(ctx, actor, "maintenance", None, Some(&format!("on minutes={}", minutes)));
+            format!("Maintenance mode on, {} sessions close in {} minutes\n", warned, minutes)
+        }
+        Some("off") => {
+            ctx.maintenance.set(false);
+            audit(ctx, actor, "maintenance", None, Some("off"));
+            "Maintenance mode off\n".to_string()
+        }
+        Some(_) => "Usage: maintenance [on [minutes]|off]\n".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyPool;
+    use crate::redact::Redactor;
+    use clap::Parser;
+    use std::fs;
+
+    #[test]
+    fn reload_swaps_the_config_and_is_audited() {
+        let dir = std::env::temp_dir().join(format!("telllm-admin-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let logs_dir = dir.to_string_lossy().into_owned();
+        let path = dir.join("telllm.toml");
+        fs::write(&path, "[[persona]]\nname = \"pirate\"\n").unwrap();
+
+        let args = crate::Args::parse_from(["telllm", "--logs-dir", &logs_dir, "--config", &path.to_string_lossy()]);
+        let config = crate::config::Config::load(args.config.as_deref()).unwrap();
+        let keys = Arc::new(KeyPool::new(Vec::new(), None));
+        let llm = crate::build_provider(&args, &config, crate::build_endpoints(&args), Arc::clone(&keys));
+        let ctx = Arc::new(crate::build_context(
+            &args,
+            config,
+            logs_dir.clone(),
+            None,
+            llm,
+            &keys,
+            Redactor::default(),
+        ));
+
+        fs::write(&path, "[[persona]]\nname = \"butler\"\n").unwrap();
+        let reply = execute("reload", "alice@127.0.0.1", &ctx, Duration::ZERO);
+        assert!(reply.starts_with("Reloaded"), "{}", reply);
+        assert!(ctx.config.get().persona("butler").is_some());
+        assert!(ctx.config.get().persona("pirate").is_none());
+
+        // A broken file keeps what is running
+        fs::write(&path, "[[persona]]\nname = 7\n").unwrap();
+        let reply = execute("reload", "alice@127.0.0.1", &ctx, Duration::ZERO);
+        assert!(reply.starts_with("Reload failed"), "{}", reply);
+        assert!(ctx.config.get().persona("butler").is_some());
+
+        let audit = fs::read_to_string(dir.join("audit.jsonl")).unwrap();
+        let entries: Vec<serde_json::Value> = audit.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["action"], "reload");
+        assert_eq!(entries[0]["actor"], "alice@127.0.0.1");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{Result, bail};
+use serde::Deserialize;
+
+use crate::llm::{LlmProvider, ModelProfile, Sampling, Tuning};
+
+/// A friendly model name users pick with /model, `[[alias]]` in the config,
+/// e.g. `smart` for `gpt-4o`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelAlias {
+    pub name: String,
+    /// What the backend calls the model
+    pub model: String,
+    /// Base URL of another backend speaking the same --provider, --endpoint if not set
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Replaces --system-prompt and the model's own while the alias is selected
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+}
+
+impl ModelAlias {
+    /// Defaults that come with the alias, a session's /set values win over them
+    pub fn tuning(&self) -> Tuning {
+        Tuning {
+            max_tokens: self.max_tokens,
+            sampling: Sampling {
+                temperature: self.temperature,
+                top_p: self.top_p,
+                presence_penalty: self.presence_penalty,
+                frequency_penalty: self.frequency_penalty,
+            },
+            json: false,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() || self.name.contains(char::is_whitespace) {
+            bail!("Alias {:?}: the name must be one word", self.name);
+        }
+        if self.name == self.model {
+            bail!("Alias {}: names its own model", self.name);
+        }
+        self.tuning()
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Alias {}: {}", self.name, e))
+    }
+}
+
+/// Where a request for a model name goes
+pub struct Route<'a> {
+    pub llm: &'a dyn LlmProvider,
+    /// The backend's model ID
+    pub model: &'a str,
+    pub tuning: Tuning,
+    /// Replaces --system-prompt for this model
+    pub system_prompt: Option<&'a str>,
+}
+
+/// The configured aliases, each with a client of its own when it names an endpoint
+pub struct Aliases {
+    list: Vec<(ModelAlias, Option<Box<dyn LlmProvider>>)>,
+}
+
+impl Aliases {
+    pub fn new(list: Vec<(ModelAlias, Option<Box<dyn LlmProvider>>)>) -> Self {
+        Self { list }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ModelAlias> {
+        self.list.iter().map(|(alias, _)| alias).find(|a| a.name == name)
+    }
+
+    /// Aliases on an endpoint of their own, with its client
+    pub fn own_clients(&self) -> impl Iterator<Item = (&ModelAlias, &dyn LlmProvider)> {
+        self.list
+            .iter()
+            .filter_map(|(alias, own)| own.as_deref().map(|llm| (alias, llm)))
+    }
+
+    /// Resolve `model`, an alias or a backend model ID, for a request with the
+    /// session's `tuning`. Anything that isn't an alias goes to `llm` unchanged.
+    /// Settings come from the session, then the alias, then the model's `profiles` entry.
+    pub fn route<'a>(
+        &'a self,
+        llm: &'a dyn LlmProvider,
+        profiles: &'a [ModelProfile],
+        model: &'a str,
+        tuning: &Tuning,
+    ) -> Route<'a> {
+        let (llm, alias, model) = match self.list.iter().find(|(alias, _)| alias.name == model) {
+            Some((alias, own)) => (own.as_deref().unwrap_or(llm), Some(alias), alias.model.as_str()),
+            None => (llm, None, model),
+        };
+        let profile = profiles.iter().find(|p| p.name == model);
+        let mut tuning = *tuning;
+        if let Some(alias) = alias {
+            tuning = tuning.or(&alias.tuning());
+        }
+        if let Some(profile) = profile {
+            tuning = tuning.or(&profile.tuning());
+        }
+        Route {
+            llm,
+            model,
+            tuning,
+            system_prompt: alias
+                .and_then(|a| a.system_prompt.as_deref())
+                .or_else(|| profile.and_then(|p| p.system_prompt.as_deref())),
+        }
+    }
+}
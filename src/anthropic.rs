@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::keys::KeyPool;
+use crate::llm::{self, Completion, LlmProvider, Message, ModelList, TokenUsage, Tuning, Upstream};
+
+const API_VERSION: &str = "2023-06-01";
+
+/// The Messages API insists on a limit
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+#[derive(Debug, Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    system: String,
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    #[serde(default)]
+    content: Vec<ContentBlock>,
+    #[serde(default)]
+    stop_reason: Option<String>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Usage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+fn authorize(req: reqwest::RequestBuilder, key: &str) -> reqwest::RequestBuilder {
+    req.header("x-api-key", key).header("anthropic-version", API_VERSION)
+}
+
+/// Anthropic's `/v1/messages` API, directly or behind a gateway
+pub struct AnthropicClient {
+    upstream: Upstream,
+    model: String,
+    keys: Arc<KeyPool>,
+    /// Only temperature and top-p are sent, the Messages API has no penalties
+    tuning: Tuning,
+}
+
+impl AnthropicClient {
+    pub fn new(upstream: Upstream, model: String, keys: Arc<KeyPool>, tuning: Tuning) -> Self {
+        Self {
+            upstream,
+            model,
+            keys,
+            tuning,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    async fn complete(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<Completion> {
+        let tuning = tuning.or(&self.tuning);
+        let (system, messages) = llm::split_system(messages);
+        let request = MessagesRequest {
+            model,
+            max_tokens: tuning.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            system,
+            messages,
+            temperature: tuning.sampling.temperature,
+            top_p: tuning.sampling.top_p,
+        };
+
+        let response =
+            llm::post_json(&self.upstream, "/messages", &request, &self.keys, model, authorize)
+                .await?;
+        let response: MessagesResponse = response
+            .json()
+            .await
+            .context("Failed to parse LLM response")?;
+
+        let content: String = response
+            .content
+            .iter()
+            .filter(|block| block.kind == "text")
+            .map(|block| block.text.as_str())
+            .collect();
+        if content.is_empty() {
+            anyhow::bail!("No response from LLM");
+        }
+        Ok(Completion {
+            content,
+            finish_reason: response.stop_reason,
+            usage: response
+                .usage
+                .map(|usage| TokenUsage::new(usage.input_tokens, usage.output_tokens)),
+            sources: Vec::new(),
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let list: ModelList = llm::get(&self.upstream, "/models", &self.keys, &self.model, authorize)
+            .await?
+            .json()
+            .await
+            .context("Failed to parse model list")?;
+        Ok(list.data.into_iter().map(|m| m.id).collect())
+    }
+}
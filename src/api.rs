@@ -0,0 +1,269 @@
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{NaiveDate, Utc};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+use crate::abuse::{AbuseDetector, Verdict};
+use crate::admin;
+use crate::audit::Outcome;
+use crate::lang;
+use crate::llm::{Completion, Message, Tuning};
+use crate::logger::{ChatLogger, QuotaRule, ResponseMeta, Tags};
+use crate::logsearch;
+use crate::render::render_response;
+use crate::session::{self, SessionContext};
+
+#[derive(Deserialize)]
+struct ChatRequest {
+    messages: Vec<Message>,
+    /// `{"type": "json_object"}` turns on JSON mode like /json
+    #[serde(default)]
+    response_format: Option<Value>,
+}
+
+/// Query string of `/admin/search`
+#[derive(Deserialize)]
+struct SearchParams {
+    q: String,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    client: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Matches `/admin/search` returns unless `limit` asks for fewer
+const MAX_SEARCH_HITS: usize = 100;
+
+/// OpenAI-style error body
+pub fn error(status: StatusCode, kind: &str, message: &str) -> Response {
+    let body = json!({ "error": { "message": message, "type": kind } });
+    (status, Json(body)).into_response()
+}
+
+/// Serve `/v1/chat/completions` through the same bans, moderation, logging
+/// and quotas as telnet sessions, and `/admin/search` to operators
+pub async fn serve(listener: TcpListener, ctx: Arc<SessionContext>, admin_password: Option<String>) {
+    let mut stopped = ctx.handover.stopped();
+    let admin_password = Arc::new(admin_password);
+    let app = Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .route(
+            "/admin/search",
+            get(move |state, info, headers, params| search(state, info, headers, params, admin_password)),
+        );
+    #[cfg(feature = "metrics")]
+    let app = app.route("/metrics", axum::routing::get(crate::metrics::metrics));
+    let app = app.with_state(ctx);
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let shutdown = async move {
+        let _ = stopped.changed().await;
+    };
+    if let Err(e) = axum::serve(listener, service).with_graceful_shutdown(shutdown).await {
+        error!("HTTP API stopped: {}", e);
+    }
+}
+
+/// Loopback callers are trusted, everyone else needs a signed client's key as bearer token
+pub fn authorized(ctx: &SessionContext, addr: SocketAddr, headers: &HeaderMap) -> bool {
+    let bearer = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match bearer {
+        Some(key) => ctx.config.get().clients.iter().any(|c| c.key == key),
+        None => addr.ip().is_loopback() && ctx.config.get().clients.is_empty(),
+    }
+}
+
+async fn chat_completions(
+    State(ctx): State<Arc<SessionContext>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(mut request): Json<ChatRequest>,
+) -> Response {
+    if ctx.bans.is_banned(addr.ip()) {
+        ctx.audit.record(addr.ip(), Outcome::Banned, Some("http"));
+        return error(StatusCode::FORBIDDEN, "banned", "You are temporarily banned.");
+    }
+    if !authorized(&ctx, addr, &headers) {
+        ctx.audit.record(addr.ip(), Outcome::AuthFailed, Some("http"));
+        return error(StatusCode::UNAUTHORIZED, "invalid_request_error", "Invalid API key.");
+    }
+    if let Some(banner) = ctx.maintenance.banner() {
+        ctx.audit.record(addr.ip(), Outcome::Maintenance, Some("http"));
+        return error(StatusCode::SERVICE_UNAVAILABLE, "maintenance", banner);
+    }
+    ctx.audit.record(addr.ip(), Outcome::Served, Some("http"));
+
+    let mut redacted = 0;
+    for message in request.messages.iter_mut().filter(|m| m.role == "user") {
+        let (text, count) = ctx.redactor.redact(&message.content);
+        message.content = text;
+        redacted += count;
+    }
+    if redacted > 0 {
+        info!("Redacted {} matches from the HTTP request of {}", redacted, addr);
+    }
+
+    let Some(last) = request.messages.iter().rev().find(|m| m.role == "user") else {
+        return error(StatusCode::BAD_REQUEST, "invalid_request_error", "No user message.");
+    };
+    if matches!(AbuseDetector::new().check(&last.content), Verdict::Flood) {
+        warn!("Suppressed paste flood from {} over HTTP", addr);
+        return error(StatusCode::BAD_REQUEST, "moderation", "That looks like a paste flood.");
+    }
+
+    let schedule = ctx.schedule.get();
+    let mut logger = ChatLogger::new(&ctx.logs_dir, addr.ip(), ctx.log_format);
+    logger.set_quota(QuotaRule {
+        override_mb: None,
+        fallback: session::schedule_quota(&ctx, schedule.as_ref()),
+    });
+    let log = |result: anyhow::Result<()>| {
+        if let Err(e) = result {
+            warn!("Failed to log HTTP request from {}: {}", addr, e);
+        }
+    };
+    log(logger.log_session_start().map(|_| ()));
+    let tags = Tags {
+        lang: lang::detect(&last.content),
+        moderation: Some(Verdict::Ok),
+    };
+    log(logger.log_tagged("HTTP", &last.content, &tags));
+
+    let model = schedule
+        .as_ref()
+        .and_then(|s| s.model.clone())
+        .unwrap_or_else(|| ctx.llm.model().to_string());
+
+    let tuning = Tuning {
+        json: request
+            .response_format
+            .as_ref()
+            .is_some_and(|format| format["type"] == "json_object"),
+        ..Tuning::default()
+    };
+    let route = ctx.route(&model, &tuning);
+    let started = Instant::now();
+    let completion = if ctx.honeypot {
+        Completion {
+            content: "Sorry, all models are busy right now. Please try again later.".to_string(),
+            finish_reason: None,
+            usage: None,
+            sources: Vec::new(),
+        }
+    } else {
+        match route.llm.complete(route.model, &request.messages, &route.tuning).await {
+            Ok(completion) => completion,
+            Err(e) => {
+                warn!("LLM error for HTTP client {}: {}", addr, e);
+                ctx.errors.record(&e);
+                return error(StatusCode::BAD_GATEWAY, "upstream_error", &e.to_string());
+            }
+        }
+    };
+
+    let content = completion.content;
+    let rendered = render_response(&content, false);
+    let meta = ResponseMeta {
+        model: route.model.to_string(),
+        finish_reason: completion.finish_reason.clone(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        lang: lang::detect(&content),
+        usage: completion.usage,
+        tuning: route.tuning,
+    };
+    log(logger.log_response(&ctx.labels.ai, &content, &rendered, &meta));
+    log(logger.log_session_end());
+
+    if let Err(e) = logger.enforce_quota() {
+        warn!("Failed to enforce quota for {}: {}", addr.ip(), e);
+    }
+    info!("Served HTTP completion for {}", addr);
+
+    let body = json!({
+        "id": format!("chatcmpl-telllm-{}", Utc::now().timestamp_millis()),
+        "object": "chat.completion",
+        "created": Utc::now().timestamp(),
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": completion.finish_reason.as_deref().unwrap_or("stop"),
+        }],
+    });
+    Json(body).into_response()
+}
+
+/// The admin password as bearer token, or a loopback caller while no password is set
+fn admin_authorized(password: Option<&str>, addr: SocketAddr, headers: &HeaderMap) -> bool {
+    let bearer = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match (password, bearer) {
+        (Some(password), Some(token)) => admin::password_matches(token, password),
+        (Some(_), None) => false,
+        (None, _) => addr.ip().is_loopback(),
+    }
+}
+
+/// Chat log search for operators, audited like the admin console's `search`
+async fn search(
+    State(ctx): State<Arc<SessionContext>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<SearchParams>,
+    admin_password: Arc<Option<String>>,
+) -> Response {
+    if ctx.bans.is_banned(addr.ip()) {
+        ctx.audit.record(addr.ip(), Outcome::Banned, Some("http"));
+        return error(StatusCode::FORBIDDEN, "banned", "You are temporarily banned.");
+    }
+    if !admin_authorized(admin_password.as_deref(), addr, &headers) {
+        ctx.audit.record(addr.ip(), Outcome::AuthFailed, Some("http admin"));
+        return error(StatusCode::UNAUTHORIZED, "invalid_request_error", "Invalid admin password.");
+    }
+
+    let query = logsearch::Query::new(&params.q, params.since, params.until, params.client);
+    if query.terms.is_empty() {
+        return error(StatusCode::BAD_REQUEST, "invalid_request_error", "Nothing to search for.");
+    }
+    admin::audit(&ctx, &format!("api@{}", addr), "search", query.client.as_deref(), Some(&params.q));
+
+    let limit = params.limit.unwrap_or(20).min(MAX_SEARCH_HITS);
+    let logs_dir = ctx.logs_dir.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        logsearch::search(&logs_dir, &query, limit).map(|hits| (hits, query))
+    })
+    .await
+    .unwrap_or_else(|e| Err(anyhow::anyhow!("Search failed: {}", e)));
+    let (hits, query) = match result {
+        Ok(found) => found,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, "server_error", &e.to_string()),
+    };
+
+    let data: Vec<_> = hits
+        .iter()
+        .map(|hit| {
+            json!({
+                "client": hit.client,
+                "time": hit.time.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                "role": hit.role,
+                "content": hit.content,
+                "snippet": hit.snippet(&query.terms),
+                "score": hit.score,
+            })
+        })
+        .collect();
+    Json(json!({ "object": "list", "data": data })).into_response()
+}
@@ -0,0 +1,170 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::kiosk;
+use crate::llm::{Message, Tuning};
+use crate::render::render_response;
+use crate::session::SessionContext;
+use crate::storage;
+
+/// Answers generated for the attract loop, kept under the logs directory
+const CACHE_FILE: &str = "attract.json";
+/// Typewriter speed
+const CHAR_DELAY: Duration = Duration::from_millis(40);
+/// Between the question and the answer
+const THINK_PAUSE: Duration = Duration::from_millis(1500);
+/// After each answer, time to read it
+const READ_PAUSE: Duration = Duration::from_secs(8);
+/// Keeps the showcase answers screen sized
+const ANSWER_MAX_TOKENS: u32 = 200;
+
+/// One example exchange shown while nobody is using the terminal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pair {
+    pub question: String,
+    pub answer: String,
+}
+
+/// Example exchanges for each of `questions`, the --starter values. Answers
+/// are asked of the model once and cached, so a restart shows the same ones
+/// without calling it again; questions it failed to answer are left out.
+pub async fn pairs(ctx: &SessionContext, questions: &[String]) -> Vec<Pair> {
+    let path = Path::new(&ctx.logs_dir).join(CACHE_FILE);
+    let mut cached: Vec<Pair> = fs::read_to_string(&path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default();
+
+    let mut generated = false;
+    for question in questions {
+        if cached.iter().any(|p| &p.question == question) {
+            continue;
+        }
+        match answer(ctx, question).await {
+            Ok(answer) => {
+                cached.push(Pair {
+                    question: question.clone(),
+                    answer,
+                });
+                generated = true;
+            }
+            Err(e) => warn!("Failed to prepare an attract loop answer to {:?}: {}", question, e),
+        }
+    }
+    if generated {
+        info!("Prepared attract loop answers for {} questions", cached.len());
+        if let Err(e) = save(&path, &cached) {
+            warn!("{:#}", e);
+        }
+    }
+
+    questions
+        .iter()
+        .filter_map(|q| cached.iter().find(|p| &p.question == q).cloned())
+        .collect()
+}
+
+async fn answer(ctx: &SessionContext, question: &str) -> Result<String> {
+    let route = ctx.route(ctx.llm.model(), &Tuning::default());
+    let request = vec![
+        Message {
+            role: "system".to_string(),
+            content: format!("{}\n\nAnswer in a few short sentences.", route.system_prompt.unwrap_or(&ctx.system_prompt)),
+        },
+        Message {
+            role: "user".to_string(),
+            content: question.to_string(),
+        },
+    ];
+    let tuning = Tuning {
+        max_tokens: Some(ANSWER_MAX_TOKENS),
+        json: false,
+        ..route.tuning
+    };
+    let completion = route.llm.complete(route.model, &request, &tuning).await?;
+    Ok(render_response(completion.content.trim(), ctx.render_markdown))
+}
+
+fn save(path: &Path, pairs: &[Pair]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("Failed to create the logs directory")?;
+    }
+    let json = serde_json::to_string_pretty(pairs).context("Failed to serialize attract loop answers")?;
+    storage::write_atomic(path, &json).context("Failed to save attract loop answers")
+}
+
+/// Type the exchanges out one after another, over and over. Never returns
+/// unless the terminal fails; the caller stops it when a key is pressed.
+pub async fn run<W: AsyncWrite + Unpin>(writer: &mut W, pairs: &[Pair], you: &str, ai: &str) -> std::io::Result<()> {
+    loop {
+        for pair in pairs {
+            writer.write_all(kiosk::RESET_TERMINAL).await?;
+            writer.write_all(b"Press any key to start your own conversation.\r\n\r\n").await?;
+            typewrite(writer, &format!("{}: {}\r\n\r\n", you, pair.question)).await?;
+            tokio::time::sleep(THINK_PAUSE).await;
+            typewrite(writer, &format!("{}: {}\r\n", ai, pair.answer.replace('\n', "\r\n"))).await?;
+            tokio::time::sleep(READ_PAUSE).await;
+        }
+    }
+}
+
+async fn typewrite<W: AsyncWrite + Unpin>(writer: &mut W, text: &str) -> std::io::Result<()> {
+    let mut buf = [0u8; 4];
+    for c in text.chars() {
+        writer.write_all(c.encode_utf8(&mut buf).as_bytes()).await?;
+        writer.flush().await?;
+        if !c.is_whitespace() {
+            tokio::time::sleep(CHAR_DELAY).await;
+        }
+    }
+    Ok(())
+}
+
+/// Hold the terminal on `stream` until a key is pressed, starting the attract
+/// loop whenever it sits untouched for `idle`. The exchanges are prepared the
+/// first time they're needed and kept in `pairs` for the next wait.
+pub async fn wait_for_key<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    idle: Duration,
+    ctx: &SessionContext,
+    pairs: &mut Option<Vec<Pair>>,
+) -> std::io::Result<()> {
+    let mut key = [0u8; 1];
+    loop {
+        stream.write_all(b"\r\nPress any key to start.\r\n").await?;
+        stream.flush().await?;
+        if let Ok(read) = tokio::time::timeout(idle, stream.read(&mut key)).await {
+            return key_pressed(read);
+        }
+        if pairs.is_none() {
+            *pairs = Some(self::pairs(ctx, &ctx.starters).await);
+        }
+        let pairs = pairs.as_deref().unwrap_or_default();
+        if pairs.is_empty() {
+            // Nothing to show, keep waiting quietly
+            return key_pressed(stream.read(&mut key).await);
+        }
+        let (mut reader, mut writer) = tokio::io::split(&mut *stream);
+        tokio::select! {
+            shown = run(&mut writer, pairs, &ctx.labels.user, &ctx.labels.ai) => shown?,
+            read = reader.read(&mut key) => {
+                key_pressed(read)?;
+                break;
+            }
+        }
+    }
+    stream.write_all(kiosk::RESET_TERMINAL).await?;
+    stream.flush().await
+}
+
+fn key_pressed(read: std::io::Result<usize>) -> std::io::Result<()> {
+    match read? {
+        0 => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,81 @@
+use chrono::Local;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// What happened to a connection or login attempt
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Served,
+    Honeypot,
+    Banned,
+    RateLimited,
+    GeoDenied,
+    Maintenance,
+    Login,
+    AuthFailed,
+}
+
+/// Append-only record of connection attempts, kept apart from chat content
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(logs_dir: &str) -> Self {
+        Self {
+            path: Path::new(logs_dir).join("audit.jsonl"),
+        }
+    }
+
+    /// Failures are only logged, auditing never takes the server down
+    pub fn record(&self, ip: IpAddr, outcome: Outcome, detail: Option<&str>) {
+        let mut entry = json!({
+            "time": Local::now().to_rfc3339(),
+            "ip": ip.to_string(),
+            "outcome": outcome,
+        });
+        if let Some(detail) = detail {
+            entry["detail"] = json!(detail);
+        }
+        self.append(&entry);
+    }
+
+    /// Record an operator action, e.g. `admin@127.0.0.1:5000 ban 203.0.113.7 minutes=60`
+    pub fn record_admin(&self, actor: &str, action: &str, target: Option<&str>, params: Option<&str>) {
+        let mut entry = json!({
+            "time": Local::now().to_rfc3339(),
+            "actor": actor,
+            "action": action,
+        });
+        if let Some(target) = target {
+            entry["target"] = json!(target);
+        }
+        if let Some(params) = params {
+            entry["params"] = json!(params);
+        }
+        self.append(&entry);
+    }
+
+    fn append(&self, entry: &Value) {
+        let result = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+            })
+            .and_then(|mut file| writeln!(file, "{}", entry));
+        if let Err(e) = result {
+            warn!("Failed to write audit log {}: {}", self.path.display(), e);
+        }
+    }
+}
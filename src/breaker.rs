@@ -0,0 +1,94 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Stops calls to a backend that keeps failing. Opens after `threshold`
+/// failures in a row; once `cooldown` has passed one request goes through
+/// as a probe, and its outcome closes or reopens the breaker.
+pub struct CircuitBreaker {
+    /// 0 disables the breaker
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    /// While open, when the next probe may go through
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Whether `admit` would let a request through, without taking the probe
+    pub fn is_available(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.open_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    /// Whether a request may go to `backend` now
+    pub fn admit(&self, backend: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match state.open_until {
+            Some(until) if now < until => false,
+            Some(_) => {
+                // Half open: this request is the probe, the others wait another cooldown
+                info!("Probing {} after {}s without requests", backend, self.cooldown.as_secs());
+                state.open_until = Some(now + self.cooldown);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Outcome of an admitted request, `healthy` unless the backend was
+    /// unreachable or answered with a server error
+    pub fn record(&self, backend: &str, healthy: bool) {
+        let mut state = self.state.lock().unwrap();
+        if healthy {
+            if state.open_until.take().is_some() {
+                info!("{} answers again, circuit breaker closed", backend);
+            }
+            state.failures = 0;
+            return;
+        }
+        state.failures = state.failures.saturating_add(1);
+        if self.threshold > 0 && state.failures >= self.threshold {
+            if state.open_until.is_none() {
+                warn!(
+                    "{} failed {} times in a row, circuit breaker open for {}s",
+                    backend,
+                    state.failures,
+                    self.cooldown.as_secs()
+                );
+            }
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    /// One-line state for the admin console
+    pub fn describe(&self) -> String {
+        let state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(until) => {
+                let wait = until.saturating_duration_since(Instant::now());
+                if wait.is_zero() {
+                    "down, next request probes it".to_string()
+                } else {
+                    format!("down, probed again in {}s", wait.as_secs() + 1)
+                }
+            }
+            None if state.failures > 0 => format!("up, {} failures in a row", state.failures),
+            None => "up".to_string(),
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use anyhow::Result;
+use rand::Rng;
+
+/// Most dice in one roll
+const MAX_DICE: u32 = 100;
+/// Most sides a die may have
+const MAX_SIDES: u32 = 1000;
+/// Deepest nesting of parentheses and unary minus, so input can't exhaust the stack
+const MAX_DEPTH: usize = 64;
+
+/// Evaluate an arithmetic expression: `+ - * / % ^`, parentheses, `pi`, `e`
+/// and the functions `sqrt abs round floor ceil ln log10 sin cos tan`
+pub fn evaluate(expression: &str) -> Result<f64> {
+    let mut parser = Parser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.sum()?;
+    if let Some(c) = parser.peek() {
+        anyhow::bail!("Unexpected {:?} at position {}", c, parser.pos + 1);
+    }
+    if !value.is_finite() {
+        anyhow::bail!("The result is not a finite number");
+    }
+    Ok(value)
+}
+
+/// `value` the way people write it: no `.0` on whole numbers
+pub fn format(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn sum(&mut self) -> Result<f64> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat('+') {
+                value += self.product()?;
+            } else if self.eat('-') {
+                value -= self.product()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<f64> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    anyhow::bail!("Division by zero");
+                }
+                value /= divisor;
+            } else if self.eat('%') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    anyhow::bail!("Division by zero");
+                }
+                value %= divisor;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Unary minus applies to a whole power: -2^2 is -4
+    fn unary(&mut self) -> Result<f64> {
+        if self.eat('-') {
+            return Ok(-self.nested(Self::unary)?);
+        }
+        if self.eat('+') {
+            return self.nested(Self::unary);
+        }
+        self.power()
+    }
+
+    /// Right-associative, and the exponent may carry its own sign: 2^3^2 is
+    /// 2^9, 2^-1 is 0.5
+    fn power(&mut self) -> Result<f64> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(base.powf(self.nested(Self::unary)?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64> {
+        if self.eat('(') {
+            let value = self.nested(Self::sum)?;
+            if !self.eat(')') {
+                anyhow::bail!("Missing closing parenthesis");
+            }
+            return Ok(value);
+        }
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => self.name(),
+            Some(c) => anyhow::bail!("Unexpected {:?} at position {}", c, self.pos + 1),
+            None => anyhow::bail!("The expression ends too early"),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text.parse().map_err(|_| anyhow::anyhow!("{:?} is not a number", text))
+    }
+
+    fn name(&mut self) -> Result<f64> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric()) {
+            self.pos += 1;
+        }
+        let name: String = self.chars[start..self.pos].iter().collect::<String>().to_lowercase();
+        match name.as_str() {
+            "pi" => return Ok(std::f64::consts::PI),
+            "e" => return Ok(std::f64::consts::E),
+            _ => {}
+        }
+        let function: fn(f64) -> f64 = match name.as_str() {
+            "sqrt" => f64::sqrt,
+            "abs" => f64::abs,
+            "round" => f64::round,
+            "floor" => f64::floor,
+            "ceil" => f64::ceil,
+            "ln" => f64::ln,
+            "log10" | "log" => f64::log10,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            _ => anyhow::bail!("Unknown function or constant {}", name),
+        };
+        if self.peek() != Some('(') {
+            anyhow::bail!("{} needs its argument in parentheses", name);
+        }
+        Ok(function(self.atom()?))
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<f64>) -> Result<f64> {
+        if self.depth >= MAX_DEPTH {
+            anyhow::bail!("The expression is nested too deeply");
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+}
+
+/// Roll dice written as `NdM+K`, e.g. `d20`, `3d6` or `2d8-1`. Returns the
+/// single rolls and the total with the modifier.
+pub fn roll(notation: &str) -> Result<(Vec<u32>, i64)> {
+    let notation = notation.trim().to_lowercase().replace(' ', "");
+    let (count, rest) = notation
+        .split_once('d')
+        .ok_or_else(|| anyhow::anyhow!("{:?} is not dice notation like 2d6+1", notation))?;
+    let count: u32 = if count.is_empty() { 1 } else { count.parse()? };
+    let (sides, modifier) = match rest.find(['+', '-']) {
+        Some(i) => (&rest[..i], rest[i..].parse::<i64>()?),
+        None => (rest, 0),
+    };
+    let sides: u32 = sides.parse()?;
+    if !(1..=MAX_DICE).contains(&count) {
+        anyhow::bail!("Roll between 1 and {} dice", MAX_DICE);
+    }
+    if !(2..=MAX_SIDES).contains(&sides) {
+        anyhow::bail!("Dice have between 2 and {} sides", MAX_SIDES);
+    }
+    let mut rng = rand::thread_rng();
+    let rolls: Vec<u32> = (0..count).map(|_| rng.gen_range(1..=sides)).collect();
+    let total = rolls
+        .iter()
+        .map(|&r| i64::from(r))
+        .sum::<i64>()
+        .checked_add(modifier)
+        .ok_or_else(|| anyhow::anyhow!("The modifier is too large"))?;
+    Ok((rolls, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> f64 {
+        evaluate(expression).unwrap_or_else(|e| panic!("{}: {}", expression, e))
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("2 * 3 ^ 2"), 18.0);
+        assert_eq!(eval("-2 ^ 2"), -4.0);
+        assert_eq!(eval("(-2) ^ 2"), 4.0);
+        assert_eq!(eval("2 ^ -1"), 0.5);
+        assert_eq!(eval("-3 * -2"), 6.0);
+        assert_eq!(eval("10 - -2"), 12.0);
+        assert_eq!(eval("7 % 4 * 2"), 6.0);
+        assert_eq!(eval("sqrt(16) + abs(-2) ^ 2"), 8.0);
+    }
+
+    #[test]
+    fn associativity() {
+        assert_eq!(eval("10 - 4 - 3"), 3.0);
+        assert_eq!(eval("64 / 4 / 2"), 8.0);
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(eval("--2"), 2.0);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("5 % 0").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("1 +").is_err());
+        assert!(evaluate("2(3)").is_err());
+        assert!(evaluate("foo(1)").is_err());
+        assert!(evaluate("sqrt 4").is_err());
+        assert!(evaluate("10 ^ 1000").is_err());
+    }
+
+    #[test]
+    fn depth_limit() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(eval(&nested(MAX_DEPTH - 1)), 1.0);
+        assert!(evaluate(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(evaluate(&nested(100_000)).is_err());
+        assert!(evaluate(&format!("{}1", "-".repeat(100_000))).is_err());
+        assert!(evaluate(&vec!["2"; 100_000].join("^")).is_err());
+    }
+
+    #[test]
+    fn format_drops_the_fraction_of_whole_numbers() {
+        assert_eq!(format(3.0), "3");
+        assert_eq!(format(-0.5), "-0.5");
+    }
+
+    #[test]
+    fn dice_rolls_stay_in_bounds() {
+        for _ in 0..100 {
+            let (rolls, total) = roll("3d6+2").unwrap();
+            assert_eq!(rolls.len(), 3);
+            assert!(rolls.iter().all(|r| (1..=6).contains(r)));
+            assert_eq!(total, rolls.iter().map(|&r| i64::from(r)).sum::<i64>() + 2);
+        }
+        let (rolls, total) = roll("D20 - 1").unwrap();
+        assert_eq!(rolls.len(), 1);
+        assert!((0..=19).contains(&total));
+    }
+
+    #[test]
+    fn dice_limits() {
+        assert!(roll("100d1000").is_ok());
+        assert!(roll("101d6").is_err());
+        assert!(roll("0d6").is_err());
+        assert!(roll("d1").is_err());
+        assert!(roll("d1001").is_err());
+        assert!(roll("2x6").is_err());
+        assert!(roll("d6+9223372036854775807").is_err());
+        assert!(roll("d6-9223372036854775808").is_ok());
+        assert!(roll("d6+99999999999999999999").is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+const WORDS: [&str; 10] = [
+    "apple", "river", "garden", "pencil", "silver", "orange", "window", "candle", "forest", "rocket",
+];
+
+/// Wrong answers before the connection is dropped
+pub const MAX_FAILURES: u32 = 3;
+
+/// A tiny human check shown before a new visitor's first LLM call
+pub struct Challenge {
+    pub question: String,
+    answer: String,
+}
+
+impl Challenge {
+    /// Either a small sum or a word to type back
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(0.5) {
+            let a = rng.gen_range(2..10);
+            let b = rng.gen_range(2..10);
+            Self {
+                question: format!("What is {} + {}?", a, b),
+                answer: (a + b).to_string(),
+            }
+        } else {
+            let word = WORDS.choose(&mut rng).copied().unwrap_or("apple");
+            Self {
+                question: format!("Please type the word \"{}\" in capital letters.", word),
+                answer: word.to_uppercase(),
+            }
+        }
+    }
+
+    pub fn check(&self, reply: &str) -> bool {
+        reply.trim() == self.answer
+    }
+}
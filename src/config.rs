@@ -0,0 +1,472 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::alias::ModelAlias;
+use crate::env;
+use crate::escalation::OfficeHours;
+use crate::llamacpp::ChatTemplate;
+use crate::llm::{self, ModelProfile};
+use crate::persona::Persona;
+use crate::redact::RedactionRule;
+use crate::schedule::Schedule;
+use crate::sentiment::FrustrationPolicy;
+use crate::template::Template;
+use crate::wizard::Wizard;
+
+/// Settings too structured for command-line flags, loaded with `--config`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Machine clients allowed to sign in with an HMAC token
+    #[serde(rename = "client")]
+    pub clients: Vec<SignedClient>,
+    /// Characters users can switch to with /persona
+    #[serde(rename = "persona")]
+    pub personas: Vec<Persona>,
+    /// Routing hints per model for OpenRouter-style gateways
+    #[serde(rename = "model")]
+    pub models: Vec<ModelProfile>,
+    /// Extra headers for every LLM request, e.g. for gateways that route on them
+    pub llm_headers: BTreeMap<String, String>,
+    /// Friendly names for models, optionally on another endpoint
+    #[serde(rename = "alias")]
+    pub aliases: Vec<ModelAlias>,
+    /// Time windows with their own default persona, model and quota
+    #[serde(rename = "schedule")]
+    pub schedules: Vec<Schedule>,
+    /// Prompts with slots users fill in step by step with /template
+    #[serde(rename = "template")]
+    pub templates: Vec<Template>,
+    /// Multi-step flows started with /wizard
+    #[serde(rename = "wizard")]
+    pub wizards: Vec<Wizard>,
+    /// Prompt layout for the llama.cpp provider, ChatML if not set
+    pub chat_template: ChatTemplate,
+    /// When operators answer /human, always if not set
+    pub office_hours: Option<OfficeHours>,
+    /// Reaction to frustrated users, none if not set
+    pub frustration: Option<FrustrationPolicy>,
+    /// Patterns replaced in user messages before logging and the LLM
+    #[serde(rename = "redaction")]
+    pub redactions: Vec<RedactionRule>,
+    /// MCP servers whose tools the model may call
+    #[serde(rename = "mcp")]
+    pub mcp_servers: Vec<McpServer>,
+}
+
+/// A script or service talking to telllm over raw TCP
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignedClient {
+    pub id: String,
+    /// Shared secret for HMAC-SHA256 tokens
+    pub key: String,
+    /// Storage quota in MB, wins over --client-quota-mb (0 for unlimited)
+    #[serde(default)]
+    pub quota_mb: Option<u64>,
+}
+
+/// A Model Context Protocol server whose tools the model may call, `[[mcp]]` in the config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "tools"), allow(dead_code))]
+pub struct McpServer {
+    /// Prefix of its tools' names, `name_tool`
+    pub name: String,
+    /// Program started with the server and spoken to over stdin and stdout
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Environment of the program on top of the server's own
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Streamable HTTP endpoint, instead of `command`
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Sent with every HTTP request, e.g. `Authorization`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl McpServer {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() || !self.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            anyhow::bail!("MCP server {:?}: the name may only have letters, digits, - and _", self.name);
+        }
+        match (&self.command, &self.url) {
+            (Some(_), Some(_)) => anyhow::bail!("MCP server {}: set command or url, not both", self.name),
+            (None, None) => anyhow::bail!("MCP server {}: needs a command or a url", self.name),
+            (None, Some(url)) => {
+                reqwest::Url::parse(url).with_context(|| format!("MCP server {}: {} is not a URL", self.name, url))?;
+            }
+            (Some(_), None) => {}
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// The `--config` file, if any, with `TELLLM_<TABLE>__<KEY>` environment
+    /// variables applied over it
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let text = match path {
+            Some(path) => fs::read_to_string(path)
+                .with_context(|| format!("Failed to read config {}", path.display()))?,
+            None => String::new(),
+        };
+        let source = path.map_or_else(|| "defaults".to_string(), |path| path.display().to_string());
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|e| anyhow::anyhow!("Invalid config {}, {}", source, describe_error(&text, &e)))?;
+
+        let overrides = env::config_overrides();
+        if !overrides.is_empty() {
+            let mut root = toml::Value::Table(toml::from_str(&text).context("Invalid config")?);
+            for o in &overrides {
+                apply_override(&mut root, &o.path, &o.value).with_context(|| format!("Invalid {}", o.var))?;
+            }
+            let vars: Vec<&str> = overrides.iter().map(|o| o.var.as_str()).collect();
+            config = root.try_into().map_err(|e| {
+                anyhow::anyhow!("Invalid config {} with {}, {}", source, vars.join(", "), describe_error("", &e))
+            })?;
+        }
+
+        for schedule in &config.schedules {
+            schedule.validate()?;
+            if let Some(persona) = &schedule.persona
+                && config.persona(persona).is_none()
+            {
+                anyhow::bail!("Schedule {}: unknown persona {}{}", schedule.name, persona, config.persona_hint(persona));
+            }
+        }
+        for profile in &config.models {
+            profile
+                .tuning()
+                .validate()
+                .map_err(|e| anyhow::anyhow!("Model {}: {}", profile.name, e))?;
+            llm::validate_logit_bias(&profile.logit_bias).map_err(|e| anyhow::anyhow!("Model {}: {}", profile.name, e))?;
+        }
+        for rule in &config.redactions {
+            rule.validate()?;
+        }
+        for (i, server) in config.mcp_servers.iter().enumerate() {
+            server.validate()?;
+            if config.mcp_servers[..i].iter().any(|s| s.name == server.name) {
+                anyhow::bail!("MCP server {} is defined twice", server.name);
+            }
+        }
+        for (i, alias) in config.aliases.iter().enumerate() {
+            alias.validate()?;
+            if config.aliases[..i].iter().any(|a| a.name == alias.name) {
+                anyhow::bail!("Alias {} is defined twice", alias.name);
+            }
+        }
+        for template in &config.templates {
+            template.validate()?;
+        }
+        for wizard in &config.wizards {
+            wizard.validate()?;
+        }
+        if let Some(hours) = &config.office_hours {
+            hours.validate()?;
+        }
+        if let Some(persona) = config.frustration.as_ref().and_then(|f| f.persona.as_ref())
+            && config.persona(persona).is_none()
+        {
+            anyhow::bail!("Frustration: unknown persona {}{}", persona, config.persona_hint(persona));
+        }
+        Ok(config)
+    }
+
+    /// ` (did you mean ...?)` for a persona name that isn't configured
+    fn persona_hint(&self, name: &str) -> String {
+        closest(name, self.personas.iter().map(|p| p.name.as_str()))
+            .map(|guess| format!(" (did you mean {}?)", guess))
+            .unwrap_or_default()
+    }
+
+    pub fn persona(&self, name: &str) -> Option<&Persona> {
+        self.personas.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn template(&self, name: &str) -> Option<&Template> {
+        self.templates.iter().find(|t| t.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn wizard(&self, name: &str) -> Option<&Wizard> {
+        self.wizards.iter().find(|w| w.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn client(&self, id: &str) -> Option<&SignedClient> {
+        self.clients.iter().find(|c| c.id == id)
+    }
+}
+
+/// The config in force, swapped by `reload` on the admin console. Sessions
+/// take what they need from `get` and keep it only as long as they need it.
+pub struct LiveConfig {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config, path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Load the file again. Models, aliases, LLM headers, the chat template,
+    /// redactions and MCP servers were built into the server at startup, so
+    /// they stay as they are until a restart.
+    pub fn reread(&self) -> Result<Config> {
+        let mut config = Config::load(self.path.as_deref())?;
+        let current = self.get();
+        config.models = current.models.clone();
+        config.llm_headers = current.llm_headers.clone();
+        config.aliases = current.aliases.clone();
+        config.chat_template = current.chat_template.clone();
+        config.redactions = current.redactions.clone();
+        config.mcp_servers = current.mcp_servers.clone();
+        Ok(config)
+    }
+
+    pub fn replace(&self, config: Config) {
+        *self.current.write().unwrap() = Arc::new(config);
+    }
+}
+
+/// `telllm config validate`: load the file as the server would and sum it up
+pub fn validate(path: &Path) -> Result<()> {
+    let config = Config::load(Some(path))?;
+    println!("{} is valid", path.display());
+    for o in env::config_overrides() {
+        println!("  {} overrides {}", o.var, o.path.join("."));
+    }
+    let counts = [
+        ("client", config.clients.len()),
+        ("persona", config.personas.len()),
+        ("model", config.models.len()),
+        ("alias", config.aliases.len()),
+        ("schedule", config.schedules.len()),
+        ("template", config.templates.len()),
+        ("wizard", config.wizards.len()),
+        ("redaction", config.redactions.len()),
+        ("mcp", config.mcp_servers.len()),
+    ];
+    for (table, count) in counts {
+        if count > 0 {
+            println!("  {:<10} {}", format!("[[{}]]", table), count);
+        }
+    }
+    for (table, set) in [
+        ("llm_headers", !config.llm_headers.is_empty()),
+        ("office_hours", config.office_hours.is_some()),
+        ("frustration", config.frustration.is_some()),
+    ] {
+        if set {
+            println!("  [{}]", table);
+        }
+    }
+    Ok(())
+}
+
+/// Set `path` in the config to `value`, read as a TOML value if it is one
+/// and as a string otherwise. Missing tables and array elements are created.
+fn apply_override(root: &mut toml::Value, path: &[String], value: &str) -> Result<()> {
+    let value = toml::from_str::<toml::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()));
+    let Some((key, parents)) = path.split_last() else {
+        anyhow::bail!("No key");
+    };
+    let mut node = root;
+    for (i, segment) in parents.iter().enumerate() {
+        let array_next = path[i + 1].parse::<usize>().is_ok();
+        node = match node {
+            toml::Value::Table(table) => table.entry(segment.clone()).or_insert_with(|| {
+                if array_next {
+                    toml::Value::Array(Vec::new())
+                } else {
+                    toml::Value::Table(toml::Table::new())
+                }
+            }),
+            toml::Value::Array(items) => element(items, segment)?,
+            _ => anyhow::bail!("{} is not a table", path[..i].join(".")),
+        };
+    }
+    match node {
+        toml::Value::Table(table) => {
+            table.insert(key.clone(), value);
+        }
+        toml::Value::Array(items) => *element(items, key)? = value,
+        _ => anyhow::bail!("{} is not a table", parents.join(".")),
+    }
+    Ok(())
+}
+
+/// Element `index` of an array of tables, padded with empty tables up to it
+fn element<'a>(items: &'a mut Vec<toml::Value>, index: &str) -> Result<&'a mut toml::Value> {
+    let index: usize = index
+        .parse()
+        .with_context(|| format!("{} is not an array index", index))?;
+    while items.len() <= index {
+        items.push(toml::Value::Table(toml::Table::new()));
+    }
+    Ok(&mut items[index])
+}
+
+/// A TOML or schema error as `line L, column C, at <key path>: <problem>`,
+/// with a guess when a field or value looks misspelt
+fn describe_error(text: &str, error: &toml::de::Error) -> String {
+    let message = error.message().trim_end();
+    let mut out = String::new();
+    if let Some(span) = error.span() {
+        let start = span.start.min(text.len());
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line = text[..start].matches('\n').count() + 1;
+        let column = text[line_start..start].chars().count() + 1;
+        out.push_str(&format!("line {}, column {}", line, column));
+        if let Some(path) = key_path(text, line_start) {
+            out.push_str(&format!(", at {}", path));
+        }
+        out.push_str(": ");
+    }
+    out.push_str(message);
+    if let Some(guess) = suggestion(message) {
+        out.push_str(&format!(" (did you mean `{}`?)", guess));
+    }
+    out
+}
+
+/// Where the line starting at `line_start` sits, e.g. `wizard[1].step[0].prompt`
+fn key_path(text: &str, line_start: usize) -> Option<String> {
+    let current = text[line_start..].lines().next().unwrap_or("").trim();
+    // Elements seen so far of each array of tables, reset with their parent
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut table: Option<String> = None;
+    for line in text[..line_start].lines().chain(std::iter::once(current)) {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix("[[").and_then(|l| l.split("]]").next()) {
+            let name = name.trim();
+            counts.retain(|k, _| !k.starts_with(&format!("{}.", name)));
+            *counts.entry(name.to_string()).or_default() += 1;
+            table = Some(indexed(name, &counts));
+        } else if let Some(name) = line.strip_prefix('[').and_then(|l| l.split(']').next()) {
+            table = Some(indexed(name.trim(), &counts));
+        }
+    }
+    let key = current
+        .split_once('=')
+        .map(|(key, _)| key.trim())
+        .filter(|key| !key.is_empty() && !current.starts_with('['));
+    match (table, key) {
+        (Some(table), Some(key)) => Some(format!("{}.{}", table, key)),
+        (table, key) => table.or(key.map(str::to_string)),
+    }
+}
+
+/// `a.b.c` with the current element index after every array of tables on the way
+fn indexed(name: &str, counts: &HashMap<String, usize>) -> String {
+    let mut prefix = String::new();
+    let mut out = String::new();
+    for part in name.split('.') {
+        if !prefix.is_empty() {
+            prefix.push('.');
+            out.push('.');
+        }
+        prefix.push_str(part.trim());
+        out.push_str(part.trim());
+        if let Some(count) = counts.get(&prefix) {
+            out.push_str(&format!("[{}]", count - 1));
+        }
+    }
+    out
+}
+
+/// The closest expected name in serde's `unknown field `x`, expected one of `a`, `b``
+fn suggestion(message: &str) -> Option<&str> {
+    let rest = message
+        .strip_prefix("unknown field `")
+        .or_else(|| message.strip_prefix("unknown variant `"))?;
+    let (name, expected) = rest.split_once('`')?;
+    let candidates = expected.split('`').skip(1).step_by(2);
+    closest(name, candidates)
+}
+
+/// The candidate a typo of `name` most likely meant, if any is close enough
+pub fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let name = name.to_lowercase();
+    let limit = (name.chars().count() / 3).max(2);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(&name, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_swaps_personas_and_keeps_aliases() {
+        let path = std::env::temp_dir().join(format!("telllm-reload-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "[[persona]]\nname = \"pirate\"\nsystem_prompt = \"Arr\"\n\n\
+             [[alias]]\nname = \"fast\"\nmodel = \"small\"\n",
+        )
+        .unwrap();
+        let live = LiveConfig::new(Config::load(Some(&path)).unwrap(), Some(path.clone()));
+
+        fs::write(
+            &path,
+            "[[persona]]\nname = \"butler\"\nsystem_prompt = \"Indeed\"\n\n\
+             [[alias]]\nname = \"slow\"\nmodel = \"big\"\n",
+        )
+        .unwrap();
+        live.replace(live.reread().unwrap());
+        let config = live.get();
+        assert!(config.persona("butler").is_some());
+        assert!(config.persona("pirate").is_none());
+        assert_eq!(config.aliases[0].name, "fast");
+
+        // A broken file leaves the running config alone
+        fs::write(&path, "[[persona]]\nname = 1\n").unwrap();
+        assert!(live.reread().is_err());
+        assert!(live.get().persona("butler").is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
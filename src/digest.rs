@@ -0,0 +1,241 @@
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, NaiveTime, Weekday};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::escalation;
+use crate::index;
+use crate::llm::Message;
+use crate::session::SessionContext;
+use crate::stats;
+
+/// Local time the digest goes out on its day
+const SEND_AT: (u32, u32) = (9, 0);
+/// Distinct error messages remembered between digests
+const MAX_ERRORS: usize = 100;
+const TOP_ERRORS: usize = 5;
+/// Conversation titles handed to the LLM for the topic summary
+const MAX_TITLES: usize = 200;
+
+const TOPICS_PROMPT: &str = "You summarize what visitors of a chat service asked about. \
+    Reply with at most five short bullet points naming the most common topics, nothing else.";
+
+/// LLM errors seen since the last digest, by message
+#[derive(Default)]
+pub struct ErrorTally(Mutex<HashMap<String, usize>>);
+
+impl ErrorTally {
+    pub fn record(&self, error: &anyhow::Error) {
+        let mut errors = self.0.lock().unwrap();
+        let message = error.to_string();
+        if errors.len() < MAX_ERRORS || errors.contains_key(&message) {
+            *errors.entry(message).or_default() += 1;
+        }
+    }
+
+    /// The most frequent errors, most frequent first, and start over
+    fn take_top(&self) -> Vec<(String, usize)> {
+        let mut errors: Vec<_> = self.0.lock().unwrap().drain().collect();
+        errors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        errors.truncate(TOP_ERRORS);
+        errors
+    }
+}
+
+/// Next `day` at `SEND_AT` strictly after `now`
+fn next_run(now: DateTime<Local>, day: Weekday) -> DateTime<Local> {
+    let at = NaiveTime::from_hms_opt(SEND_AT.0, SEND_AT.1, 0).unwrap();
+    let mut date = now.date_naive();
+    loop {
+        if date.weekday() == day
+            && let Some(run) = date.and_time(at).and_local_timezone(Local).earliest()
+            && run > now
+        {
+            return run;
+        }
+        date = date.succ_opt().unwrap_or(date);
+    }
+}
+
+/// Send the weekly digest to the webhook every `day`
+pub async fn run(ctx: Arc<SessionContext>, day: Weekday) {
+    loop {
+        let now = Local::now();
+        let next = next_run(now, day);
+        info!("Next weekly digest at {}", next.format("%d-%m-%Y %H:%M"));
+        tokio::time::sleep((next - now).to_std().unwrap_or(Duration::from_secs(60))).await;
+
+        let text = compile(&ctx).await;
+        if let Some(webhook) = &ctx.webhook {
+            webhook.notify("weekly_digest", &text).await;
+        }
+    }
+}
+
+/// Counts of connection outcomes and distinct served addresses from the audit log
+fn traffic(logs_dir: &str, since: NaiveDate, until: NaiveDate) -> (BTreeMap<String, usize>, usize) {
+    let mut outcomes = BTreeMap::new();
+    let mut served = HashSet::new();
+    let audit = fs::read_to_string(Path::new(logs_dir).join("audit.jsonl")).unwrap_or_default();
+    for line in audit.lines() {
+        let Ok(entry) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        let (Some(time), Some(outcome)) = (entry["time"].as_str(), entry["outcome"].as_str()) else {
+            // Admin actions have no outcome
+            continue;
+        };
+        let Ok(time) = DateTime::parse_from_rfc3339(time) else {
+            continue;
+        };
+        let date = time.with_timezone(&Local).date_naive();
+        if date < since || date >= until {
+            continue;
+        }
+        *outcomes.entry(outcome.to_string()).or_default() += 1;
+        if outcome == "served"
+            && let Some(ip) = entry["ip"].as_str()
+        {
+            served.insert(ip.to_string());
+        }
+    }
+    (outcomes, served.len())
+}
+
+/// Suppressed inputs by verdict; text logs don't record the verdict
+fn moderation(content: &str, events: &mut BTreeMap<String, usize>) {
+    for line in content.lines().filter(|line| line.contains("Abusive input suppressed")) {
+        let verdict = serde_json::from_str::<Value>(line)
+            .ok()
+            .and_then(|entry| entry["moderation"].as_str().map(str::to_string))
+            .unwrap_or_else(|| "unknown".to_string());
+        *events.entry(verdict).or_default() += 1;
+    }
+}
+
+/// Ask the LLM for the week's common topics, going by conversation titles only
+async fn topics(ctx: &SessionContext, titles: &[String]) -> String {
+    if titles.is_empty() {
+        return "no conversations".to_string();
+    }
+    let messages = [
+        Message {
+            role: "system".to_string(),
+            content: TOPICS_PROMPT.to_string(),
+        },
+        Message {
+            role: "user".to_string(),
+            content: titles.join("\n"),
+        },
+    ];
+    match ctx.llm.chat(&messages).await {
+        Ok(summary) => summary.trim().to_string(),
+        Err(e) => {
+            warn!("Failed to summarize digest topics: {}", e);
+            format!("unavailable ({})", e)
+        }
+    }
+}
+
+/// The digest for the seven days before today
+async fn compile(ctx: &SessionContext) -> String {
+    let until = Local::now().date_naive();
+    let since = until.checked_sub_days(Days::new(7)).unwrap_or(until);
+
+    let (outcomes, addresses) = traffic(&ctx.logs_dir, since, until);
+    let logs = match stats::chat_logs(&ctx.logs_dir, since) {
+        Ok(logs) => logs,
+        Err(e) => {
+            warn!("Failed to read chat logs for the digest: {}", e);
+            Vec::new()
+        }
+    };
+
+    let mut clients = HashSet::new();
+    let mut messages = 0;
+    let mut events = BTreeMap::new();
+    let mut titles = Vec::new();
+    let mut indexes = HashMap::new();
+    for log in logs.iter().filter(|log| log.date < until) {
+        clients.insert(&log.client_dir);
+        messages += stats::count_messages(&log.path);
+        if let Ok(content) = fs::read_to_string(&log.path) {
+            moderation(&content, &mut events);
+        }
+        let index = indexes
+            .entry(&log.client_dir)
+            .or_insert_with(|| index::load(&log.client_dir));
+        if titles.len() < MAX_TITLES
+            && let Some(title) = index
+                .get(&log.date.format("%d-%m-%y").to_string())
+                .and_then(|entry| entry.title.clone())
+        {
+            titles.push(title);
+        }
+    }
+
+    let mut text = format!(
+        "Weekly digest {} to {}\n",
+        since.format("%d-%m-%Y"),
+        until.pred_opt().unwrap_or(until).format("%d-%m-%Y")
+    );
+    text.push_str(&format!(
+        "Traffic: {} connections served to {} addresses, {} messages from {} clients\n",
+        outcomes.get("served").copied().unwrap_or(0),
+        addresses,
+        messages,
+        clients.len()
+    ));
+
+    let turned_away: Vec<String> = outcomes
+        .iter()
+        .filter(|(outcome, _)| !matches!(outcome.as_str(), "served" | "login"))
+        .map(|(outcome, count)| format!("{} {}", outcome, count))
+        .collect();
+    if !turned_away.is_empty() {
+        text.push_str(&format!("Turned away: {}\n", turned_away.join(", ")));
+    }
+
+    let errors = ctx.errors.take_top();
+    if errors.is_empty() {
+        text.push_str("Errors: none\n");
+    } else {
+        text.push_str("Top errors since the last digest:\n");
+        for (error, count) in errors {
+            text.push_str(&format!("  {}x {}\n", count, error));
+        }
+    }
+
+    let suppressed: usize = events.values().sum();
+    if suppressed > 0 {
+        let kinds: Vec<String> = events
+            .iter()
+            .map(|(verdict, count)| format!("{} {}", verdict, count))
+            .collect();
+        text.push_str(&format!(
+            "Moderation: {} inputs suppressed ({})\n",
+            suppressed,
+            kinds.join(", ")
+        ));
+    }
+
+    let handoffs = escalation::handoffs_since(&ctx.logs_dir, since);
+    if !handoffs.is_empty() {
+        text.push_str(&format!("Requests left outside office hours: {}\n", handoffs.len()));
+        for handoff in handoffs {
+            text.push_str(&format!(
+                "  {} {}: {}\n",
+                handoff.time.format("%d-%m %H:%M"),
+                handoff.name.unwrap_or_else(|| handoff.addr.ip().to_string()),
+                handoff.request
+            ));
+        }
+    }
+
+    text.push_str(&format!("Topics:\n{}", topics(ctx, &titles).await));
+    text
+}
@@ -0,0 +1,130 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::breaker::CircuitBreaker;
+
+/// How requests are spread over several endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Balance {
+    /// Always the first healthy endpoint, the others only stand by
+    Failover,
+    /// Take turns, each endpoint as often as its weight says
+    RoundRobin,
+    /// The endpoint with the fewest requests waiting for an answer, relative to its weight
+    LeastInFlight,
+}
+
+/// The `--endpoint` values in order of preference, each with its own health
+pub struct Endpoints {
+    list: Vec<Endpoint>,
+    balance: Balance,
+    /// Smooth weighted round-robin state, one counter per endpoint
+    turns: Mutex<Vec<i64>>,
+}
+
+pub struct Endpoint {
+    /// Base URL, request paths are appended to it
+    pub base: String,
+    pub weight: u32,
+    pub breaker: CircuitBreaker,
+    in_flight: AtomicUsize,
+}
+
+impl Endpoint {
+    /// Requests sent and not answered yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Count a request as in flight until the returned guard is dropped
+    pub fn start(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(&self.in_flight)
+    }
+}
+
+pub struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Endpoints {
+    /// `weights` line up with `bases`, missing ones are 1. `failures` and
+    /// `cooldown` configure each endpoint's circuit breaker.
+    pub fn new(bases: Vec<String>, weights: &[u32], balance: Balance, failures: u32, cooldown: Duration) -> Self {
+        let list: Vec<Endpoint> = bases
+            .into_iter()
+            .enumerate()
+            .map(|(i, base)| Endpoint {
+                base: base.trim_end_matches('/').to_string(),
+                weight: weights.get(i).copied().unwrap_or(1).max(1),
+                breaker: CircuitBreaker::new(failures, cooldown),
+                in_flight: AtomicUsize::new(0),
+            })
+            .collect();
+        Self {
+            turns: Mutex::new(vec![0; list.len()]),
+            list,
+            balance,
+        }
+    }
+
+    /// In order of preference
+    pub fn iter(&self) -> impl Iterator<Item = &Endpoint> {
+        self.list.iter()
+    }
+
+    pub fn balance(&self) -> Balance {
+        self.balance
+    }
+
+    /// The order one request tries the endpoints in: the one `--balance`
+    /// picks first, then the rest as configured to fail over to
+    pub fn order(&self) -> Vec<&Endpoint> {
+        let mut order: Vec<&Endpoint> = self.list.iter().collect();
+        let first = match self.balance {
+            Balance::Failover => None,
+            Balance::RoundRobin => self.next_turn(),
+            Balance::LeastInFlight => self
+                .list
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| e.breaker.is_available())
+                // a/wa < b/wb without dividing; the first configured wins ties
+                .min_by(|(_, a), (_, b)| {
+                    (a.in_flight() as u64 * u64::from(b.weight)).cmp(&(b.in_flight() as u64 * u64::from(a.weight)))
+                })
+                .map(|(i, _)| i),
+        };
+        if let Some(first) = first {
+            let chosen = order.remove(first);
+            order.insert(0, chosen);
+        }
+        order
+    }
+
+    /// Smooth weighted round-robin over the available endpoints: every turn
+    /// each gains its weight, the one furthest ahead goes and pays the total
+    fn next_turn(&self) -> Option<usize> {
+        let mut turns = self.turns.lock().unwrap();
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (i, endpoint) in self.list.iter().enumerate() {
+            if !endpoint.breaker.is_available() {
+                continue;
+            }
+            turns[i] += i64::from(endpoint.weight);
+            total += i64::from(endpoint.weight);
+            if best.is_none_or(|b| turns[i] > turns[b]) {
+                best = Some(i);
+            }
+        }
+        let best = best?;
+        turns[best] -= total;
+        Some(best)
+    }
+}
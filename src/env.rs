@@ -0,0 +1,186 @@
+use anyhow::{Result, bail};
+use clap::{Arg, Command};
+use std::ffi::OsString;
+
+use crate::config;
+use crate::handover;
+
+/// Environment variables starting with this override flags and config keys
+const PREFIX: &str = "TELLLM_";
+/// Separates the levels of a config key, `TELLLM_OFFICE_HOURS__TIMEZONE`
+const NESTING: &str = "__";
+/// Variables the server passes to itself, e.g. to the process an upgrade starts
+const INTERNAL: &[&str] = &[handover::LISTEN_FDS_ENV];
+
+/// A `TELLLM_*` variable for a key of the `--config` file
+pub struct ConfigOverride {
+    pub var: String,
+    /// Lowercase key path, array elements by index
+    pub path: Vec<String>,
+    pub value: String,
+}
+
+/// `TELLLM_*` variables with their prefix, skipping ones that aren't valid Unicode
+fn vars() -> impl Iterator<Item = (String, String)> {
+    std::env::vars_os().filter_map(|(var, value)| {
+        let var = var.into_string().ok()?;
+        let value = value.into_string().ok()?;
+        var.starts_with(PREFIX).then_some((var, value))
+    })
+}
+
+/// Variables naming config keys, i.e. with `__` between the levels
+pub fn config_overrides() -> Vec<ConfigOverride> {
+    let mut overrides: Vec<ConfigOverride> = vars()
+        .filter(|(var, _)| var.contains(NESTING))
+        .map(|(var, value)| ConfigOverride {
+            path: var[PREFIX.len()..].split(NESTING).map(str::to_lowercase).collect(),
+            var,
+            value,
+        })
+        .collect();
+    overrides.sort_by(|a, b| a.var.cmp(&b.var));
+    overrides
+}
+
+fn var_for(arg: &Arg) -> Option<String> {
+    arg.get_long()
+        .map(|long| format!("{}{}", PREFIX, long.to_uppercase().replace('-', "_")))
+}
+
+/// The command line with `TELLLM_<FLAG>` variables in place of the flags they
+/// name, so the environment wins: `TELLLM_ENDPOINT` replaces every
+/// `--endpoint`. Boolean flags take `true`/`false`, flags that accept a
+/// comma-separated list take one in the variable too.
+pub fn args_with_overrides(command: &Command) -> Result<Vec<OsString>> {
+    rewrite(command, vars(), std::env::args_os())
+}
+
+fn rewrite(
+    command: &Command,
+    vars: impl Iterator<Item = (String, String)>,
+    mut argv: impl Iterator<Item = OsString>,
+) -> Result<Vec<OsString>> {
+    let mut overridden: Vec<(&Arg, String, String)> = Vec::new();
+    for (var, value) in vars.filter(|(var, _)| !var.contains(NESTING) && !INTERNAL.contains(&var.as_str())) {
+        match command.get_arguments().find(|arg| var_for(arg).as_deref() == Some(var.as_str())) {
+            Some(arg) => overridden.push((arg, var, value)),
+            None => {
+                let known: Vec<String> = command.get_arguments().filter_map(var_for).collect();
+                let hint = config::closest(&var, known.iter().map(String::as_str))
+                    .map(|guess| format!(" (did you mean {}?)", guess))
+                    .unwrap_or_default();
+                bail!("{} doesn't name a flag{}", var, hint);
+            }
+        }
+    }
+
+    let mut out: Vec<OsString> = argv.next().into_iter().collect();
+    for (arg, var, value) in &overridden {
+        let flag = format!("--{}", arg.get_long().unwrap_or_default());
+        if !arg.get_action().takes_values() {
+            match value.to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => out.push(flag.into()),
+                "0" | "false" | "no" | "off" | "" => {}
+                _ => bail!("{} takes true or false", var),
+            }
+            continue;
+        }
+        let values: Vec<&str> = match arg.get_value_delimiter() {
+            Some(delimiter) => value.split(delimiter).map(str::trim).collect(),
+            None => vec![value.as_str()],
+        };
+        for value in values {
+            out.push(format!("{}={}", flag, value).into());
+        }
+    }
+
+    // What's left of the command line. Past the first positional argument
+    // (the subcommand) the flags aren't ours.
+    let mut verbatim = false;
+    let mut value_of: Option<bool> = None;
+    for token in argv {
+        if let Some(keep) = value_of.take() {
+            if keep {
+                out.push(token);
+            }
+            continue;
+        }
+        let text = token.to_string_lossy().into_owned();
+        if verbatim || text == "--" || !text.starts_with('-') || text == "-" {
+            verbatim = true;
+            out.push(token);
+            continue;
+        }
+        let (arg, inline_value) = match text.strip_prefix("--") {
+            Some(long) => {
+                let (name, inline) = match long.split_once('=') {
+                    Some((name, _)) => (name, true),
+                    None => (long, false),
+                };
+                (command.get_arguments().find(|a| a.get_long() == Some(name)), inline)
+            }
+            None => {
+                let short = text.chars().nth(1);
+                let arg = command.get_arguments().find(|a| a.get_short().is_some() && a.get_short() == short);
+                (arg, text.chars().count() > 2)
+            }
+        };
+        let Some(arg) = arg else {
+            out.push(token);
+            continue;
+        };
+        let keep = !overridden.iter().any(|(o, _, _)| o.get_id() == arg.get_id());
+        if arg.get_action().takes_values() && !inline_value {
+            value_of = Some(keep);
+        }
+        if keep {
+            out.push(token);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    fn run(vars: &[(&str, &str)], argv: &[&str]) -> Result<Vec<String>> {
+        let command = crate::Args::command();
+        let vars = vars.iter().map(|(var, value)| (var.to_string(), value.to_string()));
+        let argv = argv.iter().map(OsString::from);
+        Ok(rewrite(&command, vars, argv)?
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    #[test]
+    fn internal_variables_are_not_flags() {
+        let args = run(&[("TELLLM_LISTEN_FDS", "0.0.0.0:2323=3")], &["telllm", "--port", "23"]).unwrap();
+        assert_eq!(args, ["telllm", "--port", "23"]);
+    }
+
+    #[test]
+    fn unknown_variables_are_refused_with_a_hint() {
+        let e = run(&[("TELLLM_PROT", "23")], &["telllm"]).unwrap_err();
+        assert!(e.to_string().contains("did you mean TELLLM_PORT?"), "{}", e);
+    }
+
+    #[test]
+    fn variables_replace_their_flags() {
+        let args = run(
+            &[("TELLLM_ENDPOINT", "http://a/v1, http://b/v1"), ("TELLLM_FETCH", "off")],
+            &["telllm", "--endpoint", "http://c/v1", "--port=23", "selftest"],
+        )
+        .unwrap();
+        assert_eq!(args, ["telllm", "--endpoint=http://a/v1", "--endpoint=http://b/v1", "--port=23", "selftest"]);
+    }
+
+    #[test]
+    fn boolean_variables_take_true_or_false() {
+        assert_eq!(run(&[("TELLLM_FETCH", "yes")], &["telllm"]).unwrap(), ["telllm", "--fetch"]);
+        assert!(run(&[("TELLLM_FETCH", "maybe")], &["telllm"]).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::llm::Message;
+use crate::schedule;
+use crate::storage;
+
+/// Under the logs directory, one JSON file per request
+const QUEUE_DIR: &str = "escalations";
+
+/// Under the logs directory, one JSON line per request left outside office hours
+const HANDOFF_FILE: &str = "handoff.jsonl";
+
+/// When operators answer /human, `[office_hours]` in the config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OfficeHours {
+    /// Every day if empty (`mon`, `tuesday`, ...)
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// `HH:MM` local time
+    pub from: String,
+    /// May be before `from` to span midnight
+    pub until: String,
+    /// Shown to users who ask for a human outside the hours
+    pub message: String,
+}
+
+impl OfficeHours {
+    fn window(&self) -> Result<(NaiveTime, NaiveTime)> {
+        Ok((
+            schedule::parse_time("Office hours", &self.from)?,
+            schedule::parse_time("Office hours", &self.until)?,
+        ))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let (from, until) = self.window()?;
+        if from == until {
+            bail!("Office hours: from and until are the same");
+        }
+        Ok(())
+    }
+
+    pub fn is_open(&self, now: DateTime<Local>) -> bool {
+        self.window()
+            .is_ok_and(|(from, until)| schedule::in_window(&self.days, from, until, now))
+    }
+}
+
+/// What a user left for the operators outside office hours
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Handoff {
+    pub time: DateTime<Local>,
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Given with /human
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Contact details and request, in the user's words
+    pub request: String,
+}
+
+pub fn append_handoff(logs_dir: &str, handoff: &Handoff) -> Result<()> {
+    fs::create_dir_all(logs_dir).context("Failed to create logs directory")?;
+    let path = Path::new(logs_dir).join(HANDOFF_FILE);
+    let line = serde_json::to_string(handoff).context("Failed to serialize handoff")?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    writeln!(file, "{}", line).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Handoffs left on or after `since`
+pub fn handoffs_since(logs_dir: &str, since: NaiveDate) -> Vec<Handoff> {
+    let Ok(content) = fs::read_to_string(Path::new(logs_dir).join(HANDOFF_FILE)) else {
+        return Vec::new();
+    };
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Handoff>(line).ok())
+        .filter(|handoff| handoff.time.date_naive() >= since)
+        .collect()
+}
+
+/// A user's request for a human, with the conversation so far
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Escalation {
+    pub requested_at: DateTime<Local>,
+    /// Registry id, what `attach` takes on the admin console
+    pub session: u64,
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    pub messages: Vec<Message>,
+}
+
+/// Write the escalation to the queue, returning its file
+pub fn queue(logs_dir: &str, escalation: &Escalation) -> Result<PathBuf> {
+    let dir = Path::new(logs_dir).join(QUEUE_DIR);
+    fs::create_dir_all(&dir).context("Failed to create escalation queue")?;
+    let path = dir.join(format!(
+        "{}-{}.json",
+        escalation.requested_at.format("%Y%m%d-%H%M%S"),
+        escalation.session
+    ));
+    let json = serde_json::to_string_pretty(escalation).context("Failed to serialize escalation")?;
+    storage::write_atomic(&path, &json)?;
+    Ok(path)
+}
+
+/// Queued escalations, oldest first; unreadable files are skipped
+pub fn pending(logs_dir: &str) -> Vec<Escalation> {
+    let Ok(entries) = fs::read_dir(Path::new(logs_dir).join(QUEUE_DIR)) else {
+        return Vec::new();
+    };
+    let mut pending: Vec<Escalation> = entries
+        .flatten()
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+        .filter_map(|json| serde_json::from_str(&json).ok())
+        .collect();
+    pending.sort_by_key(|e| e.requested_at);
+    pending
+}
+
+/// Empty the queue once the operators have followed up, returning how many were removed
+pub fn clear(logs_dir: &str) -> Result<usize> {
+    let Ok(entries) = fs::read_dir(Path::new(logs_dir).join(QUEUE_DIR)) else {
+        return Ok(0);
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        fs::remove_file(entry.path()).with_context(|| format!("Failed to remove {}", entry.path().display()))?;
+        removed += 1;
+    }
+    Ok(removed)
+}
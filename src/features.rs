@@ -0,0 +1,33 @@
+use anyhow::{Result, bail};
+
+/// Optional subsystems and whether this binary was built with them
+const FEATURES: &[(&str, bool)] = &[
+    ("http", cfg!(feature = "http")),
+    ("metrics", cfg!(feature = "metrics")),
+    ("geoip", cfg!(feature = "geoip")),
+    ("tools", cfg!(feature = "tools")),
+    ("serial", cfg!(feature = "serial")),
+];
+
+/// The features compiled in, or left out with `false`
+pub fn names(enabled: bool) -> Vec<&'static str> {
+    FEATURES
+        .iter()
+        .filter(|(_, on)| *on == enabled)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Stop at startup when `flag` asks for a feature this build left out,
+/// rather than silently ignoring it
+pub fn require(feature: &str, flag: &str) -> Result<()> {
+    if FEATURES.iter().any(|(name, on)| *name == feature && *on) {
+        return Ok(());
+    }
+    bail!(
+        "{} needs the {} feature, this binary was built without it (cargo build --features {})",
+        flag,
+        feature,
+        feature
+    )
+}
@@ -0,0 +1,375 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::{StatusCode, Url};
+use serde_json::Value;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use crate::proxy;
+use crate::tools::Handler;
+
+/// Redirects followed, each one checked like the first URL
+const MAX_REDIRECTS: usize = 5;
+const TIMEOUT: Duration = Duration::from_secs(15);
+/// Text handed to the model, the rest of the page is cut off
+pub const MAX_TEXT_CHARS: usize = 12_000;
+
+/// Which pages /fetch and the fetch_url tool may download, from --fetch-allow,
+/// --fetch-deny and --fetch-max-kb
+#[derive(Debug, Clone)]
+pub struct FetchPolicy {
+    /// Only these hosts and their subdomains if not empty; they may be private addresses
+    pub allow: Vec<String>,
+    /// Never these hosts and their subdomains
+    pub deny: Vec<String>,
+    /// Largest download, the rest isn't read
+    pub max_bytes: usize,
+}
+
+/// A downloaded page as text
+pub struct Page {
+    pub url: Url,
+    pub text: String,
+    /// The text was longer than `MAX_TEXT_CHARS` or the download than `max_bytes`
+    pub truncated: bool,
+}
+
+impl FetchPolicy {
+    /// Download `url` and strip it to text, following redirects that pass the policy
+    pub async fn fetch(&self, url: &str) -> Result<Page> {
+        let mut url = Url::parse(url.trim()).with_context(|| format!("{} is not a URL", url.trim()))?;
+        for _ in 0..=MAX_REDIRECTS {
+            let client = match self.check(&url).await? {
+                Some(addrs) => proxy::pinned_client(url.host_str().unwrap_or_default(), &addrs)?,
+                None => proxy::no_redirects_client(),
+            };
+            let mut response = client
+                .get(url.clone())
+                .timeout(TIMEOUT)
+                .send()
+                .await
+                .with_context(|| format!("Failed to fetch {}", url))?;
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .and_then(|l| l.to_str().ok())
+                    .context("Redirect without a location")?;
+                url = url.join(location).context("Invalid redirect")?;
+                continue;
+            }
+            if response.status() != StatusCode::OK {
+                anyhow::bail!("{} answered {}", url, response.status());
+            }
+            let content_type = response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|t| t.to_str().ok())
+                .unwrap_or("text/html")
+                .to_lowercase();
+            if !content_type.starts_with("text/") && !content_type.contains("xhtml") {
+                anyhow::bail!("{} is {}, not a page", url, content_type);
+            }
+
+            let mut body = Vec::new();
+            let mut cut = false;
+            while let Some(chunk) = response.chunk().await.context("Download interrupted")? {
+                body.extend_from_slice(&chunk);
+                if body.len() >= self.max_bytes {
+                    body.truncate(self.max_bytes);
+                    cut = true;
+                    break;
+                }
+            }
+            let body = String::from_utf8_lossy(&body);
+            let text = if content_type.starts_with("text/plain") {
+                body.split_whitespace().collect::<Vec<_>>().join(" ")
+            } else {
+                html_to_text(&body)
+            };
+            let truncated = cut || text.chars().count() > MAX_TEXT_CHARS;
+            return Ok(Page {
+                url,
+                text: text.chars().take(MAX_TEXT_CHARS).collect(),
+                truncated,
+            });
+        }
+        anyhow::bail!("More than {} redirects", MAX_REDIRECTS)
+    }
+
+    /// Refuse schemes other than HTTP(S), hosts the lists rule out, and
+    /// private addresses unless the host is on the allow list. Returns the
+    /// addresses checked, which the request has to go to, `None` for an
+    /// allowed host.
+    async fn check(&self, url: &Url) -> Result<Option<Vec<SocketAddr>>> {
+        if !matches!(url.scheme(), "http" | "https") {
+            anyhow::bail!("Only http and https URLs can be fetched");
+        }
+        let host = url.host_str().context("The URL has no host")?.to_lowercase();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if self.deny.iter().any(|d| matches_host(host, d)) {
+            anyhow::bail!("{} is on the deny list", host);
+        }
+        let allowed = self.allow.iter().any(|a| matches_host(host, a));
+        if !self.allow.is_empty() && !allowed {
+            anyhow::bail!("{} is not on the allow list", host);
+        }
+        if allowed {
+            return Ok(None);
+        }
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .with_context(|| format!("Failed to resolve {}", host))?
+            .collect();
+        if addrs.is_empty() {
+            anyhow::bail!("{} has no address", host);
+        }
+        if addrs.iter().any(|addr| !is_public(addr.ip())) {
+            anyhow::bail!("{} is a private address", host);
+        }
+        Ok(Some(addrs))
+    }
+}
+
+/// `host` is `rule` or one of its subdomains
+fn matches_host(host: &str, rule: &str) -> bool {
+    let rule = rule.trim().trim_start_matches("*.").to_lowercase();
+    host == rule || host.ends_with(&format!(".{}", rule))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+                || v4.is_multicast()
+                // "This network" 0.0.0.0/8 and reserved 240.0.0.0/4
+                || a == 0
+                || a >= 240
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+                // Benchmarking, 198.18.0.0/15
+                || (a == 198 && b & 0xfe == 18))
+        }
+        IpAddr::V6(v6) => match embedded_ipv4(v6) {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => {
+                let first = v6.segments()[0];
+                !(v6.is_loopback()
+                    || v6.is_unspecified()
+                    || v6.is_multicast()
+                    // Unique local fc00::/7 and link-local fe80::/10
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The IPv4 address an IPv6 one reaches: mapped ::ffff:0:0/96, NAT64
+/// 64:ff9b::/96 and 6to4 2002::/16
+fn embedded_ipv4(v6: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = v6.segments();
+    let octets = v6.octets();
+    if let Some(v4) = v6.to_ipv4_mapped() {
+        Some(v4)
+    } else if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+    } else if segments[0] == 0x2002 {
+        Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5]))
+    } else {
+        None
+    }
+}
+
+/// The readable text of an HTML page: scripts, styles and tags dropped, block
+/// elements on lines of their own, common entities decoded
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = rest[1..end].trim().to_lowercase();
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect();
+        rest = &rest[end + 1..];
+        if !tag.starts_with('/') && matches!(name.as_str(), "script" | "style" | "noscript" | "svg" | "head") {
+            // Skip to the closing tag, the content isn't text
+            let closing = format!("</{}", name);
+            rest = match rest.to_ascii_lowercase().find(&closing) {
+                Some(i) => &rest[i..],
+                None => "",
+            };
+            continue;
+        }
+        if matches!(
+            name.as_str(),
+            "p" | "br" | "div" | "li" | "tr" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "section" | "article" | "pre" | "blockquote" | "title"
+        ) {
+            text.push('\n');
+        }
+    }
+    text.push_str(rest);
+
+    let text = decode_entities(&text);
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&mdash;", "—")
+        .replace("&ndash;", "–")
+        .replace("&hellip;", "…")
+        .replace("&amp;", "&")
+}
+
+/// The fetch_url tool, under the same policy as /fetch
+pub struct FetchTool(pub FetchPolicy);
+
+#[async_trait]
+impl Handler for FetchTool {
+    async fn call(&self, arguments: &Value) -> Result<String> {
+        let url = arguments
+            .get("url")
+            .and_then(Value::as_str)
+            .context("url is missing")?;
+        let page = self.0.fetch(url).await?;
+        let cut = if page.truncated { "\n[The page was cut off here]" } else { "" };
+        Ok(format!("Text of {}:\n\n{}{}", page.url, page.text, cut))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> FetchPolicy {
+        FetchPolicy {
+            allow: allow.iter().map(|h| h.to_string()).collect(),
+            deny: deny.iter().map(|h| h.to_string()).collect(),
+            max_bytes: 1024,
+        }
+    }
+
+    async fn check(policy: &FetchPolicy, url: &str) -> Result<Option<Vec<SocketAddr>>> {
+        policy.check(&Url::parse(url).unwrap()).await
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in [
+            "8.8.8.8",
+            "1.1.1.1",
+            "100.128.0.1",
+            "198.20.0.1",
+            "2606:4700:4700::1111",
+            "::ffff:8.8.8.8",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "255.255.255.255",
+            "192.0.2.1",
+            "100.64.0.1",
+            "100.127.255.255",
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.1.2.3",
+            "0.1.2.3",
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "ff02::1",
+            "ff0e::1",
+            "64:ff9b::a00:1",
+            "64:ff9b::7f00:1",
+            "2002:a9fe:a9fe::1",
+            "2002:c0a8:101::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn host_rules_cover_subdomains() {
+        assert!(matches_host("example.com", "example.com"));
+        assert!(matches_host("docs.example.com", "example.com"));
+        assert!(matches_host("a.b.example.com", "*.example.com"));
+        assert!(matches_host("example.com", " Example.COM "));
+        assert!(!matches_host("notexample.com", "example.com"));
+        assert!(!matches_host("example.com.evil.net", "example.com"));
+        assert!(!matches_host("example.org", "example.com"));
+    }
+
+    #[tokio::test]
+    async fn checks_refuse_what_the_policy_rules_out() {
+        let open = policy(&[], &[]);
+        assert!(check(&open, "ftp://example.com/").await.is_err());
+        assert!(check(&open, "file:///etc/passwd").await.is_err());
+        assert!(check(&open, "http://127.0.0.1/").await.is_err());
+        assert!(check(&open, "http://[::1]:8080/").await.is_err());
+        assert!(check(&open, "http://169.254.169.254/latest/meta-data").await.is_err());
+        let pinned = check(&open, "https://8.8.8.8/").await.unwrap().unwrap();
+        assert_eq!(pinned, vec!["8.8.8.8:443".parse().unwrap()]);
+
+        let denied = policy(&[], &["8.8.8.8"]);
+        assert!(check(&denied, "http://8.8.8.8/").await.is_err());
+
+        // Allowed hosts may be private and aren't pinned
+        let allowed = policy(&["127.0.0.1"], &[]);
+        assert!(check(&allowed, "http://127.0.0.1/").await.unwrap().is_none());
+        assert!(check(&allowed, "http://8.8.8.8/").await.is_err());
+    }
+
+    #[test]
+    fn html_becomes_text() {
+        let html = "<html><head><title>T</title><style>p { color: red }</style></head>\
+            <body><h1>Title</h1><p>One &amp; two&nbsp;&lt;three&gt;</p>\
+            <script>alert('<p>no</p>')</script><SCRIPT type=x>var a;</SCRIPT>\
+            <ul><li>a</li><li>b   c</li></ul>&amp;lt;</body></html>";
+        assert_eq!(html_to_text(html), "Title\nOne & two <three>\na\nb c\n&lt;");
+    }
+
+    #[test]
+    fn html_with_broken_tags() {
+        assert_eq!(html_to_text("text <b>bold</b> and <unclosed"), "text bold and");
+        assert_eq!(html_to_text("<script>never closed"), "");
+        assert_eq!(html_to_text("caf\u{e9} <br> na\u{ef}ve"), "caf\u{e9}\nna\u{ef}ve");
+    }
+}
@@ -0,0 +1,268 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::keys::KeyPool;
+use crate::llm::{self, ChatStream, Completion, LlmProvider, Message, ModelProfile, StreamEvent, TokenUsage, Tuning, Upstream};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateRequest {
+    contents: Vec<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    safety_settings: Option<Value>,
+    generation_config: GenerationConfig,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    /// `application/json` in JSON mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Content {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+    #[serde(default)]
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Part {
+    #[serde(default)]
+    text: String,
+}
+
+/// A whole reply, or one event of a streamed one
+#[derive(Debug, Deserialize)]
+struct GenerateResponse {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    #[serde(default, rename = "promptFeedback")]
+    prompt_feedback: Option<PromptFeedback>,
+    #[serde(default, rename = "usageMetadata")]
+    usage_metadata: Option<UsageMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    #[serde(default)]
+    content: Content,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    #[serde(default)]
+    block_reason: Option<String>,
+}
+
+/// Running totals, complete on the last event of a stream
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: u64,
+    #[serde(default)]
+    candidates_token_count: u64,
+    #[serde(default)]
+    total_token_count: u64,
+}
+
+impl GenerateResponse {
+    fn text(&self) -> Option<String> {
+        let candidate = self.candidates.first()?;
+        Some(candidate.content.parts.iter().map(|p| p.text.as_str()).collect())
+    }
+
+    /// The candidate's finish reason, or why the prompt was blocked outright
+    fn finish_reason(&self) -> Option<String> {
+        self.candidates
+            .first()
+            .and_then(|c| c.finish_reason.clone())
+            .or_else(|| self.prompt_feedback.as_ref()?.block_reason.clone())
+    }
+
+    fn usage(&self) -> Option<TokenUsage> {
+        self.usage_metadata.as_ref().map(|usage| TokenUsage {
+            prompt_tokens: usage.prompt_token_count,
+            completion_tokens: usage.candidates_token_count,
+            total_tokens: usage.total_token_count,
+        })
+    }
+}
+
+/// `GET /models`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelList {
+    #[serde(default)]
+    models: Vec<ModelInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelInfo {
+    /// `models/gemini-1.5-flash`
+    name: String,
+    #[serde(default)]
+    supported_generation_methods: Vec<String>,
+}
+
+/// In a header rather than `?key=`, which would end up in logged URLs
+fn authorize(req: reqwest::RequestBuilder, key: &str) -> reqwest::RequestBuilder {
+    req.header("x-goog-api-key", key)
+}
+
+/// Google's Gemini `generateContent` API
+pub struct GeminiClient {
+    upstream: Upstream,
+    model: String,
+    keys: Arc<KeyPool>,
+    profiles: Vec<ModelProfile>,
+    tuning: Tuning,
+}
+
+impl GeminiClient {
+    pub fn new(
+        upstream: Upstream,
+        model: String,
+        keys: Arc<KeyPool>,
+        profiles: Vec<ModelProfile>,
+        tuning: Tuning,
+    ) -> Self {
+        Self {
+            upstream,
+            model,
+            keys,
+            profiles,
+            tuning,
+        }
+    }
+
+    /// POST to `models/{model}:{method}` with the key in the query string
+    async fn send(&self, model: &str, messages: &[Message], method: &str, tuning: &Tuning) -> Result<reqwest::Response> {
+        let path = format!("/models/{}:{}", model, method);
+        let tuning = tuning.or(&self.tuning);
+        let (system, messages) = llm::split_system(messages);
+        let contents = messages
+            .into_iter()
+            .map(|message| Content {
+                // Gemini calls the assistant `model`
+                role: Some(if message.role == "assistant" { "model" } else { "user" }.to_string()),
+                parts: vec![Part { text: message.content }],
+            })
+            .collect();
+        let request = GenerateRequest {
+            contents,
+            system_instruction: (!system.is_empty()).then(|| Content {
+                role: None,
+                parts: vec![Part { text: system }],
+            }),
+            safety_settings: self
+                .profiles
+                .iter()
+                .find(|p| p.name == model)
+                .and_then(|p| p.safety_settings.clone()),
+            generation_config: GenerationConfig {
+                max_output_tokens: tuning.max_tokens,
+                temperature: tuning.sampling.temperature,
+                top_p: tuning.sampling.top_p,
+                presence_penalty: tuning.sampling.presence_penalty,
+                frequency_penalty: tuning.sampling.frequency_penalty,
+                response_mime_type: tuning.json.then_some("application/json"),
+            },
+        };
+
+        llm::post_json(&self.upstream, &path, &request, &self.keys, model, authorize).await
+    }
+}
+
+/// `data: {...}` events, each a partial `GenerateResponse`
+fn parse_line(line: &str) -> Result<Option<StreamEvent>> {
+    let Some(data) = line.strip_prefix("data:") else {
+        return Ok(None);
+    };
+    let chunk: GenerateResponse =
+        serde_json::from_str(data.trim()).context("Failed to parse LLM stream")?;
+    Ok(Some(StreamEvent {
+        text: chunk.text(),
+        finish_reason: chunk.finish_reason(),
+        usage: chunk.usage(),
+        done: false,
+    }))
+}
+
+#[async_trait]
+impl LlmProvider for GeminiClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    async fn complete(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<Completion> {
+        let response: GenerateResponse = self
+            .send(model, messages, "generateContent", tuning)
+            .await?
+            .json()
+            .await
+            .context("Failed to parse LLM response")?;
+
+        let finish_reason = response.finish_reason();
+        let Some(content) = response.text().filter(|text| !text.is_empty()) else {
+            if let Some(reason) = finish_reason.filter(|reason| reason != "STOP") {
+                anyhow::bail!("The AI declined to answer ({})", reason);
+            }
+            anyhow::bail!("No response from LLM");
+        };
+        Ok(Completion {
+            content,
+            finish_reason,
+            usage: response.usage(),
+            sources: Vec::new(),
+        })
+    }
+
+    async fn chat_stream(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<ChatStream> {
+        let response = self.send(model, messages, "streamGenerateContent?alt=sse", tuning).await?;
+        Ok(ChatStream::lines(response, parse_line))
+    }
+
+    /// Only models that can chat, without the `models/` prefix
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let list: ModelList = llm::get(&self.upstream, "/models", &self.keys, &self.model, authorize)
+            .await?
+            .json()
+            .await
+            .context("Failed to parse model list")?;
+        Ok(list
+            .models
+            .into_iter()
+            .filter(|m| m.supported_generation_methods.iter().any(|g| g == "generateContent"))
+            .map(|m| m.name.strip_prefix("models/").map_or(m.name.clone(), str::to_string))
+            .collect())
+    }
+}
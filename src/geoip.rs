@@ -0,0 +1,114 @@
+use anyhow::Result;
+#[cfg(feature = "geoip")]
+use anyhow::Context;
+#[cfg(feature = "geoip")]
+use maxminddb::{Reader, geoip2};
+use std::net::IpAddr;
+
+/// Country and network a client connects from
+#[derive(Debug, Clone, Default)]
+pub struct GeoTag {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub org: Option<String>,
+}
+
+impl GeoTag {
+    pub fn describe(&self) -> String {
+        let country = self.country.as_deref().unwrap_or("??");
+        match (self.asn, &self.org) {
+            (Some(asn), Some(org)) => format!("{} AS{} ({})", country, asn, org),
+            (Some(asn), None) => format!("{} AS{}", country, asn),
+            _ => country.to_string(),
+        }
+    }
+
+    fn matches(&self, rule: &str) -> bool {
+        let rule = rule.to_uppercase();
+        self.country.as_deref() == Some(rule.as_str())
+            || self.asn.map(|asn| format!("AS{}", asn)) == Some(rule)
+    }
+}
+
+/// Lookups in operator-provided MaxMind databases plus allow/deny rules.
+/// Built without the `geoip` feature every client is untagged.
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    country_db: Option<Reader<Vec<u8>>>,
+    #[cfg(feature = "geoip")]
+    asn_db: Option<Reader<Vec<u8>>>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl GeoIp {
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(_country_db: Option<&str>, _asn_db: Option<&str>, allow: Vec<String>, deny: Vec<String>) -> Result<Self> {
+        Ok(Self { allow, deny })
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn lookup(&self, _ip: IpAddr) -> GeoTag {
+        GeoTag::default()
+    }
+
+    #[cfg(feature = "geoip")]
+    pub fn open(
+        country_db: Option<&str>,
+        asn_db: Option<&str>,
+        allow: Vec<String>,
+        deny: Vec<String>,
+    ) -> Result<Self> {
+        let country_db = country_db
+            .map(|path| {
+                Reader::open_readfile(path)
+                    .with_context(|| format!("Failed to open GeoIP database {}", path))
+            })
+            .transpose()?;
+        let asn_db = asn_db
+            .map(|path| {
+                Reader::open_readfile(path)
+                    .with_context(|| format!("Failed to open ASN database {}", path))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            country_db,
+            asn_db,
+            allow,
+            deny,
+        })
+    }
+
+    #[cfg(feature = "geoip")]
+    pub fn lookup(&self, ip: IpAddr) -> GeoTag {
+        let mut tag = GeoTag::default();
+
+        if let Some(db) = &self.country_db
+            && let Ok(record) = db.lookup::<geoip2::Country>(ip)
+        {
+            tag.country = record
+                .country
+                .and_then(|c| c.iso_code)
+                .map(|code| code.to_string());
+        }
+
+        if let Some(db) = &self.asn_db
+            && let Ok(record) = db.lookup::<geoip2::Asn>(ip)
+        {
+            tag.asn = record.autonomous_system_number;
+            tag.org = record.autonomous_system_organization.map(|o| o.to_string());
+        }
+
+        tag
+    }
+
+    /// Deny rules win; with an allow list only matching clients get in.
+    /// Rules are ISO country codes ("PL") or AS numbers ("AS5617").
+    pub fn is_allowed(&self, tag: &GeoTag) -> bool {
+        if self.deny.iter().any(|rule| tag.matches(rule)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|rule| tag.matches(rule))
+    }
+}
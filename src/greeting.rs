@@ -0,0 +1,63 @@
+use chrono::{DateTime, FixedOffset, Local, Timelike, Utc};
+
+/// Parse a UTC offset such as `+02:00`, `-5` or `UTC+1`
+pub fn parse_offset(input: &str) -> Option<FixedOffset> {
+    let trimmed = input.trim();
+    let trimmed = trimmed
+        .strip_prefix("UTC")
+        .or_else(|| trimmed.strip_prefix("utc"))
+        .or_else(|| trimmed.strip_prefix("GMT"))
+        .unwrap_or(trimmed);
+    if trimmed.is_empty() {
+        return FixedOffset::east_opt(0);
+    }
+
+    let (sign, rest) = match trimmed.chars().next()? {
+        '+' => (1, &trimmed[1..]),
+        '-' => (-1, &trimmed[1..]),
+        _ => (1, trimmed),
+    };
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((h, m)) => (h.parse::<i32>().ok()?, m.parse::<i32>().ok()?),
+        None => (rest.parse::<i32>().ok()?, 0),
+    };
+    if hours > 14 || !(0..60).contains(&minutes) {
+        return None;
+    }
+
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+fn part_of_day(hour: u32) -> &'static str {
+    match hour {
+        5..=11 => "Good morning",
+        12..=17 => "Good afternoon",
+        18..=22 => "Good evening",
+        _ => "Hello, night owl",
+    }
+}
+
+/// Compose a greeting from the profile: time of day in the user's timezone,
+/// their name and how long ago they were last seen
+pub fn compose(name: Option<&str>, last_seen: Option<DateTime<Local>>, timezone: Option<FixedOffset>) -> String {
+    let hour = match timezone {
+        Some(offset) => Utc::now().with_timezone(&offset).hour(),
+        None => Local::now().hour(),
+    };
+
+    let mut greeting = part_of_day(hour).to_string();
+    if let Some(name) = name {
+        greeting.push_str(&format!(", {}", name));
+    }
+
+    let days = last_seen.map(|seen| (Local::now() - seen).num_days());
+
+    match days {
+        Some(days) if days < 1 => greeting.push_str(" — welcome back!"),
+        Some(1) => greeting.push_str(" — welcome back, your last visit was yesterday."),
+        Some(days) => greeting.push_str(&format!(" — it's been {} days!", days)),
+        None => greeting.push('!'),
+    }
+
+    greeting
+}
@@ -0,0 +1,176 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::session::SessionContext;
+
+/// `addr=fd,addr=fd` of the listeners passed on by the previous process
+pub const LISTEN_FDS_ENV: &str = "TELLLM_LISTEN_FDS";
+
+/// A new process that dies this quickly is treated as a failed upgrade
+const STARTUP_CHECK: Duration = Duration::from_secs(2);
+
+/// Listening sockets shared with a re-executed binary during an upgrade
+pub struct Handover {
+    inherited: HashMap<SocketAddr, i32>,
+    listeners: Mutex<Vec<(SocketAddr, i32)>>,
+    stop: watch::Sender<bool>,
+}
+
+impl Handover {
+    /// Pick up the sockets a previous process left in the environment;
+    /// `upgrade` overwrites the variable for our own successor
+    pub fn from_env() -> Self {
+        let inherited = std::env::var(LISTEN_FDS_ENV)
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| {
+                let (addr, fd) = pair.split_once('=')?;
+                Some((addr.parse().ok()?, fd.parse().ok()?))
+            })
+            .collect();
+
+        Self {
+            inherited,
+            listeners: Mutex::new(Vec::new()),
+            stop: watch::channel(false).0,
+        }
+    }
+
+    /// Take over the listener for `addr` from the previous process, or bind a new one
+    pub async fn bind(&self, addr: SocketAddr) -> Result<TcpListener> {
+        let listener = match self.inherited.get(&addr) {
+            Some(&fd) => {
+                info!("Took over listener {} from the previous process", addr);
+                inherit(fd)?
+            }
+            None => TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to bind {}", addr))?,
+        };
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            self.listeners.lock().unwrap().push((addr, listener.as_raw_fd()));
+        }
+        Ok(listener)
+    }
+
+    /// Resolves once the listeners have been handed to a new process
+    pub fn stopped(&self) -> watch::Receiver<bool> {
+        self.stop.subscribe()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.stop.borrow()
+    }
+}
+
+#[cfg(unix)]
+fn inherit(fd: i32) -> Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+    // SAFETY: the fd was a listening socket in the process that exec'd us
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener).context("Failed to adopt inherited listener")
+}
+
+#[cfg(not(unix))]
+fn inherit(_fd: i32) -> Result<TcpListener> {
+    anyhow::bail!("Socket handover is only supported on Unix")
+}
+
+/// Start the current binary with the same arguments and our listeners, then
+/// stop accepting once it is up. Running sessions stay with this process.
+#[cfg(unix)]
+pub async fn upgrade(ctx: Arc<SessionContext>) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    if ctx.handover.is_stopped() {
+        anyhow::bail!("Listeners were already handed over");
+    }
+    let listeners = ctx.handover.listeners.lock().unwrap().clone();
+    let env = listeners
+        .iter()
+        .map(|(addr, fd)| format!("{}={}", addr, fd))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let exe = std::env::current_exe().context("Failed to locate the telllm binary")?;
+    let mut command = Command::new(&exe);
+    command.args(std::env::args_os().skip(1)).env(LISTEN_FDS_ENV, env);
+    let fds: Vec<i32> = listeners.iter().map(|(_, fd)| *fd).collect();
+    // SAFETY: only async-signal-safe fcntl calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &fds {
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Failed to start {}", exe.display()))?;
+    tokio::time::sleep(STARTUP_CHECK).await;
+    if let Some(status) = child.try_wait()? {
+        anyhow::bail!("New process exited during startup ({}), still serving", status);
+    }
+
+    info!(
+        "Handed {} listeners to pid {}, draining {} sessions",
+        listeners.len(),
+        child.id(),
+        ctx.sessions.count()
+    );
+    ctx.handover.stop.send_replace(true);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn upgrade(_ctx: Arc<SessionContext>) -> Result<()> {
+    anyhow::bail!("Socket handover is only supported on Unix")
+}
+
+/// Upgrade and log the outcome, for callers that can't wait for it
+pub async fn upgrade_logged(ctx: Arc<SessionContext>) {
+    if let Err(e) = upgrade(ctx).await {
+        warn!("Upgrade failed: {}", e);
+    }
+}
+
+/// Re-exec on SIGUSR2, e.g. `kill -USR2 $(pidof telllm)` after replacing the binary
+#[cfg(unix)]
+pub async fn listen_for_signal(ctx: Arc<SessionContext>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Upgrade signal unavailable: {}", e);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        info!("Received SIGUSR2, upgrading");
+        upgrade_logged(Arc::clone(&ctx)).await;
+    }
+}
+
+/// Wait until every session left on this process has ended
+pub async fn drain(ctx: &SessionContext) {
+    while ctx.sessions.count() > 0 {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    info!("All sessions ended, exiting");
+}
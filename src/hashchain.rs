@@ -0,0 +1,202 @@
+use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// How the hash is appended to a JSON line, the last key of the object
+const HASH_KEY: &str = ",\"hash\":\"";
+/// Hex digits of a SHA-256
+const HASH_LEN: usize = 64;
+
+/// SHA-256 over the previous entry's hash and this entry's JSON, so changing,
+/// removing or reordering any entry breaks every hash after it
+fn chain(prev: &str, body: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(prev.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// `body`, one JSON object, with its `hash` added after `prev`
+pub fn seal(prev: &str, body: &str) -> String {
+    let hash = chain(prev, body);
+    let open = body.strip_suffix('}').unwrap_or(body);
+    format!("{}{}{}\"}}", open, HASH_KEY, hash)
+}
+
+/// The JSON a sealed line was hashed over, and its hash
+fn unseal(line: &str) -> Option<(String, &str)> {
+    let at = line.rfind(HASH_KEY)?;
+    let hash = line[at + HASH_KEY.len()..].strip_suffix("\"}")?;
+    if hash.len() != HASH_LEN || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((format!("{}}}", &line[..at]), hash))
+}
+
+/// Hash of the last entry in the log at `path`, empty to start a new chain
+/// when there is none or it wasn't sealed. Reads backwards from the end, so
+/// long logs cost no more than short ones.
+pub fn last_hash(path: &Path) -> Result<String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(String::new()),
+        Err(e) => return Err(e).context("Failed to open chat log file"),
+    };
+    let len = file.metadata()?.len();
+    let mut tail: Vec<u8> = Vec::new();
+    let mut read_from = len;
+    // The last line is complete once a newline shows up before it
+    while read_from > 0 {
+        let step = read_from.min(4096);
+        read_from -= step;
+        let mut chunk = vec![0u8; step as usize];
+        file.seek(SeekFrom::Start(read_from))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        let trimmed = tail.strip_suffix(b"\n").unwrap_or(&tail);
+        if trimmed.contains(&b'\n') {
+            break;
+        }
+    }
+    let text = String::from_utf8_lossy(&tail);
+    let last = text.trim_end().rsplit('\n').next().unwrap_or_default();
+    Ok(unseal(last).map(|(_, hash)| hash.to_string()).unwrap_or_default())
+}
+
+/// What checking a log found
+#[derive(Debug)]
+struct Verified {
+    sealed: usize,
+    /// Lines from before the chain was turned on
+    unsealed: usize,
+    last: String,
+}
+
+/// `telllm verify-log`: check every hash in a JSONL chat log and print the
+/// last one, which is what to keep elsewhere to notice entries cut off the end
+pub fn verify(path: &Path) -> Result<()> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let Some(verified) = verify_lines(&text)? else {
+        bail!("{} has no hashed entries, it was written without --hash-chain", path.display());
+    };
+    println!("{}: {} entries verified", path.display(), verified.sealed);
+    if verified.unsealed > 0 {
+        println!("{} earlier entries were written before --hash-chain and aren't covered", verified.unsealed);
+    }
+    println!("Last hash: {}", verified.last);
+    Ok(())
+}
+
+/// Check the chain through `text`, `None` when no line is sealed
+fn verify_lines(text: &str) -> Result<Option<Verified>> {
+    let mut prev: Option<String> = None;
+    let mut unsealed = 0;
+    let mut sealed = 0;
+    for (i, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let number = i + 1;
+        serde_json::from_str::<serde_json::Value>(line)
+            .with_context(|| format!("Line {} is not JSON, the log was edited or cut", number))?;
+        let Some((body, hash)) = unseal(line) else {
+            if prev.is_some() {
+                bail!("Line {} has no hash, it was added or edited by hand", number);
+            }
+            // Written before the chain was turned on
+            unsealed += 1;
+            continue;
+        };
+        let expected = chain(prev.as_deref().unwrap_or(""), &body);
+        if hash != expected {
+            bail!("Line {} doesn't match its hash, it or an entry before it was changed, removed or reordered", number);
+        }
+        prev = Some(hash.to_string());
+        sealed += 1;
+    }
+    Ok(prev.map(|last| Verified { sealed, unsealed, last }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log of `count` sealed entries, one per line
+    fn sealed_log(count: usize) -> Vec<String> {
+        let mut prev = String::new();
+        (0..count)
+            .map(|i| {
+                let line = seal(&prev, &format!("{{\"event\":\"message\",\"n\":{}}}", i));
+                prev = unseal(&line).unwrap().1.to_string();
+                line
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sealed_lines_are_json_and_verify() {
+        let lines = sealed_log(5);
+        for line in &lines {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["hash"].as_str().map(str::len), Some(HASH_LEN));
+        }
+        let verified = verify_lines(&lines.join("\n")).unwrap().unwrap();
+        assert_eq!((verified.sealed, verified.unsealed), (5, 0));
+        assert_eq!(Some(verified.last.as_str()), unseal(&lines[4]).map(|(_, hash)| hash));
+    }
+
+    #[test]
+    fn unsealed_lines_before_the_chain_are_counted() {
+        let mut lines = vec!["{\"event\":\"session_start\"}".to_string()];
+        lines.extend(sealed_log(2));
+        let verified = verify_lines(&lines.join("\n")).unwrap().unwrap();
+        assert_eq!((verified.sealed, verified.unsealed), (2, 1));
+        assert!(verify_lines("{\"a\":1}\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn tampered_line_fails() {
+        let mut lines = sealed_log(4);
+        lines[1] = lines[1].replace("\"n\":1", "\"n\":7");
+        let error = verify_lines(&lines.join("\n")).unwrap_err().to_string();
+        assert!(error.starts_with("Line 2 "), "{}", error);
+    }
+
+    #[test]
+    fn dropped_line_fails() {
+        let mut lines = sealed_log(4);
+        lines.remove(2);
+        let error = verify_lines(&lines.join("\n")).unwrap_err().to_string();
+        assert!(error.starts_with("Line 3 "), "{}", error);
+    }
+
+    #[test]
+    fn reordered_lines_fail() {
+        let mut lines = sealed_log(4);
+        lines.swap(1, 2);
+        let error = verify_lines(&lines.join("\n")).unwrap_err().to_string();
+        assert!(error.starts_with("Line 2 "), "{}", error);
+    }
+
+    #[test]
+    fn line_added_after_the_chain_fails() {
+        let mut lines = sealed_log(2);
+        lines.push("{\"event\":\"message\"}".to_string());
+        let error = verify_lines(&lines.join("\n")).unwrap_err().to_string();
+        assert!(error.contains("no hash"), "{}", error);
+    }
+
+    #[test]
+    fn last_hash_reads_the_end_of_long_logs() {
+        let path = std::env::temp_dir().join(format!("telllm-hashchain-{}.jsonl", std::process::id()));
+        assert_eq!(last_hash(&path).unwrap(), "");
+        let mut lines = sealed_log(3);
+        // Longer than one read from the end
+        lines[2] = seal(unseal(&lines[1]).unwrap().1, &format!("{{\"text\":\"{}\"}}", "x".repeat(10_000)));
+        fs::write(&path, format!("{}\n", lines.join("\n"))).unwrap();
+        let last = last_hash(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(last.unwrap(), unseal(&lines[2]).unwrap().1);
+    }
+}
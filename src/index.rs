@@ -0,0 +1,66 @@
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::storage;
+
+const INDEX_FILE: &str = "index.json";
+/// Longest title taken from the opening message
+const MAX_TITLE_CHARS: usize = 60;
+
+/// One day's conversation in a client's chat index
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub title: Option<String>,
+    pub messages: usize,
+}
+
+/// Chat log date (as in the file name, `dd-mm-yy`) to its entry
+pub type ChatIndex = BTreeMap<String, IndexEntry>;
+
+pub fn load(client_dir: &Path) -> ChatIndex {
+    fs::read_to_string(client_dir.join(INDEX_FILE))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Title from the opening message: its first line, cut at a word boundary
+fn title_from(message: &str) -> String {
+    let line = message.lines().next().unwrap_or("").trim();
+    if line.chars().count() <= MAX_TITLE_CHARS {
+        return line.to_string();
+    }
+    let cut: String = line.chars().take(MAX_TITLE_CHARS).collect();
+    let cut = cut.rsplit_once(' ').map_or(cut.as_str(), |(head, _)| head);
+    format!("{}...", cut.trim_end())
+}
+
+/// Count a message for `date`; the first user message of the day names the conversation
+pub fn record(client_dir: &Path, date: &str, user_message: Option<&str>) -> Result<()> {
+    let _lock = storage::lock(client_dir, "index.lock")?;
+
+    let mut index = load(client_dir);
+    let entry = index.entry(date.to_string()).or_default();
+    entry.messages += 1;
+    if entry.title.is_none()
+        && let Some(message) = user_message
+    {
+        entry.title = Some(title_from(message));
+    }
+
+    let json = serde_json::to_string_pretty(&index).context("Failed to serialize chat index")?;
+    storage::write_atomic(&client_dir.join(INDEX_FILE), &format!("{}\n", json))
+}
+
+/// Entries newest first
+pub fn newest_first(index: ChatIndex) -> Vec<(String, IndexEntry)> {
+    let mut entries: Vec<_> = index.into_iter().collect();
+    entries.sort_by_key(|(date, _)| {
+        std::cmp::Reverse(NaiveDate::parse_from_str(date, "%d-%m-%y").ok())
+    });
+    entries
+}
@@ -0,0 +1,73 @@
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::llm::Message;
+
+/// Accept both a bare messages array and `{"messages": [...]}`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Export {
+    Messages(Vec<Message>),
+    Wrapped { messages: Vec<Message> },
+}
+
+/// Parse an OpenAI-format `[{role, content}, ...]` conversation
+pub fn parse_messages(json: &str) -> Result<Vec<Message>> {
+    let export: Export =
+        serde_json::from_str(json).context("Expected a JSON array of {role, content} messages")?;
+    let messages = match export {
+        Export::Messages(messages) | Export::Wrapped { messages } => messages,
+    };
+
+    for message in &messages {
+        if !matches!(message.role.as_str(), "system" | "user" | "assistant") {
+            bail!("Unsupported role: {}", message.role);
+        }
+    }
+
+    if messages.is_empty() {
+        bail!("The conversation is empty");
+    }
+
+    Ok(messages)
+}
+
+/// A display label as OpenAI accepts it in `name`: at most 64 letters, digits,
+/// `_` or `-`. `None` when nothing of it is left.
+fn openai_name(label: &str) -> Option<String> {
+    let name: String = label
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .take(64)
+        .collect();
+    let name = name.trim_matches('_');
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Serialize the conversation as an OpenAI-format messages array. With
+/// `names`, the display labels go along as each message's `name`.
+pub fn to_openai_json(
+    messages: &[Message],
+    include_system: bool,
+    names: Option<(&str, &str)>,
+) -> Result<String> {
+    let exported: Vec<Value> = messages
+        .iter()
+        .filter(|m| include_system || m.role != "system")
+        .map(|m| {
+            let label = match (m.role.as_str(), names) {
+                ("assistant", Some((ai_name, _))) => Some(ai_name),
+                ("user", Some((_, user_name))) => Some(user_name),
+                _ => None,
+            };
+            let mut message = json!({ "role": m.role, "content": m.content });
+            if let Some(name) = label.and_then(openai_name) {
+                message["name"] = json!(name);
+            }
+            message
+        })
+        .collect();
+    serde_json::to_string_pretty(&exported).context("Failed to serialize conversation")
+}
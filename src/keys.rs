@@ -0,0 +1,158 @@
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long a key rests after the backend rate limited it
+const RATE_LIMIT_REST: Duration = Duration::from_secs(60);
+/// Longest rest a backend's `Retry-After` can give a key
+const MAX_REST: Duration = Duration::from_secs(24 * 60 * 60);
+
+const BUDGET_WINDOW: Duration = Duration::from_secs(60);
+
+struct Slot {
+    secret: String,
+    /// Rejected by the backend, never used again
+    retired: bool,
+    resting_until: Option<Instant>,
+    /// Requests sent within the last budget window
+    recent: VecDeque<Instant>,
+}
+
+impl Slot {
+    fn available(&mut self, now: Instant, budget: Option<usize>) -> bool {
+        while self
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > BUDGET_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        !self.retired
+            && self.resting_until.is_none_or(|until| now >= until)
+            && budget.is_none_or(|budget| self.recent.len() < budget)
+    }
+}
+
+/// Keys from `--api-key-file`: one per line, blank lines and `#` comments skipped
+pub fn read_key_file(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read API keys from {}", path.display()))?;
+    let keys: Vec<String> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if keys.is_empty() {
+        anyhow::bail!("No API keys in {}", path.display());
+    }
+    Ok(keys)
+}
+
+/// API keys for one backend, handed out round-robin. Keys are only ever
+/// referred to by their index in logs.
+pub struct KeyPool {
+    slots: Mutex<Vec<Slot>>,
+    next: Mutex<usize>,
+    /// Requests per key per minute
+    budget: Option<usize>,
+}
+
+impl KeyPool {
+    pub fn new(keys: Vec<String>, budget: Option<usize>) -> Self {
+        let slots = keys
+            .into_iter()
+            .filter(|key| !key.is_empty())
+            .map(|secret| Slot {
+                secret,
+                retired: false,
+                resting_until: None,
+                recent: VecDeque::new(),
+            })
+            .collect();
+        Self {
+            slots: Mutex::new(slots),
+            next: Mutex::new(0),
+            budget,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Next usable key as `(index, secret)`, `None` when every key is
+    /// retired, resting or out of budget
+    pub fn acquire(&self) -> Option<(usize, String)> {
+        let mut slots = self.slots.lock().unwrap();
+        let mut next = self.next.lock().unwrap();
+        let now = Instant::now();
+        let count = slots.len();
+
+        for offset in 0..count {
+            let index = (*next + offset) % count;
+            let slot = &mut slots[index];
+            if slot.available(now, self.budget) {
+                slot.recent.push_back(now);
+                *next = (index + 1) % count;
+                return Some((index, slot.secret.clone()));
+            }
+        }
+        None
+    }
+
+    /// Retire keys the backend refuses and rest rate limited ones, for
+    /// `retry_after` if the backend said how long
+    pub fn report(&self, index: usize, status: u16, retry_after: Option<Duration>) {
+        let mut slots = self.slots.lock().unwrap();
+        let Some(slot) = slots.get_mut(index) else {
+            return;
+        };
+        match status {
+            401 | 403 => {
+                slot.retired = true;
+                warn!("API key #{} retired after HTTP {}", index, status);
+            }
+            429 => {
+                let rest = retry_after.unwrap_or(RATE_LIMIT_REST).min(MAX_REST);
+                slot.resting_until = Some(Instant::now() + rest);
+                warn!("API key #{} rate limited, resting for {}s", index, rest.as_secs());
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limited_keys_rest() {
+        let pool = KeyPool::new(vec!["a".to_string(), "b".to_string()], None);
+        let (index, _) = pool.acquire().unwrap();
+        pool.report(index, 429, None);
+        let (other, _) = pool.acquire().unwrap();
+        assert_ne!(other, index);
+        pool.report(other, 401, None);
+        assert!(pool.acquire().is_none());
+    }
+
+    #[test]
+    fn endless_retry_after_is_capped() {
+        let pool = KeyPool::new(vec!["a".to_string()], None);
+        pool.report(0, 429, Some(Duration::from_secs(u64::MAX)));
+        assert!(pool.acquire().is_none());
+        // Still usable, not poisoned by a panic
+        pool.report(0, 429, Some(Duration::ZERO));
+        assert!(pool.acquire().is_some());
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Clears the screen and resets the terminal to its power-on state (RIS), for
+/// the next visitor
+pub const RESET_TERMINAL: &[u8] = b"\x1b[2J\x1b[H\x1bc";
+
+/// How much one visitor gets, from --kiosk-minutes and --kiosk-messages
+#[derive(Debug, Clone, Copy)]
+pub struct KioskLimits {
+    pub duration: Duration,
+    pub messages: usize,
+}
+
+/// A visitor's allowance as it runs down
+pub struct Kiosk {
+    limits: KioskLimits,
+    deadline: Instant,
+    sent: usize,
+}
+
+impl Kiosk {
+    pub fn start(limits: KioskLimits) -> Self {
+        Self {
+            limits,
+            deadline: Instant::now() + limits.duration,
+            sent: 0,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Count a message sent to the AI
+    pub fn record_message(&mut self) {
+        self.sent += 1;
+    }
+
+    pub fn messages_left(&self) -> usize {
+        self.limits.messages.saturating_sub(self.sent)
+    }
+
+    /// Time and messages left for the prompt, e.g. `[9:41 | 12 left]`
+    pub fn countdown(&self) -> String {
+        let left = self.deadline.saturating_duration_since(Instant::now()).as_secs();
+        format!("[{}:{:02} | {} left]", left / 60, left % 60, self.messages_left())
+    }
+}
+
+/// Where kiosk sessions log under the logs directory, each in a directory of its own
+pub const SCRATCH_DIR: &str = "kiosk";
+
+/// A fresh directory for one visitor's session, so wiping it leaves other
+/// visitors from the same address alone
+pub fn scratch_dir(logs_dir: &str, client_ip: IpAddr) -> PathBuf {
+    let ip = client_ip.to_string().replace(':', "-");
+    Path::new(logs_dir)
+        .join(SCRATCH_DIR)
+        .join(format!("{}-{:08x}", ip, rand::random::<u32>()))
+}
+
+/// Remove everything kept about the visitor: chat logs, profile, exports and
+/// snapshots under `dir`
+pub fn wipe(dir: &Path) {
+    match fs::remove_dir_all(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to wipe {}: {}", dir.display(), e),
+    }
+}
@@ -0,0 +1,6 @@
+/// English name of the language a message is written in, when the
+/// detector is confident enough to act on it
+pub fn detect(text: &str) -> Option<&'static str> {
+    let info = whatlang::detect(text)?;
+    info.is_reliable().then(|| info.lang().eng_name())
+}
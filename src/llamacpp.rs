@@ -0,0 +1,203 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::keys::KeyPool;
+use crate::llm::{self, ChatStream, Completion, LlmProvider, Message, Sampling, StreamEvent, TokenUsage, Tuning, Upstream};
+
+/// How turns are laid out in a raw prompt, `[chat_template]` in the config.
+/// `{{ content }}` stands for the message text.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatTemplate {
+    pub system: String,
+    pub user: String,
+    pub assistant: String,
+    /// Appended last, opening the turn the model writes
+    pub generation: String,
+    /// Strings that end the model's turn
+    pub stop: Vec<String>,
+}
+
+/// ChatML, understood by most instruction-tuned GGUF models
+impl Default for ChatTemplate {
+    fn default() -> Self {
+        Self {
+            system: "<|im_start|>system\n{{ content }}<|im_end|>\n".to_string(),
+            user: "<|im_start|>user\n{{ content }}<|im_end|>\n".to_string(),
+            assistant: "<|im_start|>assistant\n{{ content }}<|im_end|>\n".to_string(),
+            generation: "<|im_start|>assistant\n".to_string(),
+            stop: vec!["<|im_end|>".to_string()],
+        }
+    }
+}
+
+impl ChatTemplate {
+    fn render(&self, messages: &[Message]) -> String {
+        let mut prompt = String::new();
+        for message in messages {
+            let turn = match message.role.as_str() {
+                "system" => &self.system,
+                "assistant" => &self.assistant,
+                _ => &self.user,
+            };
+            prompt.push_str(
+                &turn
+                    .replace("{{ content }}", &message.content)
+                    .replace("{{content}}", &message.content),
+            );
+        }
+        prompt.push_str(&self.generation);
+        prompt
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CompletionRequest<'a> {
+    prompt: String,
+    /// -1 lets the model go on until it stops by itself
+    n_predict: i64,
+    stop: &'a [String],
+    stream: bool,
+    /// Reuse the KV cache for the shared start of the conversation
+    cache_prompt: bool,
+    #[serde(flatten)]
+    sampling: Sampling,
+}
+
+/// A whole reply, or one event of a streamed one
+#[derive(Debug, Deserialize)]
+struct CompletionResponse {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    stop: bool,
+    /// `eos`, `word` or `limit` on newer servers
+    #[serde(default)]
+    stop_type: Option<String>,
+    #[serde(default)]
+    stopped_limit: bool,
+    /// Prompt tokens, on the last event
+    #[serde(default)]
+    tokens_evaluated: Option<u64>,
+    #[serde(default)]
+    tokens_predicted: Option<u64>,
+}
+
+impl CompletionResponse {
+    /// In OpenAI terms
+    fn finish_reason(&self) -> Option<String> {
+        if !self.stop {
+            return None;
+        }
+        let limited = self.stopped_limit || self.stop_type.as_deref() == Some("limit");
+        Some(if limited { "length" } else { "stop" }.to_string())
+    }
+
+    fn usage(&self) -> Option<TokenUsage> {
+        if !self.stop {
+            return None;
+        }
+        Some(TokenUsage::new(
+            self.tokens_evaluated.unwrap_or(0),
+            self.tokens_predicted.unwrap_or(0),
+        ))
+    }
+}
+
+/// llama.cpp's raw `/completion`, for servers without `/v1/chat/completions`
+pub struct LlamaCppClient {
+    upstream: Upstream,
+    model: String,
+    keys: Arc<KeyPool>,
+    tuning: Tuning,
+    template: ChatTemplate,
+}
+
+impl LlamaCppClient {
+    pub fn new(
+        upstream: Upstream,
+        model: String,
+        keys: Arc<KeyPool>,
+        tuning: Tuning,
+        template: ChatTemplate,
+    ) -> Self {
+        Self {
+            upstream,
+            model,
+            keys,
+            tuning,
+            template,
+        }
+    }
+
+    /// The server runs whatever model it loaded, `model` only labels errors and logs
+    async fn send(&self, model: &str, messages: &[Message], stream: bool, tuning: &Tuning) -> Result<reqwest::Response> {
+        let tuning = tuning.or(&self.tuning);
+        let request = CompletionRequest {
+            prompt: self.template.render(messages),
+            n_predict: tuning.max_tokens.map_or(-1, i64::from),
+            stop: &self.template.stop,
+            stream,
+            cache_prompt: true,
+            sampling: tuning.sampling,
+        };
+        llm::post_json(&self.upstream, "/completion", &request, &self.keys, model, |req, key| {
+            req.header("Authorization", format!("Bearer {}", key))
+        })
+        .await
+    }
+}
+
+/// `data: {...}` events, the last one has `stop` set
+fn parse_line(line: &str) -> Result<Option<StreamEvent>> {
+    let Some(data) = line.strip_prefix("data:") else {
+        return Ok(None);
+    };
+    let chunk: CompletionResponse =
+        serde_json::from_str(data.trim()).context("Failed to parse LLM stream")?;
+    Ok(Some(StreamEvent {
+        finish_reason: chunk.finish_reason(),
+        usage: chunk.usage(),
+        done: chunk.stop,
+        text: Some(chunk.content),
+    }))
+}
+
+#[async_trait]
+impl LlmProvider for LlamaCppClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    async fn complete(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<Completion> {
+        let response: CompletionResponse = self
+            .send(model, messages, false, tuning)
+            .await?
+            .json()
+            .await
+            .context("Failed to parse LLM response")?;
+
+        let finish_reason = response.finish_reason();
+        let content = response.content.trim().to_string();
+        if content.is_empty() {
+            anyhow::bail!("No response from LLM");
+        }
+        Ok(Completion {
+            content,
+            finish_reason,
+            usage: response.usage(),
+            sources: Vec::new(),
+        })
+    }
+
+    async fn chat_stream(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<ChatStream> {
+        let response = self.send(model, messages, true, tuning).await?;
+        Ok(ChatStream::lines(response, parse_line))
+    }
+}
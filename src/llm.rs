@@ -1,86 +1,1243 @@
 use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
+use rand::Rng;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
 
-#[derive(Debug, Clone, Serialize)]
+use crate::endpoints::Endpoints;
+use crate::keys::KeyPool;
+use crate::proxy;
+use crate::tools::{self, ToolCall, ToolRegistry};
+
+/// Longest Retry-After waited out; beyond it the request fails
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+/// Pauses one request sits through before giving up
+const MAX_PAUSES: usize = 3;
+
+/// First backoff delay, doubled on every retry up to `MAX_BACKOFF`
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// Headers for every backend request from `from_config` and the `Name: value`
+/// lines of `from_flags`; a flag wins over the config for the same header
+pub fn extra_headers(from_config: &BTreeMap<String, String>, from_flags: &[String]) -> Result<HeaderMap> {
+    let mut pairs: Vec<(&str, &str)> = from_config.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    for line in from_flags {
+        let (name, value) = line
+            .split_once(':')
+            .with_context(|| format!("--llm-header {:?} is not \"Name: value\"", line))?;
+        pairs.push((name.trim(), value.trim()));
+    }
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        let name = HeaderName::from_bytes(name.as_bytes()).with_context(|| format!("Invalid header name {:?}", name))?;
+        let value = HeaderValue::from_str(value).with_context(|| format!("Invalid value for header {}", name))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// Delay before retry number `retry` (from 1): exponential, capped, and
+/// jittered so that waiting sessions don't all come back at once
+fn backoff(retry: usize) -> Duration {
+    let exponential = BASE_BACKOFF.saturating_mul(1 << retry.saturating_sub(1).min(16) as u32);
+    exponential.min(MAX_BACKOFF).mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// How requests reach one backend: its endpoints, how often a failed request
+/// is tried again, and the pause it asked for that every request to it sits out
+pub struct Upstream {
+    client: reqwest::Client,
+    endpoints: Endpoints,
+    /// Retries for 429s, 5xx answers and network errors, from `--llm-retries`
+    max_retries: usize,
+    /// Sent with every request, from `--llm-header` and `[llm_headers]`
+    headers: HeaderMap,
+    /// When the backend takes requests again after asking for a pause
+    resume_at: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    pub fn new(endpoints: Endpoints, max_retries: usize, headers: HeaderMap) -> Self {
+        Self {
+            client: proxy::client(),
+            endpoints,
+            max_retries,
+            headers,
+            resume_at: Mutex::new(None),
+        }
+    }
+
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    /// Time left of the pause the backend asked for, if any
+    pub fn paused_for(&self) -> Option<Duration> {
+        let resume_at = (*self.resume_at.lock().unwrap())?;
+        resume_at
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Hold every request back for `wait`, extending a pause already running
+    fn pause(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut resume_at = self.resume_at.lock().unwrap();
+        if resume_at.is_none_or(|at| at < until) {
+            *resume_at = Some(until);
+        }
+    }
+}
+
+/// `Retry-After` in seconds or as an HTTP date
+fn parse_retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (at.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().ok()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
 }
 
+/// A message as `/chat/completions` takes it, with the tool call fields that
+/// only the requests of one answer need
+#[derive(Debug, Clone, Serialize)]
+struct WireMessage {
+    role: String,
+    /// Left out when an assistant message only calls tools
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+impl From<&Message> for WireMessage {
+    fn from(message: &Message) -> Self {
+        Self {
+            role: message.role.clone(),
+            content: Some(message.content.clone()),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct ChatRequest {
     model: String,
-    messages: Vec<Message>,
+    messages: Vec<WireMessage>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(flatten)]
+    sampling: Sampling,
+    /// OpenRouter-style fallback list, the primary model first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    models: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_tier: Option<String>,
+    /// Asks for a last event with the `usage` of a streamed reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    logit_bias: BTreeMap<String, f32>,
+    /// `{"type": "json_object"}` in JSON mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
+}
+
+/// API dialect spoken by the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Provider {
+    /// `/chat/completions`, as served by OpenAI, OpenRouter, llama.cpp and most gateways
+    Openai,
+    /// Anthropic's `/messages`
+    Anthropic,
+    /// Ollama's native `/api/chat`
+    Ollama,
+    /// Google's `generateContent`
+    Gemini,
+    /// llama.cpp's raw `/completion` with a prompt built from `[chat_template]`
+    LlamaCpp,
+}
+
+/// Routing hints and session defaults for one model, `[[model]]` in the config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelProfile {
+    pub name: String,
+    /// Models the gateway may fall back to, in order
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+    /// Provider routing preferences passed through as they are,
+    /// e.g. `{ order = ["groq"], sort = "latency" }`
+    #[serde(default)]
+    pub provider: Option<Value>,
+    /// Priority hint such as `priority` or `flex`
+    #[serde(default)]
+    pub service_tier: Option<String>,
+    /// Ollama model options, e.g. `{ temperature = 0.7, num_ctx = 8192 }`
+    #[serde(default)]
+    pub options: Option<Value>,
+    /// Gemini `safetySettings`, passed through as they are
+    #[serde(default)]
+    pub safety_settings: Option<Value>,
+    /// Replaces --system-prompt while the model is selected, personas still win
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    /// Sequences that end the reply, added to --stop
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Bias by token ID, from -100 (never) to 100 (always); wins over --logit-bias
+    #[serde(default)]
+    pub logit_bias: BTreeMap<String, f32>,
+}
+
+impl ModelProfile {
+    /// Settings for this model, a session's /set values win over them
+    pub fn tuning(&self) -> Tuning {
+        Tuning {
+            max_tokens: self.max_tokens,
+            sampling: Sampling {
+                temperature: self.temperature,
+                top_p: self.top_p,
+                presence_penalty: self.presence_penalty,
+                frequency_penalty: self.frequency_penalty,
+            },
+            json: false,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct ChatResponse {
     choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<TokenUsage>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Choice {
     message: ResponseMessage,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+/// A finished response and what the backend said about it
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub content: String,
+    /// `stop`, `length`, `content_filter`, ... as reported by the backend
+    pub finish_reason: Option<String>,
+    /// Tokens the backend counted for the request, if it said
+    pub usage: Option<TokenUsage>,
+    /// Pages the tools the model called drew on, e.g. web search results
+    pub sources: Vec<String>,
+}
+
+/// Tokens billed for one or more requests, the `usage` of a chat completion
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    /// For backends that only count the two parts
+    pub fn new(prompt_tokens: u64, completion_tokens: u64) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    pub fn add(&mut self, other: &TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == TokenUsage::default()
+    }
+
+    /// `prompt+completion=total`, as the logs show it
+    pub fn describe(&self) -> String {
+        format!("{}+{}={}", self.prompt_tokens, self.completion_tokens, self.total_tokens)
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct ResponseMessage {
-    content: String,
+    /// `null` when the model only calls tools
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
 }
 
-pub struct LlmClient {
-    client: reqwest::Client,
-    endpoint: String,
-    model: String,
-    api_key: String,
+/// `GET /models` as answered by OpenAI-style servers and Anthropic
+#[derive(Debug, Deserialize)]
+pub struct ModelList {
+    pub data: Vec<ModelEntry>,
 }
 
-impl LlmClient {
-    pub fn new(endpoint: String, model: String, api_key: String) -> Self {
-        Self {
-            client: reqwest::Client::new(),
-            endpoint,
-            model,
-            api_key,
+#[derive(Debug, Deserialize)]
+pub struct ModelEntry {
+    pub id: String,
+}
+
+/// A chat backend. Sessions only talk to this trait, so new backends don't
+/// need changes anywhere else.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// Model used when nothing else was chosen
+    fn model(&self) -> &str;
+
+    /// How requests reach the backend
+    fn upstream(&self) -> &Upstream;
+
+    /// Where requests go, with each endpoint's health
+    fn endpoints(&self) -> &Endpoints {
+        self.upstream().endpoints()
+    }
+
+    /// Complete the conversation with `model`; `tuning` wins over the client's own
+    async fn complete(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<Completion>;
+
+    async fn chat_with(&self, model: &str, messages: &[Message]) -> Result<String> {
+        Ok(self.complete(model, messages, &Tuning::default()).await?.content)
+    }
+
+    async fn chat(&self, messages: &[Message]) -> Result<String> {
+        self.chat_with(self.model(), messages).await
+    }
+
+    /// Complete with `tools` on offer, running the calls the model makes until
+    /// it answers; backends without tool calling answer without them
+    async fn complete_with_tools(
+        &self,
+        model: &str,
+        messages: &[Message],
+        tuning: &Tuning,
+        _tools: &ToolRegistry,
+    ) -> Result<Completion> {
+        self.complete(model, messages, tuning).await
+    }
+
+    /// Stream the completion; backends without streaming deliver it in one piece
+    async fn chat_stream(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<ChatStream> {
+        let completion = self.complete(model, messages, tuning).await?;
+        Ok(ChatStream::whole(completion))
+    }
+
+    /// Models the backend offers
+    async fn list_models(&self) -> Result<Vec<String>> {
+        anyhow::bail!("This backend can't list its models")
+    }
+}
+
+/// Stop sequences and token biases for every request to an OpenAI-compatible
+/// backend, from --stop and --logit-bias
+#[derive(Debug, Clone, Default)]
+pub struct Steering {
+    pub stop: Vec<String>,
+    pub logit_bias: BTreeMap<String, f32>,
+}
+
+impl Steering {
+    /// `biases` as "TOKEN_ID=BIAS"
+    pub fn new(stop: Vec<String>, biases: &[String]) -> Result<Self> {
+        if stop.iter().any(String::is_empty) {
+            anyhow::bail!("--stop can't be empty");
+        }
+        let mut logit_bias = BTreeMap::new();
+        for entry in biases {
+            let (token, bias) = entry
+                .split_once('=')
+                .with_context(|| format!("--logit-bias {:?} is not \"TOKEN_ID=BIAS\"", entry))?;
+            let bias = bias.trim().parse().with_context(|| format!("--logit-bias {:?} takes a number", entry))?;
+            logit_bias.insert(token.trim().to_string(), bias);
+        }
+        validate_logit_bias(&logit_bias)?;
+        Ok(Self { stop, logit_bias })
+    }
+}
+
+/// Token IDs are numbers and biases within what the OpenAI API accepts
+pub fn validate_logit_bias(biases: &BTreeMap<String, f32>) -> Result<()> {
+    for (token, bias) in biases {
+        if token.parse::<u32>().is_err() {
+            anyhow::bail!("logit_bias takes token IDs, not {:?}", token);
+        }
+        if !(-100.0..=100.0).contains(bias) {
+            anyhow::bail!("logit_bias for token {} must be between -100 and 100", token);
         }
     }
+    Ok(())
+}
 
-    pub async fn chat(&self, messages: &[Message]) -> Result<String> {
-        let url = format!("{}/chat/completions", self.endpoint);
-        
-        let request = ChatRequest {
-            model: self.model.clone(),
-            messages: messages.to_vec(),
-            stream: false,
+/// Sampling settings, unset ones are left to the backend
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Sampling {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+}
+
+/// Reply length and sampling, from the command line or per session with `/set`.
+/// Whatever is unset falls back to the next level and finally to the backend.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Tuning {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(flatten)]
+    pub sampling: Sampling,
+    /// Ask for a JSON object, on with /json
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub json: bool,
+}
+
+/// What `/set` can change
+pub const TUNING_PARAMS: &[&str] = &["temperature", "top_p", "max_tokens", "presence_penalty", "frequency_penalty"];
+
+impl Tuning {
+    /// These settings, with the unset ones taken from `fallback`
+    pub fn or(&self, fallback: &Tuning) -> Tuning {
+        Tuning {
+            max_tokens: self.max_tokens.or(fallback.max_tokens),
+            sampling: Sampling {
+                temperature: self.sampling.temperature.or(fallback.sampling.temperature),
+                top_p: self.sampling.top_p.or(fallback.sampling.top_p),
+                presence_penalty: self.sampling.presence_penalty.or(fallback.sampling.presence_penalty),
+                frequency_penalty: self.sampling.frequency_penalty.or(fallback.sampling.frequency_penalty),
+            },
+            json: self.json || fallback.json,
+        }
+    }
+
+    /// The ranges the OpenAI API accepts
+    pub fn validate(&self) -> Result<()> {
+        if self.max_tokens == Some(0) {
+            anyhow::bail!("max_tokens must be at least 1");
+        }
+        let check = |value: Option<f32>, name: &str, min: f32, max: f32| match value {
+            Some(v) if !(min..=max).contains(&v) => {
+                anyhow::bail!("{} must be between {} and {}", name, min, max)
+            }
+            _ => Ok(()),
         };
+        check(self.sampling.temperature, "temperature", 0.0, 2.0)?;
+        check(self.sampling.top_p, "top_p", 0.0, 1.0)?;
+        check(self.sampling.presence_penalty, "presence_penalty", -2.0, 2.0)?;
+        check(self.sampling.frequency_penalty, "frequency_penalty", -2.0, 2.0)
+    }
 
-        let mut req = self.client.post(&url).json(&request);
-        
-        if !self.api_key.is_empty() {
-            req = req.header("Authorization", format!("Bearer {}", self.api_key));
+    /// Change one of `TUNING_PARAMS` (`top-p` works too), `default` unsets it
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let name = name.to_lowercase().replace('-', "_");
+        let float = || -> Result<Option<f32>> {
+            if value == "default" {
+                return Ok(None);
+            }
+            value.parse().map(Some).with_context(|| format!("{} takes a number", name))
+        };
+        let mut next = *self;
+        match name.as_str() {
+            "temperature" => next.sampling.temperature = float()?,
+            "top_p" => next.sampling.top_p = float()?,
+            "presence_penalty" => next.sampling.presence_penalty = float()?,
+            "frequency_penalty" => next.sampling.frequency_penalty = float()?,
+            "max_tokens" if value == "default" => next.max_tokens = None,
+            "max_tokens" => {
+                next.max_tokens = Some(value.parse().context("max_tokens takes a whole number")?)
+            }
+            _ => anyhow::bail!("Unknown parameter {}, one of: {}", name, TUNING_PARAMS.join(", ")),
         }
+        next.validate()?;
+        *self = next;
+        Ok(())
+    }
 
-        let response = req
-            .send()
-            .await
-            .context("Failed to send request to LLM")?;
+    /// Nothing set, everything is left to the backend
+    pub fn is_unset(&self) -> bool {
+        !self.json && self.values().iter().all(|(_, value)| value.is_none())
+    }
+
+    /// The settings in force as `name=value` pairs, for log annotations
+    pub fn describe(&self) -> String {
+        let mut pairs: Vec<String> = self
+            .values()
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|v| format!("{}={}", name, v)))
+            .collect();
+        if self.json {
+            pairs.push("json=on".to_string());
+        }
+        pairs.join(" ")
+    }
+
+    /// Each of `TUNING_PARAMS` with its value, if set
+    pub fn values(&self) -> Vec<(&'static str, Option<String>)> {
+        let float = |value: Option<f32>| value.map(|v| v.to_string());
+        vec![
+            ("temperature", float(self.sampling.temperature)),
+            ("top_p", float(self.sampling.top_p)),
+            ("max_tokens", self.max_tokens.map(|v| v.to_string())),
+            ("presence_penalty", float(self.sampling.presence_penalty)),
+            ("frequency_penalty", float(self.sampling.frequency_penalty)),
+        ]
+    }
+}
+
+/// Azure OpenAI serves a deployment rather than a model
+#[derive(Debug, Clone)]
+pub struct AzureDeployment {
+    pub name: String,
+    pub api_version: String,
+}
+
+/// The default backend: any OpenAI-compatible `/chat/completions` endpoint
+pub struct OpenAiClient {
+    upstream: Upstream,
+    model: String,
+    keys: Arc<KeyPool>,
+    profiles: Vec<ModelProfile>,
+    tuning: Tuning,
+    steering: Steering,
+    azure: Option<AzureDeployment>,
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    async fn complete(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<Completion> {
+        let wire = messages.iter().map(WireMessage::from).collect();
+        let (choice, usage) = self.ask(model, wire, tuning, &[]).await?;
+        Ok(Completion {
+            content: choice.message.content.unwrap_or_default(),
+            finish_reason: choice.finish_reason,
+            usage,
+            sources: Vec::new(),
+        })
+    }
+
+    async fn complete_with_tools(
+        &self,
+        model: &str,
+        messages: &[Message],
+        tuning: &Tuning,
+        tools: &ToolRegistry,
+    ) -> Result<Completion> {
+        if tools.is_empty() {
+            return self.complete(model, messages, tuning).await;
+        }
+        let schemas = tools.schemas();
+        let mut wire: Vec<WireMessage> = messages.iter().map(WireMessage::from).collect();
+        let mut total: Option<TokenUsage> = None;
+        let mut sources: Vec<String> = Vec::new();
+        for round in 1..=tools::MAX_ROUNDS {
+            // The last request offers no tools, so the model has to answer
+            let offered = if round < tools::MAX_ROUNDS { schemas.as_slice() } else { &[] };
+            let (choice, usage) = self.ask(model, wire.clone(), tuning, offered).await?;
+            if let Some(usage) = &usage {
+                total.get_or_insert_with(TokenUsage::default).add(usage);
+            }
+            if choice.message.tool_calls.is_empty() {
+                return Ok(Completion {
+                    content: choice.message.content.unwrap_or_default(),
+                    finish_reason: choice.finish_reason,
+                    usage: total,
+                    sources,
+                });
+            }
+            let calls = choice.message.tool_calls;
+            wire.push(WireMessage {
+                role: "assistant".to_string(),
+                content: choice.message.content,
+                tool_calls: calls.clone(),
+                tool_call_id: None,
+            });
+            for call in calls {
+                let output = tools.call(&call).await;
+                for source in output.sources {
+                    if !sources.contains(&source) {
+                        sources.push(source);
+                    }
+                }
+                wire.push(WireMessage {
+                    role: "tool".to_string(),
+                    content: Some(output.content),
+                    tool_calls: Vec::new(),
+                    tool_call_id: Some(call.id),
+                });
+            }
+        }
+        anyhow::bail!("No answer after {} rounds of tool calls", tools::MAX_ROUNDS)
+    }
+
+    /// Ask for a server-sent event stream and hand out the text as it arrives
+    async fn chat_stream(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<ChatStream> {
+        let wire = messages.iter().map(WireMessage::from).collect();
+        let response = self.send(model, wire, true, tuning, &[]).await?;
+        Ok(ChatStream::sse(response))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        if self.azure.is_some() {
+            anyhow::bail!("Azure serves deployments, not a model list");
+        }
+        let list: ModelList = get(&self.upstream, "/models", &self.keys, &self.model, |req, key| {
+            self.authorize(req, key)
+        })
+        .await?
+        .json()
+        .await
+        .context("Failed to parse model list")?;
+        Ok(list.data.into_iter().map(|m| m.id).collect())
+    }
+}
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await.unwrap_or_default();
-            anyhow::bail!("LLM API error {}: {}", status, text);
+impl OpenAiClient {
+    pub fn new(
+        upstream: Upstream,
+        model: String,
+        keys: Arc<KeyPool>,
+        profiles: Vec<ModelProfile>,
+        tuning: Tuning,
+        steering: Steering,
+        azure: Option<AzureDeployment>,
+    ) -> Self {
+        Self {
+            upstream,
+            model,
+            keys,
+            profiles,
+            tuning,
+            steering,
+            azure,
         }
+    }
 
+    /// One request without streaming, with `tools` on offer
+    async fn ask(
+        &self,
+        model: &str,
+        messages: Vec<WireMessage>,
+        tuning: &Tuning,
+        tools: &[Value],
+    ) -> Result<(Choice, Option<TokenUsage>)> {
+        let response = self.send(model, messages, false, tuning, tools).await?;
         let chat_response: ChatResponse = response
             .json()
             .await
             .context("Failed to parse LLM response")?;
-
-        chat_response
+        let choice = chat_response
             .choices
-            .first()
-            .map(|c| c.message.content.clone())
-            .ok_or_else(|| anyhow::anyhow!("No response from LLM"))
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No response from LLM"))?;
+        Ok((choice, chat_response.usage))
+    }
+
+    async fn send(
+        &self,
+        model: &str,
+        messages: Vec<WireMessage>,
+        stream: bool,
+        tuning: &Tuning,
+        tools: &[Value],
+    ) -> Result<reqwest::Response> {
+        let tuning = tuning.or(&self.tuning);
+        let path = match &self.azure {
+            Some(azure) => format!(
+                "/openai/deployments/{}/chat/completions?api-version={}",
+                azure.name, azure.api_version
+            ),
+            None => "/chat/completions".to_string(),
+        };
+
+        let profile = self.profiles.iter().find(|p| p.name == model);
+        let mut stop = self.steering.stop.clone();
+        let mut logit_bias = self.steering.logit_bias.clone();
+        if let Some(profile) = profile {
+            stop.extend(profile.stop.iter().filter(|s| !self.steering.stop.contains(s)).cloned());
+            logit_bias.extend(profile.logit_bias.iter().map(|(token, bias)| (token.clone(), *bias)));
+        }
+        let request = ChatRequest {
+            model: model.to_string(),
+            messages,
+            stream,
+            max_tokens: tuning.max_tokens,
+            sampling: tuning.sampling,
+            models: profile
+                .filter(|p| !p.fallbacks.is_empty())
+                .map(|p| std::iter::once(&p.name).chain(&p.fallbacks).cloned().collect())
+                .unwrap_or_default(),
+            provider: profile.and_then(|p| p.provider.clone()),
+            service_tier: profile.and_then(|p| p.service_tier.clone()),
+            // Azure's older API versions refuse stream_options
+            stream_options: (stream && self.azure.is_none()).then(|| serde_json::json!({ "include_usage": true })),
+            tools: tools.to_vec(),
+            stop,
+            logit_bias,
+            response_format: tuning.json.then(|| serde_json::json!({ "type": "json_object" })),
+        };
+
+        post_json(&self.upstream, &path, &request, &self.keys, model, |req, key| {
+            self.authorize(req, key)
+        })
+        .await
+    }
+
+    fn authorize(&self, req: reqwest::RequestBuilder, key: &str) -> reqwest::RequestBuilder {
+        if self.azure.is_some() {
+            req.header("api-key", key)
+        } else {
+            req.header("Authorization", format!("Bearer {}", key))
+        }
+    }
+}
+
+/// For backends that take the system prompt separately and want turns to
+/// alternate: system messages joined, consecutive messages of one role merged
+pub fn split_system(messages: &[Message]) -> (String, Vec<Message>) {
+    let mut system = Vec::new();
+    let mut turns: Vec<Message> = Vec::new();
+    for message in messages {
+        if message.role == "system" {
+            system.push(message.content.as_str());
+            continue;
+        }
+        match turns.last_mut() {
+            Some(last) if last.role == message.role => {
+                last.content.push_str("\n\n");
+                last.content.push_str(&message.content);
+            }
+            _ => turns.push(message.clone()),
+        }
+    }
+    (system.join("\n\n"), turns)
+}
+
+/// POST `body` to `path` on the first endpoint that answers, rotating through
+/// `keys`, and turn failures into user-facing errors. `authorize` attaches a
+/// key the way the backend expects it.
+pub async fn post_json(
+    upstream: &Upstream,
+    path: &str,
+    body: &(impl Serialize + Sync),
+    keys: &KeyPool,
+    model: &str,
+    authorize: impl Fn(reqwest::RequestBuilder, &str) -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response> {
+    send_with_failover(
+        |url| upstream.client.post(url).headers(upstream.headers.clone()).json(body),
+        upstream,
+        path,
+        keys,
+        model,
+        authorize,
+    )
+    .await
+}
+
+/// GET `path` the way `post_json` posts
+pub async fn get(
+    upstream: &Upstream,
+    path: &str,
+    keys: &KeyPool,
+    model: &str,
+    authorize: impl Fn(reqwest::RequestBuilder, &str) -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response> {
+    send_with_failover(
+        |url| upstream.client.get(url).headers(upstream.headers.clone()),
+        upstream,
+        path,
+        keys,
+        model,
+        authorize,
+    )
+    .await
+}
+
+/// Why a request to one endpoint failed
+enum Failure {
+    /// Unreachable or a server error, the next endpoint may do better
+    Down(anyhow::Error),
+    /// The endpoint answered but won't serve the request
+    Refused(anyhow::Error),
+}
+
+/// Try the endpoints in the order `--balance` gives, skipping those whose
+/// circuit breaker is open. Only the last one still available gets backoff
+/// retries, the others hand over to the next endpoint straight away.
+async fn send_with_failover(
+    request: impl Fn(&str) -> reqwest::RequestBuilder,
+    upstream: &Upstream,
+    path: &str,
+    keys: &KeyPool,
+    model: &str,
+    authorize: impl Fn(reqwest::RequestBuilder, &str) -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response> {
+    let all = upstream.endpoints.order();
+    let mut last_error = None;
+    for (i, endpoint) in all.iter().enumerate() {
+        if !endpoint.breaker.admit(&endpoint.base) {
+            continue;
+        }
+        let url = format!("{}{}", endpoint.base, path);
+        let last = !all[i + 1..].iter().any(|e| e.breaker.is_available());
+        let max_retries = if last { upstream.max_retries } else { 0 };
+        let in_flight = endpoint.start();
+        let sent = send_with_keys(|| request(&url), upstream, &url, keys, model, &authorize, max_retries).await;
+        drop(in_flight);
+        match sent {
+            Ok(response) => {
+                endpoint.breaker.record(&endpoint.base, true);
+                return Ok(response);
+            }
+            Err(Failure::Refused(e)) => {
+                endpoint.breaker.record(&endpoint.base, true);
+                return Err(e);
+            }
+            Err(Failure::Down(e)) => {
+                endpoint.breaker.record(&endpoint.base, false);
+                if !last {
+                    warn!("LLM endpoint {} failed ({:#}), failing over", endpoint.base, e);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        anyhow::anyhow!("The AI model is temporarily unavailable. Please try again in a minute.")
+    }))
+}
+
+async fn send_with_keys(
+    request: impl Fn() -> reqwest::RequestBuilder,
+    upstream: &Upstream,
+    url: &str,
+    keys: &KeyPool,
+    model: &str,
+    authorize: impl Fn(reqwest::RequestBuilder, &str) -> reqwest::RequestBuilder,
+    max_retries: usize,
+) -> Result<reqwest::Response, Failure> {
+    // A refused key is retired and the next one gets a go
+    let mut attempts = keys.len().max(1);
+    let mut pauses = 0;
+    let mut retries = 0;
+    let (response, key) = loop {
+        if let Some(wait) = upstream.paused_for() {
+            tokio::time::sleep(wait).await;
+        }
+
+        let key = if keys.is_empty() {
+            None
+        } else {
+            let key = keys.acquire();
+            if key.is_none() {
+                warn!("Every API key is retired, rate limited or out of budget");
+                return Err(Failure::Refused(anyhow::anyhow!(
+                    "The AI service is busy right now. Please try again in a moment."
+                )));
+            }
+            key
+        };
+
+        let mut req = request();
+        if let Some((_, secret)) = &key {
+            req = authorize(req, secret);
+        }
+
+        // The URL is logged on its own, reqwest's copy may carry credentials
+        let response = match req.send().await.map_err(reqwest::Error::without_url) {
+            Ok(response) => response,
+            Err(e) if retries < max_retries && (e.is_connect() || e.is_timeout() || e.is_request()) => {
+                retries += 1;
+                let delay = backoff(retries);
+                warn!(
+                    "LLM request to {} failed ({}), retry {} of {} in {}ms",
+                    url,
+                    e,
+                    retries,
+                    max_retries,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            Err(e) => {
+                let error = anyhow::Error::new(e).context("Failed to send request to LLM");
+                return Err(Failure::Down(error));
+            }
+        };
+
+        let status = response.status();
+        if status.is_success() {
+            break (response, key);
+        }
+        let retry_after = (status == StatusCode::TOO_MANY_REQUESTS)
+            .then(|| parse_retry_after(&response))
+            .flatten();
+        if let Some((index, _)) = key {
+            keys.report(index, status.as_u16(), retry_after);
+            attempts -= 1;
+            if matches!(status.as_u16(), 401 | 403 | 429) && attempts > 0 {
+                continue;
+            }
+        }
+        // No other key to turn to: everyone waits as long as the backend asked
+        if let Some(wait) = retry_after.filter(|wait| *wait <= MAX_RETRY_AFTER)
+            && pauses < MAX_PAUSES
+        {
+            warn!("LLM API at {} asked for a {}s pause, holding requests back", url, wait.as_secs());
+            upstream.pause(wait);
+            pauses += 1;
+            attempts = keys.len().max(1);
+            continue;
+        }
+        if (status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()) && retries < max_retries {
+            retries += 1;
+            let delay = backoff(retries);
+            warn!(
+                "LLM API error {} from {}, retry {} of {} in {}ms",
+                status,
+                url,
+                retries,
+                max_retries,
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+            attempts = keys.len().max(1);
+            continue;
+        }
+
+        let text = response.text().await.unwrap_or_default();
+        let error = describe_api_error(status, &text, model);
+        warn!(
+            "LLM API error {} from {}: {} (hint: {})",
+            status, url, error.detail, error.hint
+        );
+        let error = anyhow::anyhow!("{}", error.user_message);
+        return Err(if status.is_server_error() {
+            Failure::Down(error)
+        } else {
+            Failure::Refused(error)
+        });
+    };
+    if let Some((index, _)) = key {
+        info!("LLM request for {} served with API key #{}", model, index);
+    }
+    Ok(response)
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    /// Only on the last event, which has no choices
+    #[serde(default)]
+    usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    delta: Delta,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Delta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// What one line of a streamed response carried
+#[derive(Debug, Default)]
+pub struct StreamEvent {
+    pub text: Option<String>,
+    pub finish_reason: Option<String>,
+    /// Counted for the whole request, sent with the last event
+    pub usage: Option<TokenUsage>,
+    /// The backend signalled the end of the stream
+    pub done: bool,
+}
+
+/// Reads one line of a response stream, `None` for lines that carry nothing
+pub type LineParser = fn(&str) -> Result<Option<StreamEvent>>;
+
+/// `data: {...}` server-sent events with OpenAI-style deltas
+fn parse_sse(line: &str) -> Result<Option<StreamEvent>> {
+    let Some(data) = line.strip_prefix("data:") else {
+        return Ok(None);
+    };
+    let data = data.trim();
+    if data == "[DONE]" {
+        return Ok(Some(StreamEvent {
+            done: true,
+            ..Default::default()
+        }));
+    }
+    let chunk: StreamChunk = serde_json::from_str(data).context("Failed to parse LLM stream")?;
+    let usage = chunk.usage;
+    Ok(match chunk.choices.into_iter().next() {
+        Some(choice) => Some(StreamEvent {
+            text: choice.delta.content,
+            finish_reason: choice.finish_reason,
+            usage,
+            done: false,
+        }),
+        None => usage.map(|usage| StreamEvent {
+            usage: Some(usage),
+            ..Default::default()
+        }),
+    })
+}
+
+/// A completion handed out piece by piece
+pub struct ChatStream {
+    source: StreamSource,
+    finish_reason: Option<String>,
+    usage: Option<TokenUsage>,
+}
+
+enum StreamSource {
+    /// A line based stream such as server-sent events or NDJSON
+    Lines {
+        response: reqwest::Response,
+        buffer: Vec<u8>,
+        done: bool,
+        parse: LineParser,
+    },
+    /// The whole text at once, `None` after it was taken
+    Whole(Option<String>),
+}
+
+impl ChatStream {
+    pub fn sse(response: reqwest::Response) -> Self {
+        Self::lines(response, parse_sse)
+    }
+
+    pub fn lines(response: reqwest::Response, parse: LineParser) -> Self {
+        Self {
+            source: StreamSource::Lines {
+                response,
+                buffer: Vec::new(),
+                done: false,
+                parse,
+            },
+            finish_reason: None,
+            usage: None,
+        }
+    }
+
+    pub fn whole(completion: Completion) -> Self {
+        Self {
+            source: StreamSource::Whole(Some(completion.content)),
+            finish_reason: completion.finish_reason,
+            usage: completion.usage,
+        }
+    }
+
+    /// Why the backend stopped, known once the stream has ended
+    pub fn finish_reason(&self) -> Option<&str> {
+        self.finish_reason.as_deref()
+    }
+
+    /// Tokens the request took, known once the stream has ended if the backend says
+    pub fn usage(&self) -> Option<TokenUsage> {
+        self.usage
+    }
+
+    /// The next piece of text, `None` once the backend is done
+    pub async fn next(&mut self) -> Result<Option<String>> {
+        let (response, buffer, done, parse) = match &mut self.source {
+            StreamSource::Lines {
+                response,
+                buffer,
+                done,
+                parse,
+            } => (response, buffer, done, *parse),
+            StreamSource::Whole(text) => return Ok(text.take()),
+        };
+        loop {
+            // Events are line based, only parse complete lines
+            if let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let Some(event) = parse(line)? else {
+                    continue;
+                };
+                if event.finish_reason.is_some() {
+                    self.finish_reason = event.finish_reason;
+                }
+                if event.usage.is_some() {
+                    self.usage = event.usage;
+                }
+                if event.done {
+                    *done = true;
+                }
+                if let Some(text) = event.text
+                    && !text.is_empty()
+                {
+                    return Ok(Some(text));
+                }
+                if event.done {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            if *done {
+                return Ok(None);
+            }
+            match response.chunk().await.context("LLM stream interrupted")? {
+                Some(bytes) => buffer.extend_from_slice(&bytes),
+                None => {
+                    // Whatever is left may be a last event without a newline
+                    *done = true;
+                    buffer.push(b'\n');
+                }
+            }
+        }
+    }
+}
+
+/// A backend error translated for the user and the operator
+struct ApiError {
+    user_message: String,
+    hint: String,
+    detail: String,
+}
+
+/// Pull the error text out of the shapes used by OpenAI, llama.cpp, Ollama and LM Studio
+fn error_detail(body: &str) -> String {
+    let Ok(json) = serde_json::from_str::<Value>(body) else {
+        return body.trim().to_string();
+    };
+
+    let message = match &json["error"] {
+        // OpenAI, llama.cpp, newer LM Studio: {"error": {"message": "..."}}
+        Value::Object(error) => error.get("message").and_then(Value::as_str),
+        // Ollama, older LM Studio: {"error": "..."}
+        Value::String(error) => Some(error.as_str()),
+        _ => None,
+    }
+    .or_else(|| json["message"].as_str())
+    .or_else(|| json["detail"].as_str());
+
+    message.map(|m| m.to_string()).unwrap_or_else(|| body.trim().to_string())
+}
+
+fn describe_api_error(status: StatusCode, body: &str, model: &str) -> ApiError {
+    let detail = error_detail(body);
+    let lower = detail.to_lowercase();
+
+    let model_missing = lower.contains("model")
+        && (lower.contains("not found")
+            || lower.contains("does not exist")
+            || lower.contains("model_not_found")
+            || lower.contains("not loaded"));
+    let context_exceeded = lower.contains("context length")
+        || lower.contains("context window")
+        || lower.contains("maximum context")
+        || lower.contains("too many tokens");
+
+    let (user_message, hint) = if model_missing {
+        (
+            "The configured AI model is not available right now.".to_string(),
+            format!(
+                "model '{}' is unknown to the backend; check --model (Ollama: `ollama pull {}`, LM Studio: load the model first)",
+                model, model
+            ),
+        )
+    } else if context_exceeded {
+        (
+            "The conversation got too long for the model. Use /clear to start over.".to_string(),
+            "request exceeded the model's context window".to_string(),
+        )
+    } else {
+        match status.as_u16() {
+            401 | 403 => (
+                "The AI service rejected our credentials.".to_string(),
+                "check --api-key; the backend refused authentication".to_string(),
+            ),
+            404 => (
+                "The AI service could not be found.".to_string(),
+                "check --endpoint; OpenAI-compatible servers usually expect a URL ending in /v1".to_string(),
+            ),
+            429 => (
+                "The AI service is busy right now. Please try again in a moment.".to_string(),
+                "rate limited or out of quota".to_string(),
+            ),
+            500..=599 => (
+                "The AI service had an internal problem. Please try again.".to_string(),
+                "backend failure, check the backend's own logs".to_string(),
+            ),
+            _ => (
+                format!("The AI service returned an error ({}).", status.as_u16()),
+                "unexpected status from backend".to_string(),
+            ),
+        }
+    };
+
+    ApiError {
+        user_message,
+        hint,
+        detail,
     }
 }
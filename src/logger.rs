@@ -1,182 +1,534 @@
 use anyhow::{Context, Result};
-use chrono::Local;
+use chrono::{DateTime, Local};
+use serde::Serialize;
 use std::fs::{self, OpenOptions};
 use std::io::Write;
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
 
+use crate::abuse::Verdict;
+use crate::hashchain;
+use crate::index::{self, ChatIndex};
+use crate::kiosk;
+use crate::llm::{TokenUsage, Tuning};
+use crate::profile::{self, Profile};
+use crate::storage;
+
+/// On-disk format of the daily chat logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human readable `[time] ROLE: text` lines
+    Text,
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// What produced an assistant message
+#[derive(Debug, Clone, Serialize)]
+pub struct ResponseMeta {
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    /// From sending the request to the last token
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<&'static str>,
+    /// As the backend counted them, not every backend does
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+    /// Generation settings the request went out with, `/set` included
+    #[serde(skip_serializing_if = "Tuning::is_unset")]
+    pub tuning: Tuning,
+}
+
+/// Derived facts about a user message, recorded in JSONL logs only
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Tags {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moderation: Option<Verdict>,
+}
+
+impl ResponseMeta {
+    fn annotation(&self) -> String {
+        let mut annotation = match &self.usage {
+            Some(usage) => format!("model={} tokens={}", self.model, usage.describe()),
+            None => format!("model={}", self.model),
+        };
+        if !self.tuning.is_unset() {
+            annotation.push(' ');
+            annotation.push_str(&self.tuning.describe());
+        }
+        annotation
+    }
+}
+
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    time: String,
+    event: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+    /// What the user actually saw after filtering and rendering
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rendered: Option<&'a str>,
+    #[serde(flatten)]
+    meta: Option<&'a ResponseMeta>,
+    #[serde(flatten)]
+    tags: Option<&'a Tags>,
+}
+
+impl<'a> LogEntry<'a> {
+    fn new(event: &'a str) -> Self {
+        Self {
+            time: Local::now().to_rfc3339(),
+            event,
+            role: None,
+            content: None,
+            rendered: None,
+            meta: None,
+            tags: None,
+        }
+    }
+}
+
+/// Set by the disk watchdog when the logs volume runs low on space
+static LOGGING_SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_logging_suspended(suspended: bool) {
+    LOGGING_SUSPENDED.store(suspended, Ordering::Relaxed);
+}
+
+pub fn logging_suspended() -> bool {
+    LOGGING_SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// Seal JSONL entries with a hash of the one before, set from `--hash-chain`
+static HASH_CHAIN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_hash_chain(enabled: bool) {
+    HASH_CHAIN.store(enabled, Ordering::Relaxed);
+}
+
+/// Directory holding everything logged for one client
+pub fn client_dir(logs_dir: &str, client_ip: IpAddr) -> PathBuf {
+    // Sanitize IP for directory name (replace : with -)
+    let ip_str = client_ip.to_string().replace(':', "-");
+    PathBuf::from(logs_dir).join(ip_str)
+}
+
+/// Subdirectories of a client directory that are not named users
+const RESERVED_NAMES: [&str; 2] = ["chats", "exports"];
+
+/// Directory of one named user behind a shared IP, `None` if the name
+/// doesn't make a usable directory name
+pub fn named_client_dir(logs_dir: &str, client_ip: IpAddr, name: &str) -> Option<PathBuf> {
+    let slug: String = name
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect();
+    let slug = slug.trim_matches('-');
+    if slug.is_empty() || RESERVED_NAMES.contains(&slug) {
+        return None;
+    }
+    Some(client_dir(logs_dir, client_ip).join(slug))
+}
+
+/// Directories of the named profiles set up behind this IP
+pub fn named_users(logs_dir: &str, client_ip: IpAddr) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(client_dir(logs_dir, client_ip)) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.join("profile.json").is_file())
+        .collect()
+}
+
+/// Conversation queued by `telllm import`, loaded on the client's next session
+pub fn pending_import_path(logs_dir: &str, client_ip: IpAddr) -> PathBuf {
+    client_dir(logs_dir, client_ip).join("import.json")
+}
+
+/// Per-client logger. Nothing touches the disk until something is logged,
+/// so port scanners that never type don't litter the log tree.
 pub struct ChatLogger {
     client_dir: PathBuf,
     current_date: String,
+    format: LogFormat,
+    opened_at: DateTime<Local>,
+    started: AtomicBool,
+    quota: QuotaRule,
+    /// The quota in force and the bytes the client directory uses, measured on the first write
+    usage: Mutex<Option<(Option<u64>, u64)>>,
+    /// Directory to remove when the logger is dropped, with --kiosk
+    wipe: Option<PathBuf>,
+}
+
+/// Where a client's storage quota comes from; the client profile's own
+/// `quota_mb` sits between the two, and 0 MB means no limit
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuotaRule {
+    /// Set for this connection, e.g. by a signed token
+    pub override_mb: Option<u64>,
+    /// The schedule's or the server's quota in bytes
+    pub fallback: Option<u64>,
+}
+
+impl QuotaRule {
+    fn resolve(&self, profile_mb: Option<u64>) -> Option<u64> {
+        match self.override_mb.or(profile_mb) {
+            Some(0) => None,
+            Some(mb) => Some(mb * 1024 * 1024),
+            None => self.fallback,
+        }
+    }
 }
 
 impl ChatLogger {
-    pub fn new(logs_dir: &str, client_ip: IpAddr) -> Result<Self> {
-        // Sanitize IP for directory name (replace : with -)
-        let ip_str = client_ip.to_string().replace(':', "-");
-        let client_dir = PathBuf::from(logs_dir).join(&ip_str);
-        let chats_dir = client_dir.join("chats");
-        
-        // Create directories
-        fs::create_dir_all(&chats_dir)
-            .context("Failed to create chat logs directory")?;
+    pub fn new(logs_dir: &str, client_ip: IpAddr, format: LogFormat) -> Self {
+        let client_dir = client_dir(logs_dir, client_ip);
+        let opened_at = Local::now();
+        let current_date = opened_at.format("%d-%m-%y").to_string();
         
-        let current_date = Local::now().format("%d-%m-%y").to_string();
-        
-        Ok(Self {
+        Self {
             client_dir,
             current_date,
-        })
+            format,
+            opened_at,
+            started: AtomicBool::new(false),
+            quota: QuotaRule::default(),
+            usage: Mutex::new(None),
+            wipe: None,
+        }
+    }
+
+    /// Remove the directory this logger writes to once it is dropped, so
+    /// nothing is left behind however the session ends. Directories it
+    /// switches to later are never removed, they may be an account's.
+    pub fn wipe_when_dropped(&mut self) {
+        self.wipe = Some(self.client_dir.clone());
+    }
+
+    /// Keep the client directory within the quota `rule` gives while writing
+    pub fn set_quota(&mut self, rule: QuotaRule) {
+        self.quota = rule;
+        *self.usage.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    /// Log into another directory from now on, e.g. after the user logged in.
+    /// A running transcript is closed and a new one begins with the next message.
+    pub fn switch_to(&mut self, client_dir: PathBuf) -> Result<()> {
+        self.log_session_end()?;
+        self.client_dir = client_dir;
+        *self.usage.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+        self.opened_at = Local::now();
+        self.started.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn client_dir(&self) -> &Path {
+        &self.client_dir
     }
 
     fn chat_file_path(&self) -> PathBuf {
+        let extension = match self.format {
+            LogFormat::Text => "txt",
+            LogFormat::Jsonl => "jsonl",
+        };
         self.client_dir
             .join("chats")
-            .join(format!("{}.txt", self.current_date))
+            .join(format!("{}.{}", self.current_date, extension))
     }
 
-    fn summary_file_path(&self) -> PathBuf {
-        self.client_dir.join("summary.txt")
-    }
+    /// Append one entry to today's chat log in the configured format
+    fn append(&self, text: &str, entry: &LogEntry) -> Result<()> {
+        if logging_suspended() {
+            return Ok(());
+        }
 
-    pub fn log_message(&self, role: &str, content: &str) -> Result<()> {
-        let timestamp = Local::now().format("%H:%M:%S").to_string();
         let chat_path = self.chat_file_path();
+        if let Some(chats_dir) = chat_path.parent() {
+            fs::create_dir_all(chats_dir)
+                .context("Failed to create chat logs directory")?;
+        }
         
+        // Sessions from the same client share the file, the chain must not fork
+        let chain_lock = match self.format {
+            LogFormat::Jsonl if HASH_CHAIN.load(Ordering::Relaxed) => Some(storage::lock(&self.client_dir, "chats.lock")?),
+            _ => None,
+        };
+        let line = match self.format {
+            LogFormat::Text => text.to_string(),
+            LogFormat::Jsonl => serde_json::to_string(entry)?,
+        };
+        // A restarted log starts a new chain, so make room before sealing
+        self.make_room(&chat_path, line.len() as u64 + 1)?;
+        let line = match chain_lock {
+            Some(_) => hashchain::seal(&hashchain::last_hash(&chat_path)?, &line),
+            None => line,
+        };
+
         let mut file = OpenOptions::new()
             .create(true)
             .append(true)
             .open(&chat_path)
             .context("Failed to open chat log file")?;
-
-        writeln!(file, "[{}] {}: {}", timestamp, role.to_uppercase(), content)
-            .context("Failed to write to chat log")?;
+        writeln!(file, "{}", line).context("Failed to write to chat log")?;
 
         Ok(())
     }
 
-    pub fn log_session_start(&self) -> Result<()> {
-        let timestamp = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
-        let chat_path = self.chat_file_path();
-        
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&chat_path)
-            .context("Failed to open chat log file")?;
+    pub fn log_message(&self, role: &str, content: &str) -> Result<()> {
+        self.log_tagged(role, content, &Tags::default())
+    }
 
-        writeln!(file, "\n--- Session started at {} ---\n", timestamp)
-            .context("Failed to write session start")?;
+    /// Log a message together with its language and moderation verdict
+    pub fn log_tagged(&self, role: &str, content: &str, tags: &Tags) -> Result<()> {
+        let timestamp = Local::now().format("%H:%M:%S").to_string();
+        let text = format!("[{}] {}: {}", timestamp, role.to_uppercase(), content);
 
-        Ok(())
+        let mut entry = LogEntry::new("message");
+        entry.role = Some(role);
+        entry.content = Some(content);
+        entry.tags = Some(tags);
+        self.append(&text, &entry)?;
+
+        // Server notes don't count as conversation
+        if matches!(role, "SYSTEM" | "CLIENT") {
+            return Ok(());
+        }
+        self.index(Some(content))
+    }
+
+    /// Log an assistant message under its display label, together with the model
+    /// that produced it. Text logs keep the raw output, JSONL also records the rendered text.
+    pub fn log_response(&self, label: &str, raw: &str, rendered: &str, meta: &ResponseMeta) -> Result<()> {
+        let timestamp = Local::now().format("%H:%M:%S").to_string();
+        let text = format!("[{}] {} [{}]: {}", timestamp, label.to_uppercase(), meta.annotation(), raw);
+
+        let mut entry = LogEntry::new("message");
+        entry.role = Some(label);
+        entry.content = Some(raw);
+        entry.rendered = Some(rendered);
+        entry.meta = Some(meta);
+        self.append(&text, &entry)?;
+        self.index(None)
+    }
+
+    /// Keep index.json in step with the chat logs
+    fn index(&self, user_message: Option<&str>) -> Result<()> {
+        if logging_suspended() {
+            return Ok(());
+        }
+        index::record(&self.client_dir, &self.current_date, user_message)
+    }
+
+    pub fn chat_index(&self) -> ChatIndex {
+        index::load(&self.client_dir)
+    }
+
+    /// Write the deferred session-start entry, stamped with the connection time.
+    /// Returns false when the session was already started.
+    pub fn log_session_start(&self) -> Result<bool> {
+        if self.started.swap(true, Ordering::Relaxed) {
+            return Ok(false);
+        }
+
+        let timestamp = self.opened_at.format("%d-%m-%Y %H:%M:%S").to_string();
+        let text = format!("\n--- Session started at {} ---\n", timestamp);
+        let mut entry = LogEntry::new("session_start");
+        entry.time = self.opened_at.to_rfc3339();
+        self.append(&text, &entry)?;
+        Ok(true)
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started.load(Ordering::Relaxed)
     }
 
     pub fn log_session_end(&self) -> Result<()> {
+        if !self.is_started() {
+            return Ok(());
+        }
+
         let timestamp = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
-        let chat_path = self.chat_file_path();
-        
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&chat_path)
-            .context("Failed to open chat log file")?;
+        let text = format!("\n--- Session ended at {} ---\n", timestamp);
+        self.append(&text, &LogEntry::new("session_end"))
+    }
 
-        writeln!(file, "\n--- Session ended at {} ---\n", timestamp)
-            .context("Failed to write session end")?;
+    /// Marks the end of a transcript cut short by a panic, as the session unwinds
+    fn log_session_crash(&self) {
+        if !self.is_started() {
+            return;
+        }
+        let timestamp = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+        let text = format!("\n--- Session crashed at {} ---\n", timestamp);
+        let _ = self.append(&text, &LogEntry::new("session_crash"));
+    }
 
-        Ok(())
+    /// Change the client's profile; `last_seen` is refreshed on every write
+    pub fn update_profile(&self, modify: impl FnOnce(&mut Profile)) -> Result<()> {
+        if logging_suspended() {
+            return Ok(());
+        }
+
+        profile::update(&self.client_dir, |profile| {
+            modify(profile);
+            profile.last_seen = Some(Local::now());
+        })
     }
 
-    pub fn update_summary(&self, key: &str, value: &str) -> Result<()> {
-        let summary_path = self.summary_file_path();
-        
-        // Read existing summary
-        let existing = fs::read_to_string(&summary_path).unwrap_or_default();
-        
-        // Parse into key-value pairs
-        let mut entries: Vec<(String, String)> = existing
-            .lines()
-            .filter_map(|line| {
-                let parts: Vec<&str> = line.splitn(2, ": ").collect();
-                if parts.len() == 2 {
-                    Some((parts[0].to_string(), parts[1].to_string()))
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        // Update or add the key
-        let key_lower = key.to_lowercase();
-        if let Some(entry) = entries.iter_mut().find(|(k, _)| k.to_lowercase() == key_lower) {
-            entry.1 = value.to_string();
-        } else {
-            entries.push((key.to_string(), value.to_string()));
-        }
-
-        // Always update last_seen
-        let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
-        if let Some(entry) = entries.iter_mut().find(|(k, _)| k == "last_seen") {
-            entry.1 = now.clone();
-        } else {
-            entries.push(("last_seen".to_string(), now));
-        }
-
-        // Write back
-        let content: String = entries
-            .iter()
-            .map(|(k, v)| format!("{}: {}", k, v))
-            .collect::<Vec<_>>()
-            .join("\n");
-
-        fs::write(&summary_path, content + "\n")
-            .context("Failed to write summary file")?;
+    /// Save an exported conversation under the client's exports directory
+    pub fn write_export(&self, content: &str) -> Result<PathBuf> {
+        if logging_suspended() {
+            anyhow::bail!("Saving is unavailable right now (server low on disk space)");
+        }
 
-        Ok(())
+        let exports_dir = self.client_dir.join("exports");
+        fs::create_dir_all(&exports_dir)
+            .context("Failed to create exports directory")?;
+
+        let name = format!("{}.json", Local::now().format("%d-%m-%y_%H-%M-%S"));
+        let path = exports_dir.join(name);
+        fs::write(&path, content).context("Failed to write export")?;
+
+        Ok(path)
     }
 
-    pub fn get_summary(&self) -> Option<String> {
-        fs::read_to_string(self.summary_file_path()).ok()
+    /// Read and remove a conversation queued by `telllm import`
+    pub fn take_pending_import(&self) -> Option<String> {
+        let path = self.client_dir.join("import.json");
+        let json = fs::read_to_string(&path).ok()?;
+        let _ = fs::remove_file(&path);
+        Some(json)
     }
 
-    /// Update just the last_seen timestamp in the summary
-    pub fn touch_last_seen(&self) -> Result<()> {
-        let summary_path = self.summary_file_path();
-        
-        // Read existing summary
-        let existing = fs::read_to_string(&summary_path).unwrap_or_default();
-        
-        // Parse into key-value pairs
-        let mut entries: Vec<(String, String)> = existing
-            .lines()
-            .filter_map(|line| {
-                let parts: Vec<&str> = line.splitn(2, ": ").collect();
-                if parts.len() == 2 {
-                    Some((parts[0].to_string(), parts[1].to_string()))
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        // Update or add last_seen
-        let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
-        if let Some(entry) = entries.iter_mut().find(|(k, _)| k == "last_seen") {
-            entry.1 = now;
-        } else {
-            entries.push(("last_seen".to_string(), now));
-        }
-
-        // Write back
-        let content: String = entries
-            .iter()
-            .map(|(k, v)| format!("{}: {}", k, v))
-            .collect::<Vec<_>>()
-            .join("\n");
-
-        fs::write(&summary_path, content + "\n")
-            .context("Failed to write summary file")?;
+    /// The client's profile, a corrupt one is reported and treated as missing
+    pub fn profile(&self) -> Option<Profile> {
+        profile::load(&self.client_dir).unwrap_or_else(|e| {
+            tracing::warn!("{:#}", e);
+            None
+        })
+    }
 
+    /// The quota in bytes of the current client directory, `None` without one
+    pub fn quota(&self) -> Option<u64> {
+        self.quota.resolve(self.profile().and_then(|p| p.quota_mb))
+    }
+
+    /// Drop the oldest chat logs once the client's directory exceeds its quota
+    pub fn enforce_quota(&self) -> Result<usize> {
+        let Some(quota) = self.quota() else {
+            return Ok(0);
+        };
+        if !self.client_dir.exists() {
+            return Ok(0);
+        }
+        storage::enforce_quota(&self.client_dir, quota, &self.chat_file_path())
+    }
+
+    /// Keep the client directory within its quota with `bytes` more written to
+    /// `chat_path`: older logs go first, then today's log is started over
+    fn make_room(&self, chat_path: &Path, bytes: u64) -> Result<()> {
+        let mut usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+        let (quota, used) = usage.get_or_insert_with(|| (self.quota(), storage::dir_size(&self.client_dir)));
+        let Some(quota) = *quota else {
+            return Ok(());
+        };
+        if *used + bytes > quota {
+            storage::enforce_quota(&self.client_dir, quota.saturating_sub(bytes), chat_path)?;
+            *used = storage::dir_size(&self.client_dir);
+        }
+        if *used + bytes > quota
+            && let Ok(meta) = fs::metadata(chat_path)
+        {
+            OpenOptions::new()
+                .write(true)
+                .open(chat_path)
+                .and_then(|file| file.set_len(0))
+                .context("Failed to truncate chat log")?;
+            *used = used.saturating_sub(meta.len());
+            tracing::warn!("Started {} over to stay within its quota", chat_path.display());
+        }
+        *used += bytes;
         Ok(())
     }
+
+    /// Update just the last_seen timestamp in the profile
+    pub fn touch_last_seen(&self) -> Result<()> {
+        self.update_profile(|_| {})
+    }
+}
+
+impl Drop for ChatLogger {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.wipe {
+            kiosk::wipe(dir);
+        } else if std::thread::panicking() {
+            self.log_session_crash();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts;
+    use std::net::Ipv4Addr;
+
+    const KIOSK_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+
+    /// A logger the way a --kiosk session sets it up
+    fn kiosk_logger(logs_dir: &str) -> ChatLogger {
+        let mut logger = ChatLogger::new(logs_dir, KIOSK_IP, LogFormat::Text);
+        logger.switch_to(kiosk::scratch_dir(logs_dir, KIOSK_IP)).unwrap();
+        logger.wipe_when_dropped();
+        logger
+    }
+
+    #[test]
+    fn kiosk_login_leaves_the_account_alone() {
+        let dir = std::env::temp_dir().join(format!("telllm-kiosk-login-{}", std::process::id()));
+        let logs_dir = dir.to_string_lossy().into_owned();
+        let account = accounts::register(&logs_dir, "alice", "correct horse").unwrap();
+
+        let mut logger = kiosk_logger(&logs_dir);
+        logger.log_message("ALICE", "hello").unwrap();
+        let scratch = logger.client_dir().to_path_buf();
+        // Even a login that got through only moves the transcript
+        logger.switch_to(account.clone()).unwrap();
+        drop(logger);
+
+        assert!(!scratch.exists());
+        assert!(accounts::login(&logs_dir, "alice", "correct horse").is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn kiosk_sessions_from_one_address_wipe_only_their_own() {
+        let dir = std::env::temp_dir().join(format!("telllm-kiosk-shared-{}", std::process::id()));
+        let logs_dir = dir.to_string_lossy().into_owned();
+
+        let first = kiosk_logger(&logs_dir);
+        let second = kiosk_logger(&logs_dir);
+        assert_ne!(first.client_dir(), second.client_dir());
+        first.log_message("VISITOR", "first").unwrap();
+        second.log_message("VISITOR", "second").unwrap();
+        drop(first);
+
+        assert!(second.chat_file_path().is_file());
+        let kept = second.client_dir().to_path_buf();
+        drop(second);
+        assert!(!kept.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,428 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::kiosk;
+
+/// Server notes, not conversation, so never searched
+const SKIPPED_ROLES: [&str; 2] = ["SYSTEM", "CLIENT"];
+/// Characters of context shown around the first match
+const SNIPPET_CHARS: usize = 100;
+/// BM25 term frequency saturation and length normalization
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+/// Words to look for, all of which a message must contain, and the days and
+/// clients of the chat logs to look in
+#[derive(Debug, Default)]
+pub struct Query {
+    pub terms: Vec<String>,
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+    /// A client directory relative to the logs directory, the named users under it included
+    pub client: Option<String>,
+}
+
+impl Query {
+    pub fn new(words: &str, since: Option<NaiveDate>, until: Option<NaiveDate>, client: Option<String>) -> Self {
+        Self {
+            terms: words.split_whitespace().map(fold).collect(),
+            since,
+            until,
+            client,
+        }
+    }
+
+    /// Words with optional `since:YYYY-MM-DD`, `until:YYYY-MM-DD` and
+    /// `client:<dir>` among them, as typed after /search or `search`
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut query = Self::default();
+        for word in text.split_whitespace() {
+            let date = |value: &str| {
+                NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                    .with_context(|| format!("{} is not a date, write it as YYYY-MM-DD", value))
+            };
+            if let Some(value) = word.strip_prefix("since:") {
+                query.since = Some(date(value)?);
+            } else if let Some(value) = word.strip_prefix("until:") {
+                query.until = Some(date(value)?);
+            } else if let Some(value) = word.strip_prefix("client:") {
+                query.client = Some(value.to_string());
+            } else {
+                query.terms.push(fold(word));
+            }
+        }
+        Ok(query)
+    }
+
+    fn covers(&self, date: NaiveDate) -> bool {
+        self.since.is_none_or(|since| date >= since) && self.until.is_none_or(|until| date <= until)
+    }
+}
+
+/// One message found in the chat logs
+#[derive(Debug)]
+pub struct Hit {
+    /// Client directory relative to the logs directory
+    pub client: String,
+    pub time: NaiveDateTime,
+    pub role: String,
+    pub content: String,
+    pub score: f64,
+}
+
+impl Hit {
+    /// The part of the message around the first match, on one line
+    pub fn snippet(&self, terms: &[String]) -> String {
+        snippet(&self.content, terms)
+    }
+}
+
+struct LogMessage {
+    time: NaiveDateTime,
+    role: String,
+    content: String,
+}
+
+/// Collection-wide counts the ranking needs, gathered while scanning
+#[derive(Default)]
+struct Corpus {
+    messages: usize,
+    words: usize,
+    /// Messages containing each term
+    containing: HashMap<String, usize>,
+}
+
+/// The best `limit` matches in one client's chat logs, e.g. for /search
+pub fn search_client(client_dir: &Path, query: &Query, limit: usize) -> Vec<Hit> {
+    rank(&[(String::new(), client_dir.to_path_buf())], query, limit)
+}
+
+/// The best `limit` matches in every client's chat logs, or in those of
+/// `query.client` and the named users under it
+pub fn search(logs_dir: &str, query: &Query, limit: usize) -> Result<Vec<Hit>> {
+    let logs_dir = Path::new(logs_dir);
+    let mut clients = Vec::new();
+    client_dirs(logs_dir, logs_dir, 0, &mut clients)
+        .with_context(|| format!("Failed to read logs directory {}", logs_dir.display()))?;
+    if let Some(client) = &query.client {
+        // Directories of IPv6 clients use - for :
+        let client = client.replace(':', "-");
+        clients.retain(|(name, _)| *name == client || name.starts_with(&format!("{}/", client)));
+    }
+    Ok(rank(&clients, query, limit))
+}
+
+/// Directories with a `chats` folder: clients, named users behind them and accounts
+fn client_dirs(logs_dir: &Path, dir: &Path, depth: usize, clients: &mut Vec<(String, PathBuf)>) -> Result<()> {
+    for entry in fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        if !path.is_dir() || name == "chats" || (depth == 0 && (name == "honeypot" || name == kiosk::SCRATCH_DIR)) {
+            continue;
+        }
+        if path.join("chats").is_dir() {
+            let client = path.strip_prefix(logs_dir).unwrap_or(&path).to_string_lossy().into_owned();
+            clients.push((client, path.clone()));
+        }
+        if depth < 1 {
+            client_dirs(logs_dir, &path, depth + 1, clients)?;
+        }
+    }
+    Ok(())
+}
+
+fn rank(clients: &[(String, PathBuf)], query: &Query, limit: usize) -> Vec<Hit> {
+    if query.terms.is_empty() {
+        return Vec::new();
+    }
+
+    let mut corpus = Corpus::default();
+    let mut hits = Vec::new();
+    for (client, dir) in clients {
+        let Ok(entries) = fs::read_dir(dir.join("chats")) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(date) = path
+                .file_stem()
+                .and_then(|stem| NaiveDate::parse_from_str(&stem.to_string_lossy(), "%d-%m-%y").ok())
+            else {
+                continue;
+            };
+            if !query.covers(date) {
+                continue;
+            }
+            let Ok(content) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let messages = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("jsonl") => parse_jsonl(&content),
+                Some("txt") => parse_text(&content, date),
+                _ => continue,
+            };
+            for message in messages {
+                if let Some(hit) = corpus.add(client, message, &query.terms) {
+                    hits.push(hit);
+                }
+            }
+        }
+    }
+
+    for hit in &mut hits {
+        hit.score = corpus.score(&hit.content, &query.terms);
+    }
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(b.time.cmp(&a.time)));
+    hits.truncate(limit);
+    hits
+}
+
+impl Corpus {
+    /// Count the message in, returning it when it has every term
+    fn add(&mut self, client: &str, message: LogMessage, terms: &[String]) -> Option<Hit> {
+        self.messages += 1;
+        self.words += message.content.split_whitespace().count();
+        let folded = fold(&message.content);
+        let mut has_all = true;
+        for term in terms {
+            if folded.contains(term.as_str()) {
+                *self.containing.entry(term.clone()).or_default() += 1;
+            } else {
+                has_all = false;
+            }
+        }
+        has_all.then(|| Hit {
+            client: client.to_string(),
+            time: message.time,
+            role: message.role,
+            content: message.content,
+            score: 0.0,
+        })
+    }
+
+    /// BM25 of a message, so rare words and short messages that repeat them rank first
+    fn score(&self, content: &str, terms: &[String]) -> f64 {
+        let folded = fold(content);
+        let words = content.split_whitespace().count() as f64;
+        let average = (self.words as f64 / self.messages.max(1) as f64).max(1.0);
+        let total = self.messages as f64;
+        terms
+            .iter()
+            .map(|term| {
+                let containing = self.containing.get(term).copied().unwrap_or(0) as f64;
+                let idf = (1.0 + (total - containing + 0.5) / (containing + 0.5)).ln();
+                let tf = folded.matches(term.as_str()).count() as f64;
+                idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * words / average))
+            })
+            .sum()
+    }
+}
+
+fn parse_jsonl(content: &str) -> Vec<LogMessage> {
+    let mut messages = Vec::new();
+    for line in content.lines() {
+        let Ok(entry) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        if entry["event"].as_str() != Some("message") {
+            continue;
+        }
+        let (Some(role), Some(content), Some(time)) = (
+            entry["role"].as_str(),
+            entry["content"].as_str(),
+            entry["time"].as_str().and_then(|time| DateTime::parse_from_rfc3339(time).ok()),
+        ) else {
+            continue;
+        };
+        let role = role.to_uppercase();
+        if SKIPPED_ROLES.contains(&role.as_str()) {
+            continue;
+        }
+        messages.push(LogMessage {
+            time: time.with_timezone(&Local).naive_local(),
+            role,
+            content: content.to_string(),
+        });
+    }
+    messages
+}
+
+/// Messages of a text log, whose lines only carry the time of day
+fn parse_text(content: &str, date: NaiveDate) -> Vec<LogMessage> {
+    let mut messages: Vec<LogMessage> = Vec::new();
+    // Continuation lines belong to the message above, unless that one is skipped
+    let mut open = false;
+    for line in content.lines() {
+        if line.starts_with("--- Session ") && line.ends_with(" ---") {
+            open = false;
+            continue;
+        }
+        if let Some((role, _, text)) = parse_header(line) {
+            let time = line
+                .get(1..9)
+                .and_then(|time| NaiveTime::parse_from_str(time, "%H:%M:%S").ok())
+                .unwrap_or_default();
+            open = !SKIPPED_ROLES.contains(&role);
+            if open {
+                messages.push(LogMessage {
+                    time: date.and_time(time),
+                    role: role.to_string(),
+                    content: text.to_string(),
+                });
+            }
+            continue;
+        }
+        if open && let Some(message) = messages.last_mut() {
+            message.content.push('\n');
+            message.content.push_str(line);
+        }
+    }
+    messages
+}
+
+/// `[HH:MM:SS] ROLE: text` or `[HH:MM:SS] LABEL [model=...]: text` for the model
+pub fn parse_header(line: &str) -> Option<(&str, bool, &str)> {
+    let rest = line.strip_prefix('[')?;
+    let (time, rest) = rest.split_once("] ")?;
+    if time.len() != 8 || !time.bytes().all(|b| b.is_ascii_digit() || b == b':') {
+        return None;
+    }
+    if let Some((label, rest)) = rest.split_once(" [model=")
+        && !label.contains(':')
+        && let Some((_, text)) = rest.split_once("]: ")
+    {
+        return Some((label, true, text));
+    }
+    let (role, text) = rest.split_once(": ")?;
+    Some((role, false, text))
+}
+
+/// Lowercase one character for one, so positions carry over to the original text
+fn fold(text: &str) -> String {
+    text.chars().map(|c| c.to_lowercase().next().unwrap_or(c)).collect()
+}
+
+fn snippet(content: &str, terms: &[String]) -> String {
+    let chars: Vec<char> = content.chars().map(|c| if c.is_whitespace() { ' ' } else { c }).collect();
+    let folded = fold(content);
+    let first = terms
+        .iter()
+        .filter_map(|term| folded.find(term.as_str()))
+        .min()
+        .map(|at| folded[..at].chars().count())
+        .unwrap_or(0);
+    let start = first.saturating_sub(SNIPPET_CHARS / 3);
+    let end = (start + SNIPPET_CHARS).min(chars.len());
+    let start = end.saturating_sub(SNIPPET_CHARS);
+    let mut out = String::new();
+    if start > 0 {
+        out.push_str("...");
+    }
+    out.extend(&chars[start..end]);
+    if end < chars.len() {
+        out.push_str("...");
+    }
+    out
+}
+
+/// One line per match with its time, client, role and the text around the match
+pub fn render(hits: &[Hit], terms: &[String]) -> String {
+    let mut out = String::new();
+    for hit in hits {
+        out.push_str(&format!(
+            "{}  {:<24} {}: {}\n",
+            hit.time.format("%d-%m-%Y %H:%M"),
+            hit.client,
+            hit.role,
+            hit.snippet(terms)
+        ));
+    }
+    out
+}
+
+/// Print matches of the whole logs directory for `telllm logs search`
+pub fn print_search(logs_dir: &str, query: &Query, limit: usize) -> Result<()> {
+    if query.terms.is_empty() {
+        anyhow::bail!("Nothing to search for");
+    }
+    let hits = search(logs_dir, query, limit)?;
+    if hits.is_empty() {
+        println!("No matches in {}", logs_dir);
+        return Ok(());
+    }
+    print!("{}", render(&hits, &query.terms));
+    println!("\n{} matches, best first", hits.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_logs_keep_multiline_messages_and_skip_notes() {
+        let date = NaiveDate::from_ymd_opt(2026, 1, 30).unwrap();
+        let log = "--- Session started at 30-01-2026 13:00:00 ---\n\
+                   [13:00:01] CLIENT: terminal=xterm\n\
+                   [13:00:05] ALICE: my router\nkeeps rebooting\n\
+                   [13:00:09] AI [model=gpt]: Try a new power supply.\n\
+                   --- Session ended at 30-01-2026 13:05:00 ---\n";
+        let messages = parse_text(log, date);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, "ALICE");
+        assert_eq!(messages[0].content, "my router\nkeeps rebooting");
+        assert_eq!(messages[0].time, date.and_hms_opt(13, 0, 5).unwrap());
+        assert_eq!(messages[1].role, "AI");
+    }
+
+    #[test]
+    fn rare_words_in_short_messages_rank_first() {
+        let mut corpus = Corpus::default();
+        let terms = Query::new("Router", None, None, None).terms;
+        let time = NaiveDate::from_ymd_opt(2026, 1, 30).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        let texts = [
+            "my router broke",
+            "the weather is nice today and I would like to talk about it, also my router",
+            "nothing to see here",
+        ];
+        let mut hits: Vec<Hit> = texts
+            .iter()
+            .filter_map(|text| {
+                let message = LogMessage {
+                    time,
+                    role: "USER".to_string(),
+                    content: text.to_string(),
+                };
+                corpus.add("client", message, &terms)
+            })
+            .collect();
+        assert_eq!(hits.len(), 2);
+        for hit in &mut hits {
+            hit.score = corpus.score(&hit.content, &terms);
+        }
+        assert!(hits[0].score > hits[1].score);
+    }
+
+    #[test]
+    fn dates_and_client_in_the_query() {
+        let query = Query::parse("router since:2026-01-01 until:2026-01-31 client:accounts/alice").unwrap();
+        assert_eq!(query.terms, ["router"]);
+        assert_eq!(query.client.as_deref(), Some("accounts/alice"));
+        assert!(query.covers(NaiveDate::from_ymd_opt(2026, 1, 15).unwrap()));
+        assert!(!query.covers(NaiveDate::from_ymd_opt(2026, 2, 1).unwrap()));
+        assert!(Query::parse("since:yesterday").is_err());
+    }
+
+    #[test]
+    fn snippets_center_on_the_match() {
+        let text = format!("{} needle {}", "hay ".repeat(50), "hay ".repeat(50));
+        let snippet = snippet(&text, &["needle".to_string()]);
+        assert!(snippet.starts_with("...") && snippet.ends_with("..."));
+        assert!(snippet.contains("needle"));
+        assert_eq!(snippet.chars().count(), SNIPPET_CHARS + 6);
+    }
+}
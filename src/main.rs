@@ -1,93 +1,1312 @@
+mod abuse;
+mod accounts;
+mod admin;
+mod alias;
+mod anthropic;
+#[cfg(feature = "http")]
+mod api;
+#[cfg(feature = "serial")]
+mod attract;
+mod audit;
+mod breaker;
+mod calc;
+mod challenge;
+mod config;
+mod digest;
+mod endpoints;
+mod env;
+mod escalation;
+mod features;
+#[cfg(feature = "tools")]
+mod fetch;
+mod gemini;
+mod geoip;
+mod greeting;
+mod handover;
+mod hashchain;
+mod index;
+mod interop;
+mod kiosk;
+mod keys;
+mod lang;
+mod llamacpp;
 mod llm;
 mod logger;
+mod logsearch;
+#[cfg(feature = "tools")]
+mod mcp;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "serial")]
+mod modem;
+mod models;
+mod noise;
+mod ollama;
+mod persona;
+mod profile;
+mod protocol;
+mod proxy;
+mod redact;
+mod render;
+mod schedule;
+#[cfg(feature = "tools")]
+mod search;
+#[cfg(feature = "http")]
+mod selftest;
+mod sentiment;
+mod serial;
 mod session;
+mod signing;
+mod snapshot;
+mod spill;
+mod stats;
+mod storage;
+mod supervisor;
+mod telnet;
+mod template;
+mod throttle;
+mod tokens;
+mod tools;
+mod totp;
+mod training;
+mod watchdog;
+mod webhook;
+mod wizard;
 
-use anyhow::Result;
-use clap::Parser;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::llm::LlmClient;
-use crate::session::Session;
+use crate::abuse::{BanList, ConnectThrottle, LoginThrottle};
+use crate::admin::{AdminAuth, Maintenance, Registry};
+use crate::alias::Aliases;
+use crate::anthropic::AnthropicClient;
+use crate::audit::{AuditLog, Outcome};
+use crate::gemini::GeminiClient;
+use crate::geoip::GeoIp;
+use crate::config::{Config, LiveConfig, McpServer};
+use crate::digest::ErrorTally;
+use crate::endpoints::{Balance, Endpoints};
+#[cfg(feature = "tools")]
+use crate::fetch::FetchPolicy;
+use crate::handover::Handover;
+use crate::keys::KeyPool;
+use crate::kiosk::KioskLimits;
+use crate::llamacpp::LlamaCppClient;
+use crate::llm::{AzureDeployment, LlmProvider, OpenAiClient, Provider, Sampling, Steering, Tuning, Upstream};
+use crate::logger::LogFormat;
+use crate::models::ModelCatalog;
+use crate::ollama::OllamaClient;
+use crate::persona::Labels;
+use crate::redact::{Preset, Redactor};
+use crate::schedule::ActiveSchedule;
+#[cfg(feature = "tools")]
+use crate::search::SearchConfig;
+use crate::session::{Session, SessionContext};
+use crate::stats::CommandTally;
+use crate::supervisor::Supervisor;
+use crate::tools::{Builtin, Engine, ToolRegistry};
+use crate::webhook::Webhook;
 
 /// Telnet server for chatting with LLM
 #[derive(Parser, Debug)]
 #[command(name = "telllm")]
 #[command(about = "Telnet server for LLM chat with OpenAI-compatible API")]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML config file for structured settings such as signed clients
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     /// Port to listen on
     #[arg(short, long, default_value = "2323")]
     port: u16,
 
-    /// LLM API endpoint
-    #[arg(short, long, default_value = "http://localhost:8080/v1")]
-    endpoint: String,
+    /// API dialect of the endpoint
+    #[arg(long, value_enum, default_value = "openai")]
+    provider: Provider,
+
+    /// LLM API endpoint (repeat or comma-separate to fail over to the next one while one is down)
+    #[arg(short, long = "endpoint", default_value = "http://localhost:8080/v1", value_delimiter = ',')]
+    endpoints: Vec<String>,
+
+    /// Model name, `default` if unset (repeat or comma-separate to offer several that sessions switch between with /model, the first is the default)
+    #[arg(short, long = "model", value_delimiter = ',')]
+    models: Vec<String>,
+
+    /// API key (optional, repeat or comma-separate to rotate through several keys).
+    /// Shows up in `ps` and shell history, prefer TELLLM_API_KEY or --api-key-file
+    #[arg(short = 'k', long = "api-key", value_delimiter = ',')]
+    api_keys: Vec<String>,
+
+    /// File with the API keys, one per line; replaces --api-key
+    #[arg(long)]
+    api_key_file: Option<PathBuf>,
+
+    /// HTTP, HTTPS or SOCKS5 proxy for outbound requests, e.g. socks5h://127.0.0.1:1080 (HTTPS_PROXY or ALL_PROXY if unset)
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Hosts reached without the proxy, e.g. localhost,10.0.0.0/8 (comma-separated, NO_PROXY if unset)
+    #[arg(long, value_delimiter = ',')]
+    no_proxy: Vec<String>,
+
+    /// Extra header for every LLM request as "Name: value" (repeat for several)
+    #[arg(long = "llm-header")]
+    llm_headers: Vec<String>,
+
+    /// Requests per minute each API key may serve (unlimited if unset)
+    #[arg(long)]
+    key_requests_per_minute: Option<usize>,
+
+    /// Upper limit on tokens per reply (Anthropic requires one and defaults to 1024)
+    #[arg(long)]
+    max_tokens: Option<u32>,
+
+    /// Sampling temperature, 0 to 2 (backend default if unset)
+    #[arg(long)]
+    temperature: Option<f32>,
+
+    /// Nucleus sampling probability mass, 0 to 1 (backend default if unset)
+    #[arg(long)]
+    top_p: Option<f32>,
+
+    /// Penalty for tokens that already appeared, -2 to 2 (not sent to Anthropic)
+    #[arg(long, allow_hyphen_values = true)]
+    presence_penalty: Option<f32>,
+
+    /// Penalty scaled by how often tokens appeared, -2 to 2 (not sent to Anthropic)
+    #[arg(long, allow_hyphen_values = true)]
+    frequency_penalty: Option<f32>,
+
+    /// Sequence that ends the reply where the model writes it, `\n` for a line break (repeat for several; OpenAI provider only)
+    #[arg(long, allow_hyphen_values = true)]
+    stop: Vec<String>,
+
+    /// Bias for a token as "TOKEN_ID=BIAS", -100 to 100 (repeat for several; OpenAI provider only)
+    #[arg(long = "logit-bias")]
+    logit_biases: Vec<String>,
+
+    /// Built-in tools the model may call, comma-separated (OpenAI provider only)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    tools: Vec<Builtin>,
+
+    /// Let users have the AI summarize a web page with /fetch <url>
+    #[arg(long)]
+    fetch: bool,
+
+    /// Host that /fetch and the fetch tool may download from, subdomains included (repeat for several; any public host if unset)
+    #[arg(long)]
+    fetch_allow: Vec<String>,
+
+    /// Host that /fetch and the fetch tool never download from, subdomains included (repeat for several)
+    #[arg(long)]
+    fetch_deny: Vec<String>,
+
+    /// Largest page /fetch and the fetch tool download, in KB
+    #[arg(long, default_value = "512")]
+    fetch_max_kb: usize,
+
+    /// Service the search tool asks
+    #[arg(long, value_enum, default_value = "duckduckgo")]
+    search_engine: Engine,
+
+    /// Search endpoint, e.g. https://searx.example.org for SearxNG (the public API for Brave and DuckDuckGo if unset)
+    #[arg(long)]
+    search_url: Option<String>,
+
+    /// API key of the search engine, Brave's subscription token
+    #[arg(long)]
+    search_key: Option<String>,
+
+    /// Search results handed to the model and listed as sources
+    #[arg(long, default_value = "5")]
+    search_results: usize,
+
+    /// Retries for LLM requests failing with 429, 5xx or a network error, with jittered exponential backoff
+    #[arg(long, default_value = "2")]
+    llm_retries: usize,
+
+    /// How chat requests are spread over several endpoints
+    #[arg(long, value_enum, default_value = "failover")]
+    balance: Balance,
+
+    /// Weight of each endpoint for --balance, in --endpoint order (comma-separated, default 1)
+    #[arg(long, value_delimiter = ',')]
+    endpoint_weights: Vec<u32>,
 
-    /// Model name
-    #[arg(short, long, default_value = "default")]
-    model: String,
+    /// LLM failures in a row (unreachable or 5xx) after which an endpoint is skipped, 0 disables
+    #[arg(long, default_value = "5")]
+    breaker_failures: u32,
 
-    /// API key (optional)
-    #[arg(short = 'k', long, default_value = "")]
-    api_key: String,
+    /// Seconds a skipped endpoint rests before a request probes it again
+    #[arg(long, default_value = "30")]
+    breaker_cooldown: u64,
+
+    /// Azure OpenAI deployment to call instead of `/chat/completions` on the endpoint
+    #[arg(long)]
+    azure_deployment: Option<String>,
+
+    /// `api-version` sent with Azure OpenAI requests
+    #[arg(long, default_value = "2024-06-01")]
+    azure_api_version: String,
+
+    /// How long Ollama keeps the model loaded after a request, e.g. `30m` or `-1` (Ollama provider)
+    #[arg(long)]
+    keep_alive: Option<String>,
+
+    /// Minutes the backend's model list is cached for /models
+    #[arg(long, default_value = "60")]
+    models_ttl_minutes: u64,
 
     /// Custom system prompt
     #[arg(short, long, default_value = "You are a helpful AI assistant. Be concise and friendly.")]
     system_prompt: String,
 
+    /// Label shown in front of the AI's messages
+    #[arg(long, default_value = "AI")]
+    ai_label: String,
+
+    /// Label shown in front of the user's messages and on the input prompt
+    #[arg(long, default_value = "You")]
+    user_label: String,
+
     /// Logs directory
     #[arg(long, default_value = "logs")]
     logs_dir: String,
+
+    /// Chat log format
+    #[arg(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Add to every JSONL entry a hash that covers the entry before it, so edits show up with verify-log
+    #[arg(long)]
+    hash_chain: bool,
+
+    /// Minutes to ban clients caught flooding (0 disables banning)
+    #[arg(long, default_value = "10")]
+    ban_minutes: u64,
+
+    /// Connections allowed per IP per minute before new ones are refused (0 disables)
+    #[arg(long, default_value = "0")]
+    max_connects_per_minute: usize,
+
+    /// Honeypot mode: log everything typed but never call the LLM
+    #[arg(long)]
+    honeypot: bool,
+
+    /// MaxMind country (or city) database for tagging sessions
+    #[arg(long)]
+    geoip_db: Option<String>,
+
+    /// MaxMind ASN database for tagging sessions
+    #[arg(long)]
+    geoip_asn_db: Option<String>,
+
+    /// Only accept clients from these countries or ASNs (e.g. PL, AS5617)
+    #[arg(long)]
+    geo_allow: Vec<String>,
+
+    /// Reject clients from these countries or ASNs
+    #[arg(long)]
+    geo_deny: Vec<String>,
+
+    /// Maximum output bytes per second for each session (unlimited if unset)
+    #[arg(long)]
+    max_output_rate: Option<u64>,
+
+    /// Show markdown as the model wrote it instead of flattening it for plain terminals
+    #[arg(long)]
+    raw_markdown: bool,
+
+    /// Show responses as they arrive; markdown is shown as written and --max-display-lines doesn't apply
+    #[arg(long)]
+    stream: bool,
+
+    /// Don't mirror the language of the user's messages in replies
+    #[arg(long)]
+    no_auto_lang: bool,
+
+    /// Let the LLM phrase the greeting for returning users (cached)
+    #[arg(long)]
+    ai_greeting: bool,
+
+    /// Suggested conversation starter shown as a numbered menu (repeatable)
+    #[arg(long = "starter")]
+    starters: Vec<String>,
+
+    /// Minutes of silence after which the AI sends one follow-up (off if unset)
+    #[arg(long)]
+    nudge_after: Option<u64>,
+
+    /// Minutes of silence after which the conversation is summarized into the log, cleared and the screen invites a new visitor (off if unset)
+    #[arg(long)]
+    reset_after: Option<u64>,
+
+    /// Strip line noise from input, hold back lines that are mostly noise for /resend and tell the model input may be garbled
+    #[arg(long)]
+    noise_filter: bool,
+
+    /// Replace card numbers, emails, IBANs or US SSNs in user messages before logging and sending them on, comma-separated; [[redaction]] in the config adds more
+    #[arg(long, value_enum, value_delimiter = ',')]
+    redact: Vec<Preset>,
+
+    /// Lines of a response shown at once, the rest is available via /more (unlimited if unset)
+    #[arg(long)]
+    max_display_lines: Option<usize>,
+
+    /// Messages a session keeps in memory and sends to the model; older turns move to disk (unlimited if unset)
+    #[arg(long)]
+    max_history: Option<usize>,
+
+    /// Tokens a request may take, reply included; the oldest turns are left out beyond it (unlimited if unset)
+    #[arg(long)]
+    context_tokens: Option<usize>,
+
+    /// Near --context-tokens, leave the oldest turns out instead of having the model summarize them
+    #[arg(long)]
+    no_summarize: bool,
+
+    /// Also serve a terminal on this serial port, e.g. /dev/ttyUSB0
+    #[arg(long)]
+    serial: Option<String>,
+
+    /// Line speed of --serial; output is paced to it
+    #[arg(long, default_value = "9600")]
+    baud: u32,
+
+    /// Handshaking on the --serial line
+    #[arg(long, value_enum, default_value = "none")]
+    flow_control: serial::FlowControl,
+
+    /// A modem is on the --serial line: answer RING, start a session on CONNECT and end it on NO CARRIER
+    #[arg(long)]
+    modem: bool,
+
+    /// Sent to the --modem before waiting for calls
+    #[arg(long, default_value = "ATZ")]
+    modem_init: String,
+
+    /// For exhibitions: every session ends after --kiosk-minutes or --kiosk-messages and leaves nothing behind
+    #[arg(long)]
+    kiosk: bool,
+
+    /// Length of a --kiosk session
+    #[arg(long, default_value = "10")]
+    kiosk_minutes: u64,
+
+    /// Messages to the AI in a --kiosk session
+    #[arg(long, default_value = "20")]
+    kiosk_messages: usize,
+
+    /// With --kiosk on --serial: after this many idle minutes, play example answers to the --starter questions until a key is pressed
+    #[arg(long)]
+    attract_after: Option<u64>,
+
+    /// For small devices: one runtime thread, short input lines, no greeting cache and --max-history of at most 12
+    #[arg(long)]
+    low_memory: bool,
+
+    /// Disk space per client directory in MB; oldest chat logs are removed beyond it
+    #[arg(long)]
+    client_quota_mb: Option<u64>,
+
+    /// Suspend chat logging while the logs volume has less free space (MB, 0 disables)
+    #[arg(long, default_value = "100")]
+    min_free_mb: u64,
+
+    /// Webhook URL for operator alerts
+    #[arg(long)]
+    webhook: Option<String>,
+
+    /// Post a weekly digest to the webhook on this day at 09:00, e.g. `mon`
+    #[arg(long, requires = "webhook")]
+    digest_day: Option<chrono::Weekday>,
+
+    /// Port for the line-based admin console (disabled if unset)
+    #[arg(long)]
+    admin_port: Option<u16>,
+
+    /// Address the admin console binds to
+    #[arg(long, default_value = "127.0.0.1")]
+    admin_bind: IpAddr,
+
+    /// Password for the admin console, required unless it only listens on loopback.
+    /// Deprecated as it shows up in `ps`, use TELLLM_ADMIN_PASSWORD or --admin-password-file
+    #[arg(long)]
+    admin_password: Option<String>,
+
+    /// File whose first line is the admin console password; replaces --admin-password
+    #[arg(long)]
+    admin_password_file: Option<PathBuf>,
+
+    /// Port for the OpenAI-compatible HTTP endpoint on --admin-bind (disabled if unset)
+    #[arg(long)]
+    api_port: Option<u16>,
+
+    /// Also log every admin action to the console output
+    #[arg(long)]
+    announce_admin: bool,
+
+    /// Ask visitors from new addresses a simple question before their first LLM call
+    #[arg(long)]
+    challenge: bool,
+
+    /// Only serve clients whose first line is an HMAC token signed with a key from the config
+    #[arg(long)]
+    hmac_auth: bool,
+
+    /// Minutes a conversation interrupted by a shutdown can be restored on reconnect (0 disables)
+    #[arg(long, default_value = "10")]
+    restore_minutes: u64,
+
+    /// Start in maintenance mode, turn it off from the admin console
+    #[arg(long)]
+    maintenance: bool,
+
+    /// Shown to new connections during maintenance before they are disconnected
+    #[arg(long, default_value = "telllm is down for maintenance, please come back later.")]
+    maintenance_banner: String,
+
+    /// Minutes connected sessions keep after the admin console turns maintenance on
+    #[arg(long, default_value = "5")]
+    maintenance_grace: u64,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Queue an OpenAI-format JSON conversation for a client's next session
+    Import {
+        /// Client IP the conversation is loaded for
+        #[arg(long)]
+        client: IpAddr,
+
+        /// File with a `[{role, content}, ...]` messages array
+        file: PathBuf,
+    },
+    /// Show disk usage per client, an hour-by-weekday activity heatmap and slash command use
+    Stats {
+        /// Print daily rollups as JSON with noise, buckets and no identifiers, safe to publish
+        #[arg(long)]
+        anonymized: bool,
+
+        /// Days the activity heatmap and command counts cover, today included
+        #[arg(long, default_value = "28")]
+        activity_days: u32,
+
+        /// Privacy budget per published number, smaller means noisier
+        #[arg(long, default_value = "1.0")]
+        epsilon: f64,
+    },
+    /// Generate the admin console's TOTP secret and print it as an otpauth URI and QR code
+    AdminTotp,
+    /// Look through the chat logs
+    Logs {
+        #[command(subcommand)]
+        action: LogsAction,
+    },
+    /// Print a fresh AUTH line for a signed client from the config
+    AuthToken {
+        /// Client id as configured in a [[client]] table
+        client: String,
+    },
+    /// Run a scripted session against a mock backend on an ephemeral port and report pass/fail
+    Selftest,
+    /// Write prompt/response pairs from clients who opted in with /contribute as
+    /// instruction-tuning JSONL, redacted, with a manifest of the sessions used
+    ExportTraining {
+        /// JSONL file to write, the manifest goes next to it as <name>.manifest.json
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Check the hashes of a chat log written with --hash-chain
+    VerifyLog {
+        /// JSONL chat log, e.g. logs/203.0.113.7/chats/30-01-26.jsonl
+        file: PathBuf,
+    },
+    /// Work with the config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Check a config file and point at the first problem, with a suggestion for misspelt names
+    Validate {
+        /// Config file to check, --config if not given
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum LogsAction {
+    /// Find messages containing every given word, best matches first
+    Search {
+        /// Words to look for, case-insensitive
+        #[arg(required = true)]
+        words: Vec<String>,
+
+        /// Only chat logs from this day on, YYYY-MM-DD
+        #[arg(long)]
+        since: Option<chrono::NaiveDate>,
+
+        /// Only chat logs up to and including this day, YYYY-MM-DD
+        #[arg(long)]
+        until: Option<chrono::NaiveDate>,
+
+        /// Only this client directory and the named users under it, e.g. 203.0.113.7 or accounts/alice
+        #[arg(long)]
+        client: Option<String>,
+
+        /// Matches to show at most
+        #[arg(long, default_value = "20")]
+        limit: usize,
+    },
+}
+
+fn import_conversation(logs_dir: &str, client: IpAddr, file: &Path) -> Result<()> {
+    let json = fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let messages = interop::parse_messages(&json)?;
+
+    let path = logger::pending_import_path(logs_dir, client);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("Failed to create client directory")?;
+    }
+    fs::write(&path, json).context("Failed to queue import")?;
+
+    println!(
+        "Queued {} messages for {}, they load on the next connection",
+        messages.len(),
+        client
+    );
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+async fn start_api(
+    addr: SocketAddr,
+    ctx: &Arc<SessionContext>,
+    admin_password: Option<String>,
+) -> Result<()> {
+    let listener = ctx
+        .handover
+        .bind(addr)
+        .await
+        .context("Failed to start the HTTP API")?;
+    info!("OpenAI-compatible API on http://{}/v1/chat/completions", addr);
+    tokio::spawn(api::serve(listener, Arc::clone(ctx), admin_password));
+    Ok(())
+}
+
+#[cfg(not(feature = "http"))]
+async fn start_api(
+    _addr: SocketAddr,
+    _ctx: &Arc<SessionContext>,
+    _admin_password: Option<String>,
+) -> Result<()> {
+    features::require("http", "--api-port")
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// The `--endpoint` values with their weights and circuit breakers
+fn build_endpoints(args: &Args) -> Endpoints {
+    Endpoints::new(
+        args.endpoints.clone(),
+        &args.endpoint_weights,
+        args.balance,
+        args.breaker_failures,
+        Duration::from_secs(args.breaker_cooldown),
+    )
+}
+
+/// The client for `--provider` on `endpoints`. `keys` is shared by every client
+/// so a key's budget and rest hold whichever alias uses it.
+fn build_provider(args: &Args, config: &Config, endpoints: Endpoints, keys: Arc<KeyPool>) -> Box<dyn LlmProvider> {
+    let tuning = tuning(args);
+    let model = args.models.first().cloned().unwrap_or_else(|| "default".to_string());
+    // Checked at startup
+    let headers = llm::extra_headers(&config.llm_headers, &args.llm_headers).unwrap_or_default();
+    let upstream = Upstream::new(endpoints, args.llm_retries, headers);
+    match args.provider {
+        Provider::Openai => Box::new(OpenAiClient::new(
+            upstream,
+            model.clone(),
+            keys,
+            config.models.clone(),
+            tuning,
+            // Checked at startup
+            steering(args).unwrap_or_default(),
+            args.azure_deployment.clone().map(|name| AzureDeployment {
+                name,
+                api_version: args.azure_api_version.clone(),
+            }),
+        )),
+        Provider::Anthropic => Box::new(AnthropicClient::new(
+            upstream,
+            model.clone(),
+            keys,
+            tuning,
+        )),
+        Provider::Ollama => Box::new(OllamaClient::new(
+            upstream,
+            model.clone(),
+            keys,
+            config.models.clone(),
+            tuning,
+            args.keep_alive.clone(),
+        )),
+        Provider::Gemini => Box::new(GeminiClient::new(
+            upstream,
+            model.clone(),
+            keys,
+            config.models.clone(),
+            tuning,
+        )),
+        Provider::LlamaCpp => Box::new(LlamaCppClient::new(
+            upstream,
+            model.clone(),
+            keys,
+            tuning,
+            config.chat_template.clone(),
+        )),
+    }
+}
+
+/// Models the operator named with `--model`, `[[model]]` or `[[alias]]`, in that order
+fn configured_models(args: &Args, config: &Config) -> Vec<String> {
+    let mut models: Vec<String> = Vec::new();
+    let named = args
+        .models
+        .iter()
+        .chain(config.models.iter().map(|m| &m.name))
+        .chain(config.aliases.iter().map(|a| &a.name));
+    for name in named {
+        if !models.contains(name) {
+            models.push(name.clone());
+        }
+    }
+    models
+}
+
+/// Messages a session keeps in memory with --low-memory
+const LOW_MEMORY_HISTORY: usize = 12;
+
+/// `--max-history`, capped with --low-memory
+fn max_history(args: &Args) -> Option<usize> {
+    let max_history = args.max_history.filter(|n| *n > 0);
+    if !args.low_memory {
+        return max_history;
+    }
+    Some(max_history.map_or(LOW_MEMORY_HISTORY, |n| n.min(LOW_MEMORY_HISTORY)))
+}
+
+fn tuning(args: &Args) -> Tuning {
+    Tuning {
+        max_tokens: args.max_tokens,
+        sampling: Sampling {
+            temperature: args.temperature,
+            top_p: args.top_p,
+            presence_penalty: args.presence_penalty,
+            frequency_penalty: args.frequency_penalty,
+        },
+        json: false,
+    }
+}
+
+#[cfg(feature = "tools")]
+fn fetch_policy(args: &Args) -> FetchPolicy {
+    FetchPolicy {
+        allow: args.fetch_allow.clone(),
+        deny: args.fetch_deny.clone(),
+        max_bytes: args.fetch_max_kb.max(1) * 1024,
+    }
+}
+
+#[cfg(feature = "tools")]
+fn search_config(args: &Args) -> Result<SearchConfig> {
+    SearchConfig::new(
+        args.search_engine,
+        args.search_url.as_deref(),
+        args.search_key.clone(),
+        args.search_results,
+    )
+}
+
+fn steering(args: &Args) -> Result<Steering> {
+    let stop = args.stop.iter().map(|s| s.replace("\\n", "\n")).collect();
+    Steering::new(stop, &args.logit_biases)
+}
+
+/// Each `[[alias]]` with a client of its own if it names an endpoint
+fn build_aliases(args: &Args, config: &Config, keys: &Arc<KeyPool>) -> Aliases {
+    let cooldown = Duration::from_secs(args.breaker_cooldown);
+    Aliases::new(
+        config
+            .aliases
+            .iter()
+            .map(|alias| {
+                let llm = alias.endpoint.as_ref().map(|endpoint| {
+                    let endpoints = Endpoints::new(
+                        vec![endpoint.clone()],
+                        &[],
+                        Balance::Failover,
+                        args.breaker_failures,
+                        cooldown,
+                    );
+                    build_provider(args, config, endpoints, Arc::clone(keys))
+                });
+                (alias.clone(), llm)
+            })
+            .collect(),
+    )
+}
+
+/// Shared state for every listener, built from the command line
+fn build_context(
+    args: &Args,
+    config: Config,
+    logs_dir: String,
+    client_quota: Option<u64>,
+    llm: Box<dyn LlmProvider>,
+    keys: &Arc<KeyPool>,
+    redactor: Redactor,
+) -> SessionContext {
+    SessionContext {
+        llm,
+        redactor,
+        aliases: build_aliases(args, &config, keys),
+        models: ModelCatalog::new(
+            Duration::from_secs(args.models_ttl_minutes * 60),
+            configured_models(args, &config),
+        ),
+        system_prompt: args.system_prompt.clone(),
+        logs_dir,
+        log_format: args.log_format,
+        bans: BanList::new(Duration::from_secs(args.ban_minutes * 60)),
+        logins: LoginThrottle::default(),
+        honeypot: args.honeypot,
+        max_output_rate: args.max_output_rate,
+        render_markdown: !args.raw_markdown,
+        stream: args.stream,
+        tuning: tuning(args),
+        commands: CommandTally::default(),
+        auto_lang: !args.no_auto_lang,
+        ai_greeting: args.ai_greeting,
+        greeting_cache: (!args.low_memory).then(|| Mutex::new(HashMap::new())),
+        starters: args.starters.clone(),
+        nudge_after: args
+            .nudge_after
+            .filter(|m| *m > 0)
+            .map(|m| Duration::from_secs(m * 60)),
+        reset_after: args
+            .reset_after
+            .filter(|m| *m > 0)
+            .map(|m| Duration::from_secs(m * 60)),
+        #[cfg(feature = "tools")]
+        tools: ToolRegistry::with_builtins(&args.tools).with_web(
+            &args.tools,
+            &fetch_policy(args),
+            search_config(args).ok().as_ref(),
+        ),
+        #[cfg(not(feature = "tools"))]
+        tools: ToolRegistry::with_builtins(&args.tools),
+        #[cfg(feature = "tools")]
+        fetch: args.fetch.then(|| fetch_policy(args)),
+        noise_filter: args.noise_filter,
+        max_display_lines: args.max_display_lines.filter(|n| *n > 0),
+        max_history: max_history(args),
+        max_line_bytes: if args.low_memory {
+            protocol::LOW_MEMORY_LINE_BYTES
+        } else {
+            protocol::MAX_LINE_BYTES
+        },
+        context_tokens: args.context_tokens,
+        summarize: !args.no_summarize,
+        kiosk: args.kiosk.then_some(KioskLimits {
+            duration: Duration::from_secs(args.kiosk_minutes * 60),
+            messages: args.kiosk_messages,
+        }),
+        client_quota,
+        webhook: args.webhook.clone().map(Webhook::new),
+        errors: ErrorTally::default(),
+        supervisor: Supervisor::default(),
+        audit: AuditLog::new(&args.logs_dir),
+        sessions: Registry::default(),
+        announce_admin: args.announce_admin,
+        challenge: args.challenge,
+        profiles: config.models.clone(),
+        config: LiveConfig::new(config, args.config.clone()),
+        hmac_auth: args.hmac_auth,
+        schedule: ActiveSchedule::default(),
+        maintenance: Maintenance::new(args.maintenance_banner.clone(), args.maintenance),
+        handover: Handover::from_env(),
+        restore_window: (args.restore_minutes > 0).then(|| Duration::from_secs(args.restore_minutes * 60)),
+        labels: Labels {
+            ai: args.ai_label.clone(),
+            user: args.user_label.clone(),
+            ai_color: None,
+            user_color: None,
+        },
+    }
+}
+
+/// Serve a scratch logs directory from a mock backend and walk a client through it.
+/// The directory is kept when something fails.
+#[cfg(feature = "http")]
+async fn run_selftest(args: &Args) -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("telllm-selftest-{}", std::process::id()));
+    let logs_dir = dir.to_string_lossy().into_owned();
+
+    let backend = selftest::mock_backend().await?;
+    let endpoints = Endpoints::new(vec![format!("http://{}/v1", backend)], &[], Balance::Failover, 0, Duration::ZERO);
+    let keys = Arc::new(KeyPool::new(Vec::new(), None));
+    let llm = Box::new(OpenAiClient::new(
+        Upstream::new(endpoints, args.llm_retries, reqwest::header::HeaderMap::new()),
+        "selftest".to_string(),
+        Arc::clone(&keys),
+        Vec::new(),
+        Tuning::default(),
+        Steering::default(),
+        None,
+    ));
+    // Only what the script can walk through, whatever the flags say
+    let ctx = Arc::new(SessionContext {
+        audit: AuditLog::new(&logs_dir),
+        honeypot: false,
+        stream: true,
+        tuning: Tuning::default(),
+        ai_greeting: false,
+        starters: Vec::new(),
+        nudge_after: None,
+        reset_after: None,
+        tools: ToolRegistry::default(),
+        #[cfg(feature = "tools")]
+        fetch: None,
+        noise_filter: false,
+        max_display_lines: None,
+        webhook: None,
+        challenge: false,
+        hmac_auth: false,
+        maintenance: Maintenance::new(String::new(), false),
+        restore_window: None,
+        ..build_context(args, Config::default(), logs_dir, None, llm, &keys, Redactor::default())
+    });
+
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+    let addr = listener.local_addr()?;
+    let throttle = Arc::new(ConnectThrottle::new(0));
+    tokio::spawn(accept_loop(listener, Arc::clone(&ctx), throttle, None, None));
+
+    let failed = selftest::run(addr, &ctx).await.print();
+    if failed > 0 {
+        anyhow::bail!("{} selftest steps failed, logs kept in {}", failed, dir.display());
+    }
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        warn!("Failed to remove {}: {}", dir.display(), e);
+    }
+    println!("All selftest steps passed");
+    Ok(())
+}
+
+/// The mock backend is served with axum
+#[cfg(not(feature = "http"))]
+async fn run_selftest(_args: &Args) -> Result<()> {
+    features::require("http", "selftest")
+}
+
+/// Connect to the `[[mcp]]` servers and offer their tools with the built-in ones
+#[cfg(feature = "tools")]
+async fn start_mcp(servers: &[McpServer], tools: &mut ToolRegistry) -> Result<()> {
+    mcp::register(servers, tools).await;
+    Ok(())
+}
+
+#[cfg(not(feature = "tools"))]
+async fn start_mcp(servers: &[McpServer], _tools: &mut ToolRegistry) -> Result<()> {
+    if servers.is_empty() {
+        return Ok(());
+    }
+    features::require("tools", "[[mcp]]")
+}
+
+fn main() -> Result<()> {
     // Initialize logging
+    // Logs go to stderr, stdout is for what subcommands such as `stats` print
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .with(tracing_subscriber::EnvFilter::from_default_env().add_directive("telllm=info".parse()?))
         .init();
 
-    let args = Args::parse();
+    let mut args = Args::parse_from(env::args_with_overrides(&Args::command())?);
+    if let Some(path) = &args.api_key_file {
+        if !args.api_keys.is_empty() {
+            warn!("Using the keys from {}, ignoring --api-key", path.display());
+        }
+        args.api_keys = keys::read_key_file(path)?;
+    } else if !args.api_keys.is_empty() && std::env::var_os("TELLLM_API_KEY").is_none() {
+        warn!("--api-key is visible to anyone who can list processes, prefer TELLLM_API_KEY or --api-key-file");
+    }
+    if let Some(path) = &args.admin_password_file {
+        if args.admin_password.is_some() {
+            warn!("Using the password from {}, ignoring --admin-password", path.display());
+        }
+        args.admin_password = Some(admin::read_password_file(path)?);
+    } else if args.admin_password.is_some() && std::env::var_os("TELLLM_ADMIN_PASSWORD").is_none() {
+        warn!(
+            "--admin-password is deprecated, anyone who can list processes sees it; use TELLLM_ADMIN_PASSWORD or --admin-password-file"
+        );
+    }
+
+    proxy::configure(args.proxy.as_deref(), &args.no_proxy)?;
+
+    // A thread per core costs a stack each, one is plenty for a single terminal
+    let mut runtime = if args.low_memory {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    };
+    runtime.enable_all().build()?.block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<()> {
+    let client_quota = args
+        .client_quota_mb
+        .filter(|mb| *mb > 0)
+        .map(|mb| mb * 1024 * 1024);
+
+    if let Some(Command::Config { action: ConfigAction::Validate { file } }) = &args.command {
+        let path = file
+            .as_ref()
+            .or(args.config.as_ref())
+            .context("No config file to validate, pass one or --config")?;
+        return config::validate(path);
+    }
+    let config = Config::load(args.config.as_deref())?;
+    tuning(&args).validate()?;
+    steering(&args)?;
+    if (!args.stop.is_empty() || !args.logit_biases.is_empty()) && args.provider != Provider::Openai {
+        anyhow::bail!("--stop and --logit-bias need --provider openai");
+    }
+    if !args.tools.is_empty() && args.provider != Provider::Openai {
+        anyhow::bail!("--tools needs --provider openai");
+    }
+    if !config.mcp_servers.is_empty() && args.provider != Provider::Openai {
+        anyhow::bail!("[[mcp]] servers need --provider openai");
+    }
+    if args.fetch {
+        features::require("tools", "--fetch")?;
+    }
+    if let Some(tool) = args.tools.iter().find(|t| matches!(t, Builtin::Fetch | Builtin::Search)) {
+        features::require("tools", &format!("--tools {:?}", tool).to_lowercase())?;
+    }
+    #[cfg(feature = "tools")]
+    if args.tools.contains(&Builtin::Search) {
+        search_config(&args)?;
+    }
+    if args.hash_chain && args.log_format != LogFormat::Jsonl {
+        anyhow::bail!("--hash-chain needs --log-format jsonl");
+    }
+    logger::set_hash_chain(args.hash_chain);
+    if args.kiosk && (args.kiosk_minutes == 0 || args.kiosk_messages == 0) {
+        anyhow::bail!("--kiosk-minutes and --kiosk-messages must be at least 1");
+    }
+    if args.attract_after.is_some() {
+        if !args.kiosk || args.serial.is_none() || args.modem {
+            anyhow::bail!("--attract-after needs --kiosk and a --serial terminal without --modem");
+        }
+        if args.attract_after == Some(0) || args.starters.is_empty() {
+            anyhow::bail!("--attract-after needs at least 1 minute and --starter questions to show");
+        }
+    }
+    let headers = llm::extra_headers(&config.llm_headers, &args.llm_headers)?;
+    if !headers.is_empty() {
+        let names: Vec<&str> = headers.keys().map(|name| name.as_str()).collect();
+        info!("Extra headers for LLM requests: {}", names.join(", "));
+    }
+    if args.endpoint_weights.len() > args.endpoints.len() {
+        anyhow::bail!(
+            "--endpoint-weights has {} weights for {} endpoints",
+            args.endpoint_weights.len(),
+            args.endpoints.len()
+        );
+    }
+    if args.endpoint_weights.contains(&0) {
+        anyhow::bail!("--endpoint-weights must be at least 1");
+    }
+    // Rules nothing can match would silently let everyone in, or keep everyone out
+    for rule in args.geo_allow.iter().chain(&args.geo_deny) {
+        let asn = rule.to_uppercase().strip_prefix("AS").is_some_and(|n| n.parse::<u32>().is_ok());
+        match (asn, &args.geoip_db, &args.geoip_asn_db) {
+            (true, _, None) => anyhow::bail!("--geo-allow/--geo-deny {} needs --geoip-asn-db", rule),
+            (false, None, _) => anyhow::bail!("--geo-allow/--geo-deny {} needs --geoip-db", rule),
+            _ => {}
+        }
+    }
+    if args.provider == Provider::Anthropic
+        && (args.presence_penalty.is_some() || args.frequency_penalty.is_some())
+    {
+        warn!("Anthropic has no presence or frequency penalty, ignoring them");
+    }
+
+    match &args.command {
+        Some(Command::Import { client, file }) => {
+            return import_conversation(&args.logs_dir, *client, file);
+        }
+        Some(Command::Stats { anonymized: true, epsilon, .. }) => {
+            return stats::print_anonymized(&args.logs_dir, *epsilon);
+        }
+        Some(Command::Stats { activity_days, .. }) => {
+            return stats::print_usage(&args.logs_dir, client_quota, (*activity_days).max(1));
+        }
+        Some(Command::AdminTotp) => return totp::provision(&args.logs_dir),
+        Some(Command::Logs {
+            action: LogsAction::Search { words, since, until, client, limit },
+        }) => {
+            let query = logsearch::Query::new(&words.join(" "), *since, *until, client.clone());
+            return logsearch::print_search(&args.logs_dir, &query, *limit);
+        }
+        Some(Command::AuthToken { client }) => return signing::print_token(&config, client),
+        Some(Command::Selftest) => return run_selftest(&args).await,
+        Some(Command::VerifyLog { file }) => return hashchain::verify(file),
+        Some(Command::ExportTraining { out }) => {
+            // The export leaves the server, so every preset applies unless --redact picks some
+            let presets = if args.redact.is_empty() {
+                Preset::value_variants().to_vec()
+            } else {
+                args.redact.clone()
+            };
+            let redactor = Redactor::new(&presets, &config.redactions)?;
+            return training::export(&args.logs_dir, out, &redactor);
+        }
+        Some(Command::Config { .. }) | None => {}
+    }
 
     info!("Starting telllm server on port {}", args.port);
-    info!("LLM endpoint: {}", args.endpoint);
-    info!("Model: {}", args.model);
+    let left_out = features::names(false);
+    if !left_out.is_empty() {
+        info!("Built without: {}", left_out.join(", "));
+    }
+    info!("LLM endpoints: {}", args.endpoints.join(", "));
+    if !args.models.is_empty() {
+        info!("Models: {}", args.models.join(", "));
+    }
     info!("Logs directory: {}", args.logs_dir);
+    if args.honeypot {
+        info!("Honeypot mode enabled, LLM will not be called");
+    }
 
-    let llm_client = Arc::new(LlmClient::new(
-        args.endpoint.clone(),
-        args.model.clone(),
-        args.api_key.clone(),
-    ));
+    let geoip = if args.geoip_db.is_some() || args.geoip_asn_db.is_some() {
+        features::require("geoip", "--geoip-db")?;
+        Some(GeoIp::open(
+            args.geoip_db.as_deref(),
+            args.geoip_asn_db.as_deref(),
+            args.geo_allow.clone(),
+            args.geo_deny.clone(),
+        )?)
+    } else {
+        None
+    };
+
+    // Keep honeypot traffic apart from real conversations
+    let logs_dir = if args.honeypot {
+        Path::new(&args.logs_dir).join("honeypot").to_string_lossy().into_owned()
+    } else {
+        args.logs_dir.clone()
+    };
+
+    if args.hmac_auth && config.clients.is_empty() {
+        anyhow::bail!("--hmac-auth needs at least one [[client]] in the --config file");
+    }
+
+    let migrated = profile::migrate_all(Path::new(&logs_dir))?;
+    if migrated > 0 {
+        info!("Migrated {} client summaries to profile.json", migrated);
+    }
+
+    let keys = Arc::new(KeyPool::new(args.api_keys.clone(), args.key_requests_per_minute));
+    let llm = build_provider(&args, &config, build_endpoints(&args), Arc::clone(&keys));
+    let redactor = Redactor::new(&args.redact, &config.redactions)?;
+    let mcp_servers = config.mcp_servers.clone();
+    let mut ctx = build_context(&args, config, logs_dir, client_quota, llm, &keys, redactor);
+    start_mcp(&mcp_servers, &mut ctx.tools).await?;
+    let ctx = Arc::new(ctx);
+
+    // Warm the model list so /models answers without waiting
+    let warm = Arc::clone(&ctx);
+    tokio::spawn(async move {
+        match warm.models.list(warm.llm.as_ref(), true).await {
+            Ok(models) => info!("Backend offers {} models", models.len()),
+            Err(e) => warn!("Couldn't list the backend's models: {}", e),
+        }
+    });
 
-    let system_prompt = Arc::new(args.system_prompt.clone());
-    let logs_dir = Arc::new(args.logs_dir.clone());
+    // Always running, `reload` may bring in schedules
+    schedule::refresh(&ctx);
+    tokio::spawn(schedule::run(Arc::clone(&ctx)));
+
+    if let Some(day) = args.digest_day {
+        tokio::spawn(digest::run(Arc::clone(&ctx), day));
+    }
+
+    if args.min_free_mb > 0 {
+        tokio::spawn(watchdog::run(Arc::clone(&ctx), args.min_free_mb * 1024 * 1024));
+    }
+    tokio::spawn(stats::flush_commands(Arc::clone(&ctx)));
+
+    let throttle = Arc::new(ConnectThrottle::new(args.max_connects_per_minute));
+
+    if let Some(port) = args.admin_port {
+        if !args.admin_bind.is_loopback() && args.admin_password.is_none() {
+            anyhow::bail!(
+                "TELLLM_ADMIN_PASSWORD or --admin-password-file is required when the admin console listens beyond localhost"
+            );
+        }
+        let auth = AdminAuth {
+            password: args.admin_password.clone(),
+            totp: totp::load_secret(&args.logs_dir)?,
+        };
+        if !args.admin_bind.is_loopback() && auth.totp.is_none() {
+            warn!("Admin console is reachable from the network with a password only, run `telllm admin-totp` to add a second factor");
+        }
+        let admin_addr = SocketAddr::new(args.admin_bind, port);
+        let admin_listener = ctx
+            .handover
+            .bind(admin_addr)
+            .await
+            .context("Failed to start the admin console")?;
+        info!("Admin console on {}", admin_addr);
+        let grace = Duration::from_secs(args.maintenance_grace * 60);
+        tokio::spawn(admin::serve(admin_listener, Arc::clone(&ctx), auth, grace, Arc::clone(&throttle)));
+    }
+
+    if let Some(port) = args.api_port {
+        start_api(SocketAddr::new(args.admin_bind, port), &ctx, args.admin_password.clone()).await?;
+    }
+
+    if let Some(path) = &args.serial {
+        let modem = args.modem.then(|| args.modem_init.clone());
+        let attract = args.attract_after.map(|minutes| Duration::from_secs(minutes * 60));
+        serial::start(path.clone(), args.baud, args.flow_control, modem, attract, &ctx)?;
+    } else if args.modem {
+        anyhow::bail!("--modem needs --serial for the port the modem is on");
+    }
+
+    let geoip = geoip.map(Arc::new);
+
+    // Personas with their own port get a listener each, sharing bans and throttling
+    for persona in &ctx.config.get().personas {
+        let Some(port) = persona.port else {
+            continue;
+        };
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let listener = ctx
+            .handover
+            .bind(addr)
+            .await
+            .with_context(|| format!("Failed to start persona {}", persona.name))?;
+        info!("Persona {} listening on {}", persona.name, addr);
+        tokio::spawn(accept_loop(
+            listener,
+            Arc::clone(&ctx),
+            Arc::clone(&throttle),
+            geoip.clone(),
+            Some(persona.name.clone()),
+        ));
+    }
 
     let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
-    let listener = TcpListener::bind(addr).await?;
+    let listener = ctx.handover.bind(addr).await?;
 
     info!("Listening on {}", addr);
 
+    #[cfg(unix)]
+    tokio::spawn(handover::listen_for_signal(Arc::clone(&ctx)));
+
+    tokio::select! {
+        _ = async {
+            accept_loop(listener, Arc::clone(&ctx), throttle, geoip, None).await;
+            // The listeners belong to a newer process now, finish what is running here
+            handover::drain(&ctx).await;
+        } => {}
+        _ = snapshot::shutdown_signal() => snapshot::shutdown(&ctx).await,
+    }
+    if let Err(e) = ctx.commands.flush(&ctx.logs_dir) {
+        warn!("Failed to save command counts: {:#}", e);
+    }
+    Ok(())
+}
+
+/// Screen and serve connections on one listener, starting sessions with `persona` if set,
+/// until the listeners are handed over
+async fn accept_loop(
+    listener: TcpListener,
+    ctx: Arc<SessionContext>,
+    throttle: Arc<ConnectThrottle>,
+    geoip: Option<Arc<GeoIp>>,
+    persona: Option<String>,
+) {
+    let mut stopped = ctx.handover.stopped();
     loop {
-        match listener.accept().await {
-            Ok((stream, addr)) => {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = stopped.changed() => break,
+        };
+        match accepted {
+            Ok((mut stream, addr)) => {
+                if ctx.bans.is_banned(addr.ip()) {
+                    info!("Rejected banned client {}", addr);
+                    ctx.audit.record(addr.ip(), Outcome::Banned, None);
+                    let _ = stream.write_all(b"You are temporarily banned.\r\n").await;
+                    continue;
+                }
+
+                if let Some(banner) = ctx.maintenance.banner() {
+                    info!("Turned away {} during maintenance", addr);
+                    ctx.audit.record(addr.ip(), Outcome::Maintenance, None);
+                    let _ = stream.write_all(format!("{}\r\n", banner).as_bytes()).await;
+                    continue;
+                }
+
+                // Refuse reconnect storms before any banner, lookup or log write
+                if !throttle.allow(addr.ip()) {
+                    info!("Throttled reconnecting client {}", addr);
+                    ctx.audit.record(addr.ip(), Outcome::RateLimited, None);
+                    let _ = stream.write_all(b"Too many connections, slow down.\r\n").await;
+                    continue;
+                }
+
+                let geo = geoip.as_ref().map(|g| g.lookup(addr.ip()));
+                if let (Some(geoip), Some(tag)) = (&geoip, &geo)
+                    && !geoip.is_allowed(tag)
+                {
+                    info!("Rejected {} by geo rules ({})", addr, tag.describe());
+                    ctx.audit.record(addr.ip(), Outcome::GeoDenied, Some(&tag.describe()));
+                    let _ = stream.write_all(b"Service not available in your region.\r\n").await;
+                    continue;
+                }
+
                 info!("New connection from {}", addr);
+                let outcome = if ctx.honeypot { Outcome::Honeypot } else { Outcome::Served };
+                ctx.audit.record(addr.ip(), outcome, None);
                 
-                let llm = Arc::clone(&llm_client);
-                let prompt = Arc::clone(&system_prompt);
-                let logs = Arc::clone(&logs_dir);
-                
-                tokio::spawn(async move {
-                    let mut session = Session::new(stream, addr, llm, prompt, logs);
-                    if let Err(e) = session.run().await {
-                        error!("Session error for {}: {}", addr, e);
-                    }
-                    info!("Connection closed: {}", addr);
-                });
+                let ctx = Arc::clone(&ctx);
+                let persona = persona.clone();
+
+                let session = Session::new(stream, addr, Arc::clone(&ctx), geo, persona);
+                tokio::spawn(supervisor::supervise(session, addr, ctx));
             }
             Err(e) => {
                 error!("Failed to accept connection: {}", e);
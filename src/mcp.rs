@@ -0,0 +1,307 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::config::McpServer;
+use crate::proxy;
+use crate::tools::{Handler, ToolRegistry};
+
+/// MCP revision the client speaks, servers answer with the one they picked
+const PROTOCOL_VERSION: &str = "2025-03-26";
+/// How long a server may take for one request, tool calls included
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// Pages of `tools/list` read before giving up on a server that keeps paging
+const MAX_PAGES: usize = 20;
+
+/// The child process of a stdio server, one request at a time
+struct StdioTransport {
+    /// Killed with the transport
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+enum Transport {
+    Stdio(Box<Mutex<StdioTransport>>),
+    Http {
+        url: String,
+        headers: BTreeMap<String, String>,
+        /// `Mcp-Session-Id` the server handed out at initialization
+        session: Mutex<Option<String>>,
+    },
+}
+
+/// A connection to one MCP server, speaking JSON-RPC 2.0
+pub struct McpClient {
+    name: String,
+    transport: Transport,
+    next_id: AtomicU64,
+}
+
+impl McpClient {
+    /// Start or reach the server and go through the initialization handshake
+    pub async fn connect(server: &McpServer) -> Result<Self> {
+        let transport = match (&server.command, &server.url) {
+            (Some(command), _) => {
+                let mut child = Command::new(command)
+                    .args(&server.args)
+                    .envs(&server.env)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .with_context(|| format!("Failed to start {}", command))?;
+                let stdin = child.stdin.take().context("No stdin")?;
+                let stdout = BufReader::new(child.stdout.take().context("No stdout")?).lines();
+                if let Some(stderr) = child.stderr.take() {
+                    let name = server.name.clone();
+                    tokio::spawn(async move {
+                        let mut lines = BufReader::new(stderr).lines();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            debug!("MCP {}: {}", name, line);
+                        }
+                    });
+                }
+                Transport::Stdio(Box::new(Mutex::new(StdioTransport {
+                    _child: child,
+                    stdin,
+                    stdout,
+                })))
+            }
+            (None, Some(url)) => Transport::Http {
+                url: url.clone(),
+                headers: server.headers.clone(),
+                session: Mutex::new(None),
+            },
+            (None, None) => anyhow::bail!("MCP server {} has neither command nor url", server.name),
+        };
+        let client = Self {
+            name: server.name.clone(),
+            transport,
+            next_id: AtomicU64::new(1),
+        };
+
+        let init = client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": "telllm", "version": env!("CARGO_PKG_VERSION") },
+                }),
+            )
+            .await?;
+        client.notify("notifications/initialized").await?;
+        info!(
+            "MCP server {} is {} {}, protocol {}",
+            client.name,
+            init["serverInfo"]["name"].as_str().unwrap_or("unnamed"),
+            init["serverInfo"]["version"].as_str().unwrap_or(""),
+            init["protocolVersion"].as_str().unwrap_or("unknown")
+        );
+        Ok(client)
+    }
+
+    /// Every tool the server offers, following `nextCursor`
+    async fn list_tools(&self) -> Result<Vec<Value>> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+        for _ in 0..MAX_PAGES {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let page = self.request("tools/list", params).await?;
+            tools.extend(page["tools"].as_array().cloned().unwrap_or_default());
+            match page["nextCursor"].as_str() {
+                Some(next) if !next.is_empty() => cursor = Some(next.to_string()),
+                _ => break,
+            }
+        }
+        Ok(tools)
+    }
+
+    /// Text of a `tools/call` result; results the server marks as errors fail
+    async fn call_tool(&self, name: &str, arguments: &Value) -> Result<String> {
+        let result = self
+            .request("tools/call", json!({ "name": name, "arguments": arguments }))
+            .await?;
+        let text: Vec<String> = result["content"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|item| match item["type"].as_str() {
+                Some("text") => item["text"].as_str().unwrap_or_default().to_string(),
+                Some("resource") => item["resource"]["text"]
+                    .as_str()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("[resource {}]", item["resource"]["uri"].as_str().unwrap_or("?"))),
+                Some(kind) => format!("[{} content]", kind),
+                None => String::new(),
+            })
+            .collect();
+        let text = text.join("\n");
+        if result["isError"].as_bool().unwrap_or(false) {
+            anyhow::bail!("{}", if text.is_empty() { "the tool failed" } else { text.as_str() });
+        }
+        Ok(text)
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let response = tokio::time::timeout(TIMEOUT, self.exchange(&message, Some(id)))
+            .await
+            .with_context(|| format!("MCP server {} didn't answer {} in time", self.name, method))??
+            .with_context(|| format!("MCP server {} sent no answer to {}", self.name, method))?;
+        if let Some(error) = response.get("error") {
+            anyhow::bail!(
+                "MCP server {}: {} ({})",
+                self.name,
+                error["message"].as_str().unwrap_or("error"),
+                error["code"]
+            );
+        }
+        Ok(response["result"].clone())
+    }
+
+    async fn notify(&self, method: &str) -> Result<()> {
+        let message = json!({ "jsonrpc": "2.0", "method": method });
+        self.exchange(&message, None).await.map(|_| ())
+    }
+
+    /// Send `message` and, for a request, wait for the response with its `id`
+    async fn exchange(&self, message: &Value, id: Option<u64>) -> Result<Option<Value>> {
+        match &self.transport {
+            Transport::Stdio(stdio) => {
+                let mut stdio = stdio.lock().await;
+                let line = format!("{}\n", message);
+                stdio.stdin.write_all(line.as_bytes()).await.context("MCP server closed its input")?;
+                stdio.stdin.flush().await?;
+                let Some(id) = id else {
+                    return Ok(None);
+                };
+                // Notifications and requests from the server come in between, they're skipped
+                while let Some(line) = stdio.stdout.next_line().await? {
+                    if let Some(response) = response_with_id(&line, id) {
+                        return Ok(Some(response));
+                    }
+                }
+                anyhow::bail!("MCP server {} exited", self.name)
+            }
+            Transport::Http { url, headers, session } => {
+                let mut request = proxy::client()
+                    .post(url)
+                    .header(reqwest::header::ACCEPT, "application/json, text/event-stream")
+                    .json(message);
+                for (name, value) in headers {
+                    request = request.header(name, value);
+                }
+                if let Some(session) = session.lock().await.as_deref() {
+                    request = request.header("Mcp-Session-Id", session);
+                }
+                let response = request.send().await.with_context(|| format!("Failed to reach {}", url))?;
+                if !response.status().is_success() {
+                    anyhow::bail!("MCP server {} answered {}", self.name, response.status());
+                }
+                if let Some(new) = response.headers().get("mcp-session-id").and_then(|v| v.to_str().ok()) {
+                    *session.lock().await = Some(new.to_string());
+                }
+                let Some(id) = id else {
+                    return Ok(None);
+                };
+                let is_stream = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|t| t.to_str().ok())
+                    .is_some_and(|t| t.starts_with("text/event-stream"));
+                let body = response.text().await.context("MCP response interrupted")?;
+                if is_stream {
+                    Ok(body
+                        .lines()
+                        .filter_map(|line| line.strip_prefix("data:"))
+                        .find_map(|data| response_with_id(data.trim(), id)))
+                } else {
+                    Ok(response_with_id(&body, id))
+                }
+            }
+        }
+    }
+}
+
+/// The JSON-RPC response in `text` if it answers request `id`
+fn response_with_id(text: &str, id: u64) -> Option<Value> {
+    let message: Value = serde_json::from_str(text).ok()?;
+    (message["id"].as_u64() == Some(id) && message.get("method").is_none()).then_some(message)
+}
+
+/// One of a server's tools, offered to the model as `server_tool`
+struct McpTool {
+    client: Arc<McpClient>,
+    name: String,
+}
+
+#[async_trait]
+impl Handler for McpTool {
+    async fn call(&self, arguments: &Value) -> Result<String> {
+        self.client.call_tool(&self.name, arguments).await
+    }
+}
+
+/// Connect to the configured servers and add their tools to `registry`.
+/// A server that can't be reached is left out with a warning.
+pub async fn register(servers: &[McpServer], registry: &mut ToolRegistry) {
+    for server in servers {
+        let tools = match McpClient::connect(server).await {
+            Ok(client) => {
+                let client = Arc::new(client);
+                client.list_tools().await.map(|tools| (client, tools))
+            }
+            Err(e) => Err(e),
+        };
+        let (client, tools) = match tools {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("MCP server {} is left out: {:#}", server.name, e);
+                continue;
+            }
+        };
+        let mut count = 0;
+        for tool in tools {
+            let Some(name) = tool["name"].as_str() else {
+                continue;
+            };
+            // OpenAI function names are at most 64 of [a-zA-Z0-9_-]
+            let offered: String = format!("{}_{}", server.name, name)
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+                .take(64)
+                .collect();
+            let schema = match tool.get("inputSchema") {
+                Some(schema) if schema.is_object() => schema.clone(),
+                _ => json!({ "type": "object", "properties": {} }),
+            };
+            registry.register(
+                &offered,
+                tool["description"].as_str().unwrap_or_default(),
+                schema,
+                Box::new(McpTool {
+                    client: Arc::clone(&client),
+                    name: name.to_string(),
+                }),
+            );
+            count += 1;
+        }
+        info!("MCP server {} offers {} tools", server.name, count);
+    }
+}
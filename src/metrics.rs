@@ -0,0 +1,60 @@
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::admin::UsageSnapshot;
+use crate::api::{authorized, error};
+use crate::session::SessionContext;
+
+/// Name, help text, type and value of a metric reported per session
+type SessionMetric = (&'static str, &'static str, &'static str, fn(&UsageSnapshot) -> u64);
+
+/// Prometheus text format gauges for the server state
+pub async fn metrics(
+    State(ctx): State<Arc<SessionContext>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&ctx, addr, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "invalid_request_error", "Invalid API key.");
+    }
+    let mut body = format!(
+        "# HELP telllm_maintenance Whether maintenance mode is on\n\
+         # TYPE telllm_maintenance gauge\n\
+         telllm_maintenance {}\n\
+         # HELP telllm_sessions Connected telnet sessions\n\
+         # TYPE telllm_sessions gauge\n\
+         telllm_sessions {}\n\
+         # HELP telllm_session_panics_total Sessions ended by a panic\n\
+         # TYPE telllm_session_panics_total counter\n\
+         telllm_session_panics_total {}\n",
+        u8::from(ctx.maintenance.is_active()),
+        ctx.sessions.count(),
+        ctx.supervisor.panics()
+    );
+
+    // Per connected session, labelled with the id `kick` and `attach` go by
+    let sessions = ctx.sessions.list();
+    let per_session: [SessionMetric; 5] = [
+        ("telllm_session_bytes_in", "Bytes received from the client", "counter", |u| u.bytes_in),
+        ("telllm_session_bytes_out", "Bytes sent to the client", "counter", |u| u.bytes_out),
+        ("telllm_session_messages", "Chat messages the user sent", "counter", |u| u.messages),
+        ("telllm_session_tokens", "Prompt and reply tokens, estimated where the backend gave no count", "counter", |u| u.tokens),
+        ("telllm_session_history_bytes", "Conversation held in memory", "gauge", |u| u.history_bytes),
+    ];
+    for (name, help, kind, value) in per_session {
+        body.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        for s in &sessions {
+            body.push_str(&format!(
+                "{}{{session=\"{}\",addr=\"{}\"}} {}\n",
+                name,
+                s.id,
+                s.addr,
+                value(&s.usage)
+            ));
+        }
+    }
+    ([("content-type", "text/plain; version=0.0.4")], body).into_response()
+}
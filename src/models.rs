@@ -0,0 +1,55 @@
+use anyhow::Result;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::llm::LlmProvider;
+
+/// The backend's model list, fetched on first use and kept for `ttl`
+pub struct ModelCatalog {
+    ttl: Duration,
+    /// Named by the operator, offered even when the backend doesn't list them
+    configured: Vec<String>,
+    cached: Mutex<Option<(Instant, Vec<String>)>>,
+}
+
+impl ModelCatalog {
+    pub fn new(ttl: Duration, configured: Vec<String>) -> Self {
+        Self {
+            ttl,
+            configured,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// The list as last fetched, if it hasn't expired
+    pub fn cached(&self) -> Option<Vec<String>> {
+        let cached = self.cached.lock().unwrap();
+        cached
+            .as_ref()
+            .filter(|(fetched, _)| fetched.elapsed() < self.ttl)
+            .map(|(_, models)| models.clone())
+    }
+
+    /// The cached list, asking the backend when it has expired or `refresh` is set.
+    /// Configured models are part of it; they alone stand in when the backend can't list any.
+    pub async fn list(&self, llm: &dyn LlmProvider, refresh: bool) -> Result<Vec<String>> {
+        if !refresh && let Some(models) = self.cached() {
+            return Ok(models);
+        }
+        let mut models = match llm.list_models().await {
+            Ok(models) => models,
+            Err(e) if self.configured.is_empty() => return Err(e),
+            // Not cached, so it doesn't rule out what the backend would accept
+            Err(e) => {
+                warn!("Couldn't list the backend's models, offering the configured ones: {}", e);
+                return Ok(self.configured.clone());
+            }
+        };
+        models.extend(self.configured.iter().cloned());
+        models.sort();
+        models.dedup();
+        *self.cached.lock().unwrap() = Some((Instant::now(), models.clone()));
+        Ok(models)
+    }
+}
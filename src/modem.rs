@@ -0,0 +1,167 @@
+use anyhow::{Result, bail};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::{Interval, MissedTickBehavior};
+use tokio_serial::{SerialPort, SerialStream};
+use tracing::info;
+
+/// What the modem says when the other side hangs up
+const NO_CARRIER: &[u8] = b"NO CARRIER";
+/// Result codes that end a call attempt without a connection
+const FAILED: &[&str] = &["NO CARRIER", "BUSY", "NO ANSWER", "NO DIALTONE", "ERROR"];
+/// Time for the modem to acknowledge the init string
+const INIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time for the handshake after ATA, long enough for V.34 retraining
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the carrier detect pin is checked during a call
+const DCD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connected call
+pub struct Call {
+    pub line: Line,
+    /// Line speed in bits per second from the `CONNECT` message, if it gave one
+    pub speed: Option<u64>,
+}
+
+/// Reset the modem with `init`, wait for a `RING` and pick up. Returns once a
+/// call is connected; calls that fail to connect are logged and waited out.
+pub async fn answer(mut port: SerialStream, init: &str) -> Result<Call> {
+    let mut pending = Vec::new();
+    port.write_all(format!("{}\r", init).as_bytes()).await?;
+    loop {
+        let Some(line) = tokio::time::timeout(INIT_TIMEOUT, next_line(&mut port, &mut pending))
+            .await
+            .ok()
+            .transpose()?
+        else {
+            bail!("No OK from the modem after {}", init);
+        };
+        match line.as_str() {
+            "OK" => break,
+            "ERROR" => bail!("The modem rejected {}", init),
+            _ => {}
+        }
+    }
+    info!("Modem ready, waiting for a call");
+
+    loop {
+        if next_line(&mut port, &mut pending).await? != "RING" {
+            continue;
+        }
+        info!("Modem ringing, answering");
+        port.write_all(b"ATA\r").await?;
+        loop {
+            let Ok(line) = tokio::time::timeout(CONNECT_TIMEOUT, next_line(&mut port, &mut pending)).await else {
+                info!("No connection within {}s, hanging up", CONNECT_TIMEOUT.as_secs());
+                // Any character aborts the handshake
+                port.write_all(b"\r").await?;
+                break;
+            };
+            let line = line?;
+            if let Some(rest) = line.strip_prefix("CONNECT") {
+                let speed = rest
+                    .trim()
+                    .split(|c: char| !c.is_ascii_digit())
+                    .next()
+                    .and_then(|digits| digits.parse().ok());
+                // Not every modem or serial adapter wires DCD, only trust it if it is up now
+                let dcd = port.read_carrier_detect().unwrap_or(false).then(|| {
+                    let mut interval = tokio::time::interval(DCD_INTERVAL);
+                    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                    interval
+                });
+                return Ok(Call {
+                    line: Line {
+                        port,
+                        tail: Vec::new(),
+                        dcd,
+                        lost: false,
+                    },
+                    speed,
+                });
+            }
+            if FAILED.contains(&line.as_str()) {
+                info!("Call failed: {}", line);
+                break;
+            }
+        }
+    }
+}
+
+/// The next non-empty line the modem sends in command mode
+async fn next_line(port: &mut SerialStream, pending: &mut Vec<u8>) -> Result<String> {
+    loop {
+        if let Some(end) = pending.iter().position(|&b| b == b'\r' || b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            if !line.is_empty() {
+                return Ok(line);
+            }
+            continue;
+        }
+        let mut buf = [0u8; 256];
+        let n = port.read(&mut buf).await?;
+        if n == 0 {
+            bail!("The serial port closed");
+        }
+        pending.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// The serial port during a call. Reads end as if the connection closed once
+/// the modem reports `NO CARRIER` or drops carrier detect.
+pub struct Line {
+    port: SerialStream,
+    /// The last bytes read, to catch `NO CARRIER` split over two reads
+    tail: Vec<u8>,
+    dcd: Option<Interval>,
+    lost: bool,
+}
+
+impl AsyncRead for Line {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.lost {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(dcd) = this.dcd.as_mut() {
+            while dcd.poll_tick(cx).is_ready() {
+                if !this.port.read_carrier_detect().unwrap_or(true) {
+                    info!("Modem lost carrier");
+                    this.lost = true;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.port).poll_read(cx, buf))?;
+        this.tail.extend_from_slice(&buf.filled()[before..]);
+        if this.tail.windows(NO_CARRIER.len()).any(|w| w == NO_CARRIER) {
+            info!("Modem reported NO CARRIER");
+            this.lost = true;
+            buf.set_filled(before);
+            return Poll::Ready(Ok(()));
+        }
+        let keep = this.tail.len().saturating_sub(NO_CARRIER.len() - 1);
+        this.tail.drain(..keep);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Line {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.port).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.port).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.port).poll_shutdown(cx)
+    }
+}
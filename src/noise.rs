@@ -0,0 +1,97 @@
+/// Symbols people rarely type but noisy links often produce; runs of
+/// `MIN_SYMBOL_RUN` or more of them are dropped from lines made up mostly
+/// of them, so pasted code and JSON get through
+const NOISE_SYMBOLS: &[char] = &['{', '}', '|', '~', '^', '`', '\\', '[', ']'];
+const MIN_SYMBOL_RUN: usize = 3;
+
+/// A line with the obvious line noise taken out
+pub struct Cleaned {
+    pub text: String,
+    /// Characters dropped as noise
+    pub dropped: usize,
+    /// Characters in the line as received
+    pub total: usize,
+}
+
+impl Cleaned {
+    /// More than half of the line was noise, or nothing readable is left
+    pub fn is_mostly_noise(&self) -> bool {
+        self.dropped > 0 && (self.dropped * 2 > self.total || !self.text.chars().any(char::is_alphanumeric))
+    }
+}
+
+/// Drop what no one types: control characters, bytes that weren't UTF-8,
+/// private use characters and, in a line that is mostly rare symbols, runs of them
+pub fn clean(line: &str) -> Cleaned {
+    let chars: Vec<char> = line.chars().collect();
+    let symbols = chars.iter().filter(|c| NOISE_SYMBOLS.contains(c)).count();
+    let visible = chars.iter().filter(|c| !c.is_whitespace()).count();
+    let symbol_noise = symbols * 2 > visible;
+    let mut text = String::with_capacity(line.len());
+    let mut dropped = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let run = chars[i..].iter().take_while(|c| NOISE_SYMBOLS.contains(c)).count();
+        if symbol_noise && run >= MIN_SYMBOL_RUN {
+            dropped += run;
+            i += run;
+            continue;
+        }
+        let c = chars[i];
+        if is_garbage(c) {
+            dropped += 1;
+        } else {
+            text.push(c);
+        }
+        i += 1;
+    }
+    // Only close the gaps the noise left, a clean line keeps its spacing
+    if dropped > 0 {
+        text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    }
+    Cleaned {
+        text,
+        dropped,
+        total: chars.len(),
+    }
+}
+
+fn is_garbage(c: char) -> bool {
+    (c.is_control() && c != '\t')
+        || c == char::REPLACEMENT_CHARACTER
+        || ('\u{e000}'..='\u{f8ff}').contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pasted_code_passes_through() {
+        for line in [
+            r#"fn main() { let v = vec![[1, 2], [3, 4]]; println!("{:?}", v); }"#,
+            r#"{"messages": [{"role": "user", "content": "hi"}]}"#,
+            "    if a || b { return x[i][j]; }",
+        ] {
+            let cleaned = clean(line);
+            assert_eq!(cleaned.text, line);
+            assert_eq!(cleaned.dropped, 0);
+            assert!(!cleaned.is_mostly_noise());
+        }
+    }
+
+    #[test]
+    fn drops_symbol_runs_from_noisy_lines() {
+        let cleaned = clean("h}}|~^el[[]`lo");
+        assert_eq!(cleaned.text, "hello");
+        assert!(cleaned.is_mostly_noise());
+    }
+
+    #[test]
+    fn drops_control_characters() {
+        let cleaned = clean("hel\u{7}lo \u{fffd}world");
+        assert_eq!(cleaned.text, "hello world");
+        assert_eq!(cleaned.dropped, 2);
+        assert!(!cleaned.is_mostly_noise());
+    }
+}
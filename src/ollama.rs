@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::keys::KeyPool;
+use crate::llm::{self, ChatStream, Completion, LlmProvider, Message, ModelProfile, StreamEvent, TokenUsage, Tuning, Upstream};
+
+#[derive(Debug, Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: &'a [Message],
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<Value>,
+    /// `json` in JSON mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'a str>,
+}
+
+/// A whole reply, or one line of a streamed one
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    #[serde(default)]
+    message: Option<ResponseMessage>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    done_reason: Option<String>,
+    /// Token counts, on the last line
+    #[serde(default)]
+    prompt_eval_count: Option<u64>,
+    #[serde(default)]
+    eval_count: Option<u64>,
+}
+
+impl ChatResponse {
+    fn usage(&self) -> Option<TokenUsage> {
+        if self.prompt_eval_count.is_none() && self.eval_count.is_none() {
+            return None;
+        }
+        Some(TokenUsage::new(
+            self.prompt_eval_count.unwrap_or(0),
+            self.eval_count.unwrap_or(0),
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseMessage {
+    #[serde(default)]
+    content: String,
+}
+
+/// `GET /api/tags`, the locally pulled models
+#[derive(Debug, Deserialize)]
+struct TagList {
+    #[serde(default)]
+    models: Vec<Tag>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Tag {
+    name: String,
+}
+
+/// Ollama's native `/api/chat`, which takes options its OpenAI layer ignores
+pub struct OllamaClient {
+    upstream: Upstream,
+    model: String,
+    keys: Arc<KeyPool>,
+    profiles: Vec<ModelProfile>,
+    tuning: Tuning,
+    /// How long Ollama keeps the model loaded, e.g. `30m` or `-1` for ever
+    keep_alive: Option<String>,
+}
+
+impl OllamaClient {
+    pub fn new(
+        upstream: Upstream,
+        model: String,
+        keys: Arc<KeyPool>,
+        profiles: Vec<ModelProfile>,
+        tuning: Tuning,
+        keep_alive: Option<String>,
+    ) -> Self {
+        Self {
+            upstream,
+            model,
+            keys,
+            profiles,
+            tuning,
+            keep_alive,
+        }
+    }
+
+    /// The model's `options` from the config, with `max_tokens` as `num_predict`
+    /// and the sampling settings where the config doesn't set them
+    fn options(&self, model: &str, tuning: &Tuning) -> Option<Value> {
+        let mut options = self
+            .profiles
+            .iter()
+            .find(|p| p.name == model)
+            .and_then(|p| p.options.clone());
+        let flags = [
+            ("num_predict", tuning.max_tokens.map(Value::from)),
+            ("temperature", tuning.sampling.temperature.map(Value::from)),
+            ("top_p", tuning.sampling.top_p.map(Value::from)),
+            ("presence_penalty", tuning.sampling.presence_penalty.map(Value::from)),
+            ("frequency_penalty", tuning.sampling.frequency_penalty.map(Value::from)),
+        ];
+        for (name, value) in flags {
+            let Some(value) = value else { continue };
+            let map = options.get_or_insert_with(|| Value::Object(Default::default()));
+            if let Some(map) = map.as_object_mut() {
+                map.entry(name).or_insert(value);
+            }
+        }
+        options
+    }
+
+    /// POST to `/chat`. Keys are sent as bearer tokens for proxies in front of
+    /// Ollama, which itself ignores them.
+    async fn send(&self, model: &str, messages: &[Message], stream: bool, tuning: &Tuning) -> Result<reqwest::Response> {
+        let request = ChatRequest {
+            model,
+            messages,
+            stream,
+            keep_alive: self.keep_alive.as_deref(),
+            options: self.options(model, &tuning.or(&self.tuning)),
+            format: tuning.or(&self.tuning).json.then_some("json"),
+        };
+        llm::post_json(&self.upstream, "/chat", &request, &self.keys, model, authorize).await
+    }
+}
+
+fn authorize(req: reqwest::RequestBuilder, key: &str) -> reqwest::RequestBuilder {
+    req.header("Authorization", format!("Bearer {}", key))
+}
+
+/// One NDJSON object per line, the last one has `done` set
+fn parse_line(line: &str) -> Result<Option<StreamEvent>> {
+    let chunk: ChatResponse = serde_json::from_str(line).context("Failed to parse LLM stream")?;
+    Ok(Some(StreamEvent {
+        usage: chunk.usage(),
+        text: chunk.message.map(|m| m.content),
+        finish_reason: chunk.done_reason,
+        done: chunk.done,
+    }))
+}
+
+#[async_trait]
+impl LlmProvider for OllamaClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    async fn complete(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<Completion> {
+        let response: ChatResponse = self
+            .send(model, messages, false, tuning)
+            .await?
+            .json()
+            .await
+            .context("Failed to parse LLM response")?;
+
+        let usage = response.usage();
+        let content = response
+            .message
+            .map(|m| m.content)
+            .filter(|content| !content.is_empty())
+            .context("No response from LLM")?;
+        Ok(Completion {
+            content,
+            finish_reason: response.done_reason,
+            usage,
+            sources: Vec::new(),
+        })
+    }
+
+    async fn chat_stream(&self, model: &str, messages: &[Message], tuning: &Tuning) -> Result<ChatStream> {
+        let response = self.send(model, messages, true, tuning).await?;
+        Ok(ChatStream::lines(response, parse_line))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let tags: TagList = llm::get(&self.upstream, "/tags", &self.keys, &self.model, authorize)
+            .await?
+            .json()
+            .await
+            .context("Failed to parse model list")?;
+        Ok(tags.models.into_iter().map(|tag| tag.name).collect())
+    }
+}
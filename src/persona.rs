@@ -0,0 +1,116 @@
+use serde::Deserialize;
+
+/// Terminal colors a persona can use for its banner and labels
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Color {
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+    BrightBlue,
+    BrightMagenta,
+    BrightCyan,
+}
+
+impl Color {
+    fn sgr(self) -> &'static str {
+        match self {
+            Color::Red => "31",
+            Color::Green => "32",
+            Color::Yellow => "33",
+            Color::Blue => "34",
+            Color::Magenta => "35",
+            Color::Cyan => "36",
+            Color::White => "37",
+            Color::BrightRed => "91",
+            Color::BrightGreen => "92",
+            Color::BrightYellow => "93",
+            Color::BrightBlue => "94",
+            Color::BrightMagenta => "95",
+            Color::BrightCyan => "96",
+        }
+    }
+
+    pub fn paint(self, text: &str) -> String {
+        format!("\x1b[{}m{}\x1b[0m", self.sgr(), text)
+    }
+}
+
+fn paint(color: Option<Color>, text: &str) -> String {
+    match color {
+        Some(color) => color.paint(text),
+        None => text.to_string(),
+    }
+}
+
+/// Names shown in front of each side of the conversation, with their screen colors
+#[derive(Debug, Clone)]
+pub struct Labels {
+    pub ai: String,
+    pub user: String,
+    pub ai_color: Option<Color>,
+    pub user_color: Option<Color>,
+}
+
+impl Labels {
+    /// Input prompt, e.g. `You: `
+    pub fn prompt(&self) -> String {
+        format!("{} ", paint(self.user_color, &format!("{}:", self.user)))
+    }
+
+    /// Start of an AI line on screen, e.g. `AI:`
+    pub fn ai_prefix(&self) -> String {
+        paint(self.ai_color, &format!("{}:", self.ai))
+    }
+}
+
+/// A named character the server can take on, from `[[persona]]` in the config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Persona {
+    pub name: String,
+    /// Replaces --system-prompt while the persona is active
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub ai_label: Option<String>,
+    #[serde(default)]
+    pub user_label: Option<String>,
+    #[serde(default)]
+    pub ai_color: Option<Color>,
+    #[serde(default)]
+    pub user_color: Option<Color>,
+    /// Replaces the telllm logo above the command list
+    #[serde(default)]
+    pub banner: Option<String>,
+    #[serde(default)]
+    pub banner_color: Option<Color>,
+    /// Extra port whose sessions start with this persona
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+impl Persona {
+    /// The persona's labels, falling back to the global ones
+    pub fn labels(&self, global: &Labels) -> Labels {
+        Labels {
+            ai: self.ai_label.clone().unwrap_or_else(|| global.ai.clone()),
+            user: self.user_label.clone().unwrap_or_else(|| global.user.clone()),
+            ai_color: self.ai_color.or(global.ai_color),
+            user_color: self.user_color.or(global.user_color),
+        }
+    }
+
+    /// The persona's banner art in its color
+    pub fn banner(&self) -> Option<String> {
+        let banner = self.banner.as_deref()?;
+        Some(paint(self.banner_color, banner))
+    }
+}
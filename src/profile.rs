@@ -0,0 +1,173 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::greeting;
+use crate::llm::TokenUsage;
+use crate::storage;
+
+const PROFILE_FILE: &str = "profile.json";
+/// Pre-JSON `key: value` profile, migrated on first access
+const LEGACY_SUMMARY_FILE: &str = "summary.txt";
+/// Format used for `last_seen` in summary.txt
+const LEGACY_LAST_SEEN_FORMAT: &str = "%d-%m-%Y %H:%M:%S";
+
+/// Everything remembered about a client between sessions
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Local>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub terminal: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<(u16, u16)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    /// Pinned reply language, `None` follows the user's messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    /// UTC offset as typed by the user, e.g. `+02:00`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Storage quota set by an operator, wins over --client-quota-mb
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_mb: Option<u64>,
+    /// When the client agreed with /contribute to have their later conversations
+    /// used as training data, `None` if they haven't or took it back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contributing_since: Option<DateTime<Local>>,
+    /// LLM tokens of all the client's sessions, where the backend reported them
+    #[serde(skip_serializing_if = "TokenUsage::is_empty")]
+    pub tokens: TokenUsage,
+    /// Keys from an old summary.txt this version doesn't know about
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub other: BTreeMap<String, String>,
+}
+
+impl Profile {
+    pub fn utc_offset(&self) -> Option<FixedOffset> {
+        self.timezone.as_deref().and_then(greeting::parse_offset)
+    }
+
+    /// Take over whatever `other` knows that this profile doesn't
+    pub fn fill_from(&mut self, other: Profile) {
+        self.name = self.name.take().or(other.name);
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.terminal = self.terminal.take().or(other.terminal);
+        self.window = self.window.or(other.window);
+        self.country = self.country.take().or(other.country);
+        self.asn = self.asn.or(other.asn);
+        self.lang = self.lang.take().or(other.lang);
+        self.timezone = self.timezone.take().or(other.timezone);
+        self.quota_mb = self.quota_mb.or(other.quota_mb);
+        // Consent given elsewhere doesn't cover the conversations here, so contributing_since stays
+        self.tokens.add(&other.tokens);
+        for (key, value) in other.other {
+            self.other.entry(key).or_insert(value);
+        }
+    }
+
+    /// Convert the contents of a legacy summary.txt
+    fn from_summary(summary: &str) -> Self {
+        let mut profile = Profile::default();
+
+        for (key, value) in summary.lines().filter_map(|line| line.split_once(':')) {
+            let value = value.trim().to_string();
+            match key.trim().to_lowercase().as_str() {
+                "name" => profile.name = Some(value),
+                "last_seen" => {
+                    profile.last_seen = NaiveDateTime::parse_from_str(&value, LEGACY_LAST_SEEN_FORMAT)
+                        .ok()
+                        .and_then(|seen| seen.and_local_timezone(Local).single())
+                }
+                "terminal" => profile.terminal = Some(value),
+                "window" => {
+                    profile.window = value
+                        .split_once('x')
+                        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                }
+                "country" => profile.country = Some(value),
+                "asn" => profile.asn = value.trim_start_matches("AS").parse().ok(),
+                "lang" if value.eq_ignore_ascii_case("auto") => profile.lang = None,
+                "lang" => profile.lang = Some(value),
+                "timezone" => profile.timezone = Some(value),
+                other => {
+                    profile.other.insert(other.to_string(), value);
+                }
+            }
+        }
+
+        profile
+    }
+}
+
+/// Read a client's profile, falling back to a not yet migrated summary.txt.
+/// `None` when the client has neither, an unreadable or corrupt profile is an error.
+pub fn load(client_dir: &Path) -> Result<Option<Profile>> {
+    let path = client_dir.join(PROFILE_FILE);
+    match fs::read_to_string(&path) {
+        Ok(json) => {
+            let profile = serde_json::from_str(&json).with_context(|| format!("{} is corrupt", path.display()))?;
+            return Ok(Some(profile));
+        }
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+        }
+        Err(_) => {}
+    }
+    let legacy = client_dir.join(LEGACY_SUMMARY_FILE);
+    match fs::read_to_string(&legacy) {
+        Ok(summary) => Ok(Some(Profile::from_summary(&summary))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", legacy.display())),
+    }
+}
+
+/// Read-modify-write the profile under a per-client lock so concurrent
+/// sessions from the same IP can't interleave. The new file is swapped in
+/// atomically so a crash never leaves a half-written profile behind. A corrupt
+/// profile is left alone rather than replaced by one with only the new fields.
+pub fn update(client_dir: &Path, modify: impl FnOnce(&mut Profile)) -> Result<()> {
+    let _lock = storage::lock(client_dir, "profile.lock")?;
+
+    let mut profile = load(client_dir)?.unwrap_or_default();
+    modify(&mut profile);
+    let json = serde_json::to_string_pretty(&profile).context("Failed to serialize profile")?;
+    storage::write_atomic(&client_dir.join(PROFILE_FILE), &format!("{}\n", json))?;
+
+    // The summary has been carried over into profile.json
+    let legacy = client_dir.join(LEGACY_SUMMARY_FILE);
+    if legacy.exists() {
+        fs::remove_file(&legacy).context("Failed to remove migrated summary.txt")?;
+    }
+
+    Ok(())
+}
+
+/// Convert every client's summary.txt to profile.json. Returns the number migrated.
+pub fn migrate_all(logs_dir: &Path) -> Result<usize> {
+    let Ok(entries) = fs::read_dir(logs_dir) else {
+        return Ok(0);
+    };
+
+    let mut migrated = 0;
+    for entry in entries.flatten() {
+        let client_dir = entry.path();
+        if client_dir.join(LEGACY_SUMMARY_FILE).is_file() {
+            update(&client_dir, |_| {})
+                .with_context(|| format!("Failed to migrate {}", client_dir.display()))?;
+            migrated += 1;
+        }
+    }
+
+    Ok(migrated)
+}
@@ -0,0 +1,265 @@
+use std::collections::VecDeque;
+
+use crate::telnet::{ClientInfo, TelnetParser};
+
+/// Longest line kept, enough for an `/import` export pasted on one line.
+/// Bytes beyond it are dropped until the line ends.
+pub const MAX_LINE_BYTES: usize = 512 * 1024;
+/// The limit with --low-memory, a long prompt but not a whole export
+pub const LOW_MEMORY_LINE_BYTES: usize = 16 * 1024;
+
+/// What the client's bytes amount to
+#[derive(Debug, PartialEq, Eq)]
+pub enum InputEvent {
+    /// Answers to the client's telnet negotiation, to be sent back as they are
+    Reply(Vec<u8>),
+    /// A finished line without its terminator, invalid UTF-8 replaced
+    Line(String),
+}
+
+/// Where we are inside a terminal escape sequence
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    /// After ESC
+    Start,
+    /// `ESC [`, up to a final byte in `@`..`~`
+    Csi,
+    /// `ESC O`, one more byte (application cursor keys)
+    Ss3,
+    /// `ESC ]`, up to BEL or `ESC \`
+    Osc,
+    OscEsc,
+}
+
+/// Everything between the socket and a line of user input: telnet
+/// negotiation, ANSI escapes from cursor and function keys, backspace and
+/// the other line-editing keys of clients without local editing, and UTF-8
+/// split over several reads. Bytes go in with `push`, lines and replies come
+/// out of `next_event`; nothing here does I/O, so any byte sequence can be
+/// fed in any chunking.
+pub struct InputParser {
+    telnet: TelnetParser,
+    escape: Escape,
+    line: Vec<u8>,
+    max_line: usize,
+    last_was_cr: bool,
+    events: VecDeque<InputEvent>,
+}
+
+impl InputParser {
+    /// Lines are cut at `max_line` bytes
+    pub fn new(max_line: usize) -> Self {
+        Self {
+            telnet: TelnetParser::new(),
+            escape: Escape::None,
+            line: Vec::new(),
+            max_line,
+            last_was_cr: false,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// What the telnet negotiation told us about the client
+    pub fn client(&self) -> &ClientInfo {
+        &self.telnet.info
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        let (data, replies) = self.telnet.feed(bytes);
+        if !replies.is_empty() {
+            self.events.push_back(InputEvent::Reply(replies));
+        }
+        for byte in data {
+            self.push_data(byte);
+        }
+    }
+
+    pub fn next_event(&mut self) -> Option<InputEvent> {
+        self.events.pop_front()
+    }
+
+    fn push_data(&mut self, byte: u8) {
+        if self.skip_escape(byte) {
+            self.last_was_cr = false;
+            return;
+        }
+        let after_cr = std::mem::replace(&mut self.last_was_cr, byte == b'\r');
+        match byte {
+            // CR LF is one line end, not an empty line after it
+            b'\n' if after_cr => {}
+            b'\r' | b'\n' => self.end_line(),
+            0x1b => self.escape = Escape::Start,
+            // Backspace and DEL, whichever the terminal sends
+            0x08 | 0x7f => self.erase_char(),
+            // Ctrl-U
+            0x15 => self.line.clear(),
+            // Ctrl-W
+            0x17 => self.erase_word(),
+            b'\t' => self.keep(byte),
+            _ if byte < 0x20 => {}
+            _ => self.keep(byte),
+        }
+    }
+
+    /// Consume `byte` if it belongs to an escape sequence. A line end cuts a
+    /// broken sequence short, so garbage can't swallow the user's input.
+    fn skip_escape(&mut self, byte: u8) -> bool {
+        let line_end = byte == b'\r' || byte == b'\n';
+        self.escape = match self.escape {
+            Escape::None => return false,
+            _ if line_end => {
+                self.escape = Escape::None;
+                return false;
+            }
+            Escape::Start => match byte {
+                b'[' => Escape::Csi,
+                b'O' => Escape::Ss3,
+                b']' => Escape::Osc,
+                _ => Escape::None,
+            },
+            Escape::Csi if (0x40..=0x7e).contains(&byte) => Escape::None,
+            Escape::Csi => Escape::Csi,
+            Escape::Ss3 => Escape::None,
+            Escape::Osc => match byte {
+                0x07 => Escape::None,
+                0x1b => Escape::OscEsc,
+                _ => Escape::Osc,
+            },
+            Escape::OscEsc => Escape::None,
+        };
+        true
+    }
+
+    fn keep(&mut self, byte: u8) {
+        if self.line.len() < self.max_line {
+            self.line.push(byte);
+        }
+    }
+
+    /// Remove the last character, however many bytes it took
+    fn erase_char(&mut self) {
+        while let Some(byte) = self.line.pop() {
+            if byte & 0xc0 != 0x80 {
+                break;
+            }
+        }
+    }
+
+    /// Remove the last word and the spaces after it
+    fn erase_word(&mut self) {
+        while self.line.last().is_some_and(|b| b.is_ascii_whitespace()) {
+            self.line.pop();
+        }
+        while self.line.last().is_some_and(|b| !b.is_ascii_whitespace()) {
+            self.line.pop();
+        }
+    }
+
+    fn end_line(&mut self) {
+        // A cut at max_line may have split the last character
+        if self.line.len() >= self.max_line
+            && let Err(e) = std::str::from_utf8(&self.line)
+            && e.error_len().is_none()
+        {
+            self.line.truncate(e.valid_up_to());
+        }
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        self.events.push_back(InputEvent::Line(line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telnet::{IAC, WILL};
+
+    /// Lines and the replies joined, whatever the chunking split them into
+    fn parse_chunks(chunks: &[&[u8]], max_line: usize) -> (Vec<String>, Vec<u8>) {
+        let mut parser = InputParser::new(max_line);
+        let mut lines = Vec::new();
+        let mut replies = Vec::new();
+        for chunk in chunks {
+            parser.push(chunk);
+            while let Some(event) = parser.next_event() {
+                match event {
+                    InputEvent::Line(line) => lines.push(line),
+                    InputEvent::Reply(reply) => replies.extend(reply),
+                }
+            }
+        }
+        (lines, replies)
+    }
+
+    fn lines(input: &[u8]) -> Vec<String> {
+        parse_chunks(&[input], MAX_LINE_BYTES).0
+    }
+
+    #[test]
+    fn any_chunking_gives_the_same_result() {
+        let input: &[u8] = b"h\xc3\xa9llo\r\n\x1b[A\x1bOPw\xe2\x82\xacrd\x7f\x7f\r\0\xff\xff\xff\xfb\x01x\x1b]0;t\x07y\n";
+        let whole = parse_chunks(&[input], MAX_LINE_BYTES);
+        assert_eq!(whole.0, vec!["héllo", "w€", "\u{fffd}xy"]);
+        assert!(!whole.1.is_empty());
+        for cut in 0..=input.len() {
+            let (a, b) = input.split_at(cut);
+            assert_eq!(parse_chunks(&[a, b], MAX_LINE_BYTES), whole, "cut at {}", cut);
+        }
+        let bytes: Vec<&[u8]> = input.chunks(1).collect();
+        assert_eq!(parse_chunks(&bytes, MAX_LINE_BYTES), whole);
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(lines(b"a\rb\nc\r\nd\r\0e\r\n"), vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(lines(b"\r\n\r\n"), vec!["", ""]);
+        // LF CR is two line ends, only CR LF is one
+        assert_eq!(lines(b"a\n\rb\r"), vec!["a", "", "b"]);
+    }
+
+    #[test]
+    fn backspace_erases_whole_characters() {
+        assert_eq!(lines("añ\x7f€\x08b\r".as_bytes()), vec!["ab"]);
+        assert_eq!(lines("🦀🦀\x08\r".as_bytes()), vec!["🦀"]);
+        assert_eq!(lines(b"\x08\x7fa\r"), vec!["a"]);
+    }
+
+    #[test]
+    fn ctrl_u_and_ctrl_w() {
+        assert_eq!(lines(b"hello world\x15bye\r"), vec!["bye"]);
+        assert_eq!(lines(b"one two  \x17three\r"), vec!["one three"]);
+        assert_eq!(lines(b"one\x17\x17two\r"), vec!["two"]);
+    }
+
+    #[test]
+    fn escapes_are_dropped() {
+        assert_eq!(lines(b"a\x1b[1;5Cb\x1bOAc\x1b]2;title\x07d\x1b]2;x\x1b\\e\r"), vec!["abcde"]);
+    }
+
+    #[test]
+    fn line_end_cuts_escapes_short() {
+        // CSI, SS3 and OSC left open by a line end don't swallow the next line
+        assert_eq!(lines(b"a\x1b[12\rb\r"), vec!["a", "b"]);
+        assert_eq!(lines(b"a\x1bO\rb\r"), vec!["a", "b"]);
+        assert_eq!(lines(b"a\x1b]0;never closed\nb\r"), vec!["a", "b"]);
+        assert_eq!(lines(b"a\x1b\rb\r"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn long_lines_are_truncated() {
+        assert_eq!(parse_chunks(&[b"abcdefgh\rxy\r"], 4).0, vec!["abcd", "xy"]);
+        // A character cut in half at the limit is left out, not replaced
+        assert_eq!(parse_chunks(&["ab€cd\r".as_bytes()], 4).0, vec!["ab"]);
+    }
+
+    #[test]
+    fn iac_iac_is_a_data_byte() {
+        let (lines, replies) = parse_chunks(&[&[b'a', IAC, IAC, b'b', b'\r']], MAX_LINE_BYTES);
+        assert_eq!(lines, vec!["a\u{fffd}b"]);
+        assert!(replies.is_empty());
+        let (lines, replies) = parse_chunks(&[&[b'a', IAC, WILL, 1, b'b', b'\r']], MAX_LINE_BYTES);
+        assert_eq!(lines, vec!["ab"]);
+        assert!(!replies.is_empty());
+    }
+}
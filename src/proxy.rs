@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+#[cfg(feature = "tools")]
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use tracing::info;
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+static NO_REDIRECTS: OnceLock<reqwest::Client> = OnceLock::new();
+/// The proxy from `configure`, for clients built later
+static ROUTE: OnceLock<reqwest::Proxy> = OnceLock::new();
+
+fn from_env(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .filter(|value| !value.is_empty())
+}
+
+/// Send outbound requests through `proxy`, or `HTTPS_PROXY`/`ALL_PROXY`
+/// when it isn't given, except to the `no_proxy` hosts (`NO_PROXY` if
+/// empty). HTTP, HTTPS and SOCKS5 proxies work.
+pub fn configure(proxy: Option<&str>, no_proxy: &[String]) -> Result<()> {
+    let proxy = proxy
+        .map(str::to_string)
+        .or_else(|| from_env(&["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]));
+    let mut route = None;
+    if let Some(url) = &proxy {
+        let bypass = if no_proxy.is_empty() {
+            from_env(&["NO_PROXY", "no_proxy"])
+        } else {
+            Some(no_proxy.join(","))
+        };
+        route = Some(
+            reqwest::Proxy::all(url)
+                .with_context(|| format!("Invalid proxy {}", redact(url)))?
+                .no_proxy(bypass.as_deref().and_then(reqwest::NoProxy::from_string)),
+        );
+        match &bypass {
+            Some(hosts) => info!("Outbound HTTP through {}, except {}", redact(url), hosts),
+            None => info!("Outbound HTTP through {}", redact(url)),
+        }
+    }
+    let build = |redirects: reqwest::redirect::Policy| {
+        let mut builder = reqwest::Client::builder().redirect(redirects);
+        if let Some(route) = &route {
+            builder = builder.proxy(route.clone());
+        }
+        builder.build().context("Failed to set up the HTTP client")
+    };
+    if let Some(route) = &route {
+        let _ = ROUTE.set(route.clone());
+    }
+    let _ = CLIENT.set(build(reqwest::redirect::Policy::default())?);
+    let _ = NO_REDIRECTS.set(build(reqwest::redirect::Policy::none())?);
+    Ok(())
+}
+
+/// The client for every outbound request, sharing one connection pool
+pub fn client() -> reqwest::Client {
+    CLIENT.get_or_init(reqwest::Client::new).clone()
+}
+
+/// Like `client`, but redirects come back as they are, for callers that
+/// check every hop
+#[cfg(feature = "tools")]
+pub fn no_redirects_client() -> reqwest::Client {
+    NO_REDIRECTS
+        .get_or_init(|| {
+            reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap_or_default()
+        })
+        .clone()
+}
+
+/// Like `no_redirects_client`, but `host` only connects to `addrs`, the
+/// addresses the caller checked, so a second DNS answer can't send the
+/// request elsewhere. Through a proxy the proxy resolves the host.
+#[cfg(feature = "tools")]
+pub fn pinned_client(host: &str, addrs: &[SocketAddr]) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve_to_addrs(host, addrs);
+    if let Some(route) = ROUTE.get() {
+        builder = builder.proxy(route.clone());
+    }
+    builder.build().context("Failed to set up the HTTP client")
+}
+
+/// The proxy URL without its credentials, for the log
+fn redact(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) => {
+            let _ = parsed.set_username("");
+            let _ = parsed.set_password(None);
+            parsed.to_string()
+        }
+        Err(_) => "the proxy".to_string(),
+    }
+}
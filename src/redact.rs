@@ -0,0 +1,209 @@
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+/// Redaction rules that come with the server, picked with --redact
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Preset {
+    /// Payment card numbers, 13 to 19 digits that pass the Luhn check
+    Card,
+    Email,
+    /// International bank account numbers
+    Iban,
+    /// US social security numbers written as 123-45-6789
+    Ssn,
+}
+
+impl Preset {
+    fn rule(self) -> RedactionRule {
+        let (name, pattern) = match self {
+            Preset::Card => ("card", r"\b\d(?:[ -]?\d){12,18}\b"),
+            Preset::Email => ("email", r"(?i)\b[a-z0-9._%+-]+@[a-z0-9.-]+\.[a-z]{2,}\b"),
+            // Written in groups of four or run together, in capitals, so words after it stay
+            Preset::Iban => ("iban", r"\b[A-Z]{2}\d{2}(?:[A-Z0-9]{11,30}|(?: [A-Z0-9]{4}){2,7}(?: [A-Z0-9]{1,3})?)\b"),
+            Preset::Ssn => ("ssn", r"\b\d{3}-\d{2}-\d{4}\b"),
+        };
+        RedactionRule {
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            placeholder: None,
+        }
+    }
+}
+
+/// A pattern to take out of user messages, `[[redaction]]` in the config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedactionRule {
+    pub name: String,
+    /// Regular expression, e.g. `\b\d{6}/\d{4}\b`
+    pub pattern: String,
+    /// Put in place of each match, `[NAME]` if not set
+    #[serde(default)]
+    pub placeholder: Option<String>,
+}
+
+impl RedactionRule {
+    pub fn validate(&self) -> Result<()> {
+        Regex::new(&self.pattern)
+            .map(|_| ())
+            .with_context(|| format!("Redaction {} has an invalid pattern", self.name))
+    }
+}
+
+struct Compiled {
+    regex: Regex,
+    placeholder: String,
+    /// Only matches that pass the Luhn check count, for card numbers
+    luhn: bool,
+}
+
+/// Replaces sensitive data in user messages with placeholders before they
+/// are logged or sent upstream
+#[derive(Default)]
+pub struct Redactor {
+    rules: Vec<Compiled>,
+}
+
+impl Redactor {
+    /// The `presets` first, then the configured `rules`
+    pub fn new(presets: &[Preset], rules: &[RedactionRule]) -> Result<Self> {
+        let presets = presets.iter().map(|&p| (p.rule(), p == Preset::Card));
+        let configured = rules.iter().map(|rule| (rule.clone(), false));
+        let mut compiled = Vec::new();
+        for (rule, luhn) in presets.chain(configured) {
+            let regex = Regex::new(&rule.pattern)
+                .with_context(|| format!("Redaction {} has an invalid pattern", rule.name))?;
+            compiled.push(Compiled {
+                regex,
+                placeholder: rule.placeholder.unwrap_or_else(|| format!("[{}]", rule.name.to_uppercase())),
+                luhn,
+            });
+        }
+        Ok(Self { rules: compiled })
+    }
+
+    /// `text` with every match replaced, and how many there were
+    pub fn redact(&self, text: &str) -> (String, usize) {
+        let mut text = text.to_string();
+        let mut count = 0;
+        for rule in &self.rules {
+            text = rule
+                .regex
+                .replace_all(&text, |caps: &Captures| {
+                    if rule.luhn && !luhn(&caps[0]) {
+                        return caps[0].to_string();
+                    }
+                    count += 1;
+                    rule.placeholder.clone()
+                })
+                .into_owned();
+        }
+        (text, count)
+    }
+}
+
+/// The checksum every payment card number passes
+fn luhn(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match i % 2 {
+            0 => d,
+            _ if d * 2 > 9 => d * 2 - 9,
+            _ => d * 2,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preset(preset: Preset) -> Redactor {
+        Redactor::new(&[preset], &[]).unwrap()
+    }
+
+    fn assert_redacted(redactor: &Redactor, text: &str, expected: &str) {
+        assert_eq!(redactor.redact(text).0, expected, "{:?}", text);
+    }
+
+    fn assert_kept(redactor: &Redactor, text: &str) {
+        assert_eq!(redactor.redact(text), (text.to_string(), 0), "{:?}", text);
+    }
+
+    #[test]
+    fn cards() {
+        let redactor = preset(Preset::Card);
+        assert_redacted(&redactor, "card 4111 1111 1111 1111 ok", "card [CARD] ok");
+        assert_redacted(&redactor, "4111-1111-1111-1111", "[CARD]");
+        assert_redacted(&redactor, "378282246310005", "[CARD]");
+        // Fails the Luhn check
+        assert_kept(&redactor, "4111 1111 1111 1112");
+        // Too short to be a card
+        assert_kept(&redactor, "order 123456789012");
+        assert_kept(&redactor, "call +1 555 123 4567");
+    }
+
+    #[test]
+    fn emails() {
+        let redactor = preset(Preset::Email);
+        assert_redacted(&redactor, "mail Jane.Doe+x@Example.co.uk now", "mail [EMAIL] now");
+        assert_kept(&redactor, "root@localhost");
+        assert_kept(&redactor, "ping @someone");
+        assert_kept(&redactor, "a@b.c");
+    }
+
+    #[test]
+    fn ibans() {
+        let redactor = preset(Preset::Iban);
+        assert_redacted(&redactor, "to DE89 3704 0044 0532 0130 00 please", "to [IBAN] please");
+        assert_redacted(&redactor, "GB82WEST12345698765432", "[IBAN]");
+        assert_redacted(&redactor, "GB82 WEST 1234 5698 7654 32 thanks", "[IBAN] thanks");
+        assert_kept(&redactor, "DE89 3704");
+        assert_kept(&redactor, "version v1.2.3");
+        assert_kept(&redactor, "AB12 says hello there friend");
+    }
+
+    #[test]
+    fn ssns() {
+        let redactor = preset(Preset::Ssn);
+        assert_redacted(&redactor, "ssn 123-45-6789.", "ssn [SSN].");
+        assert_kept(&redactor, "123-456-789");
+        assert_kept(&redactor, "1234-56-7890");
+        assert_kept(&redactor, "2024-01-15");
+    }
+
+    #[test]
+    fn configured_rules_and_counts() {
+        let rules = [RedactionRule {
+            name: "ticket".to_string(),
+            pattern: r"\bT-\d{4}\b".to_string(),
+            placeholder: Some("<ticket>".to_string()),
+        }];
+        let redactor = Redactor::new(&[Preset::Email, Preset::Ssn], &rules).unwrap();
+        let (text, count) = redactor.redact("T-1234 and T-5678 from a@example.com, 123-45-6789");
+        assert_eq!(text, "<ticket> and <ticket> from [EMAIL], [SSN]");
+        assert_eq!(count, 4);
+        assert_kept(&redactor, "T-12345");
+    }
+
+    #[test]
+    fn invalid_patterns_are_refused() {
+        let rule = RedactionRule {
+            name: "broken".to_string(),
+            pattern: "(".to_string(),
+            placeholder: None,
+        };
+        assert!(rule.validate().is_err());
+        assert!(Redactor::new(&[], &[rule]).is_err());
+    }
+
+    #[test]
+    fn no_rules_change_nothing() {
+        assert_kept(&Redactor::default(), "4111 1111 1111 1111 a@example.com");
+    }
+}
@@ -0,0 +1,147 @@
+/// Turn raw model output into text that is safe and readable on a plain terminal.
+///
+/// Escape sequences and control characters are always stripped so the model
+/// can't drive the user's terminal; markdown is flattened unless `markdown` is false.
+pub fn render_response(raw: &str, markdown: bool) -> String {
+    let clean = strip_control(raw);
+    if !markdown {
+        return clean;
+    }
+
+    let mut lines = Vec::new();
+    let mut in_code = false;
+    for line in clean.lines() {
+        let trimmed = line.trim_start();
+
+        // Code fences only add noise, keep the code itself as it is
+        if trimmed.starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            lines.push(line.to_string());
+            continue;
+        }
+
+        let line = if let Some(heading) = strip_heading(trimmed) {
+            heading.to_uppercase()
+        } else if let Some(item) = trimmed
+            .strip_prefix("* ")
+            .or_else(|| trimmed.strip_prefix("+ "))
+        {
+            let indent = &line[..line.len() - trimmed.len()];
+            format!("{}- {}", indent, item)
+        } else {
+            line.to_string()
+        };
+
+        lines.push(strip_emphasis(&line));
+    }
+
+    lines.join("\n")
+}
+
+fn strip_heading(line: &str) -> Option<&str> {
+    let hashes = line.chars().take_while(|&c| c == '#').count();
+    if (1..=6).contains(&hashes) {
+        line[hashes..].strip_prefix(' ').map(str::trim)
+    } else {
+        None
+    }
+}
+
+/// Remove `**bold**` and `` `code` `` markers, only where they pair up
+/// around text so `x**2` and a lone backtick stay
+fn strip_emphasis(line: &str) -> String {
+    strip_pairs(&strip_pairs(line, "**"), "`")
+}
+
+fn strip_pairs(line: &str, marker: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find(marker) {
+        let before = rest[..start].chars().next_back().or_else(|| out.chars().next_back());
+        let inner = &rest[start + marker.len()..];
+        let opens = !before.is_some_and(char::is_alphanumeric)
+            && inner.chars().next().is_some_and(|c| !c.is_whitespace());
+        let close = opens
+            .then(|| {
+                inner.match_indices(marker).map(|(i, _)| i).find(|&i| {
+                    i > 0
+                        && !inner[..i].ends_with(char::is_whitespace)
+                        && !inner[i + marker.len()..].starts_with(char::is_alphanumeric)
+                })
+            })
+            .flatten();
+        match close {
+            Some(end) => {
+                out.push_str(&rest[..start]);
+                out.push_str(&inner[..end]);
+                rest = &inner[end + marker.len()..];
+            }
+            None => {
+                out.push_str(&rest[..start + marker.len()]);
+                rest = inner;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Drop ANSI escape sequences and control characters except newlines and tabs
+pub fn strip_control(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // CSI sequence: ESC [ params final-byte
+            if chars.peek() == Some(&'[') {
+                chars.next();
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            } else {
+                chars.next();
+            }
+        } else if c == '\n' || c == '\t' || !c.is_control() {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_is_flattened() {
+        let text = "## Steps\n* **Open** the `config`\n  + nested\nDone";
+        assert_eq!(render_response(text, true), "STEPS\n- Open the config\n  - nested\nDone");
+    }
+
+    #[test]
+    fn code_blocks_stay_as_written() {
+        let text = "Try:\n```python\n# square it\n* ptr\nx**2 + `y`\n```\n# After";
+        assert_eq!(render_response(text, true), "Try:\n# square it\n* ptr\nx**2 + `y`\nAFTER");
+    }
+
+    #[test]
+    fn only_paired_markers_are_removed() {
+        assert_eq!(strip_emphasis("x**2 and y**3"), "x**2 and y**3");
+        assert_eq!(strip_emphasis("a ** b ** c"), "a ** b ** c");
+        assert_eq!(strip_emphasis("it's a `lone tick"), "it's a `lone tick");
+        assert_eq!(strip_emphasis("**bold** and **more**"), "bold and more");
+        assert_eq!(strip_emphasis("(`x`)"), "(x)");
+    }
+
+    #[test]
+    fn escapes_are_stripped_either_way() {
+        assert_eq!(render_response("\u{1b}[31mred\u{1b}[0m\u{7}", false), "red");
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Datelike, Local, NaiveTime, Timelike, Weekday};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+use crate::session::SessionContext;
+
+/// Format of `from` and `until`
+const TIME_FORMAT: &str = "%H:%M";
+
+/// A time window that changes the defaults for new sessions, e.g. office hours
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    pub name: String,
+    /// Days the window applies to, every day if empty (`mon`, `tuesday`, ...)
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// Start of the window, `HH:MM` local time
+    pub from: String,
+    /// End of the window, may be before `from` to span midnight
+    pub until: String,
+    /// Default persona for new sessions
+    #[serde(default)]
+    pub persona: Option<String>,
+    /// Model used instead of --model
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Storage quota in MB used instead of --client-quota-mb (0 for unlimited)
+    #[serde(default)]
+    pub quota_mb: Option<u64>,
+}
+
+/// Parse a `HH:MM` time, naming `what` it belongs to in the error
+pub fn parse_time(what: &str, time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, TIME_FORMAT)
+        .with_context(|| format!("{}: invalid time {:?}, expected HH:MM", what, time))
+}
+
+/// Whether `now` is on one of `days` (any day if empty) between `from` and
+/// `until`, which may span midnight
+pub fn in_window(days: &[Weekday], from: NaiveTime, until: NaiveTime, now: DateTime<Local>) -> bool {
+    if !days.is_empty() && !days.contains(&now.weekday()) {
+        return false;
+    }
+    let time = now.time();
+    if from < until {
+        time >= from && time < until
+    } else {
+        time >= from || time < until
+    }
+}
+
+impl Schedule {
+    fn window(&self) -> Result<(NaiveTime, NaiveTime)> {
+        let what = format!("Schedule {}", self.name);
+        Ok((parse_time(&what, &self.from)?, parse_time(&what, &self.until)?))
+    }
+
+    /// Reject times that don't parse and windows that can never match
+    pub fn validate(&self) -> Result<()> {
+        let (from, until) = self.window()?;
+        if from == until {
+            bail!("Schedule {}: from and until are the same", self.name);
+        }
+        Ok(())
+    }
+
+    fn matches(&self, now: DateTime<Local>) -> bool {
+        let Ok((from, until)) = self.window() else {
+            return false;
+        };
+        in_window(&self.days, from, until, now)
+    }
+}
+
+/// The schedule in force right now, `None` outside every window
+#[derive(Default)]
+pub struct ActiveSchedule(Mutex<Option<Schedule>>);
+
+impl ActiveSchedule {
+    pub fn get(&self) -> Option<Schedule> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Pick the first schedule whose window contains the current time
+pub fn refresh(ctx: &SessionContext) {
+    let active = ctx.config.get().schedules.iter().find(|s| s.matches(Local::now())).cloned();
+
+    let mut current = ctx.schedule.0.lock().unwrap();
+    let before = current.as_ref().map(|s| s.name.as_str());
+    let after = active.as_ref().map(|s| s.name.as_str());
+    if before != after {
+        info!(
+            "Schedule changed from {} to {}",
+            before.unwrap_or("defaults"),
+            after.unwrap_or("defaults")
+        );
+    }
+    *current = active;
+}
+
+/// Re-evaluate the schedules at the start of every minute
+pub async fn run(ctx: Arc<SessionContext>) {
+    loop {
+        let wait = 60 - Local::now().second() as u64;
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+        refresh(&ctx);
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::Url;
+use serde_json::Value;
+use std::time::Duration;
+
+use crate::proxy;
+use crate::tools::{Engine, Handler};
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+/// Snippet length handed to the model per result
+const MAX_SNIPPET_CHARS: usize = 400;
+
+impl Engine {
+    fn default_url(self) -> Option<&'static str> {
+        match self {
+            Engine::Searxng => None,
+            Engine::Brave => Some("https://api.search.brave.com/res/v1/web/search"),
+            Engine::Duckduckgo => Some("https://api.duckduckgo.com/"),
+        }
+    }
+}
+
+/// Where and how web_search looks things up, from the --search-* options
+#[derive(Debug, Clone)]
+pub struct SearchConfig {
+    pub engine: Engine,
+    pub url: Url,
+    /// Brave's subscription token
+    pub key: Option<String>,
+    /// Results handed to the model
+    pub max_results: usize,
+}
+
+/// One search result
+pub struct Hit {
+    pub title: String,
+    pub url: String,
+    pub snippet: String,
+}
+
+impl SearchConfig {
+    /// `url` falls back to the engine's public API, SearxNG has none
+    pub fn new(engine: Engine, url: Option<&str>, key: Option<String>, max_results: usize) -> Result<Self> {
+        let url = url
+            .or(engine.default_url())
+            .context("--search-engine searxng needs --search-url of the instance")?;
+        let mut url = Url::parse(url).with_context(|| format!("--search-url {} is not a URL", url))?;
+        if engine == Engine::Searxng && !url.path().ends_with("/search") {
+            let search = format!("{}/search", url.as_str().trim_end_matches('/'));
+            url = Url::parse(&search).context("Invalid --search-url")?;
+        }
+        if engine == Engine::Brave && key.is_none() {
+            anyhow::bail!("--search-engine brave needs --search-key");
+        }
+        Ok(Self {
+            engine,
+            url,
+            key,
+            max_results: max_results.max(1),
+        })
+    }
+
+    pub async fn search(&self, query: &str) -> Result<Vec<Hit>> {
+        let count = self.max_results.to_string();
+        let request = match self.engine {
+            Engine::Searxng => proxy::client()
+                .get(self.url.clone())
+                .query(&[("q", query), ("format", "json")]),
+            Engine::Brave => proxy::client()
+                .get(self.url.clone())
+                .query(&[("q", query), ("count", count.as_str())])
+                .header("X-Subscription-Token", self.key.as_deref().unwrap_or_default()),
+            Engine::Duckduckgo => proxy::client().get(self.url.clone()).query(&[
+                ("q", query),
+                ("format", "json"),
+                ("no_html", "1"),
+                ("skip_disambig", "1"),
+            ]),
+        };
+        let response = request
+            .header(reqwest::header::ACCEPT, "application/json")
+            .timeout(TIMEOUT)
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach {}", self.url))?;
+        if !response.status().is_success() {
+            anyhow::bail!("The search engine answered {}", response.status());
+        }
+        let body: Value = response.json().await.context("The search engine sent invalid JSON")?;
+
+        let mut hits = match self.engine {
+            Engine::Searxng => hits_of(&body["results"], "title", "url", "content"),
+            Engine::Brave => hits_of(&body["web"]["results"], "title", "url", "description"),
+            Engine::Duckduckgo => duckduckgo_hits(&body),
+        };
+        hits.truncate(self.max_results);
+        Ok(hits)
+    }
+}
+
+fn hits_of(results: &Value, title: &str, url: &str, snippet: &str) -> Vec<Hit> {
+    results
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|result| {
+            Some(Hit {
+                title: result[title].as_str().unwrap_or_default().to_string(),
+                url: result[url].as_str().filter(|u| !u.is_empty())?.to_string(),
+                snippet: clean(result[snippet].as_str().unwrap_or_default()),
+            })
+        })
+        .collect()
+}
+
+/// The abstract first, then related topics, which come grouped one level down
+fn duckduckgo_hits(body: &Value) -> Vec<Hit> {
+    let mut hits = Vec::new();
+    if let Some(url) = body["AbstractURL"].as_str().filter(|u| !u.is_empty()) {
+        hits.push(Hit {
+            title: body["Heading"].as_str().unwrap_or_default().to_string(),
+            url: url.to_string(),
+            snippet: clean(body["AbstractText"].as_str().unwrap_or_default()),
+        });
+    }
+    let topics = body["RelatedTopics"].as_array().into_iter().flatten();
+    for topic in topics.flat_map(|t| t["Topics"].as_array().cloned().unwrap_or_else(|| vec![t.clone()])) {
+        let (Some(url), Some(text)) = (topic["FirstURL"].as_str(), topic["Text"].as_str()) else {
+            continue;
+        };
+        hits.push(Hit {
+            title: text.split(" - ").next().unwrap_or(text).to_string(),
+            url: url.to_string(),
+            snippet: clean(text),
+        });
+    }
+    hits
+}
+
+fn clean(snippet: &str) -> String {
+    let snippet = snippet.split_whitespace().collect::<Vec<_>>().join(" ");
+    match snippet.char_indices().nth(MAX_SNIPPET_CHARS) {
+        Some((cut, _)) => format!("{}...", &snippet[..cut]),
+        None => snippet,
+    }
+}
+
+/// The web_search tool; the result URLs become the sources listed under the reply
+pub struct SearchTool(pub SearchConfig);
+
+#[async_trait]
+impl Handler for SearchTool {
+    async fn call(&self, arguments: &Value) -> Result<String> {
+        Ok(self.call_sourced(arguments).await?.0)
+    }
+
+    async fn call_sourced(&self, arguments: &Value) -> Result<(String, Vec<String>)> {
+        let query = arguments
+            .get("query")
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .context("query is missing")?;
+        let hits = self.0.search(query).await?;
+        if hits.is_empty() {
+            return Ok((format!("No results for {:?}", query), Vec::new()));
+        }
+        let mut text = format!("Results for {:?}, cite the URLs you use:\n", query);
+        for (i, hit) in hits.iter().enumerate() {
+            text.push_str(&format!("\n{}. {}\n   {}\n   {}\n", i + 1, hit.title, hit.url, hit.snippet));
+        }
+        Ok((text, hits.into_iter().map(|hit| hit.url).collect()))
+    }
+}
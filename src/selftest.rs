@@ -0,0 +1,243 @@
+use anyhow::{Context, Result};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::json;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::llm::Message;
+use crate::logger;
+use crate::session::SessionContext;
+
+/// How long the scripted client waits for each expected reply
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+const NAME: &str = "Selftest";
+const QUESTION: &str = "hello from the selftest";
+
+#[derive(Deserialize)]
+struct MockRequest {
+    messages: Vec<Message>,
+    #[serde(default)]
+    stream: bool,
+}
+
+/// What the mock backend answers to `message`
+fn echo(message: &str) -> String {
+    format!("echo: {}", message)
+}
+
+/// Answer chat completions by echoing the last user message, streamed word by word if asked
+async fn mock_completion(Json(request): Json<MockRequest>) -> Response {
+    let last = request
+        .messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .map_or("", |m| m.content.as_str());
+    let reply = echo(last);
+
+    if !request.stream {
+        let body = json!({
+            "choices": [{
+                "message": { "role": "assistant", "content": reply },
+                "finish_reason": "stop",
+            }],
+        });
+        return Json(body).into_response();
+    }
+
+    let mut body = String::new();
+    for word in reply.split_inclusive(' ') {
+        let chunk = json!({ "choices": [{ "delta": { "content": word } }] });
+        body.push_str(&format!("data: {}\n\n", chunk));
+    }
+    let last = json!({ "choices": [{ "delta": {}, "finish_reason": "stop" }] });
+    body.push_str(&format!("data: {}\n\ndata: [DONE]\n\n", last));
+    ([("content-type", "text/event-stream")], body).into_response()
+}
+
+/// Start an OpenAI-compatible mock backend on an ephemeral loopback port
+pub async fn mock_backend() -> Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .context("Failed to start the mock backend")?;
+    let addr = listener.local_addr()?;
+    let app = Router::new().route("/v1/chat/completions", post(mock_completion));
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+    Ok(addr)
+}
+
+/// A telnet user reading raw output
+struct Client {
+    stream: TcpStream,
+    seen: String,
+}
+
+impl Client {
+    async fn connect(addr: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await.context("Failed to connect")?;
+        Ok(Self {
+            stream,
+            seen: String::new(),
+        })
+    }
+
+    async fn send(&mut self, line: &str) -> Result<()> {
+        self.stream.write_all(format!("{}\r\n", line).as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Read until `needle` shows up and consume the output up to it
+    async fn expect(&mut self, needle: &str) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            if let Some(at) = self.seen.find(needle) {
+                self.seen.drain(..at + needle.len());
+                return Ok(());
+            }
+            let read = tokio::time::timeout(STEP_TIMEOUT, self.stream.read(&mut buf))
+                .await
+                .with_context(|| format!("Timed out waiting for {:?}", needle))??;
+            if read == 0 {
+                anyhow::bail!("Connection closed before {:?}", needle);
+            }
+            self.seen.push_str(&String::from_utf8_lossy(&buf[..read]));
+        }
+    }
+
+    /// Wait for the server to hang up
+    async fn expect_closed(&mut self) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let read = tokio::time::timeout(STEP_TIMEOUT, self.stream.read(&mut buf))
+                .await
+                .context("Timed out waiting for the server to disconnect")??;
+            if read == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Outcome of each step, in order
+#[derive(Default)]
+pub struct Report {
+    steps: Vec<(&'static str, Result<()>)>,
+}
+
+impl Report {
+    fn check(&mut self, step: &'static str, result: Result<()>) {
+        self.steps.push((step, result));
+    }
+
+    /// Print one line per step, returning the number of failures
+    pub fn print(&self) -> usize {
+        for (step, result) in &self.steps {
+            match result {
+                Ok(()) => println!("PASS  {}", step),
+                Err(e) => println!("FAIL  {}: {:#}", step, e),
+            }
+        }
+        self.steps.iter().filter(|(_, result)| result.is_err()).count()
+    }
+}
+
+/// The chat log written for the named user contains the exchange
+fn check_log(ctx: &SessionContext) -> Result<()> {
+    let dir = logger::named_client_dir(&ctx.logs_dir, IpAddr::V4(Ipv4Addr::LOCALHOST), NAME)
+        .context("No directory for the selftest user")?
+        .join("chats");
+    let logged = fs::read_dir(&dir)
+        .with_context(|| format!("No chat logs in {}", dir.display()))?
+        .flatten()
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+        .any(|log| log.contains(QUESTION) && log.contains(&echo(QUESTION)));
+    if !logged {
+        anyhow::bail!("The exchange is missing from {}", dir.display());
+    }
+    Ok(())
+}
+
+/// Drive two sessions through the server at `addr`: chat, commands, logging and
+/// a returning visit that finds the saved profile and conversation
+pub async fn run(addr: SocketAddr, ctx: &SessionContext) -> Report {
+    let mut report = Report::default();
+    let prompt = ctx.labels.prompt();
+
+    let mut client = match Client::connect(addr).await {
+        Ok(client) => client,
+        Err(e) => {
+            report.check("connect", Err(e));
+            return report;
+        }
+    };
+    report.check("welcome banner", client.expect("/help").await);
+    report.check("input prompt", client.expect(&prompt).await);
+
+    let result = async {
+        client.send(&format!("/name {}", NAME)).await?;
+        client.expect(&format!("Name set to: {}", NAME)).await?;
+        client.expect(&prompt).await
+    };
+    report.check("/name", result.await);
+
+    let result = async {
+        client.send(QUESTION).await?;
+        client.expect(&echo(QUESTION)).await?;
+        client.expect(&prompt).await
+    };
+    report.check("streamed reply", result.await);
+
+    let result = async {
+        client.send("/help").await?;
+        client.expect("/quit").await?;
+        client.expect(&prompt).await
+    };
+    report.check("/help", result.await);
+
+    let result = async {
+        client.send("/quit").await?;
+        client.expect_closed().await
+    };
+    report.check("/quit", result.await);
+
+    report.check("chat log", check_log(ctx));
+
+    let mut client = match Client::connect(addr).await {
+        Ok(client) => client,
+        Err(e) => {
+            report.check("reconnect", Err(e));
+            return report;
+        }
+    };
+    let result = async {
+        client.expect(&prompt).await?;
+        client.send(&format!("/name {}", NAME)).await?;
+        client.expect(&format!("Welcome back, {}!", NAME)).await?;
+        client.expect(&prompt).await
+    };
+    report.check("profile persisted", result.await);
+
+    let result = async {
+        client.send("/conversations").await?;
+        client.expect(QUESTION).await?;
+        client.expect(&prompt).await
+    };
+    report.check("conversation index", result.await);
+
+    let result = async {
+        client.send("/quit").await?;
+        client.expect_closed().await
+    };
+    report.check("second /quit", result.await);
+
+    report
+}
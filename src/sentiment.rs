@@ -0,0 +1,77 @@
+use serde::Deserialize;
+
+/// Words and phrases of an upset user, matched on whole words in lowercase
+const NEGATIVE: &[&str] = &[
+    "useless", "stupid", "idiot", "dumb", "terrible", "awful", "worst", "ridiculous", "hate",
+    "annoying", "frustrated", "frustrating", "angry", "pissed", "wtf", "waste of time",
+    "doesn't work", "does not work", "not working", "still broken", "still wrong", "wrong again",
+    "you don't understand", "not what i asked", "listen to me", "fed up", "sick of", "give up",
+];
+
+const POSITIVE: &[&str] = &[
+    "thank you", "thanks", "great", "perfect", "awesome", "helpful", "that worked", "works now",
+    "appreciate", "nice",
+];
+
+/// Level lost per message, so calm messages let frustration fade
+const DECAY: f32 = 0.7;
+
+/// Rough mood of one message: below zero upset, above zero pleased
+pub fn score(text: &str) -> f32 {
+    let lower = text.to_lowercase();
+    let words: Vec<&str> = lower
+        .split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|w| !w.is_empty())
+        .collect();
+    let padded = format!(" {} ", words.join(" "));
+    let count = |phrases: &[&str]| {
+        phrases
+            .iter()
+            .filter(|phrase| padded.contains(&format!(" {} ", phrase)))
+            .count() as f32
+    };
+
+    let mut score = count(POSITIVE) * 0.5 - count(NEGATIVE);
+    // Shouting and piled-up punctuation
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.len() >= 8 && letters.iter().filter(|c| c.is_uppercase()).count() * 10 >= letters.len() * 8 {
+        score -= 1.0;
+    }
+    if text.contains("!!") || text.contains("?!") || text.contains("??") {
+        score -= 0.5;
+    }
+    score
+}
+
+/// Frustration built up over a conversation
+#[derive(Debug, Default)]
+pub struct Frustration {
+    level: f32,
+}
+
+impl Frustration {
+    /// Take the next user message into account, returning the new level
+    pub fn observe(&mut self, text: &str) -> f32 {
+        self.level = (self.level * DECAY - score(text)).max(0.0);
+        self.level
+    }
+}
+
+fn default_threshold() -> f32 {
+    3.0
+}
+
+/// What a session does once the user is frustrated, `[frustration]` in the config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FrustrationPolicy {
+    /// Level to react at; each upset message adds about 1
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    /// Persona to switch to, typically a calmer variant of the usual one
+    #[serde(default)]
+    pub persona: Option<String>,
+    /// Queue the conversation for human review like /human
+    #[serde(default)]
+    pub flag: bool,
+}
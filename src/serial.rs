@@ -0,0 +1,143 @@
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::session::SessionContext;
+
+/// Handshaking on the serial line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FlowControl {
+    None,
+    /// XON/XOFF, for terminals without the RTS/CTS wires
+    Software,
+    /// RTS/CTS
+    Hardware,
+}
+
+/// Serve one session after another on the terminal at `path`, reopening the
+/// port whenever a session ends or the line fails. With `modem` set to its
+/// init string, every session is a call the modem answers first. With
+/// `attract` set, each session waits for a key and the attract loop plays
+/// whenever the terminal sits idle that long.
+#[cfg(feature = "serial")]
+pub fn start(
+    path: String,
+    baud: u32,
+    flow: FlowControl,
+    modem: Option<String>,
+    attract: Option<Duration>,
+    ctx: &Arc<SessionContext>,
+) -> Result<()> {
+    match &modem {
+        Some(_) => tracing::info!("Modem on {} at {} baud, {:?} flow control", path, baud, flow),
+        None => tracing::info!("Serial terminal on {} at {} baud, {:?} flow control", path, baud, flow),
+    }
+    tokio::spawn(serve(path, baud, flow, modem, attract, Arc::clone(ctx)));
+    Ok(())
+}
+
+#[cfg(not(feature = "serial"))]
+pub fn start(
+    _path: String,
+    _baud: u32,
+    _flow: FlowControl,
+    _modem: Option<String>,
+    _attract: Option<Duration>,
+    _ctx: &Arc<SessionContext>,
+) -> Result<()> {
+    crate::features::require("serial", "--serial")
+}
+
+#[cfg(feature = "serial")]
+async fn serve(
+    path: String,
+    baud: u32,
+    flow: FlowControl,
+    modem: Option<String>,
+    attract: Option<Duration>,
+    ctx: Arc<SessionContext>,
+) {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use tokio_serial::SerialPortBuilderExt;
+    use tracing::{info, warn};
+
+    use crate::attract;
+    use crate::audit::Outcome;
+    use crate::modem;
+    use crate::session::Session;
+    use crate::supervisor;
+
+    /// A serial line has no peer address, its sessions log and register under this one
+    const SERIAL_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+    /// Pause before the next session, so a failing line doesn't spin
+    const REOPEN_DELAY: Duration = Duration::from_secs(2);
+
+    let flow = match flow {
+        FlowControl::None => tokio_serial::FlowControl::None,
+        FlowControl::Software => tokio_serial::FlowControl::Software,
+        FlowControl::Hardware => tokio_serial::FlowControl::Hardware,
+    };
+    // Ten bits per character on the wire with the start and stop bit
+    let chars_per_second = u64::from(baud / 10).max(1);
+    let mut stopped = ctx.handover.stopped();
+    let mut pairs = None;
+    loop {
+        // The port goes to the new process along with the listeners
+        if ctx.handover.is_stopped() {
+            break;
+        }
+        match tokio_serial::new(&path, baud).flow_control(flow).open_native_async() {
+            Ok(port) if ctx.maintenance.banner().is_none() && !ctx.bans.is_banned(SERIAL_ADDR.ip()) => {
+                let session = match &modem {
+                    None => {
+                        let mut port = port;
+                        let ready = match attract {
+                            Some(idle) => tokio::select! {
+                                waited = attract::wait_for_key(&mut port, idle, &ctx, &mut pairs) => match waited {
+                                    Ok(()) => true,
+                                    Err(e) => {
+                                        warn!("Serial terminal on {}: {}", path, e);
+                                        false
+                                    }
+                                },
+                                _ = stopped.changed() => break,
+                            },
+                            None => true,
+                        };
+                        ready.then(|| {
+                            Session::new(port, SERIAL_ADDR, Arc::clone(&ctx), None, None).over_serial(chars_per_second)
+                        })
+                    }
+                    Some(init) => tokio::select! {
+                        answered = modem::answer(port, init) => match answered {
+                            Ok(call) => {
+                                // The modems' link is usually slower than the port
+                                let pace = call.speed.map_or(chars_per_second, |bps| (bps / 10).max(1));
+                                Some(Session::new(call.line, SERIAL_ADDR, Arc::clone(&ctx), None, None).over_serial(pace))
+                            }
+                            Err(e) => {
+                                warn!("Modem on {}: {:#}", path, e);
+                                None
+                            }
+                        },
+                        _ = stopped.changed() => break,
+                    },
+                };
+                if let Some(session) = session {
+                    info!("Serial session started on {}", path);
+                    let outcome = if ctx.honeypot { Outcome::Honeypot } else { Outcome::Served };
+                    ctx.audit.record(SERIAL_ADDR.ip(), outcome, None);
+                    // Closing the port afterwards drops DTR, which hangs up a modem
+                    supervisor::supervise(session, SERIAL_ADDR, Arc::clone(&ctx)).await;
+                }
+            }
+            // Closed again right away, the terminal waits for maintenance or the ban to end
+            Ok(_) => {}
+            Err(e) => warn!("Failed to open serial port {}: {}", path, e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(REOPEN_DELAY) => {}
+            _ = stopped.changed() => break,
+        }
+    }
+}
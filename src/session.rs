@@ -1,14 +1,57 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, Local};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tracing::{info, warn};
 
-use crate::llm::{LlmClient, Message};
-use crate::logger::ChatLogger;
+use crate::admin::{Control, Maintenance, Registry, SessionUsage};
+use crate::alias::{Aliases, Route};
+use crate::abuse::{AbuseDetector, BanList, LoginThrottle, Verdict, STRIKE_LIMIT};
+use crate::accounts;
+use crate::audit::{AuditLog, Outcome};
+use crate::challenge::{self, Challenge};
+use crate::config::LiveConfig;
+use crate::digest::ErrorTally;
+use crate::escalation::{self, Escalation, Handoff};
+#[cfg(feature = "tools")]
+use crate::fetch::FetchPolicy;
+use crate::geoip::GeoTag;
+use crate::greeting;
+use crate::handover::Handover;
+use crate::index;
+use crate::interop;
+use crate::kiosk::{self, Kiosk, KioskLimits};
+use crate::lang;
+use crate::llm::{Completion, LlmProvider, Message, ModelProfile, TokenUsage, Tuning, Upstream};
+use crate::logger::{self, ChatLogger, LogFormat, QuotaRule, ResponseMeta, Tags};
+use crate::logsearch;
+use crate::models::ModelCatalog;
+use crate::noise;
+use crate::persona::{Labels, Persona};
+use crate::protocol::{InputEvent, InputParser};
+use crate::redact::Redactor;
+use crate::render::{self, render_response};
+use crate::schedule::{ActiveSchedule, Schedule};
+use crate::sentiment::Frustration;
+use crate::signing;
+use crate::snapshot::{self, Snapshot};
+use crate::spill::Spill;
+use crate::stats::CommandTally;
+use crate::supervisor::Supervisor;
+use crate::telnet;
+use crate::template::TemplateFill;
+use crate::throttle::ThrottledWriter;
+use crate::tokens::{self, Fit};
+use crate::tools::ToolRegistry;
+use crate::webhook::Webhook;
+use crate::wizard::{Progress, WizardRun};
 
-const WELCOME_BANNER: &str = r#"
+const WELCOME_LOGO: &str = r#"
 ╔═══════════════════════════════════════════════════════════════╗
 ║                                                               ║
 ║   ████████╗███████╗██╗     ██╗     ██╗     ███╗   ███╗        ║
@@ -20,29 +63,224 @@ const WELCOME_BANNER: &str = r#"
 ║                                                               ║
 ║           Telnet LLM Chat Server                              ║
 ╚═══════════════════════════════════════════════════════════════╝
+"#;
 
-Commands:
-  /name <your name>  - Set your name
-  /clear             - Clear conversation history
-  /help              - Show this help
-  /quit              - Disconnect
+/// A slash command with its aliases and the line it gets in the welcome banner and /help
+struct Command {
+    names: &'static [&'static str],
+    usage: &'static str,
+    help: &'static str,
+}
 
-Type your message and press Enter to chat with the AI.
-"#;
+const fn command(names: &'static [&'static str], usage: &'static str, help: &'static str) -> Command {
+    Command { names, usage, help }
+}
+
+const COMMAND_TABLE: [Command; 25] = [
+    command(&["/name"], "/name <your name>", "Set your name"),
+    command(&["/register", "/login"], "/register, /login", "Keep your history in an account"),
+    command(&["/clear"], "/clear", "Clear conversation history"),
+    command(&["/persona"], "/persona <name>", "Talk to another persona"),
+    command(&["/conversations"], "/conversations", "List your past conversations"),
+    command(&["/search"], "/search <words>", "Find messages in your past conversations"),
+    command(&["/lang"], "/lang <language>", "Pin the reply language (/lang auto to detect)"),
+    command(&["/tz"], "/tz <offset>", "Set your timezone, e.g. /tz +02:00"),
+    command(&["/contribute"], "/contribute on|off", "Offer your conversations as training data"),
+    command(&["/starters"], "/starters", "Show suggested conversation starters"),
+    command(&["/template"], "/template <name>", "Fill in a guided prompt step by step"),
+    command(&["/wizard"], "/wizard <name>", "Go through a guided multi-step flow"),
+    command(&["/models"], "/models [refresh]", "List the models you can switch to"),
+    command(&["/model"], "/model <name>", "Switch to another model"),
+    command(&["/set"], "/set [name value]", "Show or change generation settings"),
+    command(&["/json"], "/json [on|off]", "Ask for replies as JSON"),
+    command(&["/human"], "/human [reason]", "Ask for a human operator"),
+    command(&["/fetch"], "/fetch <url>", "Have the AI summarize a web page"),
+    command(&["/debug"], "/debug prompt", "Show what would be sent to the LLM next"),
+    command(&["/more", "/full"], "/more, /full", "Show the rest of a long response"),
+    command(&["/resend"], "/resend", "Send a line held back as line noise"),
+    command(&["/import"], "/import", "Paste an OpenAI-format JSON conversation"),
+    command(&["/export"], "/export openai", "Export as JSON (--system, --file)"),
+    command(&["/help", "/?"], "/help", "Show this help"),
+    command(&["/quit", "/exit", "/q"], "/quit", "Disconnect"),
+];
+
+/// Slash commands counted per day for `telllm stats`, aliases on their own.
+/// Anything else typed after a slash is left out, so typos don't pile up.
+pub fn command_names() -> impl Iterator<Item = &'static str> {
+    COMMAND_TABLE.iter().flat_map(|c| c.names.iter().copied())
+}
+
+/// Whether a session would run the command rather than call it unknown
+fn offered(command: &Command, ctx: &SessionContext) -> bool {
+    match command.names[0] {
+        "/fetch" => ctx.fetch_enabled(),
+        "/resend" => ctx.noise_filter && !ctx.honeypot,
+        "/register" => ctx.kiosk.is_none(),
+        _ => true,
+    }
+}
+
+/// The command list of the welcome banner and /help, without commands this server turned off
+fn command_help(ctx: &SessionContext) -> String {
+    let mut out = String::from("\nCommands:\n");
+    for command in COMMAND_TABLE.iter().filter(|c| offered(c, ctx)) {
+        out.push_str(&format!("  {:<18} - {}\n", command.usage, command.help));
+    }
+    out
+}
+
+/// How long a signed client has to send its AUTH line
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound for a conversation pasted with /import
+const MAX_IMPORT_BYTES: usize = 512 * 1024;
+
+/// Longest streamed reply, the generation is cut off beyond it
+const MAX_REPLY_BYTES: usize = 256 * 1024;
+
+/// Lines of a long response kept for /more, the rest is only in the transcript
+const MAX_OVERFLOW_LINES: usize = 2000;
+
+/// Matches /search shows at most
+const MAX_SEARCH_HITS: usize = 10;
+
+/// Generated greetings kept before the cache is reset
+const GREETING_CACHE_SIZE: usize = 1000;
+
+/// Numbered menu of operator-defined conversation starters
+fn starters_menu(starters: &[String]) -> String {
+    let mut menu = String::from("\nNot sure where to start? Type a number:\n");
+    for (i, starter) in starters.iter().enumerate() {
+        menu.push_str(&format!("  {}. {}\n", i + 1, starter));
+    }
+    menu
+}
+
+/// Instruction for the single follow-up sent to a quiet user
+const NUDGE_PROMPT: &str = "(The user has been quiet for a while. Send one short, gentle \
+follow-up about what we were discussing, or ask if there is anything else. Don't mention \
+that they were quiet.)";
+
+/// Share of --context-tokens at which older turns are summarized
+const SUMMARIZE_AT_PERCENT: usize = 75;
+/// Latest messages left as they are when the rest is summarized
+const SUMMARY_KEEP_RECENT: usize = 4;
+/// Length limit of the summary reply
+const SUMMARY_MAX_TOKENS: u32 = 400;
+/// Instruction for folding older turns into a summary
+const SUMMARY_PROMPT: &str = "Summarize the conversation below for the assistant that continues it. \
+Keep names, facts, decisions, open questions and anything the user asked to remember. \
+Write plain prose in the conversation's language, at most a few short paragraphs, and nothing else.";
+/// Instruction for the record kept of a conversation cleared by --reset-after
+const RESET_SUMMARY_PROMPT: &str = "Summarize the conversation below for the operator's records: \
+what the visitor asked about and what they were told, in a few sentences of plain prose and nothing else.";
+
+/// Added to the system prompt with --noise-filter
+const NOISE_PROMPT: &str = "The user types on an old terminal over a line that sometimes garbles characters. \
+Read past stray or corrupted characters and answer what they most likely meant; only ask them to retype \
+when you really can't tell.";
+
+/// Added to the system prompt with /json on; OpenAI's JSON mode also wants the word JSON in the messages
+const JSON_PROMPT: &str = "Reply with a single valid JSON object and nothing else: no prose, no code fences. \
+    Choose fitting keys if the user doesn't name them.";
+
+const HONEYPOT_REPLY: &str = "Sorry, all models are busy right now. Please try again later.";
+
+/// What woke the session up
+enum Event {
+    Line(Option<String>),
+    Idle,
+    Control(Control),
+    /// The --kiosk time is up
+    TimeUp,
+    /// Nobody typed for --reset-after
+    Reset,
+}
 
 enum CommandResult {
     Quit,
-    Continue,
     Message(String),
+    /// A wizard was started and runs up to its first question
+    Wizard,
+    /// List the backend's models, asking it again if set
+    Models(bool),
+    /// Ask for a human operator, with the user's reason if given
+    Human(Option<String>),
+    /// Check or store a password, which is too slow to do on the async workers
+    SignIn(SignIn),
+}
+
+/// A /login or /register with its credentials
+struct SignIn {
+    register: bool,
+    username: String,
+    password: String,
 }
 
 struct SessionState {
     messages: Vec<Message>,
     user_name: Option<String>,
+    /// JSON collected while the user is pasting after /import
+    import_buffer: Option<String>,
+    /// Reply language chosen with /lang, wins over detection
+    pinned_lang: Option<String>,
+    /// Language of the latest user message
+    detected_lang: Option<&'static str>,
+    /// Whether a bare number picks a conversation starter
+    starters_active: bool,
+    /// The idle follow-up is sent at most once per session
+    nudged: bool,
+    /// Lines of the last response held back by the display cap
+    overflow: Vec<String>,
+    /// Logged-in account name
+    account: Option<String>,
+    /// IP client directory waiting for the user to decide whether to link it
+    pending_link: Option<PathBuf>,
+    /// Human check a new visitor has to pass before the first LLM call
+    challenge: Option<Challenge>,
+    /// First message, sent once the challenge is solved
+    held_input: Option<String>,
+    challenge_failures: u32,
+    /// System prompt before the user's name is added, the persona's if one is active
+    base_prompt: String,
+    labels: Labels,
+    persona: Option<String>,
+    /// Model chosen when the session started or with /model
+    model: String,
+    /// Generation settings changed with /set
+    tuning: Tuning,
+    /// Conversation from before a restart, waiting for a yes or no
+    pending_restore: Option<Snapshot>,
+    /// Template started with /template, waiting for its next slot
+    template_fill: Option<TemplateFill>,
+    /// Wizard started with /wizard, waiting for the next answer
+    wizard: Option<WizardRun>,
+    /// The user asked for a human with /human
+    escalated: bool,
+    /// How upset the user seems so far
+    frustration: Frustration,
+    /// The `[frustration]` reaction already happened
+    de_escalated: bool,
+    /// Outside office hours: the /human reason, waiting for contact details
+    pending_handoff: Option<Option<String>>,
+    /// Attached operator, who gets the user's messages instead of the AI
+    operator: Option<UnboundedSender<String>>,
+    /// Turns beyond `--max-history`, no longer sent to the model
+    spill: Option<Spill>,
+    /// Tokens of the session's replies, as far as the backend reported them
+    token_usage: TokenUsage,
+    /// What the model wrote about the turns folded away near --context-tokens
+    summary: Option<String>,
+    /// What is left of the visitor's allowance with --kiosk
+    kiosk: Option<Kiosk>,
+    /// Tell the model that input may be garbled, with --noise-filter
+    noise_filter: bool,
+    /// A line held back as mostly noise, sent by /resend
+    noisy_line: Option<String>,
 }
 
 impl SessionState {
-    fn new(system_prompt: &str, user_name: Option<String>) -> Self {
+    fn new(system_prompt: &str, user_name: Option<String>, labels: Labels, model: String) -> Self {
         let full_prompt = Self::build_system_prompt(system_prompt, user_name.as_deref());
         Self {
             messages: vec![Message {
@@ -50,7 +288,184 @@ impl SessionState {
                 content: full_prompt,
             }],
             user_name,
+            import_buffer: None,
+            pinned_lang: None,
+            detected_lang: None,
+            starters_active: false,
+            nudged: false,
+            overflow: Vec::new(),
+            account: None,
+            pending_link: None,
+            challenge: None,
+            held_input: None,
+            challenge_failures: 0,
+            base_prompt: system_prompt.to_string(),
+            labels,
+            persona: None,
+            model,
+            tuning: Tuning::default(),
+            pending_restore: None,
+            template_fill: None,
+            wizard: None,
+            escalated: false,
+            frustration: Frustration::default(),
+            de_escalated: false,
+            pending_handoff: None,
+            operator: None,
+            spill: None,
+            token_usage: TokenUsage::default(),
+            summary: None,
+            kiosk: None,
+            noise_filter: false,
+            noisy_line: None,
+        }
+    }
+
+    /// Input prompt, after the time and messages left with --kiosk
+    fn prompt(&self) -> String {
+        match &self.kiosk {
+            Some(kiosk) => format!("{} {}", kiosk.countdown(), self.labels.prompt()),
+            None => self.labels.prompt(),
+        }
+    }
+
+    /// Move the oldest turns to disk until at most `max` messages follow the
+    /// system prompt. What stays starts on a user message, so it may be a
+    /// little shorter, or longer when no user message is near the end.
+    fn bound_history(&mut self, max: usize, addr: &SocketAddr) {
+        let Some(spill) = self.spill.as_mut() else {
+            return;
+        };
+        if self.messages.len() - 1 <= max {
+            return;
+        }
+        let Some(end) = (self.messages.len() - max..self.messages.len()).find(|&i| self.messages[i].role == "user") else {
+            return;
+        };
+        match spill.append(&self.messages[1..end]) {
+            Ok(()) => {
+                self.messages.drain(1..end);
+            }
+            // Keep them in memory rather than lose them
+            Err(e) => warn!("Failed to spill history of {}: {}", addr, e),
+        }
+    }
+
+    /// The whole conversation, with the turns moved to disk read back in
+    fn full_history(&self) -> Vec<Message> {
+        let spilled = match self.spill.as_ref().map(Spill::load).transpose() {
+            Ok(spilled) => spilled.unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to read spilled history: {}", e);
+                Vec::new()
+            }
+        };
+        let mut messages = Vec::with_capacity(spilled.len() + self.messages.len());
+        messages.extend(self.messages.first().cloned());
+        messages.extend(spilled);
+        messages.extend(self.messages.iter().skip(1).cloned());
+        messages
+    }
+
+    /// Rough size of the conversation held in memory
+    fn history_bytes(&self) -> u64 {
+        self.messages
+            .iter()
+            .map(|m| (m.role.len() + m.content.len() + size_of::<Message>()) as u64)
+            .sum()
+    }
+
+    /// Drop everything but the system prompt, on disk too
+    fn clear_history(&mut self) {
+        self.messages.truncate(1);
+        self.summary = None;
+        if let Some(spill) = self.spill.as_mut() {
+            spill.clear();
+        }
+    }
+
+    /// Cap how much of a response is shown at once, the rest waits for /more or /full
+    fn take_display(&mut self, rendered: &str, max_lines: Option<usize>) -> String {
+        self.overflow = rendered.lines().take(MAX_OVERFLOW_LINES).map(|l| l.to_string()).collect();
+        let count = match max_lines {
+            Some(max) if self.overflow.len() > max => max,
+            _ => self.overflow.len(),
+        };
+        self.next_page(count)
+    }
+
+    fn next_page(&mut self, count: usize) -> String {
+        let count = count.min(self.overflow.len());
+        let mut page = self.overflow.drain(..count).collect::<Vec<_>>().join("\n");
+        page.push_str(&self.more_hint());
+        page
+    }
+
+    /// How to see the rest of a response that didn't fit, empty when it all did
+    fn more_hint(&self) -> String {
+        if self.overflow.is_empty() {
+            return String::new();
+        }
+        format!(
+            "\n[{} more lines: /more to continue, /full for the rest]",
+            self.overflow.len()
+        )
+    }
+
+    /// Messages to send for the next turn, with the reply language instruction
+    fn request_messages(&self, ctx: &SessionContext) -> Vec<Message> {
+        self.fitted_request(ctx).0
+    }
+
+    /// The request for the next turn, cut down to --context-tokens, and what that took
+    fn fitted_request(&self, ctx: &SessionContext) -> (Vec<Message>, Fit) {
+        let mut messages = self.unfitted_request();
+        let fit = match ctx.context_tokens {
+            Some(limit) => tokens::fit(&mut messages, limit, ctx.route(&self.model, &self.tuning).tuning.max_tokens),
+            None => Fit::default(),
+        };
+        (messages, fit)
+    }
+
+    /// The conversation with the summary of older turns and the reply language added
+    fn unfitted_request(&self) -> Vec<Message> {
+        let mut messages = self.messages.clone();
+        if let Some(summary) = &self.summary {
+            messages.insert(
+                1,
+                Message {
+                    role: "system".to_string(),
+                    content: format!("Summary of the earlier conversation:\n{}", summary),
+                },
+            );
+        }
+        let lang = self.pinned_lang.as_deref().or(self.detected_lang);
+        if let Some(lang) = lang
+            && let Some(system) = messages.first_mut()
+        {
+            system
+                .content
+                .push_str(&format!("\n\nAlways reply in {}.", lang));
+        }
+        if self.noise_filter
+            && let Some(system) = messages.first_mut()
+        {
+            system.content.push_str(&format!("\n\n{}", NOISE_PROMPT));
+        }
+        if self.tuning.json
+            && let Some(system) = messages.first_mut()
+        {
+            system.content.push_str(&format!("\n\n{}", JSON_PROMPT));
         }
+        messages
+    }
+
+    /// Replace the conversation with imported messages, keeping our system prompt
+    fn load_history(&mut self, messages: Vec<Message>) -> usize {
+        self.clear_history();
+        self.messages
+            .extend(messages.into_iter().filter(|m| m.role != "system"));
+        self.messages.len() - 1
     }
 
     fn build_system_prompt(base_prompt: &str, user_name: Option<&str>) -> String {
@@ -63,203 +478,2323 @@ impl SessionState {
         }
     }
 
-    fn update_user_name(&mut self, name: &str, base_prompt: &str) {
+    fn update_user_name(&mut self, name: &str) {
         self.user_name = Some(name.to_string());
-        // Update the system prompt with the new name
+        self.refresh_system_prompt();
+    }
+
+    /// Rebuild the system message after the name or base prompt changed
+    fn refresh_system_prompt(&mut self) {
         if let Some(msg) = self.messages.first_mut() {
-            msg.content = Self::build_system_prompt(base_prompt, Some(name));
+            msg.content = Self::build_system_prompt(&self.base_prompt, self.user_name.as_deref());
+        }
+    }
+
+    /// Take on a persona's prompt and labels, or go back to the defaults with `None`
+    fn set_persona(&mut self, persona: Option<&Persona>, ctx: &SessionContext) {
+        self.labels = persona.map_or_else(|| ctx.labels.clone(), |p| p.labels(&ctx.labels));
+        self.persona = persona.map(|p| p.name.clone());
+        self.choose_base_prompt(ctx);
+    }
+
+    /// Rebuild the system message from the persona's prompt, else the model's,
+    /// else --system-prompt, after either changed
+    fn choose_base_prompt(&mut self, ctx: &SessionContext) {
+        let config = ctx.config.get();
+        let persona = self.persona.as_deref().and_then(|name| config.persona(name));
+        self.base_prompt = persona
+            .and_then(|p| p.system_prompt.clone())
+            .or_else(|| ctx.route(&self.model, &self.tuning).system_prompt.map(str::to_string))
+            .unwrap_or_else(|| ctx.system_prompt.clone());
+        self.refresh_system_prompt();
+    }
+
+    /// Welcome screen with the current persona's art in place of the logo
+    fn banner(&self, ctx: &SessionContext) -> String {
+        let config = ctx.config.get();
+        let logo = self
+            .persona
+            .as_deref()
+            .and_then(|name| config.persona(name))
+            .and_then(Persona::banner)
+            .unwrap_or_else(|| WELCOME_LOGO.to_string());
+        format!("{}{}\nType your message and press Enter to chat with the AI.\n", logo, command_help(ctx))
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            saved_at: Local::now(),
+            messages: self.full_history(),
+            persona: self.persona.clone(),
+            pinned_lang: self.pinned_lang.clone(),
+            model: self.model.clone(),
+        }
+    }
+
+    /// Pick up a conversation saved by `snapshot`, returns the number of messages
+    fn restore(&mut self, snapshot: Snapshot, ctx: &SessionContext) -> usize {
+        self.model = snapshot.model;
+        let config = ctx.config.get();
+        let persona = snapshot.persona.as_deref().and_then(|name| config.persona(name));
+        self.set_persona(persona, ctx);
+        self.pinned_lang = snapshot.pinned_lang.or(self.pinned_lang.take());
+        self.load_history(snapshot.messages)
+    }
+
+    /// How the user is called in logs and exports
+    fn user_display_name(&self) -> &str {
+        self.user_name.as_deref().unwrap_or(&self.labels.user)
+    }
+
+    /// Log into or create the account, with failed logins slowed down per
+    /// address and account
+    async fn sign_in(&mut self, attempt: SignIn, logger: &mut ChatLogger, addr: &SocketAddr, ctx: &SessionContext) -> String {
+        let SignIn { register, username, password } = attempt;
+        let cmd = if register { "/register" } else { "/login" };
+        if let Some(wait) = ctx.logins.wait(addr.ip(), &username) {
+            warn!("{} as {} refused for {} after too many failed logins", cmd, username, addr);
+            return format!("\nToo many failed logins, try again in {}s.\n", wait.as_secs().max(1));
+        }
+
+        let logs_dir = ctx.logs_dir.clone();
+        let name = username.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            if register {
+                accounts::register(&logs_dir, &name, &password)
+            } else {
+                accounts::login(&logs_dir, &name, &password)
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("Failed to check the password: {}", e)));
+        match result {
+            Ok(dir) => {
+                ctx.logins.succeeded(addr.ip(), &username);
+                ctx.audit.record(addr.ip(), Outcome::Login, Some(&username));
+                self.enter_account(&username, dir, logger, addr, ctx)
+            }
+            Err(e) => {
+                warn!("{} as {} failed for {}: {}", cmd, username, addr, e);
+                ctx.audit.record(addr.ip(), Outcome::AuthFailed, Some(&username));
+                if !register {
+                    ctx.logins.failed(addr.ip(), &username);
+                }
+                format!("\n{}\n", e)
+            }
+        }
+    }
+
+    /// Continue the session in an account's directory and pick up its profile
+    fn enter_account(&mut self, username: &str, dir: PathBuf, logger: &mut ChatLogger, addr: &SocketAddr, ctx: &SessionContext) -> String {
+        if let Err(e) = logger.switch_to(dir) {
+            return format!("\nError opening account: {}\n", e);
+        }
+        self.account = Some(username.to_string());
+        self.challenge = None;
+        info!("User {} logged in as {}", addr, username);
+
+        let profile = logger.profile().unwrap_or_default();
+        let name = profile.name.as_deref().unwrap_or(username);
+        self.update_user_name(name);
+        if profile.lang.is_some() {
+            self.pinned_lang = profile.lang;
+        }
+
+        let mut msg = format!("\nLogged in as {}.\n", username);
+        let ip_dir = logger::client_dir(&ctx.logs_dir, addr.ip());
+        if ctx.kiosk.is_none() && accounts::has_history(&ip_dir) {
+            msg.push_str(
+                "\nThis address has chat history from before you logged in.\n\
+                 Import it into your account? (yes / move / no)\n  \
+                 yes  - copy it, the address keeps its copy\n  \
+                 move - copy it and delete it from the address\n",
+            );
+            self.pending_link = Some(ip_dir);
+        }
+        msg
+    }
+
+    /// Handle the answer to the offer made by `enter_account`
+    fn answer_link(&mut self, answer: &str, ip_dir: PathBuf, logger: &ChatLogger, addr: &SocketAddr) -> String {
+        let remove = match answer.to_lowercase().as_str() {
+            "yes" | "y" => false,
+            "move" => true,
+            _ => return "\nYour earlier history stays with this address.\n".to_string(),
+        };
+
+        match accounts::merge_history(&ip_dir, logger.client_dir(), remove) {
+            Ok(count) => {
+                info!(
+                    "User {} linked {} chat logs from {} into their account",
+                    addr,
+                    count,
+                    ip_dir.display()
+                );
+                if self.user_name.is_none()
+                    && let Some(name) = logger.profile().and_then(|p| p.name)
+                {
+                    self.update_user_name(&name);
+                }
+                format!("\nImported {} chat logs into your account.\n", count)
+            }
+            Err(e) => {
+                warn!("Failed to link history of {}: {}", addr, e);
+                format!("\nImport failed: {}\n", e)
+            }
         }
     }
 
-    fn handle_command(&mut self, input: &str, logger: &ChatLogger, addr: &SocketAddr, base_prompt: &str) -> CommandResult {
+    /// Run a slash command, counting it for `telllm stats` once it is known to be one
+    fn handle_command(&mut self, input: &str, logger: &mut ChatLogger, addr: &SocketAddr, ctx: &SessionContext) -> CommandResult {
         let parts: Vec<&str> = input.splitn(2, ' ').collect();
         let cmd = parts[0].to_lowercase();
         let arg = parts.get(1).map(|s| s.trim());
 
-        match cmd.as_str() {
+        match self.run_command(&cmd, arg, logger, addr, ctx) {
+            None => CommandResult::Message(format!("\nUnknown command: {}\n", cmd)),
+            Some(result) => {
+                ctx.commands.record(&cmd);
+                result
+            }
+        }
+    }
+
+    /// `None` for anything this session doesn't handle, disabled commands included
+    fn run_command(
+        &mut self,
+        cmd: &str,
+        arg: Option<&str>,
+        logger: &mut ChatLogger,
+        addr: &SocketAddr,
+        ctx: &SessionContext,
+    ) -> Option<CommandResult> {
+        let config = ctx.config.get();
+        Some(match cmd {
             "/quit" | "/exit" | "/q" => CommandResult::Quit,
             "/name" => {
                 if let Some(name) = arg {
-                    self.update_user_name(name, base_prompt);
-                    if let Err(e) = logger.update_summary("name", name) {
-                        return CommandResult::Message(format!("\nError saving name: {}\n", e));
+                    // Without an account every name gets its own profile on this IP
+                    let mut returning = false;
+                    if self.account.is_none()
+                        && ctx.kiosk.is_none()
+                        && let Some(dir) = logger::named_client_dir(&ctx.logs_dir, addr.ip(), name)
+                    {
+                        // The only named user behind an IP starts out in their own directory
+                        if dir != logger.client_dir()
+                            && let Err(e) = logger.switch_to(dir)
+                        {
+                            return Some(CommandResult::Message(format!("\nError opening profile: {}\n", e)));
+                        }
+                        if let Some(profile) = logger.profile() {
+                            returning = true;
+                            if profile.lang.is_some() {
+                                self.pinned_lang = profile.lang;
+                            }
+                        }
+                    }
+
+                    self.update_user_name(name);
+                    if let Err(e) = logger.update_profile(|p| p.name = Some(name.to_string())) {
+                        return Some(CommandResult::Message(format!("\nError saving name: {}\n", e)));
                     }
                     info!("User {} set name to: {}", addr, name);
-                    CommandResult::Message(format!("\nName set to: {}\n", name))
+                    if returning {
+                        CommandResult::Message(format!("\nWelcome back, {}!\n", name))
+                    } else {
+                        CommandResult::Message(format!("\nName set to: {}\n", name))
+                    }
                 } else {
                     CommandResult::Message("\nUsage: /name <your name>\n".to_string())
                 }
             }
+            "/register" | "/login" => {
+                // A kiosk session is wiped at the end, an account must not go with it
+                if ctx.kiosk.is_some() {
+                    return Some(CommandResult::Message("\nAccounts aren't available on this terminal.\n".to_string()));
+                }
+                if let Some(account) = &self.account {
+                    return Some(CommandResult::Message(format!("\nAlready logged in as {}.\n", account)));
+                }
+                let Some((username, password)) = arg.and_then(|a| a.split_once(' ')) else {
+                    return Some(CommandResult::Message(format!("\nUsage: {} <username> <password>\n", cmd)));
+                };
+                CommandResult::SignIn(SignIn {
+                    register: cmd == "/register",
+                    username: username.to_lowercase(),
+                    password: password.trim().to_string(),
+                })
+            }
             "/clear" => {
                 // Keep only system prompt
-                self.messages.truncate(1);
+                self.clear_history();
                 info!("User {} cleared conversation", addr);
                 CommandResult::Message("\nConversation cleared.\n".to_string())
             }
-            "/help" | "/?" => {
+            "/conversations" => {
+                let entries = index::newest_first(logger.chat_index());
+                if entries.is_empty() {
+                    return Some(CommandResult::Message("\nNo past conversations yet.\n".to_string()));
+                }
+                let mut out = String::from("\nYour conversations:\n");
+                for (date, entry) in entries.iter().take(20) {
+                    out.push_str(&format!(
+                        "  {}  {:>4} messages  {}\n",
+                        date,
+                        entry.messages,
+                        entry.title.as_deref().unwrap_or("(untitled)")
+                    ));
+                }
+                CommandResult::Message(out)
+            }
+            "/search" => {
+                const USAGE: &str = "\nUsage: /search <words> [since:YYYY-MM-DD] [until:YYYY-MM-DD]\n";
+                let query = match arg.map(logsearch::Query::parse) {
+                    None => return Some(CommandResult::Message(USAGE.to_string())),
+                    Some(Err(e)) => return Some(CommandResult::Message(format!("\n{}\n", e))),
+                    // Only the admin console looks at other clients
+                    Some(Ok(query)) if query.terms.is_empty() || query.client.is_some() => {
+                        return Some(CommandResult::Message(USAGE.to_string()));
+                    }
+                    Some(Ok(query)) => query,
+                };
+                let hits = logsearch::search_client(logger.client_dir(), &query, MAX_SEARCH_HITS);
+                if hits.is_empty() {
+                    return Some(CommandResult::Message("\nNothing found in your past conversations.\n".to_string()));
+                }
+                let mut out = String::from("\nBest matches in your past conversations:\n");
+                for hit in &hits {
+                    out.push_str(&format!(
+                        "  {}  {}: {}\n",
+                        hit.time.format("%d-%m-%Y %H:%M"),
+                        hit.role,
+                        hit.snippet(&query.terms)
+                    ));
+                }
+                CommandResult::Message(out)
+            }
+            "/persona" => match arg {
+                None => {
+                    if config.personas.is_empty() {
+                        return Some(CommandResult::Message("\nNo personas configured.\n".to_string()));
+                    }
+                    let names: Vec<&str> = config.personas.iter().map(|p| p.name.as_str()).collect();
+                    CommandResult::Message(format!(
+                        "\nCurrent persona: {}\nAvailable: {}\nUsage: /persona <name> | /persona default\n",
+                        self.persona.as_deref().unwrap_or("default"),
+                        names.join(", ")
+                    ))
+                }
+                Some(name) if name.eq_ignore_ascii_case("default") => {
+                    self.set_persona(None, ctx);
+                    CommandResult::Message("\nBack to the default persona.\n".to_string())
+                }
+                Some(name) => match config.persona(name) {
+                    Some(persona) => {
+                        self.set_persona(Some(persona), ctx);
+                        info!("User {} switched to persona {}", addr, persona.name);
+                        let banner = persona.banner().map(|b| format!("\n{}", b)).unwrap_or_default();
+                        CommandResult::Message(format!("{}\nYou are now talking to {}.\n", banner, self.labels.ai))
+                    }
+                    None => CommandResult::Message(format!("\nUnknown persona: {}\n", name)),
+                },
+            },
+            "/lang" => match arg {
+                None => {
+                    let current = match (&self.pinned_lang, self.detected_lang) {
+                        (Some(lang), _) => format!("{} (pinned)", lang),
+                        (None, Some(lang)) => format!("{} (detected)", lang),
+                        (None, None) => "auto".to_string(),
+                    };
+                    CommandResult::Message(format!(
+                        "\nReply language: {}\nUsage: /lang <language> | /lang auto\n",
+                        current
+                    ))
+                }
+                Some(lang) if lang.eq_ignore_ascii_case("auto") => {
+                    self.pinned_lang = None;
+                    if let Err(e) = logger.update_profile(|p| p.lang = None) {
+                        return Some(CommandResult::Message(format!("\nError saving language: {}\n", e)));
+                    }
+                    CommandResult::Message("\nReply language follows your messages again.\n".to_string())
+                }
+                Some(lang) => {
+                    self.pinned_lang = Some(lang.to_string());
+                    if let Err(e) = logger.update_profile(|p| p.lang = Some(lang.to_string())) {
+                        return Some(CommandResult::Message(format!("\nError saving language: {}\n", e)));
+                    }
+                    info!("User {} pinned reply language to {}", addr, lang);
+                    CommandResult::Message(format!("\nReplies will be in {}.\n", lang))
+                }
+            },
+            "/tz" => match arg {
+                None => CommandResult::Message(
+                    "\nUsage: /tz <UTC offset>, e.g. /tz +02:00\n".to_string(),
+                ),
+                Some(tz) => match greeting::parse_offset(tz) {
+                    Some(offset) => {
+                        if let Err(e) = logger.update_profile(|p| p.timezone = Some(offset.to_string())) {
+                            return Some(CommandResult::Message(format!("\nError saving timezone: {}\n", e)));
+                        }
+                        CommandResult::Message(format!("\nTimezone set to UTC{}\n", offset))
+                    }
+                    None => CommandResult::Message(format!("\nInvalid UTC offset: {}\n", tz)),
+                },
+            },
+            "/contribute" => match arg.map(str::to_lowercase).as_deref() {
+                Some("on") => {
+                    let since = Local::now();
+                    if let Err(e) = logger.update_profile(|p| {
+                        p.contributing_since.get_or_insert(since);
+                    }) {
+                        return Some(CommandResult::Message(format!("\nError saving your choice: {}\n", e)));
+                    }
+                    info!("{} agreed to contribute conversations", addr);
+                    CommandResult::Message(
+                        "\nThank you! Conversations from now on may be used, with personal data taken out, \
+                         to train the models that run here. /contribute off stops it.\n"
+                            .to_string(),
+                    )
+                }
+                Some("off") => {
+                    if let Err(e) = logger.update_profile(|p| p.contributing_since = None) {
+                        return Some(CommandResult::Message(format!("\nError saving your choice: {}\n", e)));
+                    }
+                    info!("{} stopped contributing conversations", addr);
+                    CommandResult::Message(
+                        "\nYour conversations won't be used for training, including the earlier ones.\n".to_string(),
+                    )
+                }
+                _ => {
+                    let status = match logger.profile().and_then(|p| p.contributing_since) {
+                        Some(since) => format!("on since {}", since.format("%d-%m-%Y %H:%M")),
+                        None => "off".to_string(),
+                    };
+                    CommandResult::Message(format!(
+                        "\nContributing conversations as training data: {}\nUsage: /contribute on|off\n",
+                        status
+                    ))
+                }
+            },
+            "/starters" => {
+                if ctx.starters.is_empty() {
+                    return Some(CommandResult::Message("\nNo conversation starters configured.\n".to_string()));
+                }
+                self.starters_active = true;
+                CommandResult::Message(starters_menu(&ctx.starters))
+            }
+            "/template" => match arg {
+                None => {
+                    if config.templates.is_empty() {
+                        return Some(CommandResult::Message("\nNo templates configured.\n".to_string()));
+                    }
+                    let mut out = String::from("\nTemplates:\n");
+                    for template in &config.templates {
+                        out.push_str(&format!(
+                            "  {:<16} {}\n",
+                            template.name,
+                            template.description.as_deref().unwrap_or(&template.prompt)
+                        ));
+                    }
+                    out.push_str("Usage: /template <name>\n");
+                    CommandResult::Message(out)
+                }
+                Some(name) => match config.template(name) {
+                    Some(template) => {
+                        let fill = TemplateFill::new(template.clone());
+                        let question = fill.question().unwrap_or_default();
+                        self.template_fill = Some(fill);
+                        CommandResult::Message(format!(
+                            "\n{} (/cancel to stop)\n\n{}\n",
+                            template.name, question
+                        ))
+                    }
+                    None => CommandResult::Message(format!("\nUnknown template: {}\n", name)),
+                },
+            },
+            "/wizard" => match arg {
+                None => {
+                    if config.wizards.is_empty() {
+                        return Some(CommandResult::Message("\nNo wizards configured.\n".to_string()));
+                    }
+                    let mut out = String::from("\nWizards:\n");
+                    for wizard in &config.wizards {
+                        out.push_str(&format!(
+                            "  {:<16} {}\n",
+                            wizard.name,
+                            wizard.description.as_deref().unwrap_or("")
+                        ));
+                    }
+                    out.push_str("Usage: /wizard <name>\n");
+                    CommandResult::Message(out)
+                }
+                Some(name) => match config.wizard(name) {
+                    Some(wizard) => {
+                        info!("User {} started wizard {}", addr, wizard.name);
+                        self.wizard = Some(WizardRun::new(wizard.clone()));
+                        CommandResult::Wizard
+                    }
+                    None => CommandResult::Message(format!("\nUnknown wizard: {}\n", name)),
+                },
+            },
+            "/models" => match arg {
+                None => CommandResult::Models(false),
+                Some("refresh") => CommandResult::Models(true),
+                Some(_) => CommandResult::Message("\nUsage: /models [refresh]\n".to_string()),
+            },
+            "/model" => match arg {
+                None => CommandResult::Message(format!("\nCurrent model: {}\nUsage: /model <name>\n", self.model)),
+                Some(name) => {
+                    // Only a fresh list can rule a name out, the backend decides otherwise
+                    if let Some(models) = ctx.models.cached()
+                        && !models.iter().any(|m| m == name)
+                    {
+                        return Some(CommandResult::Message(format!("\nUnknown model: {} (see /models)\n", name)));
+                    }
+                    info!("User {} switched to model {}", addr, name);
+                    self.model = name.to_string();
+                    self.choose_base_prompt(ctx);
+                    // Replies name their model too, this marks where the switch happened
+                    if logger.is_started()
+                        && let Err(e) = logger.log_message("SYSTEM", &format!("Model switched to {}", name))
+                    {
+                        warn!("Failed to log model switch for {}: {}", addr, e);
+                    }
+                    CommandResult::Message(format!("\nModel set to: {}\n", name))
+                }
+            },
+            "/set" => {
+                let Some(arg) = arg else {
+                    let mut out = String::from("\nGeneration settings:\n");
+                    for (name, value) in self.tuning.values() {
+                        out.push_str(&format!("  {:<18} {}\n", name, value.as_deref().unwrap_or("default")));
+                    }
+                    out.push_str("Usage: /set <name> <value|default>\n");
+                    return Some(CommandResult::Message(out));
+                };
+                let (name, value) = arg.split_once(' ').unwrap_or((arg, ""));
+                let value = value.trim();
+                if value.is_empty() {
+                    return Some(CommandResult::Message("\nUsage: /set <name> <value|default>\n".to_string()));
+                }
+                match self.tuning.set(name, value) {
+                    Ok(()) => match (self.tuning.max_tokens, ctx.max_tokens(&self.model)) {
+                        (Some(asked), Some(limit)) if asked > limit => {
+                            self.tuning.max_tokens = Some(limit);
+                            CommandResult::Message(format!("\nmax_tokens set to {}, the most this server allows\n", limit))
+                        }
+                        _ => CommandResult::Message(format!("\n{} set to {}\n", name, value)),
+                    },
+                    Err(e) => CommandResult::Message(format!("\n{}\n", e)),
+                }
+            }
+            "/human" => {
+                if self.operator.is_some() {
+                    return Some(CommandResult::Message("\nYou're already talking to an operator.\n".to_string()));
+                }
+                if self.escalated {
+                    return Some(CommandResult::Message("\nAn operator has already been asked to join.\n".to_string()));
+                }
+                CommandResult::Human(arg.filter(|a| !a.is_empty()).map(str::to_string))
+            }
+            "/json" => {
+                self.tuning.json = match arg.map(str::to_lowercase).as_deref() {
+                    None => !self.tuning.json,
+                    Some("on") => true,
+                    Some("off") => false,
+                    Some(_) => return Some(CommandResult::Message("\nUsage: /json [on|off]\n".to_string())),
+                };
+                CommandResult::Message(if self.tuning.json {
+                    "\nJSON mode on: replies come as JSON objects, checked and pretty-printed. /json off to stop.\n"
+                        .to_string()
+                } else {
+                    "\nJSON mode off.\n".to_string()
+                })
+            }
+            "/debug" => {
+                if arg != Some("prompt") {
+                    return Some(CommandResult::Message("\nUsage: /debug prompt\n".to_string()));
+                }
+
+                // Exactly what the next request carries, without calling the LLM
+                let (messages, fit) = self.fitted_request(ctx);
+                let mut out = format!(
+                    "\n--- Prompt for the next turn: {} messages, about {} tokens, model {} ---\n",
+                    messages.len(),
+                    tokens::estimate_messages(&messages),
+                    self.model
+                );
+                if let Some(spill) = self.spill.as_ref().filter(|s| s.count() > 0) {
+                    out.push_str(&format!("({} older messages are kept on disk and not sent)\n", spill.count()));
+                }
+                if fit.dropped > 0 {
+                    out.push_str(&format!("({} older messages are left out to fit the context limit)\n", fit.dropped));
+                }
+                if fit.truncated {
+                    out.push_str("(The oldest message sent is cut short to fit the context limit)\n");
+                }
+                for (i, message) in messages.iter().enumerate() {
+                    out.push_str(&format!(
+                        "[{}] {} ({} chars, ~{} tokens):\n{}\n",
+                        i,
+                        message.role,
+                        message.content.chars().count(),
+                        tokens::estimate(&message.content),
+                        message.content
+                    ));
+                }
+                out.push_str("--- Your next message is appended as the final user turn ---\n");
+                CommandResult::Message(out)
+            }
+            "/more" | "/full" => {
+                if self.overflow.is_empty() {
+                    return Some(CommandResult::Message("\nNothing more to show.\n".to_string()));
+                }
+                let count = match (cmd, ctx.max_display_lines) {
+                    ("/more", Some(max)) => max,
+                    _ => self.overflow.len(),
+                };
+                CommandResult::Message(format!("\n{}\n", self.next_page(count)))
+            }
+            "/import" => {
+                self.import_buffer = Some(String::new());
                 CommandResult::Message(
-                    "\nCommands:\n\
-                      /name <your name>  - Set your name\n\
-                      /clear             - Clear conversation history\n\
-                      /help              - Show this help\n\
-                      /quit              - Disconnect\n"
+                    "\nPaste the JSON messages array, then send a line with a single '.' \
+                     (or /cancel to abort).\n"
                         .to_string(),
                 )
             }
-            _ => CommandResult::Message(format!("\nUnknown command: {}\n", cmd)),
+            "/export" => {
+                let options: Vec<&str> = arg.unwrap_or("").split_whitespace().collect();
+                if options.first() != Some(&"openai") {
+                    return Some(CommandResult::Message(
+                        "\nUsage: /export openai [--system] [--names] [--file]\n".to_string(),
+                    ));
+                }
+                let include_system = options.contains(&"--system");
+                let names = options
+                    .contains(&"--names")
+                    .then(|| (self.labels.ai.as_str(), self.user_display_name()));
+
+                let json = match interop::to_openai_json(&self.full_history(), include_system, names) {
+                    Ok(json) => json,
+                    Err(e) => return Some(CommandResult::Message(format!("\nExport failed: {}\n", e))),
+                };
+
+                if options.contains(&"--file") {
+                    match logger.write_export(&json) {
+                        Ok(path) => {
+                            info!("User {} exported conversation to {}", addr, path.display());
+                            CommandResult::Message(format!("\nConversation saved to {}\n", path.display()))
+                        }
+                        Err(e) => CommandResult::Message(format!("\nExport failed: {}\n", e)),
+                    }
+                } else {
+                    CommandResult::Message(format!("\n```json\n{}\n```\n", json))
+                }
+            }
+            "/help" | "/?" => CommandResult::Message(command_help(ctx)),
+            _ => return None,
+        })
+    }
+}
+
+/// Server-wide settings and services shared by every session
+pub struct SessionContext {
+    pub llm: Box<dyn LlmProvider>,
+    /// `[[alias]]` names and the clients for their endpoints
+    pub aliases: Aliases,
+    /// What /models lists
+    pub models: ModelCatalog,
+    /// `[[model]]` profiles as they were at startup, with the clients built from them
+    pub profiles: Vec<ModelProfile>,
+    pub system_prompt: String,
+    pub logs_dir: String,
+    pub log_format: LogFormat,
+    pub bans: BanList,
+    pub logins: LoginThrottle,
+    pub honeypot: bool,
+    pub max_output_rate: Option<u64>,
+    pub render_markdown: bool,
+    pub auto_lang: bool,
+    pub ai_greeting: bool,
+    /// AI greetings by the facts they were made from, `None` with --low-memory
+    pub greeting_cache: Option<Mutex<HashMap<String, String>>>,
+    pub starters: Vec<String>,
+    pub nudge_after: Option<Duration>,
+    /// Silence after which the conversation is summarized into the log and cleared
+    pub reset_after: Option<Duration>,
+    /// Functions the model may call while answering
+    pub tools: ToolRegistry,
+    /// Strip line noise from input and tell the model it may be garbled
+    pub noise_filter: bool,
+    /// Takes sensitive data out of user input before it is logged or sent
+    pub redactor: Redactor,
+    /// Pages /fetch may download, `None` without --fetch
+    #[cfg(feature = "tools")]
+    pub fetch: Option<FetchPolicy>,
+    pub max_display_lines: Option<usize>,
+    /// Messages kept in memory per session besides the system prompt
+    pub max_history: Option<usize>,
+    /// Longest input line kept, the rest is dropped
+    pub max_line_bytes: usize,
+    /// Tokens a request may take, reply included; older turns are left out beyond it
+    pub context_tokens: Option<usize>,
+    /// Fold older turns into a summary before the request reaches `context_tokens`
+    pub summarize: bool,
+    /// Limit every session and forget the visitor afterwards
+    pub kiosk: Option<KioskLimits>,
+    /// Disk space in bytes each client directory may use
+    pub client_quota: Option<u64>,
+    pub webhook: Option<Webhook>,
+    /// LLM errors for the weekly digest
+    pub errors: ErrorTally,
+    /// Panicked sessions, for `/metrics` and the crash-loop ban
+    pub supervisor: Supervisor,
+    pub audit: AuditLog,
+    pub sessions: Registry,
+    /// Echo admin actions to the operator's tracing output
+    pub announce_admin: bool,
+    /// Ask new visitors a simple question before their first LLM call
+    pub challenge: bool,
+    /// The --config file, `reload` on the admin console swaps what can change while running
+    pub config: LiveConfig,
+    /// Only serve clients whose first line is a valid HMAC token
+    pub hmac_auth: bool,
+    /// Default labels, personas may override them
+    pub labels: Labels,
+    /// Schedule from the config in force for new sessions
+    pub schedule: ActiveSchedule,
+    pub maintenance: Maintenance,
+    pub handover: Handover,
+    /// How long interrupted conversations stay restorable, `None` disables snapshots
+    pub restore_window: Option<Duration>,
+    /// Forward tokens as they arrive instead of waiting for the whole response
+    pub stream: bool,
+    /// --temperature and friends, under whatever /set, aliases and model profiles choose
+    pub tuning: Tuning,
+    /// Slash command uses not yet written to `commands.json`
+    pub commands: CommandTally,
+}
+
+impl SessionContext {
+    /// The client, backend model ID and settings for a request to `model`,
+    /// with `tuning.max_tokens` held to `max_tokens`
+    pub fn route<'a>(&'a self, model: &'a str, tuning: &Tuning) -> Route<'a> {
+        let mut route = self.aliases.route(self.llm.as_ref(), &self.profiles, model, tuning);
+        route.tuning = route.tuning.or(&self.tuning);
+        if let (Some(asked), Some(limit)) = (tuning.max_tokens, self.max_tokens(model)) {
+            route.tuning.max_tokens = Some(asked.min(limit));
         }
+        route
+    }
+
+    /// The longest reply `model` may give, as its alias, its profile or --max-tokens sets it
+    pub fn max_tokens(&self, model: &str) -> Option<u32> {
+        let route = self.aliases.route(self.llm.as_ref(), &self.profiles, model, &Tuning::default());
+        route.tuning.or(&self.tuning).max_tokens
+    }
+
+    /// /fetch needs --fetch and a build with the tools feature
+    #[cfg(feature = "tools")]
+    fn fetch_enabled(&self) -> bool {
+        self.fetch.is_some()
+    }
+
+    #[cfg(not(feature = "tools"))]
+    fn fetch_enabled(&self) -> bool {
+        false
     }
 }
 
+/// Takes the session off the registry however `serve` ends, panics included
+struct Registered {
+    ctx: Arc<SessionContext>,
+    id: u64,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.ctx.sessions.unregister(self.id);
+    }
+}
+
+/// What a session runs over, a TCP connection or a serial line
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Connection for T {}
+
 pub struct Session {
-    stream: TcpStream,
+    stream: Box<dyn Connection>,
     addr: SocketAddr,
-    llm: Arc<LlmClient>,
-    system_prompt: Arc<String>,
-    logs_dir: Arc<String>,
+    ctx: Arc<SessionContext>,
+    geo: Option<GeoTag>,
+    /// Persona of the port the client connected to
+    persona: Option<String>,
+    /// Speak telnet to the client, not on a serial line
+    telnet: bool,
+    /// Output bytes per second of the line, on top of --max-output-rate
+    line_rate: Option<u64>,
 }
 
 impl Session {
     pub fn new(
-        stream: TcpStream,
+        stream: impl Connection + 'static,
         addr: SocketAddr,
-        llm: Arc<LlmClient>,
-        system_prompt: Arc<String>,
-        logs_dir: Arc<String>,
+        ctx: Arc<SessionContext>,
+        geo: Option<GeoTag>,
+        persona: Option<String>,
     ) -> Self {
         Self {
-            stream,
+            stream: Box::new(stream),
             addr,
-            llm,
-            system_prompt,
-            logs_dir,
+            ctx,
+            geo,
+            persona,
+            telnet: true,
+            line_rate: None,
+        }
+    }
+
+    /// A terminal on a serial line: no telnet negotiation, and output paced
+    /// to the characters per second the line carries
+    #[cfg(feature = "serial")]
+    pub fn over_serial(mut self, chars_per_second: u64) -> Self {
+        self.telnet = false;
+        self.line_rate = Some(chars_per_second);
+        self
+    }
+
+    /// Greeting for the start of the session, optionally phrased by the LLM
+    async fn greeting(
+        &self,
+        name: Option<&str>,
+        last_seen: Option<DateTime<Local>>,
+        timezone: Option<FixedOffset>,
+    ) -> String {
+        let facts = greeting::compose(name, last_seen, timezone);
+        // Only returning users are worth an LLM call
+        if !self.ctx.ai_greeting || last_seen.is_none() {
+            return facts;
+        }
+
+        let cached = self
+            .ctx
+            .greeting_cache
+            .as_ref()
+            .and_then(|cache| cache.lock().unwrap().get(&facts).cloned());
+        if let Some(cached) = cached {
+            return cached;
+        }
+
+        let request = vec![
+            Message {
+                role: "system".to_string(),
+                content: self.ctx.system_prompt.clone(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: format!(
+                    "Write one short, warm sentence greeting a returning user of this chat, \
+                     based on: \"{}\". Reply with the greeting only.",
+                    facts
+                ),
+            },
+        ];
+
+        match self.ctx.llm.chat(&request).await {
+            Ok(text) => {
+                let text = render_response(text.trim(), true);
+                if let Some(cache) = &self.ctx.greeting_cache {
+                    let mut cache = cache.lock().unwrap();
+                    if cache.len() >= GREETING_CACHE_SIZE {
+                        cache.clear();
+                    }
+                    cache.insert(facts, text.clone());
+                }
+                text
+            }
+            Err(e) => {
+                warn!("Failed to generate greeting for {}: {}", self.addr, e);
+                facts
+            }
         }
     }
 
     pub async fn run(&mut self) -> Result<()> {
-        let logger = ChatLogger::new(&self.logs_dir, self.addr.ip())?;
-        logger.log_session_start()?;
+        // Reachable from the admin console while connected
+        let (id, mut control, usage) = self.ctx.sessions.register(self.addr, self.geo.clone());
+        let _registered = Registered {
+            ctx: Arc::clone(&self.ctx),
+            id,
+        };
+        self.serve(id, &mut control, &usage).await
+    }
+
+    async fn serve(&mut self, id: u64, control: &mut UnboundedReceiver<Control>, usage: &SessionUsage) -> Result<()> {
+        let mut logger = ChatLogger::new(&self.ctx.logs_dir, self.addr.ip(), self.ctx.log_format);
+        // The next kiosk visitor starts from nothing on disk, even after an error or a panic.
+        // Every visitor logs into a directory of their own, kiosks sharing an address included.
+        if self.ctx.kiosk.is_some() {
+            logger.switch_to(kiosk::scratch_dir(&self.ctx.logs_dir, self.addr.ip()))?;
+            logger.wipe_when_dropped();
+        }
 
-        // Load existing summary to get user name
-        let mut user_name: Option<String> = None;
-        if let Some(summary) = logger.get_summary() {
-            for line in summary.lines() {
-                if line.to_lowercase().starts_with("name:") {
-                    user_name = line.splitn(2, ':').nth(1).map(|s| s.trim().to_string());
+        // A single named user is who connects from this IP again. Behind a
+        // shared IP the address-wide profile belongs to nobody in particular.
+        let named = match self.ctx.kiosk {
+            Some(_) => Vec::new(),
+            None => logger::named_users(&self.ctx.logs_dir, self.addr.ip()),
+        };
+        let shared = named.len() > 1;
+        if let [dir] = named.as_slice() {
+            logger.switch_to(dir.clone())?;
+        }
+        // Where the session started, the only place a conversation is restored from
+        let home = logger.client_dir().to_path_buf();
+
+        // Load the existing profile to get user name, preferences and last visit
+        let profile = logger.profile();
+        let known = profile.is_some() || !named.is_empty();
+        let profile = profile.filter(|_| !shared).unwrap_or_default();
+
+        // Scheduled defaults are fixed for the whole session
+        let schedule = self.ctx.schedule.get();
+        let mut state = initial_state(
+            &self.ctx,
+            self.persona.as_deref(),
+            id,
+            schedule.as_ref(),
+            profile.name.clone(),
+            profile.lang.clone(),
+        );
+        if self.ctx.challenge && !known {
+            state.challenge = Some(Challenge::generate());
+        }
+
+        // Conversation queued by `telllm import`
+        let mut import_notice = None;
+        if let Some(json) = logger.take_pending_import() {
+            match interop::parse_messages(&json) {
+                Ok(messages) => {
+                    let count = state.load_history(messages);
+                    begin_transcript(&logger, self.geo.as_ref())?;
+                    logger.log_message("SYSTEM", &format!("Imported {} messages", count))?;
+                    import_notice = Some(format!(
+                        "\nLoaded an imported conversation ({} messages).\n",
+                        count
+                    ));
                 }
+                Err(e) => warn!("Ignoring invalid import for {}: {}", self.addr, e),
             }
         }
+        let mut abuse = AbuseDetector::new();
 
-        let mut state = SessionState::new(&self.system_prompt, user_name);
+        let connected_at = Instant::now();
+        let mut first_input: Option<Duration> = None;
+        let mut input = InputParser::new(self.ctx.max_line_bytes);
 
-        let (read_half, write_half) = self.stream.split();
-        let mut reader = BufReader::new(read_half);
-        let mut writer = BufWriter::new(write_half);
+        let greeting = self
+            .greeting(state.user_name.as_deref(), profile.last_seen, profile.utc_offset())
+            .await;
+
+        let (mut reader, write_half) = tokio::io::split(&mut self.stream);
+        let rate = self.line_rate.into_iter().chain(self.ctx.max_output_rate).min();
+        let mut writer = ThrottledWriter::new(write_half, rate);
+
+        // Machine clients sign in with their first line and skip the telnet niceties
+        let mut signed = None;
+        if self.ctx.hmac_auth {
+            let first = tokio::time::timeout(
+                AUTH_TIMEOUT,
+                read_line(&mut reader, &mut writer, &mut input, usage),
+            )
+            .await;
+            let client = match first {
+                Ok(Ok(Some(line))) => signing::verify(&self.ctx.config.get(), line.trim()).cloned(),
+                _ => None,
+            };
+            let Some(client) = client else {
+                warn!("Rejected unsigned client {}", self.addr);
+                self.ctx.audit.record(self.addr.ip(), Outcome::AuthFailed, Some("hmac"));
+                writer.write_all(b"AUTH required\n").await?;
+                writer.flush().await?;
+                return Ok(());
+            };
+            info!("Client {} signed in as {}", self.addr, client.id);
+            self.ctx.audit.record(self.addr.ip(), Outcome::Login, Some(&client.id));
+            writer.write_all(b"OK\n").await?;
+            state.challenge = None;
+            signed = Some(client);
+        }
+        // A signed client's quota and an operator override from the admin console win over the global quota
+        logger.set_quota(QuotaRule {
+            override_mb: signed.as_ref().and_then(|c| c.quota_mb),
+            fallback: schedule_quota(&self.ctx, schedule.as_ref()),
+        });
+
+        if signed.is_none() {
+            // Ask for terminal type and window size, answers arrive with the input
+            if self.telnet {
+                writer.write_all(&telnet::initial_negotiation()).await?;
+            }
+
+            // Send welcome banner
+            writer.write_all(state.banner(&self.ctx).as_bytes()).await?;
 
-        // Send welcome banner
-        writer.write_all(WELCOME_BANNER.as_bytes()).await?;
-        
-        if let Some(name) = &state.user_name {
             writer
-                .write_all(format!("\nWelcome back, {}!\n\n", name).as_bytes())
+                .write_all(format!("\n{}\n", greeting).as_bytes())
                 .await?;
+
+            if shared {
+                writer
+                    .write_all(b"\nSeveral people chat from this address. Type /name <your name> to pick up your own history.\n")
+                    .await?;
+            }
+
+            if !self.ctx.starters.is_empty() {
+                writer.write_all(starters_menu(&self.ctx.starters).as_bytes()).await?;
+                state.starters_active = true;
+            }
+
+            // Only offered where the address has a single user
+            if let Some(window) = self.ctx.restore_window
+                && !shared
+                && let Some(snapshot) = snapshot::take(logger.client_dir(), window)
+            {
+                writer
+                    .write_all(
+                        format!(
+                            "\nYour conversation from {} was interrupted by a server restart. Restore it? (yes / no)\n",
+                            snapshot.saved_at.format("%H:%M")
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                state.pending_restore = Some(snapshot);
+            }
+        }
+
+        if let Some(notice) = &import_notice {
+            writer.write_all(notice.as_bytes()).await?;
         }
         
-        writer.write_all(b"\nYou: ").await?;
+        writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
         writer.flush().await?;
 
-        let mut line = String::new();
-        
+        let mut last_input = tokio::time::Instant::now();
         loop {
-            line.clear();
-            
-            match reader.read_line(&mut line).await {
-                Ok(0) => {
-                    // Connection closed
+            if let Some(max) = self.ctx.max_history {
+                state.bound_history(max, &self.addr);
+            }
+            usage.set_history_bytes(state.history_bytes());
+
+            // Arm the nudge timer once the AI has said something
+            let nudge_after = self.ctx.nudge_after.filter(|_| {
+                !state.nudged
+                    && !self.ctx.honeypot
+                    && state.import_buffer.is_none()
+                    && state.wizard.is_none()
+                    && state.operator.is_none()
+                    && state.messages.iter().any(|m| m.role == "assistant")
+            });
+
+            let idle = async {
+                match nudge_after {
+                    Some(after) => tokio::time::sleep(after).await,
+                    None => std::future::pending().await,
+                }
+            };
+            // Counted from the last line typed, so a nudge doesn't postpone it
+            let reset_at = self
+                .ctx
+                .reset_after
+                .filter(|_| {
+                    !self.ctx.honeypot
+                        && state.import_buffer.is_none()
+                        && state.wizard.is_none()
+                        && state.operator.is_none()
+                        && state.messages.iter().any(|m| m.role == "user")
+                })
+                .map(|after| last_input + after);
+            let reset = async {
+                match reset_at {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let deadline = state.kiosk.as_ref().map(Kiosk::deadline);
+            let time_up = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let event = tokio::select! {
+                line = read_line(&mut reader, &mut writer, &mut input, usage) => Event::Line(line?),
+                _ = idle => Event::Idle,
+                _ = time_up => Event::TimeUp,
+                _ = reset => Event::Reset,
+                Some(msg) = control.recv() => Event::Control(msg),
+            };
+            let line = match event {
+                Event::Line(line) => {
+                    last_input = tokio::time::Instant::now();
+                    line
+                }
+                Event::Reset => {
+                    let fresh = initial_state(&self.ctx, self.persona.as_deref(), id, schedule.as_ref(), None, None);
+                    reset_idle(&self.ctx, self.addr, &mut state, fresh, &mut logger, &mut writer).await?;
+                    continue;
+                }
+                Event::TimeUp => {
+                    writer.write_all(b"\n\nTime is up. Thank you for visiting!\n").await?;
+                    writer.flush().await?;
                     break;
                 }
-                Ok(_) => {
-                    let input = line.trim().to_string();
-                    
-                    if input.is_empty() {
-                        writer.write_all(b"You: ").await?;
+                Event::Idle => {
+                    state.nudged = true;
+                    nudge(&self.ctx, self.addr, &mut state, &logger, &mut writer).await?;
+                    continue;
+                }
+                Event::Control(Control::Kick) => {
+                    info!("Kicked {} by the operator", self.addr);
+                    if logger.is_started() {
+                        logger.log_message("SYSTEM", "Disconnected by the operator")?;
+                    }
+                    writer.write_all(b"\n\nDisconnected by the operator.\n").await?;
+                    writer.flush().await?;
+                    break;
+                }
+                Event::Control(Control::Shutdown) => {
+                    let saved = self.ctx.restore_window.is_some()
+                        && self.ctx.kiosk.is_none()
+                        && !shared
+                        && logger.client_dir() == home
+                        && state.messages.len() > 1
+                        && match snapshot::save(logger.client_dir(), &state.snapshot()) {
+                            Ok(()) => true,
+                            Err(e) => {
+                                warn!("Failed to save session of {}: {}", self.addr, e);
+                                false
+                            }
+                        };
+                    let text = if saved {
+                        "\n\nThe server is restarting. Reconnect soon to continue this conversation.\n"
+                    } else {
+                        "\n\nThe server is shutting down.\n"
+                    };
+                    writer.write_all(text.as_bytes()).await?;
+                    writer.flush().await?;
+                    break;
+                }
+                Event::Control(Control::Attach(operator)) => {
+                    info!("Operator attached to {}", self.addr);
+                    begin_transcript(&logger, self.geo.as_ref())?;
+                    logger.log_message("SYSTEM", "Operator joined")?;
+                    // Catch the operator up on the conversation so far
+                    let name = state.user_display_name().to_string();
+                    for message in state.full_history().iter().filter(|m| m.role != "system") {
+                        let who = if message.role == "user" { name.as_str() } else { state.labels.ai.as_str() };
+                        let _ = operator.send(format!("{}: {}", who, message.content));
+                    }
+                    state.operator = Some(operator);
+                    state.escalated = false;
+                    writer
+                        .write_all(format!("\n\n[Operator] A human operator has joined the chat.\n\n{}", state.prompt()).as_bytes())
+                        .await?;
+                    writer.flush().await?;
+                    continue;
+                }
+                Event::Control(Control::Operator(text)) => {
+                    logger.log_message("OPERATOR", &text)?;
+                    // The AI picks up where the operator leaves off
+                    state.messages.push(Message {
+                        role: "assistant".to_string(),
+                        content: text.clone(),
+                    });
+                    writer
+                        .write_all(format!("\n\n[Operator] {}\n\n{}", text, state.prompt()).as_bytes())
+                        .await?;
+                    writer.flush().await?;
+                    continue;
+                }
+                Event::Control(Control::Release) => {
+                    if state.operator.take().is_some() {
+                        info!("Operator detached from {}", self.addr);
+                        logger.log_message("SYSTEM", "Operator left")?;
+                        writer
+                            .write_all(format!("\n\n[Operator] The operator has left, the AI is back.\n\n{}", state.prompt()).as_bytes())
+                            .await?;
+                        writer.flush().await?;
+                    }
+                    continue;
+                }
+                Event::Control(Control::Broadcast(text)) => {
+                    writer
+                        .write_all(format!("\n\n[Operator] {}\n\n{}", text, state.prompt()).as_bytes())
+                        .await?;
+                    writer.flush().await?;
+                    continue;
+                }
+            };
+            let Some(line) = line else {
+                // Connection closed
+                break;
+            };
+            // Nothing after this point sees what the rules take out, logs included.
+            // Credentials must be checked as typed and pasted JSON must still
+            // parse, so those pass as they are and imported messages are redacted one by one.
+            let line = if state.import_buffer.is_some() || is_sign_in(&line) {
+                line
+            } else {
+                match self.ctx.redactor.redact(&line) {
+                    (redacted, 0) => redacted,
+                    (redacted, count) => {
+                        info!("Redacted {} matches from the input of {}", count, self.addr);
+                        redacted
+                    }
+                }
+            };
+
+            let input = line.trim().to_string();
+            first_input.get_or_insert_with(|| connected_at.elapsed());
+
+            // Take out line noise, holding back lines that are mostly noise
+            let input = if state.noise_filter && !self.ctx.honeypot && state.import_buffer.is_none() {
+                let cleaned = noise::clean(&input);
+                if cleaned.is_mostly_noise() {
+                    info!("Held back a noisy line from {} ({} of {} characters)", self.addr, cleaned.dropped, cleaned.total);
+                    let msg = if cleaned.text.is_empty() {
+                        "\nThat line was only line noise. Please type it again.\n".to_string()
+                    } else {
+                        format!(
+                            "\nThat line looked mostly like line noise: {}\nType it again, or /resend to send it as it is.\n",
+                            cleaned.text
+                        )
+                    };
+                    state.noisy_line = Some(cleaned.text).filter(|text| !text.is_empty());
+                    writer.write_all(format!("{}\n{}", msg, state.prompt()).as_bytes()).await?;
+                    writer.flush().await?;
+                    continue;
+                }
+                if cleaned.text == "/resend" {
+                    self.ctx.commands.record("/resend");
+                    let Some(held) = state.noisy_line.take() else {
+                        writer
+                            .write_all(format!("\nNothing to resend.\n\n{}", state.prompt()).as_bytes())
+                            .await?;
                         writer.flush().await?;
                         continue;
+                    };
+                    writer.write_all(format!("{}{}\n", state.prompt(), held).as_bytes()).await?;
+                    held
+                } else {
+                    state.noisy_line = None;
+                    cleaned.text
+                }
+            } else {
+                input
+            };
+            
+            if input.is_empty() {
+                writer.write_all(state.prompt().as_bytes()).await?;
+                writer.flush().await?;
+                continue;
+            }
+
+            // Honeypot: record everything, answer with a canned reply
+            if self.ctx.honeypot {
+                begin_transcript(&logger, self.geo.as_ref())?;
+                logger.log_message("VISITOR", &input)?;
+                if matches!(input.to_lowercase().as_str(), "/quit" | "/exit" | "/q") {
+                    writer.write_all(b"\nGoodbye!\n").await?;
+                    writer.flush().await?;
+                    break;
+                }
+                writer
+                    .write_all(format!("\n{} {}\n", state.labels.ai_prefix(), HONEYPOT_REPLY).as_bytes())
+                    .await?;
+                writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+                writer.flush().await?;
+                continue;
+            }
+
+            // Collect a pasted conversation until a lone "."
+            if let Some(buffer) = state.import_buffer.as_mut() {
+                if input == "/cancel" {
+                    state.import_buffer = None;
+                    writer
+                        .write_all(format!("\nImport cancelled.\n\n{}", state.prompt()).as_bytes())
+                        .await?;
+                    writer.flush().await?;
+                    continue;
+                }
+                if input != "." {
+                    buffer.push_str(&line);
+                    if buffer.len() > MAX_IMPORT_BYTES {
+                        state.import_buffer = None;
+                        writer
+                            .write_all(format!("\nImport too large, aborted.\n\n{}", state.prompt()).as_bytes())
+                            .await?;
+                        writer.flush().await?;
                     }
+                    continue;
+                }
 
-                    // Handle commands
-                    if input.starts_with('/') {
-                        match state.handle_command(&input, &logger, &self.addr, &self.system_prompt) {
-                            CommandResult::Quit => {
-                                writer.write_all(b"\nGoodbye!\n").await?;
-                                writer.flush().await?;
-                                break;
-                            }
-                            CommandResult::Continue => {
-                                writer.write_all(b"\nYou: ").await?;
-                                writer.flush().await?;
-                                continue;
-                            }
-                            CommandResult::Message(msg) => {
-                                writer.write_all(msg.as_bytes()).await?;
-                                writer.write_all(b"\nYou: ").await?;
-                                writer.flush().await?;
-                                continue;
-                            }
+                let json = state.import_buffer.take().unwrap_or_default();
+                let msg = match interop::parse_messages(&json) {
+                    Ok(mut messages) => {
+                        for message in &mut messages {
+                            message.content = self.ctx.redactor.redact(&message.content).0;
                         }
+                        let count = state.load_history(messages);
+                        info!("User {} imported {} messages", self.addr, count);
+                        begin_transcript(&logger, self.geo.as_ref())?;
+                        logger.log_message("SYSTEM", &format!("Imported {} messages", count))?;
+                        format!("\nImported {} messages. Continue the conversation!\n", count)
                     }
+                    Err(e) => format!("\nImport failed: {}\n", e),
+                };
+                writer.write_all(msg.as_bytes()).await?;
+                writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+                writer.flush().await?;
+                continue;
+            }
 
-                    // Log user message
-                    let display_name = state.user_name.as_deref().unwrap_or("User");
-                    logger.log_message(display_name, &input)?;
+            // Answer to the offer to restore the conversation from before a restart
+            if let Some(snapshot) = state.pending_restore.take() {
+                let msg = if matches!(input.to_lowercase().as_str(), "yes" | "y") {
+                    let count = state.restore(snapshot, &self.ctx);
+                    info!("Restored {} messages for {}", count, self.addr);
+                    format!("\nRestored your conversation ({} messages).\n", count)
+                } else {
+                    "\nStarting a new conversation.\n".to_string()
+                };
+                writer.write_all(msg.as_bytes()).await?;
+                writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+                writer.flush().await?;
+                continue;
+            }
 
-                    // Add user message to history
-                    state.messages.push(Message {
-                        role: "user".to_string(),
-                        content: input.clone(),
-                    });
+            // Answer to the offer to link earlier history into the account
+            if let Some(ip_dir) = state.pending_link.take() {
+                let msg = state.answer_link(&input, ip_dir, &logger, &self.addr);
+                writer.write_all(msg.as_bytes()).await?;
+                writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+                writer.flush().await?;
+                continue;
+            }
+
+            // Contact details left for the operators outside office hours
+            if let Some(reason) = state.pending_handoff.take() {
+                begin_transcript(&logger, self.geo.as_ref())?;
+                let msg = if input == "/cancel" {
+                    "\nNothing was left for the operators.\n".to_string()
+                } else {
+                    leave_handoff(&self.ctx, self.addr, &state, reason, &input, &logger).await?
+                };
+                writer.write_all(msg.as_bytes()).await?;
+                writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+                writer.flush().await?;
+                continue;
+            }
 
-                    // Show typing indicator
-                    writer.write_all(b"\nAI: (thinking...)\r").await?;
+            // Answers to a wizard's questions
+            if let Some(run) = state.wizard.take() {
+                begin_transcript(&logger, self.geo.as_ref())?;
+                if input == "/cancel" {
+                    logger.log_message("SYSTEM", &format!("Wizard {} cancelled", run.name()))?;
+                    writer
+                        .write_all(format!("\nWizard cancelled.\n\n{}", state.prompt()).as_bytes())
+                        .await?;
                     writer.flush().await?;
+                    continue;
+                }
+                logger.log_message(state.user_display_name(), &input)?;
+                step_wizard(&self.ctx, &mut state, run, Some(&input), &logger, &mut writer).await?;
+                continue;
+            }
 
-                    // Call LLM
-                    match self.llm.chat(&state.messages).await {
-                        Ok(response) => {
-                            // Clear the thinking indicator and show response
-                            writer
-                                .write_all(format!("AI: {}\n", response).as_bytes())
-                                .await?;
+            // Answers to a template's questions, the last one sends the composed message
+            let input = match state.template_fill.take() {
+                None => input,
+                Some(_) if input == "/cancel" => {
+                    writer
+                        .write_all(format!("\nTemplate cancelled.\n\n{}", state.prompt()).as_bytes())
+                        .await?;
+                    writer.flush().await?;
+                    continue;
+                }
+                Some(mut fill) => {
+                    fill.answer(&input);
+                    if let Some(question) = fill.question() {
+                        state.template_fill = Some(fill);
+                        writer
+                            .write_all(format!("\n{}\n\n{}", question, state.prompt()).as_bytes())
+                            .await?;
+                        writer.flush().await?;
+                        continue;
+                    }
+                    let message = fill.message();
+                    writer.write_all(format!("\n{}{}\n", state.prompt(), message).as_bytes()).await?;
+                    message
+                }
+            };
 
-                            // Log and store response
-                            logger.log_message("AI", &response)?;
-                            state.messages.push(Message {
-                                role: "assistant".to_string(),
-                                content: response,
-                            });
-                        }
-                        Err(e) => {
-                            warn!("LLM error for {}: {}", self.addr, e);
+            // A fetched page goes to the AI with a request for its summary
+            #[cfg(feature = "tools")]
+            let input = match (&self.ctx.fetch, input.strip_prefix("/fetch")) {
+                (Some(policy), Some(url)) if url.is_empty() || url.starts_with(' ') => {
+                    self.ctx.commands.record("/fetch");
+                    let url = url.trim();
+                    if url.is_empty() {
+                        writer
+                            .write_all(format!("\nUsage: /fetch <url>\n\n{}", state.prompt()).as_bytes())
+                            .await?;
+                        writer.flush().await?;
+                        continue;
+                    }
+                    writer.write_all(b"\n(fetching...)\r").await?;
+                    writer.flush().await?;
+                    match policy.fetch(url).await {
+                        Ok(page) => {
+                            info!("{} fetched {}", self.addr, page.url);
+                            let cut = if page.truncated { ", cut off" } else { "" };
                             writer
                                 .write_all(
-                                    format!("AI: Sorry, I encountered an error: {}\n", e)
+                                    format!("Fetched {} ({} characters{}).\n", page.url, page.text.chars().count(), cut)
                                         .as_bytes(),
                                 )
                                 .await?;
+                            format!("Summarize this page from {}:\n\n{}", page.url, page.text)
                         }
+                        Err(e) => {
+                            writer
+                                .write_all(format!("Couldn't fetch that page: {:#}\n\n{}", e, state.prompt()).as_bytes())
+                                .await?;
+                            writer.flush().await?;
+                            continue;
+                        }
+                    }
+                }
+                _ => input,
+            };
+
+            // Handle commands
+            if input.starts_with('/') {
+                match state.handle_command(&input, &mut logger, &self.addr, &self.ctx) {
+                    CommandResult::Quit => {
+                        writer.write_all(b"\nGoodbye!\n").await?;
+                        writer.flush().await?;
+                        break;
+                    }
+                    CommandResult::Message(msg) => {
+                        writer.write_all(msg.as_bytes()).await?;
+                        writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+                        writer.flush().await?;
+                        continue;
+                    }
+                    CommandResult::Wizard => {
+                        if let Some(run) = state.wizard.take() {
+                            writer.write_all(format!("\n{} (/cancel to stop)\n", run.name()).as_bytes()).await?;
+                            step_wizard(&self.ctx, &mut state, run, None, &logger, &mut writer).await?;
+                        }
+                        continue;
+                    }
+                    CommandResult::SignIn(attempt) => {
+                        let msg = state.sign_in(attempt, &mut logger, &self.addr, &self.ctx).await;
+                        writer.write_all(msg.as_bytes()).await?;
+                        writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+                        writer.flush().await?;
+                        continue;
+                    }
+                    CommandResult::Human(reason) => {
+                        begin_transcript(&logger, self.geo.as_ref())?;
+                        let msg = request_human(&self.ctx, id, self.addr, &mut state, reason, &logger).await?;
+                        writer.write_all(msg.as_bytes()).await?;
+                        writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+                        writer.flush().await?;
+                        continue;
+                    }
+                    CommandResult::Models(refresh) => {
+                        let msg = match self.ctx.models.list(self.ctx.llm.as_ref(), refresh).await {
+                            Ok(models) if models.is_empty() => "\nThe backend lists no models.\n".to_string(),
+                            Ok(models) => {
+                                let mut out = String::from("\nModels:\n");
+                                for model in models {
+                                    let marker = if model == state.model { "*" } else { " " };
+                                    match self.ctx.aliases.get(&model) {
+                                        Some(alias) => out.push_str(&format!(" {} {} -> {}\n", marker, model, alias.model)),
+                                        None => out.push_str(&format!(" {} {}\n", marker, model)),
+                                    }
+                                }
+                                out.push_str("Switch with /model <name>\n");
+                                out
+                            }
+                            Err(e) => {
+                                warn!("Failed to list models for {}: {}", self.addr, e);
+                                format!("\nCouldn't list models: {}\n", e)
+                            }
+                        };
+                        writer.write_all(msg.as_bytes()).await?;
+                        writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+                        writer.flush().await?;
+                        continue;
                     }
+                }
+            }
 
-                    writer.write_all(b"\nYou: ").await?;
+            // A number right after the menu picks a conversation starter
+            let starter = input
+                .parse::<usize>()
+                .ok()
+                .filter(|_| state.starters_active)
+                .and_then(|n| n.checked_sub(1))
+                .and_then(|i| self.ctx.starters.get(i));
+            let input = match starter {
+                Some(starter) => {
+                    writer.write_all(format!("{}{}\n", state.prompt(), starter).as_bytes()).await?;
+                    starter.clone()
+                }
+                None => input,
+            };
+            state.starters_active = false;
+
+            // New visitors prove they're human before the first LLM call
+            let input = match (&state.challenge, state.held_input.take()) {
+                (None, _) => input,
+                (Some(challenge), None) => {
+                    let question = format!(
+                        "\nBefore we start, a quick check: {}\n\n{}",
+                        challenge.question,
+                        state.prompt()
+                    );
+                    state.held_input = Some(input);
+                    writer.write_all(question.as_bytes()).await?;
+                    writer.flush().await?;
+                    continue;
+                }
+                (Some(challenge), Some(held)) if challenge.check(&input) => {
+                    state.challenge = None;
+                    info!("{} passed the challenge", self.addr);
+                    writer
+                        .write_all(format!("\nThanks! {}{}\n", state.prompt(), held).as_bytes())
+                        .await?;
+                    held
+                }
+                (Some(_), Some(held)) => {
+                    state.challenge_failures += 1;
+                    warn!("{} failed the challenge ({})", self.addr, state.challenge_failures);
+                    if state.challenge_failures >= challenge::MAX_FAILURES {
+                        writer.write_all(b"\nToo many wrong answers. Disconnecting.\n").await?;
+                        writer.flush().await?;
+                        break;
+                    }
+                    let next = Challenge::generate();
+                    let question = format!(
+                        "\nThat's not it. Try this one: {}\n\n{}",
+                        next.question,
+                        state.prompt()
+                    );
+                    state.challenge = Some(next);
+                    state.held_input = Some(held);
+                    writer.write_all(question.as_bytes()).await?;
                     writer.flush().await?;
+                    continue;
+                }
+            };
+
+            // First real message: now the session is worth a transcript
+            begin_transcript(&logger, self.geo.as_ref())?;
+
+            // Short-circuit bots and paste floods without calling the LLM
+            // Signed clients are trusted scripts, repetition is expected from them
+            let verdict = if signed.is_some() { Verdict::Ok } else { abuse.check(&input) };
+            let reply = match verdict {
+                Verdict::Ok => None,
+                Verdict::Repeated => Some("You already said that. Try asking something new."),
+                Verdict::Flood => Some("That looks like a paste flood. Please send a shorter message."),
+            };
+            if let Some(reply) = reply {
+                warn!("Suppressed abusive input from {} (strike {})", self.addr, abuse.strikes());
+                let tags = Tags {
+                    moderation: Some(verdict),
+                    ..Tags::default()
+                };
+                logger.log_tagged("SYSTEM", "Abusive input suppressed", &tags)?;
+
+                if abuse.strikes() >= STRIKE_LIMIT {
+                    self.ctx.bans.ban(self.addr.ip());
+                    warn!("Banned {} after {} strikes", self.addr, abuse.strikes());
+                    writer.write_all(b"\nToo many abusive messages. Disconnecting.\n").await?;
+                    writer.flush().await?;
+                    break;
+                }
+
+                writer
+                    .write_all(format!("\n{} {}\n\n{}", state.labels.ai_prefix(), reply, state.prompt()).as_bytes())
+                    .await?;
+                writer.flush().await?;
+                continue;
+            }
+
+            // Log user message
+            let input_lang = lang::detect(&input);
+            let tags = Tags {
+                lang: input_lang,
+                moderation: Some(verdict),
+            };
+            logger.log_tagged(state.user_display_name(), &input, &tags)?;
+
+            if self.ctx.auto_lang
+                && let Some(lang) = input_lang
+            {
+                state.detected_lang = Some(lang);
+            }
+
+            // Add user message to history
+            state.messages.push(Message {
+                role: "user".to_string(),
+                content: input.clone(),
+            });
+            usage.add_message();
+            if let Some(kiosk) = state.kiosk.as_mut() {
+                kiosk.record_message();
+            }
+
+            // Calm things down before the reply to the message that tipped it over
+            let level = state.frustration.observe(&input);
+            let config = self.ctx.config.get();
+            if let Some(policy) = &config.frustration
+                && !state.de_escalated
+                && level >= policy.threshold
+            {
+                state.de_escalated = true;
+                info!("User {} seems frustrated (level {:.1})", self.addr, level);
+                logger.log_message("SYSTEM", &format!("Frustration detected (level {:.1})", level))?;
+                if let Some(persona) = policy.persona.as_deref().and_then(|name| config.persona(name)) {
+                    state.set_persona(Some(persona), &self.ctx);
+                }
+                if policy.flag && !state.escalated && state.operator.is_none() {
+                    let reason = Some(format!("frustration detected (level {:.1})", level));
+                    flag_for_human(&self.ctx, id, self.addr, &mut state, reason, "frustration", "seems frustrated").await;
+                }
+            }
+
+            // An attached operator answers instead of the AI
+            if let Some(operator) = &state.operator {
+                if operator.send(format!("{}: {}", state.user_display_name(), input)).is_ok() {
+                    writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+                    writer.flush().await?;
+                    continue;
+                }
+                // The console went away without detaching
+                state.operator = None;
+            }
+
+            // Show typing indicator
+            writer
+                .write_all(format!("\n{} (thinking...)\r", state.labels.ai_prefix()).as_bytes())
+                .await?;
+            writer.flush().await?;
+
+            // Call LLM
+            let started = Instant::now();
+            let prompt_tokens = tokens::estimate_messages(&state.request_messages(&self.ctx));
+            // Tool calls need the whole response, so they turn streaming off
+            // So do JSON replies, which are checked before they're shown
+            let streamed = self.ctx.stream && self.ctx.tools.is_empty() && !state.tuning.json;
+            let reply = if streamed {
+                stream_reply(&self.ctx, &mut state, &mut writer).await?
+            } else if !self.ctx.tools.is_empty() {
+                let messages = state.request_messages(&self.ctx);
+                let route = self.ctx.route(&state.model, &state.tuning);
+                let call = route.llm.complete_with_tools(route.model, &messages, &route.tuning, &self.ctx.tools);
+                with_countdown(call, route.llm.upstream(), &state.labels, &mut writer).await?
+            } else {
+                let messages = state.request_messages(&self.ctx);
+                let route = self.ctx.route(&state.model, &state.tuning);
+                let call = route.llm.complete(route.model, &messages, &route.tuning);
+                with_countdown(call, route.llm.upstream(), &state.labels, &mut writer).await?
+            };
+            match reply {
+                Ok(completion) => {
+                    let response = completion.content;
+                    match completion.usage {
+                        Some(tokens) => {
+                            usage.add_tokens(tokens.total_tokens);
+                            state.token_usage.add(&tokens);
+                        }
+                        None => usage.add_estimated((prompt_tokens + tokens::estimate(&response)) as u64),
+                    }
+                    let mut rendered = if state.tuning.json {
+                        render_json(&response)
+                    } else {
+                        render_response(&response, self.ctx.render_markdown)
+                    };
+                    if !completion.sources.is_empty() {
+                        rendered.push_str(&sources_footer(&completion.sources));
+                    }
+
+                    // Clear the thinking indicator and show response, streamed ones are already on screen
+                    if !streamed {
+                        let shown = state.take_display(&rendered, self.ctx.max_display_lines);
+                        writer
+                            .write_all(format!("{} {}\n", state.labels.ai_prefix(), shown).as_bytes())
+                            .await?;
+                    }
+
+                    // Log and store response
+                    let route = self.ctx.route(&state.model, &state.tuning);
+                    let meta = ResponseMeta {
+                        model: route.model.to_string(),
+                        finish_reason: completion.finish_reason,
+                        latency_ms: started.elapsed().as_millis() as u64,
+                        lang: lang::detect(&response),
+                        usage: completion.usage,
+                        tuning: route.tuning,
+                    };
+                    logger.log_response(&state.labels.ai, &response, &rendered, &meta)?;
+                    state.messages.push(Message {
+                        role: "assistant".to_string(),
+                        content: response,
+                    });
+                    if self.ctx.summarize
+                        && let Some(limit) = self.ctx.context_tokens
+                    {
+                        summarize_older(&self.ctx, self.addr, id, limit, &mut state, &logger).await?;
+                    }
                 }
                 Err(e) => {
-                    return Err(e).context("Failed to read from client");
+                    warn!("LLM error for {}: {}", self.addr, e);
+                    self.ctx.errors.record(&e);
+                    writer
+                        .write_all(
+                            format!("{} Sorry, I encountered an error: {}\n", state.labels.ai_prefix(), e)
+                                .as_bytes(),
+                        )
+                        .await?;
                 }
             }
+
+            if state.kiosk.as_ref().is_some_and(|k| k.messages_left() == 0) {
+                writer.write_all(b"\nThat was the last message of this session. Thank you for visiting!\n").await?;
+                writer.flush().await?;
+                break;
+            }
+
+            writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+            writer.flush().await?;
+        }
+
+        // Record what client software connected and how it behaved
+        let client = input.client();
+        let first_input = first_input
+            .map(|d| format!("{:.1}s", d.as_secs_f64()))
+            .unwrap_or_else(|| "never".to_string());
+        let fingerprint = format!(
+            "{} first_input={} duration={}s",
+            client.describe(),
+            first_input,
+            connected_at.elapsed().as_secs()
+        );
+        info!("Client {}: {}", self.addr, fingerprint);
+
+        if self.ctx.kiosk.is_some() {
+            // The next visitor starts from nothing on screen, the logger clears the disk
+            let _ = writer.write_all(kiosk::RESET_TERMINAL).await;
+            let _ = writer.flush().await;
+            return Ok(());
+        }
+
+        // Connections that never sent a real message leave no transcript
+        if logger.is_started() {
+            logger.log_message("CLIENT", &fingerprint)?;
+            if !state.token_usage.is_empty() {
+                logger.log_message("SYSTEM", &format!("Tokens used: {}", state.token_usage.describe()))?;
+            }
+            logger.update_profile(|p| {
+                p.tokens.add(&state.token_usage);
+                if client.terminal_type.is_some() {
+                    p.terminal = client.terminal_type.clone();
+                }
+                if client.window_size.is_some() {
+                    p.window = client.window_size;
+                }
+            })?;
+            logger.log_session_end()?;
+        }
+        if logger.is_started() || known {
+            logger.touch_last_seen()?;
         }
 
-        logger.log_session_end()?;
-        logger.touch_last_seen()?;
+        let removed = logger.enforce_quota()?;
+        if removed > 0 {
+            info!("Removed {} old chat logs of {} to stay within quota", removed, self.addr.ip());
+        }
         Ok(())
     }
 }
+
+/// The schedule's quota in bytes, or the server's when the schedule sets none
+pub fn schedule_quota(ctx: &SessionContext, schedule: Option<&Schedule>) -> Option<u64> {
+    match schedule.and_then(|s| s.quota_mb) {
+        Some(0) => None,
+        Some(mb) => Some(mb * 1024 * 1024),
+        None => ctx.client_quota,
+    }
+}
+
+/// `/register` or `/login`, whose arguments are credentials rather than chat
+fn is_sign_in(line: &str) -> bool {
+    line.split_whitespace()
+        .next()
+        .is_some_and(|cmd| matches!(cmd.to_lowercase().as_str(), "/register" | "/login"))
+}
+
+/// Write the deferred session-start entry and client tags before the first real message
+fn begin_transcript(logger: &ChatLogger, geo: Option<&GeoTag>) -> Result<()> {
+    if !logger.log_session_start()? {
+        return Ok(());
+    }
+
+    if let Some(geo) = geo {
+        logger.log_message("CLIENT", &format!("geo={}", geo.describe()))?;
+        logger.update_profile(|p| {
+            if geo.country.is_some() {
+                p.country = geo.country.clone();
+            }
+            if geo.asn.is_some() {
+                p.asn = geo.asn;
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Write the response while it arrives and return it whole. The outer error is
+/// the connection failing, the inner one the LLM. Once a page's worth of lines
+/// is on screen, the rest is only kept in the response and split into lines
+/// for /more when it is complete. Replies past `MAX_REPLY_BYTES` are cut off.
+async fn stream_reply<W: AsyncWrite + Unpin>(
+    ctx: &SessionContext,
+    state: &mut SessionState,
+    writer: &mut ThrottledWriter<W>,
+) -> Result<Result<Completion>> {
+    let messages = state.request_messages(ctx);
+    let route = ctx.route(&state.model, &state.tuning);
+    let call = route.llm.chat_stream(route.model, &messages, &route.tuning);
+    let mut stream = match with_countdown(call, route.llm.upstream(), &state.labels, writer).await? {
+        Ok(stream) => stream,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    writer.write_all(format!("{} ", state.labels.ai_prefix()).as_bytes()).await?;
+    state.overflow.clear();
+    let mut response = String::new();
+    let mut lines_shown = 0;
+    // Where the part waiting for /more starts once the page is full
+    let mut held_from: Option<usize> = None;
+    let mut cut_off = false;
+    loop {
+        match stream.next().await {
+            Ok(Some(delta)) => {
+                let start = response.len();
+                let mut delta = delta.as_str();
+                if start + delta.len() > MAX_REPLY_BYTES {
+                    let mut end = MAX_REPLY_BYTES - start;
+                    while !delta.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    delta = &delta[..end];
+                    cut_off = true;
+                }
+                response.push_str(delta);
+                if held_from.is_none() {
+                    let mut shown = delta;
+                    if let Some(max) = ctx.max_display_lines
+                        && let Some(end) = delta.match_indices('\n').map(|(i, _)| i).find(|_| {
+                            lines_shown += 1;
+                            lines_shown == max
+                        })
+                    {
+                        held_from = Some(start + end + 1);
+                        shown = &delta[..end];
+                    }
+                    writer.write_all(render::strip_control(shown).as_bytes()).await?;
+                    writer.flush().await?;
+                }
+                if cut_off {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                writer.write_all(b"\n").await?;
+                return Ok(Err(e));
+            }
+        }
+    }
+    if let Some(from) = held_from {
+        state.overflow = render::strip_control(&response[from..])
+            .lines()
+            .take(MAX_OVERFLOW_LINES)
+            .map(str::to_string)
+            .collect();
+    }
+    if cut_off {
+        warn!("Cut off a streamed reply at {} bytes", MAX_REPLY_BYTES);
+        if held_from.is_none() {
+            writer.write_all(b"\n[The reply got too long and was cut off]").await?;
+        } else {
+            state.overflow.push("[The reply got too long and was cut off]".to_string());
+        }
+    }
+    writer.write_all(format!("{}\n", state.more_hint()).as_bytes()).await?;
+    Ok(Ok(Completion {
+        content: response,
+        finish_reason: if cut_off {
+            Some("length".to_string())
+        } else {
+            stream.finish_reason().map(str::to_string)
+        },
+        usage: stream.usage(),
+        sources: Vec::new(),
+    }))
+}
+
+/// Await an LLM call, counting down on screen while the backend has asked for a pause
+async fn with_countdown<T, W: AsyncWrite + Unpin>(
+    call: impl Future<Output = T>,
+    upstream: &Upstream,
+    labels: &Labels,
+    writer: &mut ThrottledWriter<W>,
+) -> Result<T> {
+    let mut call = std::pin::pin!(call);
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut shown = false;
+    let result = loop {
+        tokio::select! {
+            result = &mut call => break result,
+            _ = tick.tick() => {
+                if let Some(wait) = upstream.paused_for() {
+                    shown = true;
+                    let line = format!(
+                        "\r\x1b[K{} (the AI service asked us to wait, resuming in {}s)",
+                        labels.ai_prefix(),
+                        wait.as_secs() + 1
+                    );
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.flush().await?;
+                }
+            }
+        }
+    };
+    if shown {
+        writer.write_all(b"\r\x1b[K").await?;
+    }
+    Ok(result)
+}
+
+/// Queue the conversation for the operators and tell them someone wants a human
+async fn request_human(
+    ctx: &SessionContext,
+    id: u64,
+    addr: SocketAddr,
+    state: &mut SessionState,
+    reason: Option<String>,
+    logger: &ChatLogger,
+) -> Result<String> {
+    if let Some(hours) = &ctx.config.get().office_hours
+        && !hours.is_open(Local::now())
+    {
+        info!("User {} asked for a human outside office hours", addr);
+        logger.log_message("SYSTEM", "Asked for a human outside office hours")?;
+        state.pending_handoff = Some(reason);
+        return Ok(format!(
+            "\n{}\n\nLeave your contact details and what you need in one message (/cancel to skip):\n",
+            hours.message
+        ));
+    }
+
+    let described = reason.as_deref().unwrap_or("no reason given");
+    logger.log_message("SYSTEM", &format!("Asked for a human: {}", described))?;
+    info!("User {} asked for a human (session #{}): {}", addr, id, described);
+    flag_for_human(ctx, id, addr, state, reason, "human_requested", "asks for a human").await;
+    Ok("\nAn operator has been notified and will join if available. You can keep chatting with the AI meanwhile.\n".to_string())
+}
+
+/// Queue the conversation, mark the session on the admin console and post
+/// `event` to the webhook, e.g. "Anna (203.0.113.7) asks for a human: ..."
+async fn flag_for_human(
+    ctx: &SessionContext,
+    id: u64,
+    addr: SocketAddr,
+    state: &mut SessionState,
+    reason: Option<String>,
+    event: &str,
+    summary: &str,
+) {
+    let escalation = Escalation {
+        requested_at: Local::now(),
+        session: id,
+        addr,
+        name: state.user_name.clone(),
+        reason,
+        messages: state.full_history().into_iter().filter(|m| m.role != "system").collect(),
+    };
+    if let Err(e) = escalation::queue(&ctx.logs_dir, &escalation) {
+        warn!("Failed to queue escalation of {}: {}", addr, e);
+    }
+    ctx.sessions.escalate(id);
+    state.escalated = true;
+
+    if let Some(webhook) = &ctx.webhook {
+        let text = format!(
+            "{} {}: {} (attach {} on the admin console)",
+            state.user_name.as_deref().map_or_else(|| addr.to_string(), |name| format!("{} ({})", name, addr)),
+            summary,
+            escalation.reason.as_deref().unwrap_or("no reason given"),
+            id
+        );
+        webhook.notify(event, &text).await;
+    }
+}
+
+/// Queue what the user left outside office hours and let the operators know
+async fn leave_handoff(
+    ctx: &SessionContext,
+    addr: SocketAddr,
+    state: &SessionState,
+    reason: Option<String>,
+    request: &str,
+    logger: &ChatLogger,
+) -> Result<String> {
+    let handoff = Handoff {
+        time: Local::now(),
+        addr,
+        name: state.user_name.clone(),
+        reason,
+        request: request.to_string(),
+    };
+    if let Err(e) = escalation::append_handoff(&ctx.logs_dir, &handoff) {
+        warn!("Failed to queue handoff of {}: {}", addr, e);
+        return Ok("\nSorry, your request couldn't be saved. Please try again later.\n".to_string());
+    }
+    logger.log_message("SYSTEM", &format!("Left for the operators: {}", request))?;
+    info!("User {} left a request for the operators", addr);
+    if let Some(webhook) = &ctx.webhook {
+        let text = format!(
+            "{} left a request outside office hours: {}",
+            state.user_name.as_deref().map_or_else(|| addr.to_string(), |name| format!("{} ({})", name, addr)),
+            request
+        );
+        webhook.notify("handoff_left", &text).await;
+    }
+    Ok("\nThanks, an operator will get back to you.\n".to_string())
+}
+
+/// Run a wizard up to its next question or its end and show where it stands
+async fn step_wizard<W: AsyncWrite + Unpin>(
+    ctx: &SessionContext,
+    state: &mut SessionState,
+    mut run: WizardRun,
+    answer: Option<&str>,
+    logger: &ChatLogger,
+    writer: &mut ThrottledWriter<W>,
+) -> Result<()> {
+    let route = ctx.route(&state.model, &state.tuning);
+    let text = match run.advance(answer, route.llm, route.model).await {
+        Progress::Waiting(question) => {
+            state.wizard = Some(run);
+            question
+        }
+        Progress::Finished(text) => {
+            logger.log_message("SYSTEM", &format!("Wizard {} finished", run.name()))?;
+            text
+        }
+    };
+    writer
+        .write_all(format!("\n{}\n\n{}", text, state.prompt()).as_bytes())
+        .await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Once the request nears `limit` tokens, have the model summarize all but the
+/// latest turns and send the summary in their place. The turns themselves move
+/// to the spill file, so /export and the logs still have them.
+async fn summarize_older(
+    ctx: &SessionContext,
+    addr: SocketAddr,
+    id: u64,
+    limit: usize,
+    state: &mut SessionState,
+    logger: &ChatLogger,
+) -> Result<()> {
+    if tokens::estimate_messages(&state.unfitted_request()) * 100 <= limit * SUMMARIZE_AT_PERCENT {
+        return Ok(());
+    }
+    // Keep the latest turns from a user message on
+    let Some(split) = (1..state.messages.len().saturating_sub(SUMMARY_KEEP_RECENT))
+        .rev()
+        .find(|&i| state.messages[i].role == "user")
+    else {
+        return Ok(());
+    };
+    if split <= 1 {
+        return Ok(());
+    }
+    // Trimming to --context-tokens still keeps the next request in bounds if this fails
+    let Some(summary) = summarize(ctx, addr, state, split, SUMMARY_PROMPT).await else {
+        return Ok(());
+    };
+
+    let spill = state.spill.get_or_insert_with(|| Spill::new(&ctx.logs_dir, id));
+    if let Err(e) = spill.append(&state.messages[1..split]) {
+        warn!("Failed to spill history of {}: {}", addr, e);
+        return Ok(());
+    }
+    state.messages.drain(1..split);
+    state.summary = Some(summary.to_string());
+    info!("Summarized {} older messages of {}", split - 1, addr);
+    logger.log_message("SYSTEM", &format!("Summarized {} earlier messages: {}", split - 1, summary))?;
+    Ok(())
+}
+
+/// Ask the model to sum up the messages before `end`, building on the earlier
+/// summary; `None` if it failed or said nothing
+async fn summarize(
+    ctx: &SessionContext,
+    addr: SocketAddr,
+    state: &mut SessionState,
+    end: usize,
+    prompt: &str,
+) -> Option<String> {
+    let mut transcript = String::new();
+    if let Some(summary) = &state.summary {
+        transcript.push_str(&format!("Earlier summary: {}\n\n", summary));
+    }
+    for message in &state.messages[1..end] {
+        transcript.push_str(&format!("{}: {}\n\n", message.role, message.content));
+    }
+    let mut request = vec![
+        Message {
+            role: "system".to_string(),
+            content: prompt.to_string(),
+        },
+        Message {
+            role: "user".to_string(),
+            content: transcript,
+        },
+    ];
+    if let Some(limit) = ctx.context_tokens {
+        tokens::fit(&mut request, limit, Some(SUMMARY_MAX_TOKENS));
+    }
+
+    let route = ctx.route(&state.model, &state.tuning);
+    let tuning = Tuning {
+        max_tokens: Some(SUMMARY_MAX_TOKENS),
+        // A summary is prose even while the user has /json on
+        json: false,
+        ..route.tuning
+    };
+    let completion = match route.llm.complete(route.model, &request, &tuning).await {
+        Ok(completion) => completion,
+        Err(e) => {
+            warn!("Failed to summarize the conversation of {}: {}", addr, e);
+            return None;
+        }
+    };
+    if let Some(tokens) = &completion.usage {
+        state.token_usage.add(tokens);
+    }
+    let summary = completion.content.trim();
+    (!summary.is_empty()).then(|| summary.to_string())
+}
+
+/// What a visitor starts with: the scheduled model, the persona of the port
+/// or the schedule, and the name and language of their profile
+fn initial_state(
+    ctx: &SessionContext,
+    port_persona: Option<&str>,
+    id: u64,
+    schedule: Option<&Schedule>,
+    name: Option<String>,
+    lang: Option<String>,
+) -> SessionState {
+    let model = schedule
+        .and_then(|s| s.model.clone())
+        .unwrap_or_else(|| ctx.llm.model().to_string());
+
+    let mut state = SessionState::new(&ctx.system_prompt, name, ctx.labels.clone(), model);
+    state.pinned_lang = lang;
+    if ctx.max_history.is_some() {
+        state.spill = Some(Spill::new(&ctx.logs_dir, id));
+    }
+    let persona = port_persona.or_else(|| schedule.and_then(|s| s.persona.as_deref()));
+    let config = ctx.config.get();
+    match persona.and_then(|name| config.persona(name)) {
+        Some(persona) => state.set_persona(Some(persona), ctx),
+        None => state.choose_base_prompt(ctx),
+    }
+    state.kiosk = ctx.kiosk.map(Kiosk::start);
+    state.noise_filter = ctx.noise_filter;
+    state
+}
+
+/// After --reset-after of silence: keep a summary in the log, start over as
+/// `fresh`, logged out and back on the address's log, clear the screen, and
+/// invite the next visitor
+async fn reset_idle<W: AsyncWrite + Unpin>(
+    ctx: &SessionContext,
+    addr: SocketAddr,
+    state: &mut SessionState,
+    fresh: SessionState,
+    logger: &mut ChatLogger,
+    writer: &mut ThrottledWriter<W>,
+) -> Result<()> {
+    let end = state.messages.len();
+    let summary = summarize(ctx, addr, state, end, RESET_SUMMARY_PROMPT).await;
+    if logger.is_started() {
+        let note = match &summary {
+            Some(summary) => format!("Cleared after inactivity. Summary: {}", summary),
+            None => "Cleared after inactivity".to_string(),
+        };
+        logger.log_message("SYSTEM", &note)?;
+    }
+    state.clear_history();
+    let token_usage = state.token_usage;
+    *state = fresh;
+    state.token_usage = token_usage;
+    let ip_dir = logger::client_dir(&ctx.logs_dir, addr.ip());
+    if logger.client_dir() != ip_dir {
+        logger.switch_to(ip_dir)?;
+    }
+    info!("Cleared the idle conversation of {}", addr);
+
+    writer.write_all(kiosk::RESET_TERMINAL).await?;
+    writer.write_all(b"New visitor? Say hi!\n").await?;
+    if !ctx.starters.is_empty() {
+        writer.write_all(starters_menu(&ctx.starters).as_bytes()).await?;
+        state.starters_active = true;
+    }
+    writer.write_all(format!("\n{}", state.prompt()).as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// A JSON mode reply pretty-printed, or as it came with the parse error when
+/// it isn't valid JSON
+fn render_json(response: &str) -> String {
+    let trimmed = response.trim();
+    // Some models fence it anyway
+    let body = trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|rest| rest.strip_suffix("```"))
+        .unwrap_or(trimmed)
+        .trim();
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(value) => serde_json::to_string_pretty(&value).unwrap_or_else(|_| body.to_string()),
+        Err(e) => format!("{}\n\n[Not valid JSON: {}]", trimmed, e),
+    }
+}
+
+/// The pages tool results came from, listed under the reply
+fn sources_footer(sources: &[String]) -> String {
+    let mut footer = "\n\nSources:".to_string();
+    for (i, url) in sources.iter().enumerate() {
+        footer.push_str(&format!("\n  [{}] {}", i + 1, url));
+    }
+    footer
+}
+
+/// Send one gentle AI follow-up after the user went quiet
+async fn nudge<W: AsyncWrite + Unpin>(
+    ctx: &SessionContext,
+    addr: SocketAddr,
+    state: &mut SessionState,
+    logger: &ChatLogger,
+    writer: &mut ThrottledWriter<W>,
+) -> Result<()> {
+    let mut request = state.request_messages(ctx);
+    request.push(Message {
+        role: "user".to_string(),
+        content: NUDGE_PROMPT.to_string(),
+    });
+
+    let started = Instant::now();
+    let route = ctx.route(&state.model, &state.tuning);
+    match route.llm.complete(route.model, &request, &route.tuning).await {
+        Ok(completion) => {
+            let response = completion.content;
+            if let Some(tokens) = &completion.usage {
+                state.token_usage.add(tokens);
+            }
+            let rendered = render_response(&response, ctx.render_markdown);
+            let shown = state.take_display(&rendered, ctx.max_display_lines);
+            writer
+                .write_all(format!("\n{} {}\n\n{}", state.labels.ai_prefix(), shown, state.prompt()).as_bytes())
+                .await?;
+            writer.flush().await?;
+
+            let route = ctx.route(&state.model, &state.tuning);
+            let meta = ResponseMeta {
+                model: route.model.to_string(),
+                finish_reason: completion.finish_reason,
+                latency_ms: started.elapsed().as_millis() as u64,
+                lang: lang::detect(&response),
+                usage: completion.usage,
+                tuning: route.tuning,
+            };
+            logger.log_response(&state.labels.ai, &response, &rendered, &meta)?;
+            state.messages.push(Message {
+                role: "assistant".to_string(),
+                content: response,
+            });
+            info!("Nudged idle user {}", addr);
+        }
+        Err(e) => warn!("Failed to nudge {}: {}", addr, e),
+    }
+
+    Ok(())
+}
+
+/// Read the next line from the client, answering telnet negotiation on the way
+async fn read_line<R, W>(
+    reader: &mut R,
+    writer: &mut ThrottledWriter<W>,
+    input: &mut InputParser,
+    usage: &SessionUsage,
+) -> Result<Option<String>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = [0u8; 1024];
+    loop {
+        usage.set_bytes_out(writer.written());
+        while let Some(event) = input.next_event() {
+            match event {
+                InputEvent::Reply(replies) => {
+                    writer.write_all(&replies).await?;
+                    writer.flush().await?;
+                }
+                InputEvent::Line(line) => return Ok(Some(line)),
+            }
+        }
+
+        let n = reader
+            .read(&mut buf)
+            .await
+            .context("Failed to read from client")?;
+        if n == 0 {
+            // Connection closed
+            return Ok(None);
+        }
+        usage.add_bytes_in(n as u64);
+        input.push(&buf[..n]);
+    }
+}
@@ -0,0 +1,56 @@
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, SignedClient};
+
+/// How far a token's timestamp may be from the server clock
+const MAX_SKEW_SECS: u64 = 300;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn mac(key: &str, id: &str, timestamp: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}:{}", id, timestamp).as_bytes());
+    mac
+}
+
+/// Token line for a client: `AUTH <id> <unix time> <hex hmac-sha256("id:time")>`
+pub fn token(client: &SignedClient) -> String {
+    let timestamp = now_secs();
+    let signature = hex::encode(mac(&client.key, &client.id, timestamp).finalize().into_bytes());
+    format!("AUTH {} {} {}", client.id, timestamp, signature)
+}
+
+/// Check a token line, returning the client it signs in
+pub fn verify<'a>(config: &'a Config, line: &str) -> Option<&'a SignedClient> {
+    let mut parts = line.split_whitespace();
+    if parts.next() != Some("AUTH") {
+        return None;
+    }
+    let id = parts.next()?;
+    let timestamp: u64 = parts.next()?.parse().ok()?;
+    let signature = hex::decode(parts.next()?).ok()?;
+
+    if now_secs().abs_diff(timestamp) > MAX_SKEW_SECS {
+        return None;
+    }
+    let client = config.client(id)?;
+    mac(&client.key, id, timestamp).verify_slice(&signature).ok()?;
+    Some(client)
+}
+
+/// Print a fresh token for `telllm auth-token`
+pub fn print_token(config: &Config, id: &str) -> Result<()> {
+    let client = config
+        .client(id)
+        .with_context(|| format!("No client {} in the config", id))?;
+    println!("{}", token(client));
+    Ok(())
+}
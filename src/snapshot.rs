@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::admin::Control;
+use crate::handover;
+use crate::llm::Message;
+use crate::session::SessionContext;
+use crate::storage;
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// How long sessions get to save themselves before the process exits
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A conversation cut short by a restart
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub saved_at: DateTime<Local>,
+    pub messages: Vec<Message>,
+    #[serde(default)]
+    pub persona: Option<String>,
+    #[serde(default)]
+    pub pinned_lang: Option<String>,
+    pub model: String,
+}
+
+pub fn save(client_dir: &Path, snapshot: &Snapshot) -> Result<()> {
+    fs::create_dir_all(client_dir).context("Failed to create client directory")?;
+    let json = serde_json::to_string(snapshot).context("Failed to serialize snapshot")?;
+    storage::write_atomic(&client_dir.join(SNAPSHOT_FILE), &json)
+}
+
+/// Remove the client's snapshot, returning it if it is younger than `max_age`
+pub fn take(client_dir: &Path, max_age: Duration) -> Option<Snapshot> {
+    let path = client_dir.join(SNAPSHOT_FILE);
+    let json = fs::read_to_string(&path).ok()?;
+    if let Err(e) = fs::remove_file(&path) {
+        warn!("Failed to remove {}: {}", path.display(), e);
+    }
+
+    let snapshot: Snapshot = serde_json::from_str(&json).ok()?;
+    let age = (Local::now() - snapshot.saved_at).to_std().unwrap_or_default();
+    (age <= max_age).then_some(snapshot)
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("SIGTERM handler unavailable: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Ask every session to save its conversation and wait briefly for them to finish
+pub async fn shutdown(ctx: &SessionContext) {
+    let count = ctx.sessions.send_all(|| Control::Shutdown);
+    info!("Shutting down, saving {} sessions", count);
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, handover::drain(ctx)).await.is_err() {
+        warn!("{} sessions did not finish in time", ctx.sessions.count());
+    }
+}
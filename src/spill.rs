@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::llm::Message;
+
+/// Under the logs directory, one JSON-lines file per live session
+const SPILL_DIR: &str = "spill";
+
+/// Turns of a long conversation moved out of memory, removed when the session ends
+pub struct Spill {
+    path: PathBuf,
+    /// Messages written so far, the file is started over when this is 0
+    count: usize,
+}
+
+impl Spill {
+    /// `id` is the registry id, unique among the sessions of this process
+    pub fn new(logs_dir: &str, id: u64) -> Self {
+        Self {
+            path: Path::new(logs_dir).join(SPILL_DIR).join(format!("{}.jsonl", id)),
+            count: 0,
+        }
+    }
+
+    pub fn append(&mut self, messages: &[Message]) -> Result<()> {
+        let mut lines = String::new();
+        for message in messages {
+            lines.push_str(&serde_json::to_string(message).context("Failed to serialize message")?);
+            lines.push('\n');
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).context("Failed to create spill directory")?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(self.count > 0)
+            .write(true)
+            .truncate(self.count == 0)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        file.write_all(lines.as_bytes())
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        self.count += messages.len();
+        Ok(())
+    }
+
+    /// Everything spilled so far, oldest first
+    pub fn load(&self) -> Result<Vec<Message>> {
+        if self.count == 0 {
+            return Ok(Vec::new());
+        }
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        content
+            .lines()
+            .map(|line| serde_json::from_str(line).context("Corrupt spill file"))
+            .collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Forget the spilled turns, e.g. on /clear
+    pub fn clear(&mut self) {
+        if self.count > 0 {
+            let _ = fs::remove_file(&self.path);
+            self.count = 0;
+        }
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
@@ -0,0 +1,489 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use rand::Rng;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::geoip::GeoTag;
+use crate::profile;
+use crate::session::{self, SessionContext};
+use crate::storage;
+
+struct ClientUsage {
+    client: String,
+    path: PathBuf,
+    name: Option<String>,
+    last_seen: Option<String>,
+    /// Where the client last connected from, with --geoip-db or --geoip-asn-db
+    country: Option<String>,
+    asn: Option<u32>,
+    chat_files: usize,
+    bytes: u64,
+    /// Operator override from the admin console
+    quota_mb: Option<u64>,
+}
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn collect_usage(logs_dir: &Path) -> Result<Vec<ClientUsage>> {
+    let mut clients = Vec::new();
+    collect_dir(logs_dir, "", &mut clients)?;
+
+    // Registered accounts use the same layout one level down
+    let accounts_dir = logs_dir.join("accounts");
+    if accounts_dir.is_dir() {
+        collect_dir(&accounts_dir, "accounts/", &mut clients)?;
+    }
+
+    clients.sort_by_key(|c| std::cmp::Reverse(c.bytes));
+    Ok(clients)
+}
+
+fn collect_dir(dir: &Path, prefix: &str, clients: &mut Vec<ClientUsage>) -> Result<()> {
+    for entry in fs::read_dir(dir)
+        .with_context(|| format!("Failed to read logs directory {}", dir.display()))?
+        .flatten()
+    {
+        let path = entry.path();
+        let chats_dir = path.join("chats");
+        let profile = profile::load(&path);
+        // Only client directories, not e.g. honeypot/
+        if !chats_dir.is_dir() && matches!(profile, Ok(None)) {
+            continue;
+        }
+
+        let profile = profile.unwrap_or_else(|e| {
+            warn!("{:#}", e);
+            None
+        });
+        let profile = profile.unwrap_or_default();
+        let chat_files = fs::read_dir(&chats_dir)
+            .map(|entries| entries.flatten().count())
+            .unwrap_or(0);
+
+        clients.push(ClientUsage {
+            client: format!("{}{}", prefix, entry.file_name().to_string_lossy()),
+            path: path.clone(),
+            name: profile.name,
+            last_seen: profile
+                .last_seen
+                .map(|seen| seen.format("%d-%m-%Y %H:%M:%S").to_string()),
+            country: profile.country,
+            asn: profile.asn,
+            chat_files,
+            bytes: storage::dir_size(&path),
+            quota_mb: profile.quota_mb,
+        });
+    }
+
+    Ok(())
+}
+
+/// Print per-client disk usage, largest first, then when the clients were
+/// active over the last `activity_days` days
+pub fn print_usage(logs_dir: &str, quota: Option<u64>, activity_days: u32) -> Result<()> {
+    let clients = collect_usage(Path::new(logs_dir))?;
+    if clients.is_empty() {
+        println!("No clients in {}", logs_dir);
+        return Ok(());
+    }
+
+    println!(
+        "{:<40} {:<16} {:<20} {:<10} {:>6} {:>10} {:>6}",
+        "CLIENT", "NAME", "LAST SEEN", "GEO", "CHATS", "SIZE", "QUOTA"
+    );
+    for client in &clients {
+        let quota = match client.quota_mb {
+            Some(0) => None,
+            Some(mb) => Some(mb * 1024 * 1024),
+            None => quota,
+        };
+        let quota_used = quota
+            .map(|q| format!("{}%", client.bytes * 100 / q.max(1)))
+            .unwrap_or_else(|| "-".to_string());
+        let geo = match (&client.country, client.asn) {
+            (None, None) => "-".to_string(),
+            (country, asn) => GeoTag {
+                country: country.clone(),
+                asn,
+                org: None,
+            }
+            .describe(),
+        };
+        println!(
+            "{:<40} {:<16} {:<20} {:<10} {:>6} {:>10} {:>6}",
+            client.client,
+            client.name.as_deref().unwrap_or("-"),
+            client.last_seen.as_deref().unwrap_or("-"),
+            geo,
+            client.chat_files,
+            human_size(client.bytes),
+            quota_used
+        );
+    }
+
+    let total: u64 = clients.iter().map(|c| c.bytes).sum();
+    println!("\n{} clients, {} total", clients.len(), human_size(total));
+    if let Some(countries) = render_countries(&clients) {
+        println!("\n{}", countries);
+    }
+
+    println!("\n{}", activity_of(&clients, first_day(activity_days)).render(activity_days));
+    println!("{}", render_commands(&load_commands(Path::new(logs_dir)), activity_days));
+    Ok(())
+}
+
+/// Clients per country, most first, or `None` when no client has one
+fn render_countries(clients: &[ClientUsage]) -> Option<String> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for client in clients {
+        *counts.entry(client.country.as_deref().unwrap_or("??")).or_default() += 1;
+    }
+    if counts.keys().all(|country| *country == "??") {
+        return None;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    let mut out = String::from("Clients by country:\n");
+    for (country, count) in counts {
+        out.push_str(&format!("  {:<4} {:>6}\n", country, count));
+    }
+    Some(out)
+}
+
+const COMMANDS_FILE: &str = "commands.json";
+
+/// Slash commands typed per day, `YYYY-MM-DD` to command to count
+type CommandCounts = BTreeMap<String, BTreeMap<String, u64>>;
+
+fn load_commands(logs_dir: &Path) -> CommandCounts {
+    fs::read_to_string(logs_dir.join(COMMANDS_FILE))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// How often counted command uses are added to `commands.json`
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Slash command uses since the last flush, so typing a command never waits on the disk
+#[derive(Default)]
+pub struct CommandTally(Mutex<CommandCounts>);
+
+impl CommandTally {
+    /// Count one use of `command` today
+    pub fn record(&self, command: &str) {
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        let mut counts = self.0.lock().unwrap();
+        *counts.entry(today).or_default().entry(command.to_string()).or_default() += 1;
+    }
+
+    /// Add the uses counted so far to `commands.json`, keeping them for the
+    /// next try if that fails
+    pub fn flush(&self, logs_dir: &str) -> Result<()> {
+        let pending = std::mem::take(&mut *self.0.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let result = add_commands(Path::new(logs_dir), &pending);
+        if result.is_err() {
+            let mut counts = self.0.lock().unwrap();
+            merge_commands(&mut counts, &pending);
+        }
+        result
+    }
+}
+
+fn merge_commands(into: &mut CommandCounts, from: &CommandCounts) {
+    for (day, commands) in from {
+        let day = into.entry(day.clone()).or_default();
+        for (command, count) in commands {
+            *day.entry(command.clone()).or_default() += count;
+        }
+    }
+}
+
+fn add_commands(dir: &Path, pending: &CommandCounts) -> Result<()> {
+    let _lock = storage::lock(dir, "commands.lock")?;
+    let mut counts = load_commands(dir);
+    merge_commands(&mut counts, pending);
+    let json = serde_json::to_string_pretty(&counts).context("Failed to serialize command counts")?;
+    storage::write_atomic(&dir.join(COMMANDS_FILE), &format!("{}\n", json))
+}
+
+/// Write counted command uses out every `FLUSH_INTERVAL`
+pub async fn flush_commands(ctx: Arc<SessionContext>) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = ctx.commands.flush(&ctx.logs_dir) {
+            warn!("Failed to save command counts: {:#}", e);
+        }
+    }
+}
+
+/// Uses of each command over the last `days` days, most used first, with the
+/// ones nobody typed at the bottom
+fn render_commands(counts: &CommandCounts, days: u32) -> String {
+    let since = first_day(days).format("%Y-%m-%d").to_string();
+    // command -> (uses, days used, last day)
+    let mut usage: BTreeMap<&str, (u64, usize, &str)> =
+        session::command_names().map(|cmd| (cmd, (0, 0, "-"))).collect();
+    for (day, commands) in counts.range(since..) {
+        for (cmd, count) in commands {
+            let entry = usage.entry(cmd.as_str()).or_insert((0, 0, "-"));
+            entry.0 += count;
+            entry.1 += 1;
+            entry.2 = day.as_str();
+        }
+    }
+    let mut usage: Vec<_> = usage.into_iter().collect();
+    usage.sort_by(|a, b| b.1.0.cmp(&a.1.0).then_with(|| a.0.cmp(b.0)));
+
+    let mut out = format!("Commands over the last {} days\n", days);
+    out.push_str(&format!("{:<16} {:>8} {:>6}  {}\n", "COMMAND", "USES", "DAYS", "LAST USED"));
+    for (cmd, (uses, used_days, last)) in usage {
+        out.push_str(&format!("{:<16} {:>8} {:>6}  {}\n", cmd, uses, used_days, last));
+    }
+    out
+}
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+/// Heatmap cells from none to the busiest hour
+const SHADES: [char; 9] = [' ', '.', ':', '-', '=', '+', '*', '#', '@'];
+
+/// Sessions started and messages sent by weekday (Monday first) and hour of
+/// day, in the server's time zone
+#[derive(Default)]
+pub struct Activity {
+    sessions: [[usize; 24]; 7],
+    messages: [[usize; 24]; 7],
+}
+
+impl Activity {
+    fn add(&mut self, time: NaiveDateTime, session: bool) {
+        let (day, hour) = (time.weekday().num_days_from_monday() as usize, time.hour() as usize);
+        if session {
+            self.sessions[day][hour] += 1;
+        } else {
+            self.messages[day][hour] += 1;
+        }
+    }
+
+    /// Both heatmaps and the quietest and busiest hours, over `days` days
+    pub fn render(&self, days: u32) -> String {
+        let mut out = format!("Activity over the last {} days, server time\n", days);
+        for (title, grid) in [("Sessions", &self.sessions), ("Messages", &self.messages)] {
+            out.push_str(&format!("\n{}\n    ", title));
+            for hour in (0..24).step_by(3) {
+                out.push_str(&format!("{:<6}", hour));
+            }
+            let max = grid.iter().flatten().copied().max().unwrap_or(0);
+            for (name, row) in WEEKDAYS.iter().zip(grid) {
+                out.push_str(&format!("\n{} ", name));
+                for &count in row {
+                    let shade = match count {
+                        0 => SHADES[0],
+                        _ => SHADES[1 + (count - 1) * (SHADES.len() - 1) / max],
+                    };
+                    out.push(shade);
+                    out.push(shade);
+                }
+            }
+            out.push_str(&format!("\n    max {} per hour, shades {}\n", max, SHADES[1..].iter().collect::<String>()));
+        }
+
+        // Whole hours across the week, for a daily maintenance window
+        let by_hour: Vec<usize> = (0..24).map(|hour| self.messages.iter().map(|row| row[hour]).sum()).collect();
+        let quietest = (0..24).min_by_key(|&hour| (by_hour[hour], hour)).unwrap_or(0);
+        let busiest = (0..24).max_by_key(|&hour| (by_hour[hour], 24 - hour)).unwrap_or(0);
+        out.push_str(&format!(
+            "\nQuietest hour {:02}:00-{:02}:00 with {} messages, busiest {:02}:00-{:02}:00 with {}\n",
+            quietest,
+            (quietest + 1) % 24,
+            by_hour[quietest],
+            busiest,
+            (busiest + 1) % 24,
+            by_hour[busiest]
+        ));
+        out
+    }
+}
+
+/// Session starts and messages of every chat log dated `since` or later
+fn activity_of(clients: &[ClientUsage], since: NaiveDate) -> Activity {
+    let mut activity = Activity::default();
+    for log in chat_logs_of(clients, Some(since)) {
+        let Ok(content) = fs::read_to_string(&log.path) else {
+            continue;
+        };
+        for line in content.lines() {
+            if let Some(started) = line
+                .strip_prefix("--- Session started at ")
+                .and_then(|rest| rest.strip_suffix(" ---"))
+            {
+                if let Ok(time) = NaiveDateTime::parse_from_str(started, "%d-%m-%Y %H:%M:%S") {
+                    activity.add(time, true);
+                }
+            } else if line.starts_with('[') {
+                // Text logs only have the time of day, the date is the file's
+                if let Some(time) = line.get(1..9).and_then(|t| NaiveTime::parse_from_str(t, "%H:%M:%S").ok()) {
+                    activity.add(log.date.and_time(time), false);
+                }
+            } else if line.starts_with('{')
+                && let Ok(entry) = serde_json::from_str::<serde_json::Value>(line)
+                && let Some(time) = entry["time"].as_str().and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            {
+                let time = time.with_timezone(&Local).naive_local();
+                match entry["event"].as_str() {
+                    Some("session_start") => activity.add(time, true),
+                    Some("message") => activity.add(time, false),
+                    _ => {}
+                }
+            }
+        }
+    }
+    activity
+}
+
+/// Activity of the last `days` days, for the admin console
+pub fn activity(logs_dir: &str, days: u32) -> Result<Activity> {
+    let clients = collect_usage(Path::new(logs_dir))?;
+    Ok(activity_of(&clients, first_day(days)))
+}
+
+/// The first of the last `days` days, today included
+fn first_day(days: u32) -> NaiveDate {
+    Local::now().date_naive() - Days::new(u64::from(days.saturating_sub(1)))
+}
+
+/// Counts below this are published as null
+const SUPPRESS_BELOW: i64 = 5;
+/// Published counts are rounded down to multiples of this
+const BUCKET: i64 = 5;
+/// Messages one client adds to a day's count at most, bounding its influence
+const MESSAGE_CAP: usize = 50;
+
+/// Messages in one chat log, in either log format
+pub fn count_messages(path: &Path) -> usize {
+    let Ok(content) = fs::read_to_string(path) else {
+        return 0;
+    };
+    content
+        .lines()
+        .filter(|line| line.starts_with('[') || line.contains("\"event\":\"message\""))
+        .count()
+}
+
+/// Laplace noise with scale `sensitivity / epsilon`, where `sensitivity` is the most
+/// one client can change the count by; this makes the number epsilon-differentially private
+fn laplace(sensitivity: f64, epsilon: f64) -> f64 {
+    let u: f64 = rand::thread_rng().gen_range(-0.5..0.5);
+    -(sensitivity / epsilon) * u.signum() * (1.0 - 2.0 * u.abs()).max(f64::MIN_POSITIVE).ln()
+}
+
+/// Noisy, bucketed and small-count-suppressed version of a count
+fn anonymize(count: usize, sensitivity: usize, epsilon: f64) -> Option<i64> {
+    let noisy = (count as f64 + laplace(sensitivity as f64, epsilon)).round() as i64;
+    (noisy >= SUPPRESS_BELOW).then(|| noisy / BUCKET * BUCKET)
+}
+
+/// One client's chat log for one day
+pub struct ChatLog {
+    pub client_dir: PathBuf,
+    pub date: NaiveDate,
+    pub path: PathBuf,
+}
+
+fn chat_logs_of(clients: &[ClientUsage], since: Option<NaiveDate>) -> Vec<ChatLog> {
+    let mut logs = Vec::new();
+    for client in clients {
+        let Ok(entries) = fs::read_dir(client.path.join("chats")) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(date) = path
+                .file_stem()
+                .and_then(|stem| NaiveDate::parse_from_str(&stem.to_string_lossy(), "%d-%m-%y").ok())
+            else {
+                continue;
+            };
+            if since.is_none_or(|since| date >= since) {
+                logs.push(ChatLog {
+                    client_dir: client.path.clone(),
+                    date,
+                    path,
+                });
+            }
+        }
+    }
+    logs
+}
+
+/// Every client's chat logs dated `since` or later
+pub fn chat_logs(logs_dir: &str, since: NaiveDate) -> Result<Vec<ChatLog>> {
+    let clients = collect_usage(Path::new(logs_dir))?;
+    Ok(chat_logs_of(&clients, Some(since)))
+}
+
+/// Print daily active clients and messages as JSON without any identifiers,
+/// safe to publish from a public instance. Every number costs `epsilon`, so a
+/// client active on every day is covered by the reported `epsilon_total`
+pub fn print_anonymized(logs_dir: &str, epsilon: f64) -> Result<()> {
+    if epsilon <= 0.0 {
+        anyhow::bail!("--epsilon must be positive");
+    }
+
+    // date -> (active clients, messages)
+    let mut days: BTreeMap<NaiveDate, (usize, usize)> = BTreeMap::new();
+    let clients = collect_usage(Path::new(logs_dir))?;
+    for log in chat_logs_of(&clients, None) {
+        let day = days.entry(log.date).or_default();
+        day.0 += 1;
+        day.1 += count_messages(&log.path).min(MESSAGE_CAP);
+    }
+
+    let rollups: Vec<_> = days
+        .iter()
+        .map(|(date, (active, messages))| {
+            json!({
+                "date": date.format("%Y-%m-%d").to_string(),
+                "active_clients": anonymize(*active, 1, epsilon),
+                "messages": anonymize(*messages, MESSAGE_CAP, epsilon),
+            })
+        })
+        .collect();
+    // Two numbers a day plus the client total, composed sequentially
+    let published = 2 * rollups.len() + 1;
+    let export = json!({
+        "epsilon": epsilon,
+        "epsilon_total": epsilon * published as f64,
+        "bucket": BUCKET,
+        "suppressed_below": SUPPRESS_BELOW,
+        "messages_capped_at": MESSAGE_CAP,
+        "clients": anonymize(clients.len(), 1, epsilon),
+        "days": rollups,
+    });
+
+    println!("{}", serde_json::to_string_pretty(&export)?);
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Take an exclusive lock on `dir/name`, held until the returned file is dropped.
+/// Serializes read-modify-write cycles of concurrent sessions from the same client.
+pub fn lock(dir: &Path, name: &str) -> Result<File> {
+    fs::create_dir_all(dir).context("Failed to create client directory")?;
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(name))
+        .with_context(|| format!("Failed to open {}", name))?;
+    lock.lock_exclusive()
+        .with_context(|| format!("Failed to lock {}", name))?;
+    Ok(lock)
+}
+
+/// Write through a temp file and rename, so readers never see a half-written file
+pub fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    tmp.write_all(content.as_bytes())
+        .and_then(|_| tmp.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+/// Total size of all files below `path`
+pub fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Delete the oldest chat logs of a client until its directory fits in `quota` bytes.
+/// `keep` (today's log) is never removed. Returns the number of files deleted.
+pub fn enforce_quota(client_dir: &Path, quota: u64, keep: &Path) -> Result<usize> {
+    let mut usage = dir_size(client_dir);
+    if usage <= quota {
+        return Ok(0);
+    }
+
+    let mut chats: Vec<(SystemTime, u64, PathBuf)> = fs::read_dir(client_dir.join("chats"))
+        .context("Failed to list chat logs")?
+        .flatten()
+        .filter(|entry| entry.path() != keep)
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok()?;
+            let modified = meta.modified().ok()?;
+            Some((modified, meta.len(), entry.path()))
+        })
+        .collect();
+    chats.sort();
+
+    let mut removed = 0;
+    for (_, size, path) in chats {
+        if usage <= quota {
+            break;
+        }
+        fs::remove_file(&path)
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+        usage = usage.saturating_sub(size);
+        removed += 1;
+    }
+
+    Ok(removed)
+}
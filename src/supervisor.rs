@@ -0,0 +1,101 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::session::{Session, SessionContext};
+
+/// Panics from one address within this window count towards a crash loop
+const CRASH_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Panics within the window after which the address is banned
+const CRASH_LIMIT: usize = 3;
+const CRASH_BAN: Duration = Duration::from_secs(30 * 60);
+
+/// Panicked sessions, in total and recently per address
+#[derive(Default)]
+pub struct Supervisor {
+    panics: AtomicU64,
+    recent: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+}
+
+impl Supervisor {
+    /// Sessions that panicked since startup
+    #[cfg(feature = "metrics")]
+    pub fn panics(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    /// Count a panic of a session from `ip`, true once the address is crash looping
+    fn record(&self, ip: IpAddr) -> bool {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+        let mut recent = self.recent.lock().unwrap();
+        let now = Instant::now();
+        recent.retain(|_, times| {
+            times.retain(|t| now.duration_since(*t) < CRASH_WINDOW);
+            !times.is_empty()
+        });
+        let times = recent.entry(ip).or_default();
+        times.push(now);
+        if times.len() >= CRASH_LIMIT {
+            recent.remove(&ip);
+            return true;
+        }
+        false
+    }
+}
+
+/// Serve the connection in a task of its own, so a panic ends only this
+/// session. The panic is logged with the client's address, counted for
+/// `/metrics` and sent to the webhook; an address that keeps crashing
+/// sessions is banned for a while.
+pub async fn supervise(mut session: Session, addr: SocketAddr, ctx: Arc<SessionContext>) {
+    let handle = tokio::spawn(async move { session.run().await });
+    let panic = match handle.await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => {
+            error!("Session error for {}: {}", addr, e);
+            None
+        }
+        Err(e) if e.is_panic() => Some(describe(e.into_panic())),
+        // Cancelled with the runtime at shutdown
+        Err(_) => None,
+    };
+    info!("Connection closed: {}", addr);
+
+    let Some(message) = panic else {
+        return;
+    };
+    error!("Session for {} panicked: {}", addr, message);
+    let looping = ctx.supervisor.record(addr.ip());
+    if looping {
+        warn!(
+            "{} crashed {} sessions within {} minutes, banned for {} minutes",
+            addr.ip(),
+            CRASH_LIMIT,
+            CRASH_WINDOW.as_secs() / 60,
+            CRASH_BAN.as_secs() / 60
+        );
+        ctx.bans.ban_for(addr.ip(), CRASH_BAN);
+    }
+    if let Some(webhook) = &ctx.webhook {
+        let mut text = format!("Session for {} panicked: {}", addr, message);
+        if looping {
+            text.push_str(&format!(" (crash loop, banned for {} minutes)", CRASH_BAN.as_secs() / 60));
+        }
+        webhook.notify("session_panic", &text).await;
+    }
+}
+
+fn describe(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
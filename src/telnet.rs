@@ -0,0 +1,351 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+pub const IAC: u8 = 255;
+pub const DONT: u8 = 254;
+pub const DO: u8 = 253;
+pub const WONT: u8 = 252;
+pub const WILL: u8 = 251;
+pub const SB: u8 = 250;
+pub const SE: u8 = 240;
+
+pub const OPT_TTYPE: u8 = 24;
+pub const OPT_NAWS: u8 = 31;
+
+const TTYPE_IS: u8 = 0;
+const TTYPE_SEND: u8 = 1;
+
+/// Longest subnegotiation we keep, NAWS and TTYPE fit in far less
+const MAX_SUB: usize = 256;
+/// Longest terminal type we record
+const MAX_TTYPE: usize = 40;
+
+/// Options we ask every client about right after connecting
+pub fn initial_negotiation() -> Vec<u8> {
+    vec![IAC, DO, OPT_TTYPE, IAC, DO, OPT_NAWS]
+}
+
+fn option_name(opt: u8) -> String {
+    match opt {
+        0 => "BINARY".to_string(),
+        1 => "ECHO".to_string(),
+        3 => "SGA".to_string(),
+        5 => "STATUS".to_string(),
+        24 => "TTYPE".to_string(),
+        31 => "NAWS".to_string(),
+        32 => "TSPEED".to_string(),
+        33 => "LFLOW".to_string(),
+        34 => "LINEMODE".to_string(),
+        36 => "ENVIRON".to_string(),
+        39 => "NEW-ENVIRON".to_string(),
+        other => other.to_string(),
+    }
+}
+
+fn verb_name(verb: u8) -> &'static str {
+    match verb {
+        WILL => "WILL",
+        WONT => "WONT",
+        DO => "DO",
+        _ => "DONT",
+    }
+}
+
+/// Where one option stands, as the client last said
+#[derive(Debug, Clone, Copy, Default)]
+struct OptionState {
+    /// WILL (true) or WONT
+    will: Option<bool>,
+    /// DO (true) or DONT
+    asked: Option<bool>,
+}
+
+/// What we learned about the client software during the connection
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub terminal_type: Option<String>,
+    pub window_size: Option<(u16, u16)>,
+    options: BTreeMap<u8, OptionState>,
+    pub negotiation_ms: Option<u128>,
+    ttype_done: bool,
+    naws_done: bool,
+    started: Instant,
+}
+
+impl ClientInfo {
+    fn new() -> Self {
+        Self {
+            terminal_type: None,
+            window_size: None,
+            options: BTreeMap::new(),
+            negotiation_ms: None,
+            ttype_done: false,
+            naws_done: false,
+            started: Instant::now(),
+        }
+    }
+
+    fn mark_done(&mut self, opt: u8) {
+        match opt {
+            OPT_TTYPE => self.ttype_done = true,
+            OPT_NAWS => self.naws_done = true,
+            _ => {}
+        }
+        if self.ttype_done && self.naws_done && self.negotiation_ms.is_none() {
+            self.negotiation_ms = Some(self.started.elapsed().as_millis());
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let window = self
+            .window_size
+            .map(|(w, h)| format!("{}x{}", w, h))
+            .unwrap_or_else(|| "unknown".to_string());
+        let negotiation = self
+            .negotiation_ms
+            .map(|ms| format!("{}ms", ms))
+            .unwrap_or_else(|| "incomplete".to_string());
+        let options: Vec<String> = self
+            .options
+            .iter()
+            .flat_map(|(&opt, state)| {
+                let will = state.will.map(|will| if will { WILL } else { WONT });
+                let asked = state.asked.map(|asked| if asked { DO } else { DONT });
+                [will, asked]
+                    .into_iter()
+                    .flatten()
+                    .map(move |verb| format!("{} {}", verb_name(verb), option_name(opt)))
+            })
+            .collect();
+        let options = if options.is_empty() {
+            "none".to_string()
+        } else {
+            options.join(",")
+        };
+
+        format!(
+            "terminal={} window={} options={} negotiation={}",
+            self.terminal_type.as_deref().unwrap_or("unknown"),
+            window,
+            options,
+            negotiation
+        )
+    }
+}
+
+enum State {
+    Data,
+    Iac,
+    Verb(u8),
+    Sub,
+    SubIac,
+}
+
+/// Incremental telnet parser: strips IAC sequences out of the byte stream,
+/// records what the client negotiated and produces our replies.
+pub struct TelnetParser {
+    state: State,
+    sub: Vec<u8>,
+    /// The subnegotiation outgrew MAX_SUB and is dropped at its SE
+    sub_overflow: bool,
+    last_was_cr: bool,
+    pub info: ClientInfo,
+}
+
+impl TelnetParser {
+    pub fn new() -> Self {
+        Self {
+            state: State::Data,
+            sub: Vec::new(),
+            sub_overflow: false,
+            last_was_cr: false,
+            info: ClientInfo::new(),
+        }
+    }
+
+    /// Feed raw bytes from the socket, returning (user data, replies to send)
+    pub fn feed(&mut self, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut data = Vec::with_capacity(input.len());
+        let mut replies = Vec::new();
+
+        for &byte in input {
+            match self.state {
+                State::Data => {
+                    if byte == IAC {
+                        self.state = State::Iac;
+                    } else if byte == 0 && self.last_was_cr {
+                        // Telnet sends CR NUL for a bare carriage return
+                    } else {
+                        data.push(byte);
+                    }
+                    self.last_was_cr = byte == b'\r';
+                }
+                State::Iac => {
+                    self.state = match byte {
+                        IAC => {
+                            data.push(IAC);
+                            State::Data
+                        }
+                        WILL | WONT | DO | DONT => State::Verb(byte),
+                        SB => {
+                            self.sub.clear();
+                            self.sub_overflow = false;
+                            State::Sub
+                        }
+                        // NOP, AYT, GA and friends carry no data
+                        _ => State::Data,
+                    };
+                }
+                State::Verb(verb) => {
+                    self.handle_verb(verb, byte, &mut replies);
+                    self.state = State::Data;
+                }
+                State::Sub => {
+                    if byte == IAC {
+                        self.state = State::SubIac;
+                    } else {
+                        self.push_sub(byte);
+                    }
+                }
+                State::SubIac => {
+                    if byte == SE {
+                        if !self.sub_overflow {
+                            self.handle_subnegotiation();
+                        }
+                        self.state = State::Data;
+                    } else {
+                        // Escaped IAC inside the subnegotiation
+                        self.push_sub(byte);
+                        self.state = State::Sub;
+                    }
+                }
+            }
+        }
+
+        (data, replies)
+    }
+
+    fn push_sub(&mut self, byte: u8) {
+        if self.sub.len() < MAX_SUB {
+            self.sub.push(byte);
+        } else {
+            self.sub_overflow = true;
+        }
+    }
+
+    /// Answer only when the client changes an option's state, so two ends that
+    /// both acknowledge acknowledgements can't loop (RFC 1143)
+    fn handle_verb(&mut self, verb: u8, opt: u8, replies: &mut Vec<u8>) {
+        let state = self.info.options.entry(opt).or_default();
+        let (slot, value) = match verb {
+            WILL => (&mut state.will, true),
+            WONT => (&mut state.will, false),
+            DO => (&mut state.asked, true),
+            _ => (&mut state.asked, false),
+        };
+        let previous = slot.replace(value);
+        if previous == Some(value) {
+            return;
+        }
+
+        match (verb, opt) {
+            (WILL, OPT_TTYPE | OPT_NAWS) => {
+                // Our DO went out with the initial negotiation, once refused it's asked again
+                if previous == Some(false) {
+                    replies.extend_from_slice(&[IAC, DO, opt]);
+                }
+                // The size arrives in a subnegotiation right after, the type has to be asked for
+                if opt == OPT_TTYPE {
+                    replies.extend_from_slice(&[IAC, SB, OPT_TTYPE, TTYPE_SEND, IAC, SE]);
+                }
+            }
+            (WONT, OPT_TTYPE | OPT_NAWS) => {
+                // A client that takes back an option it agreed to is acknowledged
+                if previous == Some(true) {
+                    replies.extend_from_slice(&[IAC, DONT, opt]);
+                }
+                self.info.mark_done(opt);
+            }
+            (WILL, _) => replies.extend_from_slice(&[IAC, DONT, opt]),
+            (DO, _) => replies.extend_from_slice(&[IAC, WONT, opt]),
+            _ => {}
+        }
+    }
+
+    fn handle_subnegotiation(&mut self) {
+        match self.sub.first() {
+            Some(&OPT_NAWS) if self.sub.len() >= 5 => {
+                let width = u16::from_be_bytes([self.sub[1], self.sub[2]]);
+                let height = u16::from_be_bytes([self.sub[3], self.sub[4]]);
+                self.info.window_size = Some((width, height));
+                self.info.mark_done(OPT_NAWS);
+            }
+            Some(&OPT_TTYPE) if self.sub.get(1) == Some(&TTYPE_IS) => {
+                // Printable ASCII only, the name ends up in logs and the admin console
+                let name: String = self.sub[2..]
+                    .iter()
+                    .filter(|b| b.is_ascii_graphic() || **b == b' ')
+                    .take(MAX_TTYPE)
+                    .map(|&b| b as char)
+                    .collect();
+                let name = name.trim().to_string();
+                if !name.is_empty() {
+                    self.info.terminal_type = Some(name);
+                }
+                self.info.mark_done(OPT_TTYPE);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_requests_are_answered_once() {
+        let mut parser = TelnetParser::new();
+        let (_, replies) = parser.feed(&[IAC, WILL, 1, IAC, WILL, 1, IAC, DO, 3, IAC, DO, 3]);
+        assert_eq!(replies, vec![IAC, DONT, 1, IAC, WONT, 3]);
+        let (_, replies) = parser.feed(&[IAC, WILL, OPT_TTYPE, IAC, WILL, OPT_TTYPE]);
+        assert_eq!(replies, vec![IAC, SB, OPT_TTYPE, TTYPE_SEND, IAC, SE]);
+        assert_eq!(parser.info.options.len(), 3);
+    }
+
+    #[test]
+    fn oversize_subnegotiation_is_dropped() {
+        let mut parser = TelnetParser::new();
+        let mut input = vec![IAC, SB, OPT_TTYPE, TTYPE_IS];
+        input.extend(std::iter::repeat_n(b'x', MAX_SUB * 4));
+        input.extend_from_slice(&[IAC, SE]);
+        input.extend_from_slice(b"hi");
+        let (data, _) = parser.feed(&input);
+        assert_eq!(data, b"hi");
+        assert!(parser.sub.len() <= MAX_SUB);
+        assert_eq!(parser.info.terminal_type, None);
+    }
+
+    #[test]
+    fn terminal_type_is_printable_and_bounded() {
+        let mut parser = TelnetParser::new();
+        let mut input = vec![IAC, SB, OPT_TTYPE, TTYPE_IS];
+        input.extend_from_slice(b"XTERM\x1b[2J\n");
+        input.extend_from_slice(&[IAC, SE]);
+        parser.feed(&input);
+        assert_eq!(parser.info.terminal_type.as_deref(), Some("XTERM[2J"));
+
+        let mut input = vec![IAC, SB, OPT_TTYPE, TTYPE_IS];
+        input.extend(std::iter::repeat_n(b'A', 100));
+        input.extend_from_slice(&[IAC, SE]);
+        parser.feed(&input);
+        assert_eq!(parser.info.terminal_type.map(|name| name.len()), Some(MAX_TTYPE));
+    }
+
+    #[test]
+    fn naws_sets_the_window_size() {
+        let mut parser = TelnetParser::new();
+        parser.feed(&[IAC, WILL, OPT_NAWS, IAC, SB, OPT_NAWS, 0, 80, 0, 24, IAC, SE]);
+        assert_eq!(parser.info.window_size, Some((80, 24)));
+    }
+}
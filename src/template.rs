@@ -0,0 +1,127 @@
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// A guided prompt with `{slot}` placeholders the user is asked to fill in,
+/// e.g. `Review this {language} code for {concern}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Template {
+    pub name: String,
+    /// Shown in the `/template` list
+    #[serde(default)]
+    pub description: Option<String>,
+    pub prompt: String,
+    /// Question asked for a slot, by slot name; `language?` if not set
+    #[serde(default)]
+    pub questions: HashMap<String, String>,
+}
+
+fn is_slot_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Byte ranges of the `{slot}` placeholders in `prompt`, braces included
+fn placeholders(prompt: &str) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    let mut rest = 0;
+    while let Some(open) = prompt[rest..].find('{').map(|i| rest + i) {
+        let Some(close) = prompt[open..].find('}').map(|i| open + i) else {
+            break;
+        };
+        if is_slot_name(&prompt[open + 1..close]) {
+            found.push((open, close + 1));
+            rest = close + 1;
+        } else {
+            rest = open + 1;
+        }
+    }
+    found
+}
+
+impl Template {
+    pub fn slots(&self) -> Vec<String> {
+        slots(&self.prompt)
+    }
+
+    /// Fixed prompts belong in --starter, and every question needs a slot
+    pub fn validate(&self) -> Result<()> {
+        let slots = self.slots();
+        if slots.is_empty() {
+            bail!("Template {}: prompt has no {{slot}} to fill in", self.name);
+        }
+        if let Some(unused) = self.questions.keys().find(|q| !slots.contains(q)) {
+            bail!("Template {}: question for unknown slot {}", self.name, unused);
+        }
+        Ok(())
+    }
+
+    fn question(&self, slot: &str) -> String {
+        self.questions
+            .get(slot)
+            .cloned()
+            .unwrap_or_else(|| format!("{}?", slot))
+    }
+}
+
+/// Slot names in `text` in order of first appearance
+pub fn slots(text: &str) -> Vec<String> {
+    let mut slots: Vec<String> = Vec::new();
+    for (start, end) in placeholders(text) {
+        let name = &text[start + 1..end - 1];
+        if !slots.iter().any(|s| s == name) {
+            slots.push(name.to_string());
+        }
+    }
+    slots
+}
+
+/// Replace every `{slot}` in `text` with its value, unknown slots with nothing
+pub fn fill(text: &str, values: &HashMap<String, String>) -> String {
+    let mut filled = String::new();
+    let mut rest = 0;
+    for (start, end) in placeholders(text) {
+        filled.push_str(&text[rest..start]);
+        let name = &text[start + 1..end - 1];
+        filled.push_str(values.get(name).map_or("", String::as_str));
+        rest = end;
+    }
+    filled.push_str(&text[rest..]);
+    filled
+}
+
+/// A template being filled in, one answer per slot
+pub struct TemplateFill {
+    template: Template,
+    /// Slots still to ask, next one last
+    remaining: Vec<String>,
+    values: HashMap<String, String>,
+}
+
+impl TemplateFill {
+    pub fn new(template: Template) -> Self {
+        let mut remaining = template.slots();
+        remaining.reverse();
+        Self {
+            template,
+            remaining,
+            values: HashMap::new(),
+        }
+    }
+
+    /// Question for the next slot, `None` once every slot has a value
+    pub fn question(&self) -> Option<String> {
+        self.remaining.last().map(|slot| self.template.question(slot))
+    }
+
+    pub fn answer(&mut self, value: &str) {
+        if let Some(slot) = self.remaining.pop() {
+            self.values.insert(slot, value.to_string());
+        }
+    }
+
+    /// The message the filled-in template composes
+    pub fn message(&self) -> String {
+        fill(&self.template.prompt, &self.values)
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+
+/// Classic token bucket refilled at `rate` bytes per second
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        Self {
+            rate,
+            capacity: rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Wait until `n` bytes may be sent (`n` must not exceed the capacity)
+    async fn take(&mut self, n: usize) {
+        let n = n as f64;
+        loop {
+            self.refill();
+            if self.tokens >= n {
+                self.tokens -= n;
+                return;
+            }
+            let wait = (n - self.tokens) / self.rate;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
+
+/// Buffered session writer that optionally caps output bandwidth
+pub struct ThrottledWriter<W> {
+    inner: BufWriter<W>,
+    bucket: Option<TokenBucket>,
+    chunk_size: usize,
+    /// Bytes handed to `write_all` so far
+    written: u64,
+}
+
+impl<W: AsyncWrite + Unpin> ThrottledWriter<W> {
+    pub fn new(inner: W, max_rate: Option<u64>) -> Self {
+        let max_rate = max_rate.filter(|rate| *rate > 0);
+        // Small chunks keep the output smooth instead of bursty
+        let chunk_size = max_rate.map(|rate| (rate / 10).clamp(1, 1024) as usize).unwrap_or(0);
+
+        Self {
+            inner: BufWriter::new(inner),
+            bucket: max_rate.map(TokenBucket::new),
+            chunk_size,
+            written: 0,
+        }
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.written += buf.len() as u64;
+        let Some(bucket) = self.bucket.as_mut() else {
+            return self.inner.write_all(buf).await;
+        };
+
+        for chunk in buf.chunks(self.chunk_size) {
+            bucket.take(chunk.len()).await;
+            self.inner.write_all(chunk).await?;
+            self.inner.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().await
+    }
+}
@@ -0,0 +1,76 @@
+use crate::llm::Message;
+
+/// Tokens each message costs beyond its text, for the role and separators
+const MESSAGE_OVERHEAD: usize = 4;
+/// Room left for the reply when no max_tokens is set
+const DEFAULT_REPLY_RESERVE: usize = 512;
+/// Marks the cut at the start of a truncated message
+const TRUNCATED: &str = "[...] ";
+
+/// What `fit` had to do to a request
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Fit {
+    pub dropped: usize,
+    pub truncated: bool,
+}
+
+/// Quarter tokens for one character: English runs about four characters per
+/// token, other scripts closer to one
+fn quarters(c: char) -> usize {
+    if c.is_ascii() { 1 } else { 4 }
+}
+
+/// Rough token count of `text`, on the high side for anything but English
+pub fn estimate(text: &str) -> usize {
+    text.chars().map(quarters).sum::<usize>().div_ceil(4)
+}
+
+pub fn estimate_messages(messages: &[Message]) -> usize {
+    messages.iter().map(|m| estimate(&m.content) + MESSAGE_OVERHEAD).sum()
+}
+
+/// Make `messages` fit in `limit` tokens with room for a reply of
+/// `reply_tokens`: the oldest turns after the system messages are dropped,
+/// and when the latest message alone is too long, its beginning is cut off.
+/// The system messages are always kept.
+pub fn fit(messages: &mut Vec<Message>, limit: usize, reply_tokens: Option<u32>) -> Fit {
+    let reserve = reply_tokens.map_or(DEFAULT_REPLY_RESERVE, |t| t as usize);
+    let budget = limit.saturating_sub(reserve);
+    let mut fit = Fit::default();
+    let first_turn = messages.iter().take_while(|m| m.role == "system").count();
+
+    while estimate_messages(messages) > budget && messages.len() > first_turn + 1 {
+        messages.remove(first_turn);
+        fit.dropped += 1;
+        // Backends want the conversation to open with the user
+        while messages.len() > first_turn + 1 && messages[first_turn].role != "user" {
+            messages.remove(first_turn);
+            fit.dropped += 1;
+        }
+    }
+
+    let used = estimate_messages(messages);
+    if used > budget
+        && let Some(last) = messages.get_mut(first_turn)
+    {
+        let keep = estimate(&last.content).saturating_sub(used - budget);
+        last.content = format!("{}{}", TRUNCATED, tail(&last.content, keep.saturating_sub(estimate(TRUNCATED))));
+        fit.truncated = true;
+    }
+    fit
+}
+
+/// The end of `text` worth at most `tokens`
+fn tail(text: &str, tokens: usize) -> &str {
+    let mut left = tokens * 4;
+    let mut start = text.len();
+    for (i, c) in text.char_indices().rev() {
+        let cost = quarters(c);
+        if cost > left {
+            break;
+        }
+        left -= cost;
+        start = i;
+    }
+    &text[start..]
+}
@@ -0,0 +1,290 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::calc;
+#[cfg(feature = "tools")]
+use crate::fetch::{FetchPolicy, FetchTool};
+use crate::greeting;
+#[cfg(feature = "tools")]
+use crate::search::{SearchConfig, SearchTool};
+
+/// Requests one answer may take before the model has to reply without tools
+pub const MAX_ROUNDS: usize = 5;
+
+/// Answers a call with the text the model reads, from its parsed arguments
+#[async_trait]
+pub trait Handler: Send + Sync {
+    async fn call(&self, arguments: &Value) -> Result<String>;
+
+    /// The answer with the URLs it came from, listed to the user under the reply
+    async fn call_sourced(&self, arguments: &Value) -> Result<(String, Vec<String>)> {
+        Ok((self.call(arguments).await?, Vec::new()))
+    }
+}
+
+/// Plain functions answer right away
+#[async_trait]
+impl<F> Handler for F
+where
+    F: Fn(&Value) -> Result<String> + Send + Sync,
+{
+    async fn call(&self, arguments: &Value) -> Result<String> {
+        self(arguments)
+    }
+}
+
+/// Tools that come with the server, offered with --tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Builtin {
+    /// Current date and time, optionally at a UTC offset
+    Time,
+    /// Arithmetic expressions
+    Calculator,
+    /// Dice rolls such as 2d6+1
+    Dice,
+    /// Web pages as text, under --fetch-allow and --fetch-deny
+    Fetch,
+    /// Web searches through --search-engine
+    Search,
+}
+
+/// Search services the web_search tool can ask, picked with --search-engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Engine {
+    /// A SearxNG instance with the JSON format enabled, at --search-url
+    Searxng,
+    /// The Brave Search API, with --search-key
+    Brave,
+    /// DuckDuckGo's Instant Answer API, no key but mostly encyclopedic answers
+    Duckduckgo,
+}
+
+/// What a call gives back to the model, and the pages that backed it
+pub struct ToolOutput {
+    pub content: String,
+    pub sources: Vec<String>,
+}
+
+/// A function the model asked for, as `/chat/completions` reports it in `tool_calls`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON object as a string, as the model wrote it
+    #[serde(default)]
+    pub arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+struct Tool {
+    name: String,
+    description: String,
+    /// JSON Schema of the arguments object
+    parameters: Value,
+    handler: Box<dyn Handler>,
+}
+
+/// The tools offered to the model. Sessions hand it to the provider with every
+/// request; providers that can call tools send the schemas and run the calls.
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+}
+
+impl ToolRegistry {
+    /// A registry with the `builtins` in it, except fetch and search which come
+    /// with `with_web`
+    pub fn with_builtins(builtins: &[Builtin]) -> Self {
+        let mut registry = Self::default();
+        for builtin in builtins {
+            match builtin {
+                Builtin::Time => registry.register(
+                    "current_time",
+                    "Get the current date, time and weekday. Use it whenever the answer depends on today's date or the time.",
+                    json!({
+                        "type": "object",
+                        "properties": {
+                            "utc_offset": {
+                                "type": "string",
+                                "description": "UTC offset such as +02:00 or -5; the server's local time if left out"
+                            }
+                        }
+                    }),
+                    Box::new(current_time),
+                ),
+                Builtin::Calculator => registry.register(
+                    "calculate",
+                    "Evaluate an arithmetic expression exactly instead of working it out. Supports + - * / % ^, \
+                     parentheses, pi, e, sqrt, abs, round, floor, ceil, ln, log10, sin, cos and tan.",
+                    json!({
+                        "type": "object",
+                        "properties": {
+                            "expression": { "type": "string", "description": "e.g. (17.5 * 3) / 4 + 2^10" }
+                        },
+                        "required": ["expression"]
+                    }),
+                    Box::new(calculate),
+                ),
+                Builtin::Dice => registry.register(
+                    "roll_dice",
+                    "Roll dice for games and random choices.",
+                    json!({
+                        "type": "object",
+                        "properties": {
+                            "dice": { "type": "string", "description": "Dice notation such as d20, 3d6 or 2d8+1" }
+                        },
+                        "required": ["dice"]
+                    }),
+                    Box::new(roll_dice),
+                ),
+                Builtin::Fetch | Builtin::Search => {}
+            }
+        }
+        registry
+    }
+
+    /// Add the fetch and search tools among `builtins`, fetching under `fetch`
+    /// and searching with `search`; the search tool is left out without one
+    #[cfg(feature = "tools")]
+    pub fn with_web(mut self, builtins: &[Builtin], fetch: &FetchPolicy, search: Option<&SearchConfig>) -> Self {
+        for builtin in builtins {
+            match builtin {
+                Builtin::Fetch => self.register(
+                    "fetch_url",
+                    "Download a web page and read its text, e.g. to summarize a page the user links to.",
+                    json!({
+                        "type": "object",
+                        "properties": {
+                            "url": { "type": "string", "description": "http or https URL of the page" }
+                        },
+                        "required": ["url"]
+                    }),
+                    Box::new(FetchTool(fetch.clone())),
+                ),
+                Builtin::Search => {
+                    if let Some(search) = search {
+                        self.register(
+                            "web_search",
+                            "Search the web for current events, recent facts or anything that may have changed since \
+                             your training. Cite the URLs of the results you use.",
+                            json!({
+                                "type": "object",
+                                "properties": {
+                                    "query": { "type": "string", "description": "Search terms, e.g. weather Warsaw tomorrow" }
+                                },
+                                "required": ["query"]
+                            }),
+                            Box::new(SearchTool(search.clone())),
+                        );
+                    }
+                }
+                Builtin::Time | Builtin::Calculator | Builtin::Dice => {}
+            }
+        }
+        self
+    }
+
+    /// Offer `name` to the model; `parameters` is the JSON Schema of its arguments
+    pub fn register(&mut self, name: &str, description: &str, parameters: Value, handler: Box<dyn Handler>) {
+        self.tools.retain(|t| t.name != name);
+        self.tools.push(Tool {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters,
+            handler,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// The `tools` of a chat completion request
+    pub fn schemas(&self) -> Vec<Value> {
+        self.tools
+            .iter()
+            .map(|t| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": t.name,
+                        "description": t.description,
+                        "parameters": t.parameters,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Run `call` and return what the model gets back. Failures are told to the
+    /// model too, so it can correct the arguments or answer without the tool.
+    pub async fn call(&self, call: &ToolCall) -> ToolOutput {
+        let failed = |content: String| ToolOutput {
+            content,
+            sources: Vec::new(),
+        };
+        let Some(tool) = self.tools.iter().find(|t| t.name == call.function.name) else {
+            warn!("The model called unknown tool {}", call.function.name);
+            return failed(format!("Error: there is no tool named {}", call.function.name));
+        };
+        let arguments = match call.function.arguments.trim() {
+            "" => Value::Object(Default::default()),
+            text => match serde_json::from_str(text) {
+                Ok(arguments) => arguments,
+                Err(e) => return failed(format!("Error: the arguments are not valid JSON: {}", e)),
+            },
+        };
+        match tool.handler.call_sourced(&arguments).await {
+            Ok((content, sources)) => {
+                info!("Tool {} called with {}", tool.name, arguments);
+                ToolOutput { content, sources }
+            }
+            Err(e) => {
+                info!("Tool {} failed with {}: {}", tool.name, arguments, e);
+                failed(format!("Error: {}", e))
+            }
+        }
+    }
+}
+
+/// String argument `name`, if the model passed one
+fn argument<'a>(arguments: &'a Value, name: &str) -> Option<&'a str> {
+    arguments.get(name).and_then(Value::as_str)
+}
+
+fn current_time(arguments: &Value) -> Result<String> {
+    let now = match argument(arguments, "utc_offset") {
+        Some(offset) => {
+            let offset = greeting::parse_offset(offset)
+                .ok_or_else(|| anyhow::anyhow!("{:?} is not a UTC offset like +02:00", offset))?;
+            chrono::Utc::now().with_timezone(&offset).fixed_offset()
+        }
+        None => chrono::Local::now().fixed_offset(),
+    };
+    Ok(now.format("%A, %Y-%m-%d %H:%M:%S (UTC%:z)").to_string())
+}
+
+fn calculate(arguments: &Value) -> Result<String> {
+    let expression = argument(arguments, "expression").ok_or_else(|| anyhow::anyhow!("expression is missing"))?;
+    Ok(format!("{} = {}", expression, calc::format(calc::evaluate(expression)?)))
+}
+
+fn roll_dice(arguments: &Value) -> Result<String> {
+    let dice = argument(arguments, "dice").ok_or_else(|| anyhow::anyhow!("dice is missing"))?;
+    let (rolls, total) = calc::roll(dice)?;
+    let rolls: Vec<String> = rolls.iter().map(u32::to_string).collect();
+    Ok(format!("{}: rolled {} for a total of {}", dice, rolls.join(", "), total))
+}
@@ -0,0 +1,242 @@
+use anyhow::{Context, Result, bail};
+use hmac::{Hmac, Mac};
+use qrcode::QrCode;
+use qrcode::render::unicode;
+use sha1::Sha1;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Where the admin console's TOTP secret lives, base32 encoded
+pub fn secret_path(logs_dir: &str) -> PathBuf {
+    Path::new(logs_dir).join("admin-totp.secret")
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.trim().trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// RFC 6238 code for one time step
+fn code_at(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]])
+        & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+/// The time step `code` belongs to, the current one or one either side for clock drift
+fn step_of(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    let code = code.trim().parse::<u32>().ok()?;
+    let counter = now / STEP_SECS;
+    [counter.saturating_sub(1), counter, counter + 1]
+        .into_iter()
+        .find(|&c| code_at(secret, c) == code)
+}
+
+/// A provisioned secret and the last time step a code was accepted for
+pub struct Authenticator {
+    secret: Vec<u8>,
+    last_step: AtomicU64,
+}
+
+impl Authenticator {
+    fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret,
+            last_step: AtomicU64::new(0),
+        }
+    }
+
+    /// Accept a valid code once; a code that was overheard, or any code of an
+    /// earlier step, is refused after a login with it
+    pub fn verify(&self, code: &str) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.verify_at(code, now)
+    }
+
+    fn verify_at(&self, code: &str, now: u64) -> bool {
+        let Some(step) = step_of(&self.secret, code, now) else {
+            return false;
+        };
+        self.last_step
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| (step > last).then_some(step))
+            .is_ok()
+    }
+}
+
+/// The provisioned secret, `None` when two-factor login isn't set up. A secret
+/// file that exists but can't be read is an error, not a reason to skip the factor.
+pub fn load_secret(logs_dir: &str) -> Result<Option<Authenticator>> {
+    let path = secret_path(logs_dir);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read TOTP secret from {}", path.display())),
+    };
+    match base32_decode(&text) {
+        Some(secret) if !secret.is_empty() => Ok(Some(Authenticator::new(secret))),
+        _ => bail!("Invalid TOTP secret in {}", path.display()),
+    }
+}
+
+/// Generate a new secret, store it and print the otpauth URI and a QR code for authenticator apps
+pub fn provision(logs_dir: &str) -> Result<()> {
+    let secret: [u8; 20] = rand::random();
+    let encoded = base32_encode(&secret);
+
+    let path = secret_path(logs_dir);
+    fs::create_dir_all(logs_dir).context("Failed to create logs directory")?;
+    fs::write(&path, format!("{}\n", encoded)).context("Failed to write TOTP secret")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+            .context("Failed to restrict TOTP secret permissions")?;
+    }
+
+    let uri = format!(
+        "otpauth://totp/telllm:admin?secret={}&issuer=telllm&digits={}&period={}",
+        encoded, DIGITS, STEP_SECS
+    );
+    let qr = QrCode::new(uri.as_bytes())
+        .context("Failed to encode QR code")?
+        .render::<unicode::Dense1x2>()
+        .quiet_zone(true)
+        .build();
+
+    println!("{}\n", qr);
+    println!("{}\n", uri);
+    println!("Secret saved to {}", path.display());
+    println!("The admin console asks for a code from now on (restart the server to apply).");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 key of RFC 6238 appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc6238_vectors() {
+        // The RFC lists 8 digits, we hand out the last 6
+        for (time, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ] {
+            assert_eq!(code_at(RFC_SECRET, time / STEP_SECS), code, "T = {}", time);
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift() {
+        let now = 1111111111;
+        let code = |time: u64| format!("{:06}", code_at(RFC_SECRET, time / STEP_SECS));
+        let step = now / STEP_SECS;
+        assert_eq!(step_of(RFC_SECRET, &code(now), now), Some(step));
+        assert_eq!(step_of(RFC_SECRET, &code(now - STEP_SECS), now), Some(step - 1));
+        assert_eq!(step_of(RFC_SECRET, &code(now + STEP_SECS), now), Some(step + 1));
+        assert_eq!(step_of(RFC_SECRET, &code(now - 2 * STEP_SECS), now), None);
+        assert_eq!(step_of(RFC_SECRET, &code(now + 2 * STEP_SECS), now), None);
+        assert_eq!(step_of(RFC_SECRET, "not a code", now), None);
+    }
+
+    #[test]
+    fn refuses_replayed_and_older_codes() {
+        let now = 1111111111;
+        let code = |time: u64| format!("{:06}", code_at(RFC_SECRET, time / STEP_SECS));
+        let auth = Authenticator::new(RFC_SECRET.to_vec());
+        assert!(auth.verify_at(&code(now), now));
+        assert!(!auth.verify_at(&code(now), now));
+        assert!(!auth.verify_at(&code(now - STEP_SECS), now));
+        assert!(auth.verify_at(&code(now + STEP_SECS), now + STEP_SECS));
+    }
+
+    #[test]
+    fn unreadable_secret_is_an_error() {
+        let dir = std::env::temp_dir().join(format!("telllm-totp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let logs_dir = dir.to_str().unwrap();
+        assert!(load_secret(logs_dir).unwrap().is_none());
+        // A directory in the secret's place can't be read as a file
+        fs::create_dir_all(secret_path(logs_dir)).unwrap();
+        assert!(load_secret(logs_dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn base32_rfc4648_vectors() {
+        for (plain, encoded) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32_encode(plain.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).as_deref(), Some(plain.as_bytes()));
+        }
+        assert_eq!(base32_decode("mzxw6===\n").as_deref(), Some(&b"foo"[..]));
+        assert_eq!(base32_decode("MZXW1"), None);
+    }
+
+    #[test]
+    fn base32_round_trips_secrets() {
+        for len in 0..=40 {
+            let secret: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+            assert_eq!(base32_decode(&base32_encode(&secret)), Some(secret));
+        }
+    }
+}
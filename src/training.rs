@@ -0,0 +1,284 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDateTime};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::profile;
+use crate::redact::Redactor;
+
+/// Roles in the logs that aren't the user talking to the model
+const NOT_USER_ROLES: [&str; 5] = ["SYSTEM", "CLIENT", "OPERATOR", "HTTP", "VISITOR"];
+
+enum Turn {
+    User(String),
+    Assistant(String),
+}
+
+struct Session {
+    started: Option<DateTime<Local>>,
+    turns: Vec<Turn>,
+}
+
+/// One user message and the reply to it, a line of the export
+#[derive(Serialize)]
+struct Pair {
+    prompt: String,
+    response: String,
+}
+
+/// Where the pairs of one session came from, so they can be traced or pulled later
+#[derive(Serialize)]
+struct ManifestEntry {
+    client: String,
+    log: String,
+    started: String,
+    pairs: usize,
+}
+
+/// Write prompt/response pairs from the sessions of clients who opted in with
+/// /contribute to `out` as JSONL, redacted, with a manifest of the sessions next to it
+pub fn export(logs_dir: &str, out: &Path, redactor: &Redactor) -> Result<()> {
+    let logs_dir = Path::new(logs_dir);
+    let mut clients = Vec::new();
+    consenting_clients(logs_dir, &mut clients, 0);
+
+    let file = File::create(out).with_context(|| format!("Failed to create {}", out.display()))?;
+    let mut writer = BufWriter::new(file);
+    let mut manifest = Vec::new();
+    let mut total_pairs = 0;
+    let mut redacted = 0;
+
+    for (client_dir, since) in &clients {
+        let client = client_dir
+            .strip_prefix(logs_dir)
+            .unwrap_or(client_dir)
+            .to_string_lossy()
+            .into_owned();
+        for log in chat_files(client_dir) {
+            let Ok(content) = fs::read_to_string(&log) else {
+                continue;
+            };
+            let sessions = match log.extension().and_then(|ext| ext.to_str()) {
+                Some("jsonl") => parse_jsonl(&content),
+                _ => parse_text(&content),
+            };
+            for session in sessions {
+                // Sessions from before the opt-in, or with no start we can check, stay out
+                let Some(started) = session.started.filter(|started| started >= since) else {
+                    continue;
+                };
+                let mut pairs = 0;
+                for (prompt, response) in pairs_of(&session.turns) {
+                    let (prompt, a) = redactor.redact(prompt);
+                    let (response, b) = redactor.redact(response);
+                    redacted += a + b;
+                    serde_json::to_writer(&mut writer, &Pair { prompt, response })?;
+                    writeln!(writer)?;
+                    pairs += 1;
+                }
+                if pairs > 0 {
+                    total_pairs += pairs;
+                    manifest.push(ManifestEntry {
+                        client: client.clone(),
+                        log: log
+                            .strip_prefix(client_dir)
+                            .unwrap_or(&log)
+                            .to_string_lossy()
+                            .into_owned(),
+                        started: started.to_rfc3339(),
+                        pairs,
+                    });
+                }
+            }
+        }
+    }
+    writer.flush().with_context(|| format!("Failed to write {}", out.display()))?;
+
+    let manifest_path = manifest_path(out);
+    let manifest_json = json!({
+        "created": Local::now().to_rfc3339(),
+        "export": out.file_name().map(|name| name.to_string_lossy().into_owned()),
+        "clients": clients.len(),
+        "pairs": total_pairs,
+        "redactions": redacted,
+        "sessions": manifest,
+    });
+    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest_json)?)
+        .with_context(|| format!("Failed to write {}", manifest_path.display()))?;
+
+    println!(
+        "Exported {} pairs from {} sessions of {} contributing clients to {}, {} redactions",
+        total_pairs,
+        manifest_json["sessions"].as_array().map_or(0, Vec::len),
+        clients.len(),
+        out.display(),
+        redacted
+    );
+    println!("Manifest: {}", manifest_path.display());
+    Ok(())
+}
+
+/// `data.jsonl` -> `data.manifest.json`
+fn manifest_path(out: &Path) -> PathBuf {
+    let stem = out.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    out.with_file_name(format!("{}.manifest.json", stem))
+}
+
+/// Client directories whose profile has consent, with when it was given. Covers
+/// IP directories, named users under them and accounts/.
+fn consenting_clients(dir: &Path, clients: &mut Vec<(PathBuf, DateTime<Local>)>, depth: usize) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).filter(|path| path.is_dir()).collect();
+    paths.sort();
+    for path in paths {
+        if path.file_name().is_some_and(|name| name == "chats") {
+            continue;
+        }
+        // A profile that can't be read gives no consent
+        let profile = profile::load(&path).unwrap_or_else(|e| {
+            warn!("{:#}", e);
+            None
+        });
+        if let Some(since) = profile.and_then(|p| p.contributing_since) {
+            clients.push((path.clone(), since));
+        }
+        if depth < 1 {
+            consenting_clients(&path, clients, depth + 1);
+        }
+    }
+}
+
+fn chat_files(client_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(client_dir.join("chats")) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt" || ext == "jsonl"))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Each user message answered directly by the model. When the user sent several
+/// lines before the reply only the last one is the prompt.
+fn pairs_of(turns: &[Turn]) -> Vec<(&str, &str)> {
+    turns
+        .windows(2)
+        .filter_map(|pair| match pair {
+            [Turn::User(prompt), Turn::Assistant(response)] => Some((prompt.as_str(), response.as_str())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn parse_jsonl(content: &str) -> Vec<Session> {
+    let mut sessions: Vec<Session> = Vec::new();
+    for line in content.lines() {
+        let Ok(entry) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        match entry["event"].as_str() {
+            Some("session_start") => sessions.push(Session {
+                started: entry["time"]
+                    .as_str()
+                    .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                    .map(|time| time.with_timezone(&Local)),
+                turns: Vec::new(),
+            }),
+            Some("message") => {
+                let (Some(role), Some(content), Some(session)) =
+                    (entry["role"].as_str(), entry["content"].as_str(), sessions.last_mut())
+                else {
+                    continue;
+                };
+                if entry.get("model").is_some() {
+                    session.turns.push(Turn::Assistant(content.to_string()));
+                } else if !NOT_USER_ROLES.contains(&role) {
+                    session.turns.push(Turn::User(content.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    sessions
+}
+
+fn parse_text(content: &str) -> Vec<Session> {
+    let mut sessions: Vec<Session> = Vec::new();
+    // Lines that belong to no one, e.g. SYSTEM notes, are read into None
+    let mut current: Option<Turn> = None;
+    let mut skipping = false;
+
+    fn flush(sessions: &mut [Session], current: &mut Option<Turn>) {
+        if let (Some(turn), Some(session)) = (current.take(), sessions.last_mut()) {
+            let turn = match turn {
+                Turn::User(text) => Turn::User(text.trim_end().to_string()),
+                Turn::Assistant(text) => Turn::Assistant(text.trim_end().to_string()),
+            };
+            session.turns.push(turn);
+        }
+    }
+
+    for line in content.lines() {
+        if let Some(started) = line.strip_prefix("--- Session started at ").and_then(|rest| rest.strip_suffix(" ---")) {
+            flush(&mut sessions, &mut current);
+            skipping = false;
+            sessions.push(Session {
+                started: NaiveDateTime::parse_from_str(started, "%d-%m-%Y %H:%M:%S")
+                    .ok()
+                    .and_then(|time| time.and_local_timezone(Local).single()),
+                turns: Vec::new(),
+            });
+            continue;
+        }
+        if line.starts_with("--- Session ") && line.ends_with(" ---") {
+            flush(&mut sessions, &mut current);
+            skipping = true;
+            continue;
+        }
+        if let Some((role, assistant, text)) = parse_header(line) {
+            flush(&mut sessions, &mut current);
+            skipping = !assistant && NOT_USER_ROLES.contains(&role);
+            if !skipping {
+                current = Some(if assistant {
+                    Turn::Assistant(text.to_string())
+                } else {
+                    Turn::User(text.to_string())
+                });
+            }
+            continue;
+        }
+        // Multi-line messages carry on without a header
+        if !skipping && let Some(Turn::User(text) | Turn::Assistant(text)) = &mut current {
+            text.push('\n');
+            text.push_str(line);
+        }
+    }
+    flush(&mut sessions, &mut current);
+    sessions
+}
+
+/// `[HH:MM:SS] ROLE: text` or `[HH:MM:SS] LABEL [model=...]: text` for the model
+fn parse_header(line: &str) -> Option<(&str, bool, &str)> {
+    let rest = line.strip_prefix('[')?;
+    let (time, rest) = rest.split_once("] ")?;
+    if time.len() != 8 || !time.bytes().all(|b| b.is_ascii_digit() || b == b':') {
+        return None;
+    }
+    if let Some((label, rest)) = rest.split_once(" [model=")
+        && !label.contains(':')
+        && let Some((_, text)) = rest.split_once("]: ")
+    {
+        return Some((label, true, text));
+    }
+    let (role, text) = rest.split_once(": ")?;
+    Some((role, false, text))
+}
@@ -0,0 +1,59 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::logger;
+use crate::session::SessionContext;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Watch free space on the logs volume: below `min_free` bytes logging is
+/// suspended (degraded mode) until space frees up again
+pub async fn run(ctx: Arc<SessionContext>, min_free: u64) {
+    let logs_dir = Path::new(&ctx.logs_dir);
+    if let Err(e) = fs::create_dir_all(logs_dir) {
+        warn!("Disk watchdog disabled, can't create {}: {}", logs_dir.display(), e);
+        return;
+    }
+
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let free = match fs2::available_space(logs_dir) {
+            Ok(free) => free,
+            Err(e) => {
+                warn!("Failed to check free space on {}: {}", logs_dir.display(), e);
+                continue;
+            }
+        };
+
+        let low = free < min_free;
+        if low == logger::logging_suspended() {
+            continue;
+        }
+        logger::set_logging_suspended(low);
+
+        let text = if low {
+            let text = format!(
+                "Low disk space ({} MB free), chat logging suspended",
+                free / (1024 * 1024)
+            );
+            warn!("{}", text);
+            text
+        } else {
+            let text = format!(
+                "Disk space recovered ({} MB free), chat logging resumed",
+                free / (1024 * 1024)
+            );
+            info!("{}", text);
+            text
+        };
+
+        if let Some(webhook) = &ctx.webhook {
+            webhook.notify("disk_space", &text).await;
+        }
+    }
+}
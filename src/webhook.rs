@@ -0,0 +1,36 @@
+use serde_json::json;
+use tracing::warn;
+
+use crate::proxy;
+
+/// Operator notifications posted as JSON (`{"event", "text"}`, Slack/Discord friendly)
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: proxy::client(),
+            url,
+        }
+    }
+
+    /// Fire and forget: failures are only logged
+    pub async fn notify(&self, event: &str, text: &str) {
+        let payload = json!({
+            "event": event,
+            "text": format!("[telllm] {}", text),
+            "content": format!("[telllm] {}", text),
+        });
+
+        match self.client.post(&self.url).json(&payload).send().await {
+            Ok(response) if !response.status().is_success() => {
+                warn!("Webhook returned {} for {}", response.status(), event);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to deliver webhook for {}: {}", event, e),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::llm::{LlmProvider, Message};
+use crate::proxy;
+use crate::template;
+
+/// One step of a wizard. Texts may use `{value}` placeholders for earlier answers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Step {
+    /// Ask the user and store the answer as `ask`
+    Ask {
+        ask: String,
+        #[serde(default)]
+        question: Option<String>,
+    },
+    /// Send `llm` as a one-off prompt and store the reply as `into`
+    Llm { llm: String, into: String },
+    /// Show `confirm` and stop unless the user says yes
+    Confirm { confirm: String },
+    /// POST every value collected so far as JSON to this URL
+    Webhook { webhook: String },
+}
+
+/// An operator-defined multi-step flow, `[[wizard]]` in the config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Wizard {
+    pub name: String,
+    /// Shown in the `/wizard` list
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(rename = "step")]
+    pub steps: Vec<Step>,
+    /// Shown once the last step is done
+    #[serde(default)]
+    pub done: Option<String>,
+}
+
+impl Wizard {
+    /// Every value a step uses has to come from an earlier step
+    pub fn validate(&self) -> Result<()> {
+        if self.steps.is_empty() {
+            bail!("Wizard {}: no steps", self.name);
+        }
+        let mut known: Vec<&str> = Vec::new();
+        let check = |text: &str, known: &[&str], what: &str| {
+            match template::slots(text).into_iter().find(|slot| !known.contains(&slot.as_str())) {
+                Some(slot) => bail!("Wizard {}: {} uses {{{}}} before any step sets it", self.name, what, slot),
+                None => Ok(()),
+            }
+        };
+        for (index, step) in self.steps.iter().enumerate() {
+            let what = format!("step {}", index + 1);
+            match step {
+                Step::Ask { ask, question } => {
+                    check(question.as_deref().unwrap_or(""), &known, &what)?;
+                    known.push(ask);
+                }
+                Step::Llm { llm, into } => {
+                    check(llm, &known, &what)?;
+                    known.push(into);
+                }
+                Step::Confirm { confirm } => check(confirm, &known, &what)?,
+                Step::Webhook { .. } => {}
+            }
+        }
+        check(self.done.as_deref().unwrap_or(""), &known, "done")
+    }
+}
+
+/// Where a running wizard stands after the user's last answer
+pub enum Progress {
+    /// Show this and wait for the next answer
+    Waiting(String),
+    /// The wizard is over, successfully or not; show this
+    Finished(String),
+}
+
+/// A wizard in progress within one session
+pub struct WizardRun {
+    wizard: Wizard,
+    step: usize,
+    values: HashMap<String, String>,
+    client: reqwest::Client,
+}
+
+impl WizardRun {
+    pub fn new(wizard: Wizard) -> Self {
+        Self {
+            wizard,
+            step: 0,
+            values: HashMap::new(),
+            client: proxy::client(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.wizard.name
+    }
+
+    /// Take the answer to the current question, if any, and run steps until the
+    /// next question or the end. LLM steps use `model`.
+    pub async fn advance(&mut self, answer: Option<&str>, llm: &dyn LlmProvider, model: &str) -> Progress {
+        if let Some(answer) = answer {
+            match self.wizard.steps.get(self.step) {
+                Some(Step::Ask { ask, .. }) => {
+                    self.values.insert(ask.clone(), answer.to_string());
+                }
+                Some(Step::Confirm { .. }) if !matches!(answer.to_lowercase().as_str(), "yes" | "y") => {
+                    return Progress::Finished(format!("{} cancelled.", self.wizard.name));
+                }
+                _ => {}
+            }
+            self.step += 1;
+        }
+
+        while let Some(step) = self.wizard.steps.get(self.step) {
+            match step {
+                Step::Ask { ask, question } => {
+                    let question = question.clone().unwrap_or_else(|| format!("{}?", ask));
+                    return Progress::Waiting(template::fill(&question, &self.values));
+                }
+                Step::Confirm { confirm } => {
+                    let text = template::fill(confirm, &self.values);
+                    return Progress::Waiting(format!("{} (yes / no)", text));
+                }
+                Step::Llm { llm: prompt, into } => {
+                    let messages = [Message {
+                        role: "user".to_string(),
+                        content: template::fill(prompt, &self.values),
+                    }];
+                    match llm.chat_with(model, &messages).await {
+                        Ok(reply) => {
+                            self.values.insert(into.clone(), reply.trim().to_string());
+                        }
+                        Err(e) => {
+                            warn!("Wizard {} LLM step failed: {}", self.wizard.name, e);
+                            return Progress::Finished(format!("Sorry, I encountered an error: {}", e));
+                        }
+                    }
+                }
+                Step::Webhook { webhook } => {
+                    let body = json!({ "wizard": self.wizard.name, "values": self.values });
+                    let sent = self.client.post(webhook).json(&body).send().await;
+                    if let Err(e) = sent.and_then(|response| response.error_for_status()) {
+                        warn!("Wizard {} webhook failed: {}", self.wizard.name, e);
+                        let text = "Sorry, that couldn't be submitted. Please try again later.";
+                        return Progress::Finished(text.to_string());
+                    }
+                }
+            }
+            self.step += 1;
+        }
+
+        let done = self.wizard.done.as_deref().unwrap_or("Done!");
+        Progress::Finished(template::fill(done, &self.values))
+    }
+}